@@ -1,7 +1,57 @@
+use crate::layout::LayoutTranslation;
 use enigo::{Direction, Enigo, Key, Keyboard};
 use std::thread;
 use std::time::Duration;
 
+/// 修饰键位于键盘的哪一侧，未指明时视为左侧
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// 修饰键
+/// macOS 下 Control 会被当作 Command 发送（与 "ctrl+c" 在 macOS 上的习惯一致）
+/// 排序即按下顺序：Shift、Control、Alt、Meta，同种先左后右
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Modifier {
+    Shift(Side),
+    Control(Side),
+    Alt(Side),
+    Meta(Side),
+}
+
+/// 无法用单个字符表示的按键
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamedKey {
+    Numpad(u8), // 小键盘 0-9
+    NumpadAdd,
+    NumpadSubtract,
+    NumpadMultiply,
+    NumpadDivide,
+    NumpadDecimal,
+    NumpadEnter,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MainKey {
+    Char(char),
+    Named(NamedKey),
+}
+
+/// 解析后的按键字符串
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedKey {
+    /// 已排序去重，与书写顺序无关
+    pub modifiers: Vec<Modifier>,
+    /// 为 None 时表示只按修饰键本身（如 "rshift"、"shift+ctrl"）
+    pub main: Option<MainKey>,
+}
+
 /// 将字符映射到 macOS 虚拟键码
 /// 使用 kVK_ANSI_* 键码
 #[cfg(target_os = "macos")]
@@ -14,11 +64,24 @@ fn char_to_macos_keycode(ch: char) -> Option<u16> {
         'u' => Some(0x20), 'v' => Some(0x09), 'w' => Some(0x0D), 'x' => Some(0x07), 'y' => Some(0x10),
         'z' => Some(0x06), '0' => Some(0x1D), '1' => Some(0x12), '2' => Some(0x13), '3' => Some(0x14),
         '4' => Some(0x15), '5' => Some(0x17), '6' => Some(0x16), '7' => Some(0x1A), '8' => Some(0x1C),
-        '9' => Some(0x19),
+        '9' => Some(0x19), '-' => Some(0x1B), '=' => Some(0x18), '[' => Some(0x21), ']' => Some(0x1E),
+        ';' => Some(0x29), '\'' => Some(0x27), '`' => Some(0x32), '\\' => Some(0x2A), ',' => Some(0x2B),
+        '.' => Some(0x2F), '/' => Some(0x2C),
         _ => None,
     }
 }
 
+/// 将命名按键映射到 macOS 虚拟键码（kVK_ANSI_Keypad* 与方向键）
+#[cfg(target_os = "macos")]
+fn named_to_macos_keycode(key: NamedKey) -> u16 {
+    match key {
+        NamedKey::Numpad(n) => [0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5B, 0x5C][n as usize],
+        NamedKey::NumpadAdd => 0x45, NamedKey::NumpadSubtract => 0x4E, NamedKey::NumpadMultiply => 0x43,
+        NamedKey::NumpadDivide => 0x4B, NamedKey::NumpadDecimal => 0x41, NamedKey::NumpadEnter => 0x4C,
+        NamedKey::Up => 0x7E, NamedKey::Down => 0x7D, NamedKey::Left => 0x7B, NamedKey::Right => 0x7C,
+    }
+}
+
 /// 将字符映射到 Windows 扫描码
 #[cfg(target_os = "windows")]
 fn char_to_windows_scancode(ch: char) -> Option<u16> {
@@ -30,123 +93,577 @@ fn char_to_windows_scancode(ch: char) -> Option<u16> {
         'u' => Some(0x16), 'v' => Some(0x2F), 'w' => Some(0x11), 'x' => Some(0x2D), 'y' => Some(0x15),
         'z' => Some(0x2C), '0' => Some(0x0B), '1' => Some(0x02), '2' => Some(0x03), '3' => Some(0x04),
         '4' => Some(0x05), '5' => Some(0x06), '6' => Some(0x07), '7' => Some(0x08), '8' => Some(0x09),
-        '9' => Some(0x0A),
+        '9' => Some(0x0A), '-' => Some(0x0C), '=' => Some(0x0D), '[' => Some(0x1A), ']' => Some(0x1B),
+        ';' => Some(0x27), '\'' => Some(0x28), '`' => Some(0x29), '\\' => Some(0x2B), ',' => Some(0x33),
+        '.' => Some(0x34), '/' => Some(0x35),
         _ => None,
     }
 }
 
-/// 将修饰键映射到 Windows 扫描码
-/// 用于游戏的 DirectInput 识别
+/// 将命名按键映射到 Windows 扫描码，返回 (扫描码, 是否为扩展键)
+/// 小键盘除号、小键盘回车和方向键是扩展键，需要 KEYEVENTF_EXTENDEDKEY
 #[cfg(target_os = "windows")]
-fn modifier_to_windows_scancode(modifier: Key) -> Option<u16> {
+fn named_to_windows_scancode(key: NamedKey) -> (u16, bool) {
+    match key {
+        NamedKey::Numpad(n) => ([0x52, 0x4F, 0x50, 0x51, 0x4B, 0x4C, 0x4D, 0x47, 0x48, 0x49][n as usize], false),
+        NamedKey::NumpadAdd => (0x4E, false), NamedKey::NumpadSubtract => (0x4A, false),
+        NamedKey::NumpadMultiply => (0x37, false), NamedKey::NumpadDecimal => (0x53, false),
+        NamedKey::NumpadDivide => (0x35, true), NamedKey::NumpadEnter => (0x1C, true),
+        NamedKey::Up => (0x48, true), NamedKey::Down => (0x50, true),
+        NamedKey::Left => (0x4B, true), NamedKey::Right => (0x4D, true),
+    }
+}
+
+/// 将修饰键映射到 Windows 扫描码，返回 (扫描码, 是否为扩展键)
+/// 用于游戏的 DirectInput 识别；右侧 Ctrl/Alt 与 Win 键都是扩展键
+#[cfg(target_os = "windows")]
+fn modifier_to_windows_scancode(modifier: Modifier) -> (u16, bool) {
     match modifier {
-        Key::Shift => Some(0x2A),   // Left Shift scan code
-        Key::Control => Some(0x1D), // Left Ctrl scan code
-        Key::Alt => Some(0x38),     // Left Alt scan code
-        Key::Meta => Some(0x5B),    // Left Windows key scan code (extended)
-        _ => None,
+        Modifier::Shift(Side::Left) => (0x2A, false),
+        Modifier::Shift(Side::Right) => (0x36, false),
+        Modifier::Control(Side::Left) => (0x1D, false),
+        Modifier::Control(Side::Right) => (0x1D, true),
+        Modifier::Alt(Side::Left) => (0x38, false),
+        Modifier::Alt(Side::Right) => (0x38, true),
+        Modifier::Meta(Side::Left) => (0x5B, true),
+        Modifier::Meta(Side::Right) => (0x5C, true),
+    }
+}
+
+// 注入事件的标记，录制等功能可据此识别本程序发出的按键
+#[cfg(target_os = "windows")]
+pub const INJECTED_EXTRA_INFO: usize = 0x4F47_4150;
+
+/// 直接通过 SendInput 发送扫描码，扩展键带上 KEYEVENTF_EXTENDEDKEY
+#[cfg(target_os = "windows")]
+fn send_windows_scancode(scancode: u16, extended: bool, direction: Direction) -> Result<(), String> {
+    use windows::Win32::UI::Input::KeyboardAndMouse::{
+        SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, KEYBDINPUT, KEYEVENTF_EXTENDEDKEY, KEYEVENTF_KEYUP,
+        KEYEVENTF_SCANCODE, VIRTUAL_KEY,
+    };
+
+    let mut flags = KEYEVENTF_SCANCODE;
+    if extended { flags |= KEYEVENTF_EXTENDEDKEY; }
+
+    let event = |flags| INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT { wVk: VIRTUAL_KEY(0), wScan: scancode, dwFlags: flags, time: 0, dwExtraInfo: INJECTED_EXTRA_INFO },
+        },
+    };
+    let inputs = match direction {
+        Direction::Press => vec![event(flags)],
+        Direction::Release => vec![event(flags | KEYEVENTF_KEYUP)],
+        Direction::Click => vec![event(flags), event(flags | KEYEVENTF_KEYUP)],
+    };
+
+    let sent = unsafe { SendInput(&inputs, std::mem::size_of::<INPUT>() as i32) };
+    if sent as usize != inputs.len() {
+        return Err(format!("SendInput failed for scan code {:#04x}", scancode));
+    }
+    Ok(())
+}
+
+/// X11 keysym，Linux 下经 enigo 的 Key::Other 发送
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn named_to_keysym(key: NamedKey) -> u32 {
+    match key {
+        NamedKey::Numpad(n) => 0xFFB0 + n as u32,
+        NamedKey::NumpadAdd => 0xFFAB, NamedKey::NumpadSubtract => 0xFFAD, NamedKey::NumpadMultiply => 0xFFAA,
+        NamedKey::NumpadDivide => 0xFFAF, NamedKey::NumpadDecimal => 0xFFAE, NamedKey::NumpadEnter => 0xFF8D,
+        NamedKey::Up => 0xFF52, NamedKey::Down => 0xFF54, NamedKey::Left => 0xFF51, NamedKey::Right => 0xFF53,
     }
 }
 
+fn parse_modifier(name: &str) -> Option<Modifier> {
+    let modifier = match name {
+        "shift" | "lshift" => Modifier::Shift(Side::Left),
+        "rshift" => Modifier::Shift(Side::Right),
+        "ctrl" | "control" | "lctrl" => Modifier::Control(Side::Left),
+        "rctrl" => Modifier::Control(Side::Right),
+        "alt" | "lalt" => Modifier::Alt(Side::Left),
+        "ralt" => Modifier::Alt(Side::Right),
+        "meta" | "cmd" | "win" | "lmeta" | "lcmd" | "lwin" => Modifier::Meta(Side::Left),
+        "rmeta" | "rcmd" | "rwin" => Modifier::Meta(Side::Right),
+        _ => return None,
+    };
+    Some(modifier)
+}
+
+fn parse_named_key(name: &str) -> Result<Option<NamedKey>, String> {
+    let key = match name {
+        "numpadadd" | "numpadplus" => NamedKey::NumpadAdd,
+        "numpadsubtract" | "numpadminus" | "numpad-" => NamedKey::NumpadSubtract,
+        "numpadmultiply" | "numpad*" => NamedKey::NumpadMultiply,
+        "numpaddivide" | "numpad/" => NamedKey::NumpadDivide,
+        "numpaddecimal" | "numpad." => NamedKey::NumpadDecimal,
+        "numpadenter" => NamedKey::NumpadEnter,
+        "up" => NamedKey::Up,
+        "down" => NamedKey::Down,
+        "left" => NamedKey::Left,
+        "right" => NamedKey::Right,
+        "numpad" | "num" | "kp" => {
+            return Err(format!(
+                "Ambiguous key '{}': use numpad0-numpad9, numpadadd, numpadsubtract, numpadmultiply, numpaddivide, numpaddecimal or numpadenter",
+                name
+            ))
+        }
+        "enter" | "return" => {
+            return Err(format!("Ambiguous key '{}': only the keypad Enter is supported, use 'numpadenter'", name))
+        }
+        _ => {
+            if let Some(digit) = name.strip_prefix("numpad") {
+                return match digit.parse::<u8>() {
+                    Ok(n) if n <= 9 => Ok(Some(NamedKey::Numpad(n))),
+                    _ => Err(format!("Unknown numpad key '{}': expected numpad0-numpad9", name)),
+                };
+            }
+            // 常见的其他写法，给出正确名称
+            for prefix in ["num", "kp"] {
+                if let Some(rest) = name.strip_prefix(prefix) {
+                    let full = format!("numpad{}", rest);
+                    if !rest.is_empty() && matches!(parse_named_key(&full), Ok(Some(_))) {
+                        return Err(format!("Unknown key '{}', did you mean '{}'?", name, full));
+                    }
+                }
+            }
+            return Ok(None);
+        }
+    };
+    Ok(Some(key))
+}
+
 /// 解析按键字符串，返回修饰键和主键
-fn parse_key_string(key_str: &str) -> Result<(Vec<Key>, Option<char>), String> {
+/// 形如 "a"、"shift+a"、"rctrl+numpad5"、"up"；修饰键的顺序无关，重复的修饰键只算一次
+/// 最后一段也可以是修饰键，此时只按修饰键本身（如 "rshift"、"shift+ctrl"）
+pub fn parse_key_string(key_str: &str) -> Result<ParsedKey, String> {
     let parts: Vec<&str> = key_str.split('+').collect();
     let mut modifiers = Vec::new();
-    let mut main_key: Option<char> = None;
-
-    let is_macos = cfg!(target_os = "macos");
+    let mut main = None;
 
     for (i, part) in parts.iter().enumerate() {
+        let part = part.trim();
         let part_lower = part.to_lowercase();
-        if i < parts.len() - 1 {
-            match part_lower.as_str() {
-                "shift" => modifiers.push(Key::Shift),
-                "ctrl" | "control" => if is_macos { modifiers.push(Key::Meta) } else { modifiers.push(Key::Control) },
-                "alt" => modifiers.push(Key::Alt),
-                "meta" | "cmd" | "win" => modifiers.push(Key::Meta),
-                _ => return Err(format!("Unknown modifier: {}", part)),
+        let is_last = i == parts.len() - 1;
+        // 出错时指明是第几段
+        let segment = || format!("segment {} of '{}'", i + 1, key_str);
+
+        if part.is_empty() {
+            return Err(format!("Empty key name at {}", segment()));
+        }
+        if let Some(modifier) = parse_modifier(&part_lower) {
+            modifiers.push(modifier);
+            continue;
+        }
+
+        let key = match parse_named_key(&part_lower).map_err(|e| format!("{} ({})", e, segment()))? {
+            Some(named) => Some(MainKey::Named(named)),
+            None if part.chars().count() == 1 => part.chars().next().map(MainKey::Char),
+            None => None,
+        };
+        match key {
+            Some(key) if is_last => main = Some(key),
+            Some(_) => {
+                return Err(format!("Main key '{}' must be the last segment ({})", part, segment()))
+            }
+            None if is_last => return Err(format!("Invalid main key '{}' ({})", part, segment())),
+            None => return Err(format!("Unknown modifier '{}' ({})", part, segment())),
+        }
+    }
+
+    modifiers.sort();
+    modifiers.dedup();
+    Ok(ParsedKey { modifiers, main })
+}
+
+// 常见的其他写法，只在 normalize_key_string 中接受
+fn alias(name: &str) -> Option<&'static str> {
+    let canonical = match name {
+        "command" | "super" | "windows" => "meta",
+        "rcommand" | "rsuper" => "rmeta",
+        "option" | "opt" => "alt",
+        "roption" | "altgr" => "ralt",
+        "ctl" | "lcontrol" => "ctrl",
+        "rcontrol" => "rctrl",
+        "leftshift" => "shift",
+        "rightshift" => "rshift",
+        "arrowup" | "uparrow" => "up",
+        "arrowdown" | "downarrow" => "down",
+        "arrowleft" | "leftarrow" => "left",
+        "arrowright" | "rightarrow" => "right",
+        "kpenter" | "numenter" => "numpadenter",
+        _ => return None,
+    };
+    Some(canonical)
+}
+
+// 规范写法的修饰键和命名按键，用于给出拼写建议
+fn known_names() -> Vec<String> {
+    let mut names: Vec<String> = [
+        "shift", "rshift", "ctrl", "rctrl", "alt", "ralt", "meta", "rmeta", "numpadadd",
+        "numpadsubtract", "numpadmultiply", "numpaddivide", "numpaddecimal", "numpadenter", "up",
+        "down", "left", "right",
+    ]
+    .iter()
+    .map(|name| name.to_string())
+    .collect();
+    names.extend((0..=9).map(|n| format!("numpad{}", n)));
+    names
+}
+
+// 编辑距离（相邻字符交换算一次）
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (rows[i - 1][j] + 1).min(row[j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+// 与 name 最接近的规范名称，短名称最多差一个字符，长名称最多差两个
+fn closest_name(name: &str) -> Option<String> {
+    let limit = if name.chars().count() <= 4 { 1 } else { 2 };
+    known_names()
+        .into_iter()
+        .map(|known| (edit_distance(name, &known), known))
+        .filter(|&(distance, _)| distance <= limit)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, known)| known)
+}
+
+// 单独一段是否是合法的修饰键或主键
+fn is_valid_segment(segment: &str) -> bool {
+    parse_modifier(segment).is_some()
+        || matches!(parse_named_key(segment), Ok(Some(_)))
+        || segment.chars().count() == 1
+}
+
+// 容错地切分按键字符串：去掉所有空白，"+" 是分隔符，"-" 跟在修饰键后面时也是分隔符
+// 名称转为小写并换成规范写法
+fn lenient_segments(key_str: &str) -> Vec<String> {
+    let compact: String = key_str.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut segments = Vec::new();
+    for part in compact.split('+') {
+        let mut rest = part;
+        // "ctrl-shift-a"、"ctrl--"：前面的修饰键逐个拆出，剩下的整体作为一段
+        while let Some((head, tail)) = rest.split_once('-') {
+            let head_lower = head.to_lowercase();
+            let head_name = alias(&head_lower).unwrap_or(&head_lower);
+            if tail.is_empty() || parse_modifier(head_name).is_none() {
+                break;
             }
+            segments.push(head_name.to_string());
+            rest = tail;
+        }
+        // 单个字符保持原样：文本模式下 "A" 与 "a" 输入的字符不同
+        let segment = if rest.chars().count() == 1 {
+            rest.to_string()
         } else {
-            if part.len() == 1 { main_key = part.chars().next(); }
-            else { return Err(format!("Invalid main key: {}", part)); }
+            let lower = rest.to_lowercase();
+            alias(&lower).map_or(lower, str::to_string)
+        };
+        segments.push(segment);
+    }
+    segments
+}
+
+fn modifier_name(modifier: Modifier) -> &'static str {
+    match modifier {
+        Modifier::Shift(Side::Left) => "shift",
+        Modifier::Shift(Side::Right) => "rshift",
+        Modifier::Control(Side::Left) => "ctrl",
+        Modifier::Control(Side::Right) => "rctrl",
+        Modifier::Alt(Side::Left) => "alt",
+        Modifier::Alt(Side::Right) => "ralt",
+        Modifier::Meta(Side::Left) => "meta",
+        Modifier::Meta(Side::Right) => "rmeta",
+    }
+}
+
+fn named_key_name(key: NamedKey) -> String {
+    let name = match key {
+        NamedKey::Numpad(n) => return format!("numpad{}", n),
+        NamedKey::NumpadAdd => "numpadadd",
+        NamedKey::NumpadSubtract => "numpadsubtract",
+        NamedKey::NumpadMultiply => "numpadmultiply",
+        NamedKey::NumpadDivide => "numpaddivide",
+        NamedKey::NumpadDecimal => "numpaddecimal",
+        NamedKey::NumpadEnter => "numpadenter",
+        NamedKey::Up => "up",
+        NamedKey::Down => "down",
+        NamedKey::Left => "left",
+        NamedKey::Right => "right",
+    };
+    name.to_string()
+}
+
+impl std::fmt::Display for ParsedKey {
+    /// 规范写法：修饰键按按下顺序，用 "+" 连接，名称全部小写，字符主键保持原样
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut segments: Vec<String> = self.modifiers.iter().map(|m| modifier_name(*m).to_string()).collect();
+        match self.main {
+            Some(MainKey::Char(ch)) => segments.push(ch.to_string()),
+            Some(MainKey::Named(key)) => segments.push(named_key_name(key)),
+            None => {}
+        }
+        f.write_str(&segments.join("+"))
+    }
+}
+
+/// 把用户输入的按键字符串整理成规范写法，如 "Shift + a" -> "shift+a"、"CTRL-c" -> "ctrl+c"、"option+Up" -> "alt+up"
+/// 容忍多余的空白、用 "-" 分隔、大小写和常见别名；结果可直接交给 parse_key_string，再次整理不变
+/// 仍无法解析时错误信息中给出拼写最接近的写法
+pub fn normalize_key_string(key_str: &str) -> Result<String, String> {
+    let segments = lenient_segments(key_str);
+    match parse_key_string(&segments.join("+")) {
+        Ok(parsed) => Ok(parsed.to_string()),
+        Err(e) if e.contains("did you mean") => Err(e),
+        Err(e) => {
+            // 逐段替换成最接近的名称，整体能解析时作为建议
+            let suggestion: Option<Vec<String>> = segments
+                .iter()
+                .map(|segment| {
+                    if is_valid_segment(segment) {
+                        Some(segment.clone())
+                    } else {
+                        closest_name(segment)
+                    }
+                })
+                .collect();
+            let suggestion = suggestion
+                .and_then(|segments| parse_key_string(&segments.join("+")).ok())
+                .map(|parsed| parsed.to_string());
+            match suggestion {
+                Some(suggestion) => Err(format!("{}, did you mean '{}'?", e, suggestion)),
+                None => Err(e),
+            }
         }
     }
-    Ok((modifiers, main_key))
+}
+
+/// 文本模式下按键字符串对应的字符，用于在聊天框里把歌曲打成文字
+/// 只允许 shift 修饰（转为大写），小键盘数字与运算符按其字符输出，方向键和小键盘回车没有对应字符
+pub fn text_char(key_str: &str) -> Result<char, String> {
+    let ParsedKey { modifiers, main } = parse_key_string(key_str)?;
+    if !modifiers.iter().all(|m| matches!(m, Modifier::Shift(_))) {
+        return Err(format!("Only shift is allowed in text mode: '{}'", key_str));
+    }
+    let ch = match main {
+        Some(MainKey::Char(ch)) => ch,
+        Some(MainKey::Named(NamedKey::Numpad(n))) => char::from(b'0' + n),
+        Some(MainKey::Named(NamedKey::NumpadAdd)) => '+',
+        Some(MainKey::Named(NamedKey::NumpadSubtract)) => '-',
+        Some(MainKey::Named(NamedKey::NumpadMultiply)) => '*',
+        Some(MainKey::Named(NamedKey::NumpadDivide)) => '/',
+        Some(MainKey::Named(NamedKey::NumpadDecimal)) => '.',
+        _ => return Err(format!("Key has no text character: '{}'", key_str)),
+    };
+    if modifiers.is_empty() {
+        return Ok(ch);
+    }
+    // shift 只对字母有确定的结果，符号的上档字符依赖键盘布局
+    if !ch.is_alphabetic() {
+        return Err(format!("Shift is only allowed on letters in text mode: '{}'", key_str));
+    }
+    let mut upper = ch.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(upper), None) => Ok(upper),
+        _ => Err(format!("Key has no single uppercase character: '{}'", key_str)),
+    }
+}
+
+/// 实际发送的修饰键，按下顺序
+/// macOS 下 Control 按 Command 发送，"ctrl+cmd" 这类写法只按一次
+fn physical_modifiers(modifiers: &[Modifier]) -> Vec<Modifier> {
+    #[cfg(target_os = "macos")]
+    {
+        let mut physical: Vec<Modifier> = modifiers
+            .iter()
+            .map(|m| match *m {
+                Modifier::Control(side) => Modifier::Meta(side),
+                other => other,
+            })
+            .collect();
+        physical.sort();
+        physical.dedup();
+        physical
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        modifiers.to_vec()
+    }
+}
+
+/// 按下或释放单个修饰键
+/// Windows 下走扫描码，保证游戏的 DirectInput 能识别
+fn send_modifier_key(enigo: &mut Enigo, modifier: Modifier, direction: Direction) -> Result<(), String> {
+    #[cfg(target_os = "windows")]
+    {
+        let _ = enigo;
+        let (scancode, extended) = modifier_to_windows_scancode(modifier);
+        send_windows_scancode(scancode, extended, direction)
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let key = match modifier {
+            Modifier::Shift(Side::Left) => Key::Shift,
+            Modifier::Shift(Side::Right) => Key::RShift,
+            Modifier::Alt(Side::Left) => Key::Alt,
+            Modifier::Alt(Side::Right) => Key::ROption,
+            Modifier::Control(Side::Left) | Modifier::Meta(Side::Left) => Key::Meta,
+            Modifier::Control(Side::Right) | Modifier::Meta(Side::Right) => Key::RCommand,
+        };
+        enigo.key(key, direction).map_err(|e| format!("{:?}", e))
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    {
+        let key = match modifier {
+            Modifier::Shift(Side::Left) => Key::Shift,
+            Modifier::Control(Side::Left) => Key::Control,
+            Modifier::Alt(Side::Left) => Key::Alt,
+            Modifier::Meta(Side::Left) => Key::Meta,
+            Modifier::Shift(Side::Right) => Key::Other(0xFFE2),
+            Modifier::Control(Side::Right) => Key::Other(0xFFE4),
+            Modifier::Alt(Side::Right) => Key::Other(0xFFEA),
+            Modifier::Meta(Side::Right) => Key::Other(0xFFEC),
+        };
+        enigo.key(key, direction).map_err(|e| format!("{:?}", e))
+    }
+}
+
+/// 按下或释放主键
+/// macOS 使用虚拟键码，Windows 使用扫描码，其余情况回退到 Unicode
+/// 布局转换只作用于键码/扫描码路径，Unicode 路径由系统按字符输入，无需转换
+fn send_main_key(enigo: &mut Enigo, key: MainKey, layout: LayoutTranslation, direction: Direction) -> Result<(), String> {
+    let ch = match key {
+        MainKey::Char(ch) => ch,
+        MainKey::Named(named) => return send_named_key(enigo, named, direction),
+    };
+
+    #[cfg(target_os = "macos")]
+    if let Some(code) = char_to_macos_keycode(layout.to_qwerty_position(ch)) {
+        return enigo.raw(code, direction).map_err(|e| format!("{:?}", e));
+    }
+
+    #[cfg(target_os = "windows")]
+    if let Some(code) = char_to_windows_scancode(layout.to_qwerty_position(ch)) {
+        return send_windows_scancode(code, false, direction);
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    let _ = layout;
+
+    enigo.key(Key::Unicode(ch), direction).map_err(|e| format!("{:?}", e))
+}
+
+// 小键盘与方向键与布局无关，直接按位置发送
+fn send_named_key(enigo: &mut Enigo, key: NamedKey, direction: Direction) -> Result<(), String> {
+    #[cfg(target_os = "macos")]
+    {
+        enigo.raw(named_to_macos_keycode(key), direction).map_err(|e| format!("{:?}", e))
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let _ = enigo;
+        let (scancode, extended) = named_to_windows_scancode(key);
+        send_windows_scancode(scancode, extended, direction)
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    {
+        enigo.key(Key::Other(named_to_keysym(key)), direction).map_err(|e| format!("{:?}", e))
+    }
 }
 
 pub trait SmartKeyboard {
+    /// 完整的一次按键（按下后短暂保持再释放）
     fn simulate_keypress_smart(&mut self, key_str: &str) -> Result<(), String>;
+    /// 只按下，不释放（由调用方控制保持时长）
+    fn key_down_smart(&mut self, key_str: &str) -> Result<(), String>;
+    /// 释放之前按下的按键
+    fn key_up_smart(&mut self, key_str: &str) -> Result<(), String>;
+    /// 按指定键盘布局换算物理位置后按下
+    fn key_down_with_layout(&mut self, key_str: &str, layout: LayoutTranslation) -> Result<(), String>;
+    /// 按指定键盘布局换算物理位置后释放
+    fn key_up_with_layout(&mut self, key_str: &str, layout: LayoutTranslation) -> Result<(), String>;
+    /// 以 Unicode 字符的方式按下（见 text_char），不经过键码表和布局转换
+    fn key_down_text(&mut self, key_str: &str) -> Result<(), String>;
+    /// 释放以 Unicode 字符方式按下的按键
+    fn key_up_text(&mut self, key_str: &str) -> Result<(), String>;
 }
 
 impl SmartKeyboard for Enigo {
     fn simulate_keypress_smart(&mut self, key_str: &str) -> Result<(), String> {
-        let (modifiers, main_key) = parse_key_string(key_str)?;
+        self.key_down_smart(key_str)?;
+        thread::sleep(Duration::from_millis(20)); // Short hold
+        self.key_up_smart(key_str)
+    }
+
+    fn key_down_smart(&mut self, key_str: &str) -> Result<(), String> {
+        self.key_down_with_layout(key_str, LayoutTranslation::None)
+    }
+
+    fn key_up_smart(&mut self, key_str: &str) -> Result<(), String> {
+        self.key_up_with_layout(key_str, LayoutTranslation::None)
+    }
+
+    fn key_down_with_layout(&mut self, key_str: &str, layout: LayoutTranslation) -> Result<(), String> {
+        let ParsedKey { modifiers, main } = parse_key_string(key_str)?;
+        let modifiers = physical_modifiers(&modifiers);
 
         // Press modifiers
         for modifier in &modifiers {
-             #[cfg(target_os = "windows")]
-             {
-                 if let Some(scancode) = modifier_to_windows_scancode(*modifier) {
-                     self.raw(scancode, Direction::Press).map_err(|e| format!("{:?}",e))?;
-                 } else {
-                     self.key(*modifier, Direction::Press).map_err(|e| format!("{:?}",e))?;
-                 }
-             }
-
-             #[cfg(not(target_os = "windows"))]
-             {
-                 self.key(*modifier, Direction::Press).map_err(|e| format!("{:?}",e))?;
-             }
-             
-             thread::sleep(Duration::from_millis(5));
+            send_modifier_key(self, *modifier, Direction::Press)?;
+            thread::sleep(Duration::from_millis(5));
         }
 
-        if !modifiers.is_empty() { thread::sleep(Duration::from_millis(10)); }
+        if let Some(key) = main {
+            if !modifiers.is_empty() { thread::sleep(Duration::from_millis(10)); }
+            send_main_key(self, key, layout, Direction::Press)?;
+        }
 
-        if let Some(ch) = main_key {
-            #[cfg(target_os = "macos")]
-            if let Some(code) = char_to_macos_keycode(ch) {
-                self.raw(code, Direction::Press).map_err(|e| format!("{:?}",e))?;
-                thread::sleep(Duration::from_millis(20)); // Short hold
-                self.raw(code, Direction::Release).map_err(|e| format!("{:?}",e))?;
-            } else {
-                 self.key(Key::Unicode(ch), Direction::Click).map_err(|e| format!("{:?}",e))?;
-            }
+        Ok(())
+    }
 
-            #[cfg(target_os = "windows")]
-            if let Some(code) = char_to_windows_scancode(ch) {
-                self.raw(code, Direction::Press).map_err(|e| format!("{:?}",e))?;
-                thread::sleep(Duration::from_millis(20));
-                self.raw(code, Direction::Release).map_err(|e| format!("{:?}",e))?;
-            } else {
-                 self.key(Key::Unicode(ch), Direction::Click).map_err(|e| format!("{:?}",e))?;
-            }
-            
-            #[cfg(not(any(target_os = "macos", target_os = "windows")))]
-            self.key(Key::Unicode(ch), Direction::Click).map_err(|e| format!("{:?}",e))?;
-        }
+    fn key_up_with_layout(&mut self, key_str: &str, layout: LayoutTranslation) -> Result<(), String> {
+        let ParsedKey { modifiers, main } = parse_key_string(key_str)?;
+        let modifiers = physical_modifiers(&modifiers);
 
-        if !modifiers.is_empty() { thread::sleep(Duration::from_millis(10)); }
+        if let Some(key) = main {
+            send_main_key(self, key, layout, Direction::Release)?;
+            if !modifiers.is_empty() { thread::sleep(Duration::from_millis(10)); }
+        }
 
         // Release modifiers
         for modifier in modifiers.iter().rev() {
-             #[cfg(target_os = "windows")]
-             {
-                 if let Some(scancode) = modifier_to_windows_scancode(*modifier) {
-                     self.raw(scancode, Direction::Release).map_err(|e| format!("{:?}",e))?;
-                 } else {
-                     self.key(*modifier, Direction::Release).map_err(|e| format!("{:?}",e))?;
-                 }
-             }
-
-             #[cfg(not(target_os = "windows"))]
-             {
-                 self.key(*modifier, Direction::Release).map_err(|e| format!("{:?}",e))?;
-             }
-
-             thread::sleep(Duration::from_millis(30));
+            send_modifier_key(self, *modifier, Direction::Release)?;
+            thread::sleep(Duration::from_millis(30));
         }
 
         Ok(())
     }
+
+    fn key_down_text(&mut self, key_str: &str) -> Result<(), String> {
+        let ch = text_char(key_str)?;
+        self.key(Key::Unicode(ch), Direction::Press).map_err(|e| format!("{:?}", e))
+    }
+
+    fn key_up_text(&mut self, key_str: &str) -> Result<(), String> {
+        let ch = text_char(key_str)?;
+        self.key(Key::Unicode(ch), Direction::Release).map_err(|e| format!("{:?}", e))
+    }
 }
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+
+/// 键盘布局转换
+/// 扫描码/键码表按 QWERTY 的物理位置编写，非 QWERTY 布局下需要先把
+/// 目标字符换算成“在该布局上能打出这个字符的 QWERTY 位置”
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayoutTranslation {
+    #[default]
+    None,
+    Azerty,
+    Qwertz,
+    Dvorak,
+}
+
+impl LayoutTranslation {
+    /// 返回在当前布局上产生 ch 的 QWERTY 位置字符
+    /// 没有对应关系的字符（数字等）原样返回
+    pub fn to_qwerty_position(self, ch: char) -> char {
+        let lower = ch.to_ascii_lowercase();
+        let mapped = match self {
+            LayoutTranslation::None => None,
+            LayoutTranslation::Azerty => azerty_position(lower),
+            LayoutTranslation::Qwertz => qwertz_position(lower),
+            LayoutTranslation::Dvorak => dvorak_position(lower),
+        };
+        mapped.unwrap_or(ch)
+    }
+
+    /// to_qwerty_position 的逆运算：QWERTY 位置在当前布局上产生的字符
+    pub fn from_qwerty_position(self, position: char) -> char {
+        if self == LayoutTranslation::None {
+            return position;
+        }
+        ('a'..='z')
+            .chain(";,./".chars())
+            .find(|&ch| self.to_qwerty_position(ch) == position && ch != position)
+            .unwrap_or(position)
+    }
+}
+
+// 法语 AZERTY：A/Q、Z/W 互换，M 在 QWERTY 分号的位置
+fn azerty_position(ch: char) -> Option<char> {
+    match ch {
+        'a' => Some('q'), 'q' => Some('a'), 'z' => Some('w'), 'w' => Some('z'), 'm' => Some(';'),
+        _ => None,
+    }
+}
+
+// 德语 QWERTZ：Y/Z 互换
+fn qwertz_position(ch: char) -> Option<char> {
+    match ch {
+        'y' => Some('z'), 'z' => Some('y'),
+        _ => None,
+    }
+}
+
+// 美式 Dvorak
+fn dvorak_position(ch: char) -> Option<char> {
+    match ch {
+        'p' => Some('r'), 'y' => Some('t'), 'f' => Some('y'), 'g' => Some('u'), 'c' => Some('i'),
+        'r' => Some('o'), 'l' => Some('p'), 'o' => Some('s'), 'e' => Some('d'), 'u' => Some('f'),
+        'i' => Some('g'), 'd' => Some('h'), 'h' => Some('j'), 't' => Some('k'), 'n' => Some('l'),
+        's' => Some(';'), 'q' => Some('x'), 'j' => Some('c'), 'k' => Some('v'), 'x' => Some('b'),
+        'b' => Some('n'), 'w' => Some(','), 'v' => Some('.'), 'z' => Some('/'),
+        _ => None,
+    }
+}
+
+// 从系统布局标识推断转换方式，无法识别时返回 None
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn classify_layout(id: &str) -> Option<LayoutTranslation> {
+    let id = id.to_lowercase();
+    if id.contains("dvorak") {
+        Some(LayoutTranslation::Dvorak)
+    } else if id.contains("french") || id.contains("belgian") || id == "fr" || id == "be" {
+        Some(LayoutTranslation::Azerty)
+    } else if id.contains("german") || id.contains("swiss") || id == "de" || id == "ch" {
+        Some(LayoutTranslation::Qwertz)
+    } else if id.contains("u.s.") || id.contains("abc") || id == "us" || id == "gb" {
+        Some(LayoutTranslation::None)
+    } else {
+        None
+    }
+}
+
+/// 检测当前键盘布局
+/// 系统不提供或无法识别时返回 None
+#[cfg(target_os = "windows")]
+pub fn detect_keyboard_layout() -> Option<LayoutTranslation> {
+    use windows::Win32::UI::Input::KeyboardAndMouse::GetKeyboardLayoutNameW;
+
+    let mut klid = [0u16; 9];
+    unsafe { GetKeyboardLayoutNameW(&mut klid) }.ok()?;
+    let klid = String::from_utf16_lossy(&klid[..8]).to_uppercase();
+    match klid.as_str() {
+        "00010409" => Some(LayoutTranslation::Dvorak),
+        "0000040C" | "0000080C" => Some(LayoutTranslation::Azerty),
+        "00000407" | "00000807" | "00000C07" => Some(LayoutTranslation::Qwertz),
+        "00000409" | "00000809" => Some(LayoutTranslation::None),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn detect_keyboard_layout() -> Option<LayoutTranslation> {
+    // 形如 com.apple.keylayout.French
+    let output = std::process::Command::new("defaults")
+        .args([
+            "read",
+            "com.apple.HIToolbox",
+            "AppleCurrentKeyboardLayoutInputSourceID",
+        ])
+        .output()
+        .ok()?;
+    let id = String::from_utf8_lossy(&output.stdout);
+    let name = id.trim().rsplit('.').next()?;
+    if name.eq_ignore_ascii_case("US") {
+        return Some(LayoutTranslation::None);
+    }
+    classify_layout(name)
+}
+
+#[cfg(target_os = "linux")]
+pub fn detect_keyboard_layout() -> Option<LayoutTranslation> {
+    // X11 下使用 setxkbmap，Wayland 下退回 localectl 的系统默认布局
+    let query = |program: &str, args: &[&str], layout_key: &str, variant_key: &str| {
+        let output = std::process::Command::new(program).args(args).output().ok()?;
+        let text = String::from_utf8_lossy(&output.stdout).to_string();
+        let field = |key: &str| {
+            text.lines()
+                .find_map(|line| line.trim().strip_prefix(key))
+                .map(|v| v.trim().split(',').next().unwrap_or("").to_string())
+        };
+        let layout = field(layout_key)?;
+        Some((layout, field(variant_key).unwrap_or_default()))
+    };
+
+    let (layout, variant) = query("setxkbmap", &["-query"], "layout:", "variant:")
+        .or_else(|| query("localectl", &["status"], "X11 Layout:", "X11 Variant:"))?;
+    if variant.contains("dvorak") {
+        return Some(LayoutTranslation::Dvorak);
+    }
+    classify_layout(&layout)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+pub fn detect_keyboard_layout() -> Option<LayoutTranslation> {
+    None
+}
@@ -10,9 +10,17 @@ use uni_window::WindowInfo;
 
 pub mod mouse;
 pub mod keyboard;
+pub mod layout;
+pub mod permission;
+#[cfg(target_os = "linux")]
+pub mod uinput;
 
 pub use mouse::SmoothMouse;
-pub use keyboard::SmartKeyboard;
+pub use keyboard::{normalize_key_string, parse_key_string, text_char, MainKey, Modifier, NamedKey, ParsedKey, Side, SmartKeyboard};
+pub use layout::{detect_keyboard_layout, LayoutTranslation};
+pub use permission::{check_input_permission, open_permission_settings, InputPermission};
+#[cfg(target_os = "linux")]
+pub use uinput::{UinputError, UinputKeyboard};
 
 pub struct InputController {
     pub enigo: Enigo,
@@ -0,0 +1,59 @@
+/// 模拟输入所需的系统权限状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputPermission {
+    Granted,
+    Denied,
+    /// 当前平台不需要额外授权（Windows / Linux）
+    NotApplicable,
+}
+
+/// 检查当前进程是否允许模拟输入
+/// macOS 上 prompt 为 true 时，未授权会弹出系统的辅助功能授权提示
+#[cfg(target_os = "macos")]
+pub fn check_input_permission(prompt: bool) -> InputPermission {
+    use core_foundation::base::TCFType;
+    use core_foundation::boolean::CFBoolean;
+    use core_foundation::dictionary::{CFDictionary, CFDictionaryRef};
+    use core_foundation::string::{CFString, CFStringRef};
+
+    #[link(name = "ApplicationServices", kind = "framework")]
+    extern "C" {
+        fn AXIsProcessTrustedWithOptions(options: CFDictionaryRef) -> bool;
+        static kAXTrustedCheckOptionPrompt: CFStringRef;
+    }
+
+    // 系统常量不归我们所有，按 get rule 引用
+    let key = unsafe { CFString::wrap_under_get_rule(kAXTrustedCheckOptionPrompt) };
+    let value = if prompt {
+        CFBoolean::true_value()
+    } else {
+        CFBoolean::false_value()
+    };
+    let options = CFDictionary::from_CFType_pairs(&[(key, value)]);
+
+    if unsafe { AXIsProcessTrustedWithOptions(options.as_concrete_TypeRef()) } {
+        InputPermission::Granted
+    } else {
+        InputPermission::Denied
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn check_input_permission(_prompt: bool) -> InputPermission {
+    InputPermission::NotApplicable
+}
+
+/// 打开系统设置中的辅助功能隐私面板
+#[cfg(target_os = "macos")]
+pub fn open_permission_settings() -> Result<(), String> {
+    std::process::Command::new("open")
+        .arg("x-apple.systempreferences:com.apple.preference.security?Privacy_Accessibility")
+        .spawn()
+        .map(|_| ())
+        .map_err(|e| format!("Failed to open Privacy settings: {}", e))
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn open_permission_settings() -> Result<(), String> {
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! Linux uinput 键盘后端
+//! 直接创建虚拟键盘设备注入按键，Wayland 下 enigo 无法工作时使用
+
+use crate::keyboard::{parse_key_string, MainKey, Modifier, NamedKey, ParsedKey, Side};
+use crate::layout::LayoutTranslation;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+const UINPUT_PATH: &str = "/dev/uinput";
+
+// linux/uinput.h 中的 ioctl 编号
+const UI_SET_EVBIT: libc::c_ulong = 0x4004_5564;
+const UI_SET_KEYBIT: libc::c_ulong = 0x4004_5565;
+const UI_DEV_SETUP: libc::c_ulong = 0x405c_5503;
+const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const SYN_REPORT: u16 = 0;
+const BUS_USB: u16 = 0x03;
+
+const MAX_KEYCODE: u16 = 248;
+
+#[derive(Debug, Error)]
+pub enum UinputError {
+    #[error("Permission denied opening /dev/uinput. Add a udev rule such as \
+             `KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\"` and add your user to the \
+             `input` group (then log out and back in)")]
+    PermissionDenied,
+    #[error("/dev/uinput not found. Load the kernel module with `sudo modprobe uinput`")]
+    NotFound,
+    #[error("uinput error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[repr(C)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[repr(C)]
+struct UinputSetup {
+    id: InputId,
+    name: [u8; 80],
+    ff_effects_max: u32,
+}
+
+#[repr(C)]
+struct InputEvent {
+    time: libc::timeval,
+    type_: u16,
+    code: u16,
+    value: i32,
+}
+
+/// 将字符映射到 Linux 键码（KEY_*）
+fn char_to_linux_keycode(ch: char) -> Option<u16> {
+    match ch.to_ascii_lowercase() {
+        'a' => Some(30), 'b' => Some(48), 'c' => Some(46), 'd' => Some(32), 'e' => Some(18),
+        'f' => Some(33), 'g' => Some(34), 'h' => Some(35), 'i' => Some(23), 'j' => Some(36),
+        'k' => Some(37), 'l' => Some(38), 'm' => Some(50), 'n' => Some(49), 'o' => Some(24),
+        'p' => Some(25), 'q' => Some(16), 'r' => Some(19), 's' => Some(31), 't' => Some(20),
+        'u' => Some(22), 'v' => Some(47), 'w' => Some(17), 'x' => Some(45), 'y' => Some(21),
+        'z' => Some(44), '0' => Some(11), '1' => Some(2), '2' => Some(3), '3' => Some(4),
+        '4' => Some(5), '5' => Some(6), '6' => Some(7), '7' => Some(8), '8' => Some(9),
+        '9' => Some(10), '-' => Some(12), '=' => Some(13), '[' => Some(26), ']' => Some(27),
+        ';' => Some(39), '\'' => Some(40), '`' => Some(41), '\\' => Some(43), ',' => Some(51),
+        '.' => Some(52), '/' => Some(53), ' ' => Some(57),
+        _ => None,
+    }
+}
+
+fn named_to_linux_keycode(key: NamedKey) -> u16 {
+    match key {
+        NamedKey::Numpad(n) => [82, 79, 80, 81, 75, 76, 77, 71, 72, 73][n as usize],
+        NamedKey::NumpadAdd => 78, NamedKey::NumpadSubtract => 74, NamedKey::NumpadMultiply => 55,
+        NamedKey::NumpadDivide => 98, NamedKey::NumpadDecimal => 83, NamedKey::NumpadEnter => 96,
+        NamedKey::Up => 103, NamedKey::Down => 108, NamedKey::Left => 105, NamedKey::Right => 106,
+    }
+}
+
+fn modifier_to_linux_keycode(modifier: Modifier) -> u16 {
+    match modifier {
+        Modifier::Shift(Side::Left) => 42,
+        Modifier::Shift(Side::Right) => 54,
+        Modifier::Control(Side::Left) => 29,
+        Modifier::Control(Side::Right) => 97,
+        Modifier::Alt(Side::Left) => 56,
+        Modifier::Alt(Side::Right) => 100,
+        Modifier::Meta(Side::Left) => 125,
+        Modifier::Meta(Side::Right) => 126,
+    }
+}
+
+/// 通过 /dev/uinput 创建的虚拟键盘
+pub struct UinputKeyboard {
+    file: File,
+}
+
+impl UinputKeyboard {
+    pub fn new() -> Result<Self, UinputError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(UINPUT_PATH)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::PermissionDenied => UinputError::PermissionDenied,
+                io::ErrorKind::NotFound => UinputError::NotFound,
+                _ => UinputError::Io(e),
+            })?;
+        let fd = file.as_raw_fd();
+
+        let mut name = [0u8; 80];
+        let label = b"OpenGamesAutoPlay Virtual Keyboard";
+        name[..label.len()].copy_from_slice(label);
+        let setup = UinputSetup {
+            id: InputId {
+                bustype: BUS_USB,
+                vendor: 0x1234,
+                product: 0x5678,
+                version: 1,
+            },
+            name,
+            ff_effects_max: 0,
+        };
+
+        unsafe {
+            ioctl(fd, UI_SET_EVBIT, EV_KEY as libc::c_ulong)?;
+            // 注册整个标准键码范围，设备本身不限制可发送的键
+            for code in 1..=MAX_KEYCODE {
+                ioctl(fd, UI_SET_KEYBIT, code as libc::c_ulong)?;
+            }
+            ioctl(fd, UI_DEV_SETUP, &setup as *const UinputSetup as libc::c_ulong)?;
+            ioctl(fd, UI_DEV_CREATE, 0)?;
+        }
+
+        // 新设备需要一点时间被合成器识别，否则最初几个按键会丢失
+        thread::sleep(Duration::from_millis(200));
+
+        Ok(Self { file })
+    }
+
+    /// 按下按键（修饰键先按下）
+    /// uinput 键码对应物理位置，同样需要按布局换算
+    pub fn key_down(&mut self, key_str: &str, layout: LayoutTranslation) -> Result<(), String> {
+        let ParsedKey { modifiers, main } = parse_key_string(key_str)?;
+        for modifier in &modifiers {
+            self.emit_key(modifier_to_linux_keycode(*modifier), 1)?;
+        }
+        if let Some(key) = main {
+            self.emit_key(main_keycode(key, layout)?, 1)?;
+        }
+        Ok(())
+    }
+
+    /// 释放按键（主键先释放，修饰键逆序释放）
+    pub fn key_up(&mut self, key_str: &str, layout: LayoutTranslation) -> Result<(), String> {
+        let ParsedKey { modifiers, main } = parse_key_string(key_str)?;
+        if let Some(key) = main {
+            self.emit_key(main_keycode(key, layout)?, 0)?;
+        }
+        for modifier in modifiers.iter().rev() {
+            self.emit_key(modifier_to_linux_keycode(*modifier), 0)?;
+        }
+        Ok(())
+    }
+
+    fn emit_key(&mut self, code: u16, value: i32) -> Result<(), String> {
+        self.write_event(EV_KEY, code, value)
+            .and_then(|_| self.write_event(EV_SYN, SYN_REPORT, 0))
+            .map_err(|e| format!("Failed to write uinput event: {}", e))
+    }
+
+    fn write_event(&mut self, type_: u16, code: u16, value: i32) -> io::Result<()> {
+        let event = InputEvent {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &event as *const InputEvent as *const u8,
+                std::mem::size_of::<InputEvent>(),
+            )
+        };
+        self.file.write_all(bytes)
+    }
+}
+
+impl Drop for UinputKeyboard {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY);
+        }
+    }
+}
+
+fn main_keycode(key: MainKey, layout: LayoutTranslation) -> Result<u16, String> {
+    match key {
+        MainKey::Char(ch) => char_to_linux_keycode(layout.to_qwerty_position(ch))
+            .ok_or_else(|| format!("Unsupported key for uinput: {}", ch)),
+        MainKey::Named(named) => Ok(named_to_linux_keycode(named)),
+    }
+}
+
+unsafe fn ioctl(fd: libc::c_int, request: libc::c_ulong, arg: libc::c_ulong) -> io::Result<()> {
+    if libc::ioctl(fd, request, arg) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,326 @@
+use uni_input::{
+    normalize_key_string, parse_key_string, text_char, MainKey, Modifier, NamedKey, ParsedKey, Side,
+};
+
+fn parsed(key: &str) -> ParsedKey {
+    parse_key_string(key).unwrap_or_else(|e| panic!("{key}: {e}"))
+}
+
+#[test]
+fn plain_and_modified_characters() {
+    assert_eq!(
+        parsed("a"),
+        ParsedKey {
+            modifiers: vec![],
+            main: Some(MainKey::Char('a'))
+        }
+    );
+    assert_eq!(
+        parsed("shift+a"),
+        ParsedKey {
+            modifiers: vec![Modifier::Shift(Side::Left)],
+            main: Some(MainKey::Char('a'))
+        }
+    );
+    assert_eq!(
+        parsed("ctrl+alt+1").modifiers,
+        vec![Modifier::Control(Side::Left), Modifier::Alt(Side::Left)]
+    );
+}
+
+#[test]
+fn numpad_keys() {
+    for n in 0..=9u8 {
+        assert_eq!(
+            parsed(&format!("numpad{n}")).main,
+            Some(MainKey::Named(NamedKey::Numpad(n)))
+        );
+    }
+    assert_eq!(
+        parsed("numpadadd").main,
+        Some(MainKey::Named(NamedKey::NumpadAdd))
+    );
+    assert_eq!(
+        parsed("NumpadEnter").main,
+        Some(MainKey::Named(NamedKey::NumpadEnter))
+    );
+    assert_eq!(
+        parsed("numpad/").main,
+        Some(MainKey::Named(NamedKey::NumpadDivide))
+    );
+    assert_eq!(parsed("up").main, Some(MainKey::Named(NamedKey::Up)));
+}
+
+#[test]
+fn side_specific_modifiers() {
+    assert_eq!(
+        parsed("rctrl+numpad5").modifiers,
+        vec![Modifier::Control(Side::Right)]
+    );
+    assert_eq!(
+        parsed("lshift+q").modifiers,
+        vec![Modifier::Shift(Side::Left)]
+    );
+    // 单独的修饰键也是合法的按键
+    assert_eq!(
+        parsed("rshift"),
+        ParsedKey {
+            modifiers: vec![Modifier::Shift(Side::Right)],
+            main: None
+        }
+    );
+}
+
+#[test]
+fn rejects_ambiguous_and_unknown_names() {
+    let err = parse_key_string("numpad").unwrap_err();
+    assert!(err.contains("Ambiguous"), "{err}");
+
+    let err = parse_key_string("num1").unwrap_err();
+    assert!(err.contains("numpad1"), "{err}");
+
+    let err = parse_key_string("numpad10").unwrap_err();
+    assert!(err.contains("numpad0-numpad9"), "{err}");
+
+    assert!(parse_key_string("enter").is_err());
+    assert!(parse_key_string("hyper+a").is_err());
+    assert!(parse_key_string("ab").is_err());
+}
+
+#[test]
+fn modifier_order_does_not_matter() {
+    assert_eq!(parsed("ctrl+shift+a"), parsed("shift+ctrl+a"));
+    assert_eq!(
+        parsed("alt+meta+ctrl+shift+q"),
+        parsed("shift+ctrl+alt+meta+q")
+    );
+    assert_eq!(parsed("rshift+shift+1"), parsed("shift+rshift+1"));
+    // 按 Shift、Control、Alt、Meta 的顺序，同种先左后右
+    assert_eq!(
+        parsed("cmd+alt+rshift+ctrl+shift+z").modifiers,
+        vec![
+            Modifier::Shift(Side::Left),
+            Modifier::Shift(Side::Right),
+            Modifier::Control(Side::Left),
+            Modifier::Alt(Side::Left),
+            Modifier::Meta(Side::Left),
+        ]
+    );
+}
+
+#[test]
+fn repeated_modifiers_are_deduplicated() {
+    assert_eq!(parsed("shift+shift+a"), parsed("shift+a"));
+    // 同一个键的不同写法
+    assert_eq!(
+        parsed("lshift+shift+a").modifiers,
+        vec![Modifier::Shift(Side::Left)]
+    );
+    assert_eq!(parsed("ctrl+control+lctrl+x"), parsed("ctrl+x"));
+    assert_eq!(
+        parsed("win+cmd+meta").modifiers,
+        vec![Modifier::Meta(Side::Left)]
+    );
+    // 左右两侧是不同的键
+    assert_eq!(parsed("shift+rshift").modifiers.len(), 2);
+}
+
+#[test]
+fn modifier_only_chords() {
+    assert_eq!(
+        parsed("shift+ctrl"),
+        ParsedKey {
+            modifiers: vec![Modifier::Shift(Side::Left), Modifier::Control(Side::Left)],
+            main: None
+        }
+    );
+    assert_eq!(parsed("ctrl+shift"), parsed("shift+ctrl"));
+    assert_eq!(parsed("ralt+rctrl").main, None);
+    assert_eq!(parsed("shift+shift"), parsed("shift"));
+    assert_eq!(
+        parsed("Shift + Alt"),
+        ParsedKey {
+            modifiers: vec![Modifier::Shift(Side::Left), Modifier::Alt(Side::Left)],
+            main: None
+        }
+    );
+}
+
+#[test]
+fn errors_point_at_the_offending_segment() {
+    let err = parse_key_string("shift+hyper+a").unwrap_err();
+    assert!(
+        err.contains("'hyper'") && err.contains("segment 2 of 'shift+hyper+a'"),
+        "{err}"
+    );
+
+    let err = parse_key_string("a+shift").unwrap_err();
+    assert!(
+        err.contains("must be the last") && err.contains("segment 1"),
+        "{err}"
+    );
+
+    let err = parse_key_string("shift+a+b").unwrap_err();
+    assert!(err.contains("'a'") && err.contains("segment 2"), "{err}");
+
+    let err = parse_key_string("ctrl+ab").unwrap_err();
+    assert!(
+        err.contains("Invalid main key 'ab'") && err.contains("segment 2"),
+        "{err}"
+    );
+
+    let err = parse_key_string("shift++a").unwrap_err();
+    assert!(err.contains("Empty") && err.contains("segment 2"), "{err}");
+
+    let err = parse_key_string("ctrl+").unwrap_err();
+    assert!(err.contains("Empty") && err.contains("segment 2"), "{err}");
+
+    let err = parse_key_string("ctrl+num5").unwrap_err();
+    assert!(
+        err.contains("numpad5") && err.contains("segment 2"),
+        "{err}"
+    );
+
+    let err = parse_key_string("numpad+shift").unwrap_err();
+    assert!(
+        err.contains("Ambiguous") && err.contains("segment 1"),
+        "{err}"
+    );
+
+    assert!(parse_key_string("").is_err());
+    assert!(parse_key_string("+").is_err());
+}
+
+#[test]
+fn text_mode_characters() {
+    assert_eq!(text_char("a"), Ok('a'));
+    assert_eq!(text_char("shift+a"), Ok('A'));
+    assert_eq!(text_char("rshift+z"), Ok('Z'));
+    assert_eq!(text_char("numpad7"), Ok('7'));
+    assert_eq!(text_char("numpadadd"), Ok('+'));
+    assert_eq!(text_char("1"), Ok('1'));
+    assert!(text_char("ctrl+c").is_err());
+    assert!(text_char("alt+shift+a").is_err());
+    assert!(text_char("shift+1").is_err());
+    assert!(text_char("up").is_err());
+    assert!(text_char("numpadenter").is_err());
+    assert!(text_char("rshift").is_err());
+}
+
+fn normalized(key: &str) -> String {
+    normalize_key_string(key).unwrap_or_else(|e| panic!("{key}: {e}"))
+}
+
+#[test]
+fn normalization_tolerates_common_mistakes() {
+    assert_eq!(normalized("Shift + a"), "shift+a");
+    // 字符主键的大小写保持原样，文本模式下含义不同
+    assert_eq!(normalized("Shift + A"), "shift+A");
+    assert_eq!(normalized("CTRL-c"), "ctrl+c");
+    assert_eq!(normalized("ctrl-shift-z"), "shift+ctrl+z");
+    assert_eq!(normalized("Option+Up"), "alt+up");
+    assert_eq!(normalized("command+q"), "meta+q");
+    assert_eq!(normalized("win + rshift"), "rshift+meta");
+    assert_eq!(normalized("Numpad Enter"), "numpadenter");
+    assert_eq!(normalized(" NUMPAD5 "), "numpad5");
+    // "-" 本身和 "numpad-" 仍是按键
+    assert_eq!(normalized("-"), "-");
+    assert_eq!(normalized("ctrl--"), "ctrl+-");
+    assert_eq!(normalized("shift+numpad-"), "shift+numpadsubtract");
+    assert_eq!(normalized("a"), "a");
+}
+
+#[test]
+fn normalization_suggests_close_names() {
+    let err = normalize_key_string("shfit+a").unwrap_err();
+    assert!(err.contains("did you mean 'shift+a'"), "{err}");
+
+    let err = normalize_key_string("ctr+x").unwrap_err();
+    assert!(err.contains("did you mean 'ctrl+x'"), "{err}");
+
+    let err = normalize_key_string("alt+numpda5").unwrap_err();
+    assert!(err.contains("did you mean 'alt+numpad5'"), "{err}");
+
+    // 已有建议时不重复
+    let err = normalize_key_string("num1").unwrap_err();
+    assert_eq!(err.matches("did you mean").count(), 1, "{err}");
+
+    // 没有足够接近的名称时不给建议
+    let err = normalize_key_string("cmd+Space").unwrap_err();
+    assert!(
+        err.contains("'space'") && !err.contains("did you mean"),
+        "{err}"
+    );
+    assert!(normalize_key_string("").is_err());
+    assert!(normalize_key_string("ctrl+").is_err());
+}
+
+#[test]
+fn normalization_is_idempotent() {
+    let pieces = [
+        "a",
+        "A",
+        "1",
+        "-",
+        "/",
+        "shift",
+        "Shift",
+        "RSHIFT",
+        "ctrl",
+        "control",
+        "CTL",
+        "alt",
+        "option",
+        "ralt",
+        "meta",
+        "cmd",
+        "super",
+        "rwin",
+        "numpad3",
+        "NumPad-",
+        "numpadenter",
+        "up",
+        "ArrowLeft",
+        "num4",
+        "numpad",
+        "enter",
+        "shfit",
+        "xyz",
+        "",
+        " ",
+    ];
+    let separators = ["+", " + ", "-", "++"];
+    // 固定种子的伪随机组合，失败时可以复现
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = |n: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % n as u64) as usize
+    };
+    for _ in 0..5000 {
+        let count = 1 + next(3);
+        let mut input = String::new();
+        for i in 0..count {
+            if i > 0 {
+                input.push_str(separators[next(separators.len())]);
+            }
+            input.push_str(pieces[next(pieces.len())]);
+        }
+        let Ok(once) = normalize_key_string(&input) else {
+            continue;
+        };
+        let parsed_once =
+            parse_key_string(&once).unwrap_or_else(|e| panic!("{input:?} -> {once:?}: {e}"));
+        assert_eq!(
+            normalize_key_string(&once).as_deref(),
+            Ok(once.as_str()),
+            "{input:?}"
+        );
+        assert_eq!(parsed_once.to_string(), once, "{input:?}");
+        // 原本就能解析的写法整理后含义不变
+        if let Ok(parsed) = parse_key_string(&input) {
+            assert_eq!(parsed, parsed_once, "{input:?}");
+        }
+    }
+}
@@ -2,6 +2,10 @@ use xcap::Window;
 use std::error::Error;
 
 use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+mod toplevel;
+pub use toplevel::{list_windows, pick_window_under_cursor, TopLevelWindow, WindowQueryError};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WindowInfo {
@@ -15,6 +19,30 @@ pub struct WindowInfo {
     pub is_maximized: bool,
 }
 
+/// 当前前台窗口
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForegroundWindow {
+    pub pid: u32,
+    pub title: String,
+    pub app_name: String,
+}
+
+impl ForegroundWindow {
+    /// 标题或应用名包含指定子串（不区分大小写）
+    pub fn matches(&self, pattern: &str) -> bool {
+        let pattern = pattern.to_lowercase();
+        self.title.to_lowercase().contains(&pattern) || self.app_name.to_lowercase().contains(&pattern)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ForegroundError {
+    #[error("Foreground window detection is not supported: {0}")]
+    Unsupported(String),
+    #[error("Failed to query foreground window: {0}")]
+    Query(String),
+}
+
 pub fn enumerate_windows() -> Result<Vec<WindowInfo>, Box<dyn Error>> {
     let windows = Window::all()?;
     let infos = windows.into_iter().map(|w| WindowInfo {
@@ -84,3 +112,107 @@ pub fn activate_window(_id: u32) -> Result<(), Box<dyn Error>> {
     // So better interface: activate_window(info: &WindowInfo).
     Err("On macOS, please use activate_window_by_pid with the pid from WindowInfo".into())
 }
+
+#[cfg(target_os = "windows")]
+pub fn foreground_window() -> Result<ForegroundWindow, ForegroundError> {
+    use windows::Win32::UI::WindowsAndMessaging::{GetForegroundWindow, GetWindowTextW, GetWindowThreadProcessId};
+
+    unsafe {
+        let hwnd = GetForegroundWindow();
+        if hwnd.0.is_null() {
+            return Err(ForegroundError::Query("no foreground window".into()));
+        }
+
+        let mut buf = [0u16; 512];
+        let len = GetWindowTextW(hwnd, &mut buf);
+        let title = String::from_utf16_lossy(&buf[..len.max(0) as usize]);
+
+        let mut pid = 0u32;
+        GetWindowThreadProcessId(hwnd, Some(&mut pid));
+
+        Ok(ForegroundWindow { pid, title, app_name: String::new() })
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn foreground_window() -> Result<ForegroundWindow, ForegroundError> {
+    // 与 activate_window_by_pid 一样通过 System Events 查询
+    // 读取窗口标题需要辅助功能权限，失败时标题留空
+    let script = r#"
+        tell application "System Events"
+            set p to first application process whose frontmost is true
+            set n to name of p
+            set i to unix id of p
+            try
+                set t to name of front window of p
+            on error
+                set t to ""
+            end try
+        end tell
+        return (i as text) & linefeed & n & linefeed & t
+    "#;
+
+    let output = std::process::Command::new("osascript")
+        .arg("-e")
+        .arg(script)
+        .output()
+        .map_err(|e| ForegroundError::Query(e.to_string()))?;
+    if !output.status.success() {
+        return Err(ForegroundError::Query(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    let mut lines = text.lines();
+    let pid = lines.next().and_then(|l| l.trim().parse().ok()).unwrap_or(0);
+    let app_name = lines.next().unwrap_or_default().to_string();
+    let title = lines.next().unwrap_or_default().to_string();
+
+    Ok(ForegroundWindow { pid, title, app_name })
+}
+
+#[cfg(target_os = "linux")]
+pub fn foreground_window() -> Result<ForegroundWindow, ForegroundError> {
+    if std::env::var("XDG_SESSION_TYPE").is_ok_and(|t| t.eq_ignore_ascii_case("wayland")) {
+        return Err(ForegroundError::Unsupported("Wayland does not expose the foreground window".into()));
+    }
+
+    // X11：通过 xprop 读取 _NET_ACTIVE_WINDOW
+    let xprop = |args: &[&str]| -> Result<String, ForegroundError> {
+        let output = std::process::Command::new("xprop")
+            .args(args)
+            .output()
+            .map_err(|_| ForegroundError::Unsupported("xprop is required on X11".into()))?;
+        if !output.status.success() {
+            return Err(ForegroundError::Query(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+
+    let active = xprop(&["-root", "_NET_ACTIVE_WINDOW"])?;
+    let id = active
+        .rsplit('#')
+        .next()
+        .map(|s| s.trim().to_string())
+        .filter(|s| s.starts_with("0x") && s != "0x0")
+        .ok_or_else(|| ForegroundError::Query("no foreground window".into()))?;
+
+    let props = xprop(&["-id", &id, "_NET_WM_NAME", "_NET_WM_PID", "WM_CLASS"])?;
+    let mut window = ForegroundWindow { pid: 0, title: String::new(), app_name: String::new() };
+    for line in props.lines() {
+        let Some((name, value)) = line.split_once(" = ") else { continue };
+        if name.starts_with("_NET_WM_NAME") {
+            window.title = value.trim_matches('"').to_string();
+        } else if name.starts_with("_NET_WM_PID") {
+            window.pid = value.trim().parse().unwrap_or(0);
+        } else if name.starts_with("WM_CLASS") {
+            // WM_CLASS = "instance", "Class"，取后者作为应用名
+            window.app_name = value.rsplit(", ").next().unwrap_or_default().trim_matches('"').to_string();
+        }
+    }
+    Ok(window)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+pub fn foreground_window() -> Result<ForegroundWindow, ForegroundError> {
+    Err(ForegroundError::Unsupported("unsupported platform".into()))
+}
@@ -0,0 +1,143 @@
+//! macOS：CGWindowList 列出窗口，读取其他应用的窗口标题需要屏幕录制权限
+
+use super::{TopLevelWindow, WindowQueryError, POLL_INTERVAL};
+use core_foundation::base::{CFType, TCFType};
+use core_foundation::dictionary::{CFDictionary, CFDictionaryRef};
+use core_foundation::number::CFNumber;
+use core_foundation::string::{CFString, CFStringRef};
+use core_graphics::access::ScreenCaptureAccess;
+use core_graphics::event::CGEvent;
+use core_graphics::event_source::{CGEventSource, CGEventSourceStateID};
+use core_graphics::geometry::{CGPoint, CGRect};
+use core_graphics::window::{
+    copy_window_info, kCGNullWindowID, kCGWindowBounds, kCGWindowLayer,
+    kCGWindowListExcludeDesktopElements, kCGWindowListOptionOnScreenOnly, kCGWindowName,
+    kCGWindowNumber, kCGWindowOwnerName, kCGWindowOwnerPID,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+// kCGMouseButtonLeft 和虚拟键码 kVK_Return / kVK_Escape
+const LEFT_BUTTON: u32 = 0;
+const KEY_RETURN: u16 = 36;
+const KEY_ESCAPE: u16 = 53;
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGEventSourceButtonState(state: CGEventSourceStateID, button: u32) -> bool;
+    fn CGEventSourceKeyState(state: CGEventSourceStateID, key: u16) -> bool;
+}
+
+struct Entry {
+    window: TopLevelWindow,
+    bounds: Option<CGRect>,
+}
+
+pub fn list_windows() -> Result<Vec<TopLevelWindow>, WindowQueryError> {
+    Ok(on_screen_windows()?
+        .into_iter()
+        .map(|entry| entry.window)
+        .filter(|window| !window.title.is_empty())
+        .collect())
+}
+
+// 屏幕上的普通窗口，CGWindowList 按从前到后的顺序返回
+fn on_screen_windows() -> Result<Vec<Entry>, WindowQueryError> {
+    // 没有屏幕录制权限时 kCGWindowName 全部为空，无法按标题选择
+    if !ScreenCaptureAccess.preflight() {
+        // 第一次调用时弹出系统授权提示
+        ScreenCaptureAccess.request();
+        return Err(WindowQueryError::PermissionDenied(
+            "Screen Recording permission is required to read window titles. \
+             Enable it in System Settings > Privacy & Security > Screen Recording, then restart the app"
+                .into(),
+        ));
+    }
+
+    let list = copy_window_info(
+        kCGWindowListOptionOnScreenOnly | kCGWindowListExcludeDesktopElements,
+        kCGNullWindowID,
+    )
+    .ok_or_else(|| WindowQueryError::Query("CGWindowListCopyWindowInfo returned no list".into()))?;
+
+    let mut entries = Vec::new();
+    for item in list.iter() {
+        let info: CFDictionary<CFString, CFType> =
+            unsafe { CFDictionary::wrap_under_get_rule(*item as CFDictionaryRef) };
+        // 菜单栏、Dock、输入法候选框等都在更高的层
+        if number(&info, unsafe { kCGWindowLayer }) != Some(0) {
+            continue;
+        }
+        let bounds = find(&info, unsafe { kCGWindowBounds })
+            .and_then(|value| value.downcast::<CFDictionary>())
+            .and_then(|dict| CGRect::from_dict_representation(&dict));
+        entries.push(Entry {
+            window: TopLevelWindow {
+                id: number(&info, unsafe { kCGWindowNumber }).unwrap_or(0) as u64,
+                pid: number(&info, unsafe { kCGWindowOwnerPID }).unwrap_or(0) as u32,
+                title: string(&info, unsafe { kCGWindowName }).unwrap_or_default(),
+                process_name: string(&info, unsafe { kCGWindowOwnerName }),
+            },
+            bounds,
+        });
+    }
+    Ok(entries)
+}
+
+fn find(info: &CFDictionary<CFString, CFType>, key: CFStringRef) -> Option<CFType> {
+    let key = unsafe { CFString::wrap_under_get_rule(key) };
+    info.find(&key).map(|value| value.clone())
+}
+
+fn number(info: &CFDictionary<CFString, CFType>, key: CFStringRef) -> Option<i64> {
+    find(info, key)?.downcast::<CFNumber>()?.to_i64()
+}
+
+fn string(info: &CFDictionary<CFString, CFType>, key: CFStringRef) -> Option<String> {
+    Some(find(info, key)?.downcast::<CFString>()?.to_string())
+}
+
+pub fn pick(deadline: Instant, cancel: &AtomicBool) -> Result<TopLevelWindow, WindowQueryError> {
+    // 先检查权限，免得用户点完才发现取不到标题
+    on_screen_windows()?;
+
+    let state = CGEventSourceStateID::CombinedSessionState;
+    // 调用时用户可能还按着点选按钮，先等按键松开再算一次新的点击
+    let mut was_down = true;
+    while Instant::now() < deadline {
+        let (clicked, escape) = unsafe {
+            (
+                CGEventSourceButtonState(state, LEFT_BUTTON)
+                    || CGEventSourceKeyState(state, KEY_RETURN),
+                CGEventSourceKeyState(state, KEY_ESCAPE),
+            )
+        };
+        if cancel.load(Ordering::SeqCst) || escape {
+            return Err(WindowQueryError::Cancelled);
+        }
+        if clicked && !was_down {
+            return window_under_cursor();
+        }
+        was_down = clicked;
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Err(WindowQueryError::TimedOut)
+}
+
+fn window_under_cursor() -> Result<TopLevelWindow, WindowQueryError> {
+    let point = cursor_location()?;
+    on_screen_windows()?
+        .into_iter()
+        .find(|entry| entry.bounds.is_some_and(|bounds| bounds.contains(&point)))
+        .map(|entry| entry.window)
+        .ok_or_else(|| WindowQueryError::Query("no window under the cursor".into()))
+}
+
+// 全局坐标，原点在主屏幕左上角，和 kCGWindowBounds 一致
+fn cursor_location() -> Result<CGPoint, WindowQueryError> {
+    let source = CGEventSource::new(CGEventSourceStateID::CombinedSessionState)
+        .map_err(|_| WindowQueryError::Query("failed to create event source".into()))?;
+    let event = CGEvent::new(source)
+        .map_err(|_| WindowQueryError::Query("failed to read cursor position".into()))?;
+    Ok(event.location())
+}
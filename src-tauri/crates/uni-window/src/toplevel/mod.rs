@@ -0,0 +1,88 @@
+//! 列出和点选其他应用的顶层窗口，用于填写焦点守卫和配置规则的目标窗口
+//! 各平台的实现在各自的子模块里，返回同一种 TopLevelWindow
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "windows")]
+mod win32;
+#[cfg(target_os = "linux")]
+mod x11;
+
+#[cfg(target_os = "macos")]
+use macos as platform;
+#[cfg(target_os = "windows")]
+use win32 as platform;
+#[cfg(target_os = "linux")]
+use x11 as platform;
+
+// 点选时检查鼠标和按键的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(15);
+
+/// 当前桌面上的一个顶层窗口
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopLevelWindow {
+    pub id: u64, // HWND / CGWindowID / X11 窗口 ID
+    pub pid: u32,
+    pub title: String,
+    pub process_name: Option<String>, // 进程名，取不到时为 None
+}
+
+#[derive(Debug, Error)]
+pub enum WindowQueryError {
+    /// 当前环境无法查看其他应用的窗口（如 Wayland）
+    #[error("Window listing is not supported: {0}")]
+    Unsupported(String),
+    /// 缺少系统权限（macOS 屏幕录制）
+    #[error("Permission required: {0}")]
+    PermissionDenied(String),
+    #[error("Window picking was cancelled")]
+    Cancelled,
+    #[error("Timed out waiting for a window to be picked")]
+    TimedOut,
+    #[error("Failed to query windows: {0}")]
+    Query(String),
+}
+
+/// 可见的顶层窗口，从前到后排列，不含没有标题的窗口
+pub fn list_windows() -> Result<Vec<TopLevelWindow>, WindowQueryError> {
+    platform::list_windows()
+}
+
+/// 等待用户点击一个窗口，返回鼠标下的顶层窗口
+/// Windows 和 macOS 上也可以把鼠标移到窗口上按回车，Esc 取消；X11 上只能点击
+/// 点到本应用自己的窗口时忽略，继续等待
+pub fn pick_window_under_cursor(
+    timeout: Duration,
+    cancel: &AtomicBool,
+) -> Result<TopLevelWindow, WindowQueryError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let window = platform::pick(deadline, cancel)?;
+        if window.pid != std::process::id() {
+            return Ok(window);
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+mod platform {
+    use super::{TopLevelWindow, WindowQueryError};
+    use std::sync::atomic::AtomicBool;
+    use std::time::Instant;
+
+    pub fn list_windows() -> Result<Vec<TopLevelWindow>, WindowQueryError> {
+        Err(WindowQueryError::Unsupported("unsupported platform".into()))
+    }
+
+    pub fn pick(
+        _deadline: Instant,
+        _cancel: &AtomicBool,
+    ) -> Result<TopLevelWindow, WindowQueryError> {
+        Err(WindowQueryError::Unsupported("unsupported platform".into()))
+    }
+}
@@ -0,0 +1,133 @@
+//! Win32：EnumWindows 列出窗口，GetAsyncKeyState 轮询点击
+
+use super::{TopLevelWindow, WindowQueryError, POLL_INTERVAL};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use windows::core::PWSTR;
+use windows::Win32::Foundation::{CloseHandle, BOOL, HWND, LPARAM, POINT};
+use windows::Win32::Graphics::Dwm::{DwmGetWindowAttribute, DWMWA_CLOAKED};
+use windows::Win32::System::Threading::{
+    OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+};
+use windows::Win32::UI::Input::KeyboardAndMouse::{
+    GetAsyncKeyState, VIRTUAL_KEY, VK_ESCAPE, VK_LBUTTON, VK_RETURN,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    EnumWindows, GetAncestor, GetCursorPos, GetWindow, GetWindowTextW, GetWindowThreadProcessId,
+    IsWindowVisible, WindowFromPoint, GA_ROOT, GW_OWNER,
+};
+
+pub fn list_windows() -> Result<Vec<TopLevelWindow>, WindowQueryError> {
+    let mut handles: Vec<HWND> = Vec::new();
+    unsafe {
+        EnumWindows(
+            Some(collect),
+            LPARAM(&mut handles as *mut Vec<HWND> as isize),
+        )
+        .map_err(|e| WindowQueryError::Query(e.to_string()))?;
+    }
+    // EnumWindows 按 Z 序从前到后枚举
+    Ok(handles
+        .into_iter()
+        .filter(|&hwnd| is_listed(hwnd))
+        .map(describe)
+        .collect())
+}
+
+unsafe extern "system" fn collect(hwnd: HWND, lparam: LPARAM) -> BOOL {
+    let handles = &mut *(lparam.0 as *mut Vec<HWND>);
+    handles.push(hwnd);
+    true.into()
+}
+
+// 和任务栏上能看到的窗口一致：可见、没有所有者、没有被隐藏（cloaked，如后台的 UWP 窗口）、有标题
+fn is_listed(hwnd: HWND) -> bool {
+    unsafe {
+        if !IsWindowVisible(hwnd).as_bool() || GetWindow(hwnd, GW_OWNER).is_ok() {
+            return false;
+        }
+        let mut cloaked = 0u32;
+        let queried = DwmGetWindowAttribute(
+            hwnd,
+            DWMWA_CLOAKED,
+            &mut cloaked as *mut u32 as *mut _,
+            std::mem::size_of::<u32>() as u32,
+        );
+        if queried.is_ok() && cloaked != 0 {
+            return false;
+        }
+    }
+    !window_title(hwnd).is_empty()
+}
+
+fn window_title(hwnd: HWND) -> String {
+    let mut buf = [0u16; 512];
+    let len = unsafe { GetWindowTextW(hwnd, &mut buf) };
+    String::from_utf16_lossy(&buf[..len.max(0) as usize])
+}
+
+fn describe(hwnd: HWND) -> TopLevelWindow {
+    let mut pid = 0u32;
+    unsafe {
+        GetWindowThreadProcessId(hwnd, Some(&mut pid));
+    }
+    TopLevelWindow {
+        id: hwnd.0 as u64,
+        pid,
+        title: window_title(hwnd),
+        process_name: process_name(pid),
+    }
+}
+
+// 可执行文件名，如 YuanShen.exe；以管理员身份运行的进程可能打不开
+fn process_name(pid: u32) -> Option<String> {
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid).ok()?;
+        let mut buf = [0u16; 1024];
+        let mut len = buf.len() as u32;
+        let queried = QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(buf.as_mut_ptr()),
+            &mut len,
+        );
+        let _ = CloseHandle(process);
+        queried.ok()?;
+        let path = String::from_utf16_lossy(&buf[..len as usize]);
+        path.rsplit('\\').next().map(str::to_string)
+    }
+}
+
+pub fn pick(deadline: Instant, cancel: &AtomicBool) -> Result<TopLevelWindow, WindowQueryError> {
+    // 调用时用户可能还按着点选按钮，先等按键松开再算一次新的点击
+    let mut was_down = true;
+    while Instant::now() < deadline {
+        if cancel.load(Ordering::SeqCst) || is_down(VK_ESCAPE) {
+            return Err(WindowQueryError::Cancelled);
+        }
+        let down = is_down(VK_LBUTTON) || is_down(VK_RETURN);
+        if down && !was_down {
+            return window_under_cursor();
+        }
+        was_down = down;
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Err(WindowQueryError::TimedOut)
+}
+
+fn is_down(key: VIRTUAL_KEY) -> bool {
+    unsafe { GetAsyncKeyState(key.0 as i32) < 0 }
+}
+
+fn window_under_cursor() -> Result<TopLevelWindow, WindowQueryError> {
+    let mut point = POINT::default();
+    unsafe {
+        GetCursorPos(&mut point).map_err(|e| WindowQueryError::Query(e.to_string()))?;
+        // 鼠标下可能是按钮等子窗口，取它所属的顶层窗口
+        let hwnd = GetAncestor(WindowFromPoint(point), GA_ROOT);
+        if hwnd.0.is_null() {
+            return Err(WindowQueryError::Query("no window under the cursor".into()));
+        }
+        Ok(describe(hwnd))
+    }
+}
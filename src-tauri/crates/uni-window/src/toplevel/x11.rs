@@ -0,0 +1,155 @@
+//! X11：通过 xprop 读取 _NET_CLIENT_LIST，点选窗口借用 xwininfo
+//! Wayland 不允许应用查看其他应用的窗口，也没有可用的门户接口
+
+use super::{TopLevelWindow, WindowQueryError, POLL_INTERVAL};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+fn ensure_x11() -> Result<(), WindowQueryError> {
+    if std::env::var("XDG_SESSION_TYPE").is_ok_and(|t| t.eq_ignore_ascii_case("wayland")) {
+        return Err(WindowQueryError::Unsupported(
+            "Wayland does not let applications list or pick other applications' windows".into(),
+        ));
+    }
+    if std::env::var_os("DISPLAY").is_none() {
+        return Err(WindowQueryError::Unsupported("no X11 display".into()));
+    }
+    Ok(())
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String, WindowQueryError> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|_| WindowQueryError::Unsupported(format!("{} is required on X11", program)))?;
+    if !output.status.success() {
+        return Err(WindowQueryError::Query(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub fn list_windows() -> Result<Vec<TopLevelWindow>, WindowQueryError> {
+    ensure_x11()?;
+    // _NET_CLIENT_LIST_STACKING(WINDOW): window id # 0x1e00003, 0x2200007，从后到前
+    let clients = run("xprop", &["-root", "_NET_CLIENT_LIST_STACKING"])?;
+    let ids: Vec<&str> = clients
+        .split_once('#')
+        .map(|(_, list)| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|id| id.starts_with("0x"))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut windows = Vec::new();
+    for id in ids.into_iter().rev() {
+        // 枚举期间窗口可能已经关闭
+        let Ok(Some(window)) = describe(id) else {
+            continue;
+        };
+        windows.push(window);
+    }
+    Ok(windows)
+}
+
+// 不在任务栏上显示的窗口（面板、通知等）和没有标题的窗口返回 None
+fn describe(id: &str) -> Result<Option<TopLevelWindow>, WindowQueryError> {
+    let props = run(
+        "xprop",
+        &[
+            "-id",
+            id,
+            "_NET_WM_NAME",
+            "WM_NAME",
+            "_NET_WM_PID",
+            "WM_CLASS",
+            "_NET_WM_STATE",
+        ],
+    )?;
+    let mut title = None;
+    let mut legacy_title = None;
+    let mut pid = 0;
+    let mut class = None;
+    for line in props.lines() {
+        let Some((name, value)) = line.split_once(" = ") else {
+            continue;
+        };
+        if name.starts_with("_NET_WM_NAME") {
+            title = Some(unquote(value));
+        } else if name.starts_with("WM_NAME") {
+            legacy_title = Some(unquote(value));
+        } else if name.starts_with("_NET_WM_PID") {
+            pid = value.trim().parse().unwrap_or(0);
+        } else if name.starts_with("WM_CLASS") {
+            // WM_CLASS = "instance", "Class"，取后者
+            class = value.rsplit(", ").next().map(unquote);
+        } else if name.starts_with("_NET_WM_STATE") && value.contains("_NET_WM_STATE_SKIP_TASKBAR")
+        {
+            return Ok(None);
+        }
+    }
+    let title = title.or(legacy_title).unwrap_or_default();
+    if title.is_empty() {
+        return Ok(None);
+    }
+    let process_name = std::fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|name| name.trim().to_string())
+        .or(class);
+    Ok(Some(TopLevelWindow {
+        id: u64::from_str_radix(id.trim_start_matches("0x"), 16).unwrap_or(0),
+        pid,
+        title,
+        process_name,
+    }))
+}
+
+fn unquote(value: &str) -> String {
+    value.trim().trim_matches('"').replace("\\\"", "\"")
+}
+
+pub fn pick(deadline: Instant, cancel: &AtomicBool) -> Result<TopLevelWindow, WindowQueryError> {
+    ensure_x11()?;
+    // xwininfo 抓住鼠标，等用户点击一个窗口后输出它的信息
+    let mut child = Command::new("xwininfo")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|_| WindowQueryError::Unsupported("xwininfo is required on X11".into()))?;
+    loop {
+        if child
+            .try_wait()
+            .map_err(|e| WindowQueryError::Query(e.to_string()))?
+            .is_some()
+        {
+            break;
+        }
+        let timed_out = Instant::now() >= deadline;
+        if timed_out || cancel.load(Ordering::SeqCst) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(if timed_out {
+                WindowQueryError::TimedOut
+            } else {
+                WindowQueryError::Cancelled
+            });
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| WindowQueryError::Query(e.to_string()))?;
+    // xwininfo: Window id: 0x3a00007 "title"
+    let text = String::from_utf8_lossy(&output.stdout);
+    let id = text
+        .lines()
+        .find_map(|line| line.split_once("Window id: "))
+        .and_then(|(_, rest)| rest.split_whitespace().next())
+        .ok_or_else(|| WindowQueryError::Query("xwininfo did not report a window".into()))?;
+    describe(id)?.ok_or_else(|| WindowQueryError::Query("the picked window has no title".into()))
+}
@@ -0,0 +1,214 @@
+//! 播放时的本机提示音：游戏静音时也能听出播放是否在进行
+//! 声音在单独的音频线程里生成和播放，播放线程只往通道里放一条消息，不会拖慢按键发送
+
+use rodio::source::{SineWave, Source};
+use rodio::OutputStream;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// 等待音频设备打开的最长时间
+const OPEN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// 排队中的提示音上限，音频线程跟不上时直接丢弃
+const MAX_PENDING: usize = 64;
+
+const CLICK_FREQUENCY: f32 = 2000.0;
+const CLICK_LENGTH: Duration = Duration::from_millis(8);
+const BEEP_LENGTH: Duration = Duration::from_millis(40);
+const FADE: Duration = Duration::from_millis(2);
+
+// 常见的 21 键布局：每行七个键对应 C 大调的一个八度，从低到高
+const KEY_ROWS: [&str; 3] = ["zxcvbnm", "asdfghj", "qwertyu"];
+const MAJOR_SCALE: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+const MIDDLE_C: f32 = 261.63;
+// 不在布局里的键
+const OTHER_KEY_FREQUENCY: f32 = 880.0;
+
+/// 每次按键发出的声音
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CueSound {
+    Click, // 短促的咔嗒声
+    Beep,  // 按键位区分音高的哔声
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioFeedbackSettings {
+    pub enabled: bool,
+    pub volume: f32, // 0-1
+    pub sound: CueSound,
+}
+
+impl Default for AudioFeedbackSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            volume: 0.3,
+            sound: CueSound::Click,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioFeedbackStatus {
+    pub settings: AudioFeedbackSettings,
+    // 打开音频设备失败时的原因，此时 enabled 被自动关闭
+    pub warning: Option<String>,
+}
+
+struct Cue {
+    frequency: f32,
+    length: Duration,
+    volume: f32,
+}
+
+struct AudioThread {
+    sender: SyncSender<Cue>,
+    handle: JoinHandle<()>,
+}
+
+/// 按键提示音，通过 Tauri `.manage()` 注册
+#[derive(Default)]
+pub struct AudioFeedback {
+    settings: Mutex<AudioFeedbackSettings>,
+    thread: Mutex<Option<AudioThread>>,
+    warning: Mutex<Option<String>>,
+}
+
+impl AudioFeedback {
+    pub fn status(&self) -> AudioFeedbackStatus {
+        AudioFeedbackStatus {
+            settings: self.settings.lock().unwrap().clone(),
+            warning: self.warning.lock().unwrap().clone(),
+        }
+    }
+
+    /// 更新设置；开启时打开音频设备，没有可用设备时自动关闭并在 warning 中说明
+    pub fn set_settings(
+        &self,
+        mut settings: AudioFeedbackSettings,
+    ) -> Result<AudioFeedbackStatus, String> {
+        if !(0.0..=1.0).contains(&settings.volume) {
+            return Err(format!(
+                "volume must be within 0-1, got {}",
+                settings.volume
+            ));
+        }
+        let mut warning = None;
+        if settings.enabled {
+            if let Err(e) = self.start() {
+                eprintln!("Audio feedback disabled: {}", e);
+                settings.enabled = false;
+                warning = Some(e);
+            }
+        } else {
+            self.shutdown();
+        }
+        *self.settings.lock().unwrap() = settings;
+        *self.warning.lock().unwrap() = warning;
+        Ok(self.status())
+    }
+
+    /// 为一次按键排一个提示音，未开启或音频线程忙不过来时什么都不做
+    pub fn cue(&self, key: &str) {
+        let (volume, sound) = {
+            let settings = self.settings.lock().unwrap();
+            if !settings.enabled {
+                return;
+            }
+            (settings.volume, settings.sound)
+        };
+        let cue = match sound {
+            CueSound::Click => Cue {
+                frequency: CLICK_FREQUENCY,
+                length: CLICK_LENGTH,
+                volume,
+            },
+            CueSound::Beep => Cue {
+                frequency: key_frequency(key),
+                length: BEEP_LENGTH,
+                volume,
+            },
+        };
+        if let Some(thread) = &*self.thread.lock().unwrap() {
+            match thread.sender.try_send(cue) {
+                Ok(()) | Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Disconnected(_)) => {
+                    eprintln!("Audio feedback thread exited");
+                }
+            }
+        }
+    }
+
+    /// 关闭音频线程和设备，应用退出时调用
+    /// 提示音都很短，停止播放时不需要单独打断
+    pub fn shutdown(&self) {
+        let thread = self.thread.lock().unwrap().take();
+        if let Some(AudioThread { sender, handle }) = thread {
+            // 通道关闭后音频线程放完手上的声音就退出
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+
+    // 等待设备打开时不持有 thread 锁，播放线程的 cue 不会被卡住
+    fn start(&self) -> Result<(), String> {
+        if self.thread.lock().unwrap().is_some() {
+            return Ok(());
+        }
+        let (sender, receiver) = mpsc::sync_channel(MAX_PENDING);
+        let (ready_tx, ready_rx) = mpsc::channel();
+        // OutputStream 不能跨线程移动，在音频线程内打开
+        let handle = thread::spawn(move || run_audio(receiver, ready_tx));
+        match ready_rx.recv_timeout(OPEN_TIMEOUT) {
+            Ok(Ok(())) => {
+                *self.thread.lock().unwrap() = Some(AudioThread { sender, handle });
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err("Timed out opening the audio device".to_string()),
+        }
+    }
+}
+
+fn run_audio(receiver: Receiver<Cue>, ready: mpsc::Sender<Result<(), String>>) {
+    let (_stream, handle) = match OutputStream::try_default() {
+        Ok(output) => output,
+        Err(e) => {
+            let _ = ready.send(Err(format!("No audio output device: {}", e)));
+            return;
+        }
+    };
+    let _ = ready.send(Ok(()));
+    while let Ok(cue) = receiver.recv() {
+        let source = SineWave::new(cue.frequency)
+            .take_duration(cue.length)
+            .fade_in(FADE)
+            .amplify(cue.volume);
+        if let Err(e) = handle.play_raw(source) {
+            eprintln!("Failed to play audio feedback: {}", e);
+        }
+    }
+}
+
+/// 按键在 21 键布局里的音高，组合键取最后的主键
+fn key_frequency(key: &str) -> f32 {
+    let main = key.rsplit('+').next().unwrap_or(key).to_lowercase();
+    let mut chars = main.chars();
+    let (Some(c), None) = (chars.next(), chars.next()) else {
+        return OTHER_KEY_FREQUENCY;
+    };
+    KEY_ROWS
+        .iter()
+        .enumerate()
+        .find_map(|(row, keys)| {
+            let index = keys.find(c)?;
+            let semitones = (row as i32 - 1) * 12 + MAJOR_SCALE[index];
+            Some(MIDDLE_C * 2f32.powf(semitones as f32 / 12.0))
+        })
+        .unwrap_or(OTHER_KEY_FREQUENCY)
+}
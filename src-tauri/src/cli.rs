@@ -0,0 +1,328 @@
+//! 无界面的命令行模式，供脚本调用
+//! 例如 `opengamesautoplay --parse song.mid --range 48:84 --black-keys drop --play --delay 3`
+
+use crate::keypress_simulator::{
+    self, KeyEvent, KeyEventOptions, PlaybackController, PlaybackOptions,
+};
+use crate::midi_analyzer::{self, AnalyzerOptions, BlackKeyMode, KeyMap, KeyMapSpec};
+use crate::presets;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use uni_input::InputPermission;
+
+// 退出码
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_USAGE: i32 = 1;
+pub const EXIT_PARSE_ERROR: i32 = 2;
+pub const EXIT_PERMISSION_DENIED: i32 = 3;
+pub const EXIT_PLAYBACK_ERROR: i32 = 4;
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+const USAGE: &str = "Usage: opengamesautoplay --parse <file.mid> [options]
+
+Options:
+  --range <min>:<max>       Playable note range (default 48:83)
+  --black-keys <mode>       keep | sharp | drop (default keep)
+  --transpose <semitones>   Shift all notes before mapping
+  --trim-long-notes         Cut notes longer than 1s to 0.99s
+  --preset <name>           Use the key map of a built-in preset
+  --keymap <file.json>      Key map as {\"note\": \"key\"} or as layers
+                            [{\"min_note\", \"max_note\", \"keys\"}, ...], overrides --preset
+  --play                    Play the song after printing the analysis
+  --delay <seconds>         Wait before playing (default 0)
+  -h, --help                Show this help";
+
+// 与前端默认映射一致
+#[rustfmt::skip]
+const DEFAULT_KEYMAP: [(u8, &str); 36] = [
+    (48, "z"), (49, "shift+z"), (50, "x"), (51, "ctrl+c"), (52, "c"), (53, "v"),
+    (54, "shift+v"), (55, "b"), (56, "shift+b"), (57, "n"), (58, "ctrl+m"), (59, "m"),
+    (60, "a"), (61, "shift+a"), (62, "s"), (63, "ctrl+d"), (64, "d"), (65, "f"),
+    (66, "shift+f"), (67, "g"), (68, "shift+g"), (69, "h"), (70, "ctrl+j"), (71, "j"),
+    (72, "q"), (73, "shift+q"), (74, "w"), (75, "ctrl+e"), (76, "e"), (77, "r"),
+    (78, "shift+r"), (79, "t"), (80, "shift+t"), (81, "y"), (82, "ctrl+u"), (83, "u"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlackKeys {
+    Keep,
+    Sharp,
+    Drop,
+}
+
+#[derive(Debug)]
+struct CliArgs {
+    file: String,
+    min_note: u8,
+    max_note: u8,
+    black_keys: BlackKeys,
+    transpose: i32,
+    trim_long_notes: bool,
+    preset: Option<String>,
+    keymap: Option<String>,
+    play: bool,
+    delay: f64,
+}
+
+#[derive(Serialize)]
+struct Summary<'a> {
+    file: &'a str,
+    duration: f64,
+    note_count: usize,
+    playable_count: usize, // 在范围内且有按键映射的音符数
+    min_note: Option<u8>,
+    max_note: Option<u8>,
+    min_note_name: &'a str,
+    max_note_name: &'a str,
+    under_min_count: usize,
+    over_max_count: usize,
+    tracks: Vec<&'a str>,
+}
+
+/// 是否以命令行模式启动；没有 --parse 时照常启动界面
+pub fn requested(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == "--parse")
+}
+
+fn parse_args(args: &[String]) -> Result<CliArgs, String> {
+    let mut parsed = CliArgs {
+        file: String::new(),
+        min_note: 48,
+        max_note: 83,
+        black_keys: BlackKeys::Keep,
+        transpose: 0,
+        trim_long_notes: false,
+        preset: None,
+        keymap: None,
+        play: false,
+        delay: 0.0,
+    };
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--parse" => parsed.file = value()?,
+            "--range" => {
+                let range = value()?;
+                let (min, max) = range
+                    .split_once(':')
+                    .ok_or_else(|| format!("Invalid range: {}", range))?;
+                parsed.min_note = min
+                    .parse()
+                    .map_err(|_| format!("Invalid range: {}", range))?;
+                parsed.max_note = max
+                    .parse()
+                    .map_err(|_| format!("Invalid range: {}", range))?;
+                if parsed.min_note > parsed.max_note {
+                    return Err(format!("Invalid range: {}", range));
+                }
+            }
+            "--black-keys" => {
+                parsed.black_keys = match value()?.as_str() {
+                    "keep" => BlackKeys::Keep,
+                    "sharp" => BlackKeys::Sharp,
+                    "drop" => BlackKeys::Drop,
+                    other => return Err(format!("Invalid black key mode: {}", other)),
+                }
+            }
+            "--transpose" => {
+                let transpose = value()?;
+                parsed.transpose = transpose
+                    .parse()
+                    .map_err(|_| format!("Invalid transpose: {}", transpose))?;
+            }
+            "--trim-long-notes" => parsed.trim_long_notes = true,
+            "--preset" => parsed.preset = Some(value()?),
+            "--keymap" => parsed.keymap = Some(value()?),
+            "--play" => parsed.play = true,
+            "--delay" => {
+                let delay = value()?;
+                parsed.delay = delay
+                    .parse()
+                    .ok()
+                    .filter(|d: &f64| d.is_finite() && *d >= 0.0)
+                    .ok_or_else(|| format!("Invalid delay: {}", delay))?;
+            }
+            other => return Err(format!("Unknown argument: {}", other)),
+        }
+    }
+
+    if parsed.file.is_empty() {
+        return Err("--parse requires a MIDI file".to_string());
+    }
+    Ok(parsed)
+}
+
+fn load_keymap(args: &CliArgs) -> Result<KeyMap, String> {
+    if let Some(path) = &args.keymap {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read keymap: {}", e))?;
+        let spec: KeyMapSpec =
+            serde_json::from_str(&content).map_err(|e| format!("Invalid keymap: {}", e))?;
+        return spec.resolve().map(|resolved| resolved.keys);
+    }
+    if let Some(name) = &args.preset {
+        // 命令行模式不启动 Tauri，拿不到应用配置目录，只支持内置预设
+        return presets::builtin_preset(name)
+            .map(|preset| preset.note_to_key)
+            .ok_or_else(|| format!("Unknown built-in preset: {}", name));
+    }
+    Ok(DEFAULT_KEYMAP
+        .iter()
+        .map(|(note, key)| (*note, key.to_string()))
+        .collect())
+}
+
+// Ctrl-C 时置位，播放等待循环据此停止
+fn install_interrupt_handler() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&interrupted);
+    thread::spawn(move || {
+        let Ok(runtime) = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        else {
+            return;
+        };
+        if runtime.block_on(tokio::signal::ctrl_c()).is_ok() {
+            flag.store(true, Ordering::SeqCst);
+        }
+    });
+    interrupted
+}
+
+fn play(events: Vec<KeyEvent>, delay: f64) -> i32 {
+    if uni_input::check_input_permission(true) == InputPermission::Denied {
+        eprintln!("Accessibility permission is required to simulate key presses");
+        return EXIT_PERMISSION_DENIED;
+    }
+
+    let interrupted = install_interrupt_handler();
+    let deadline = Instant::now() + Duration::from_secs_f64(delay);
+    while Instant::now() < deadline {
+        if interrupted.load(Ordering::SeqCst) {
+            return EXIT_INTERRUPTED;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let controller = PlaybackController::new(Arc::new(keypress_simulator::create_sender));
+    if let Err(e) = controller.start(events, PlaybackOptions::default()) {
+        eprintln!("{}", e);
+        return EXIT_PLAYBACK_ERROR;
+    }
+    while controller.is_active() {
+        if interrupted.load(Ordering::SeqCst) {
+            // stop 会等播放线程释放所有按住的键
+            let _ = controller.stop();
+            return EXIT_INTERRUPTED;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    match controller.last_report() {
+        Some(report) if report.failed > 0 => EXIT_PLAYBACK_ERROR,
+        _ => EXIT_OK,
+    }
+}
+
+// Release 版在 Windows 上是 GUI 子系统，没有控制台，需要挂到启动它的终端上才能输出
+#[cfg(windows)]
+fn attach_console() {
+    use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+    let _ = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+}
+
+#[cfg(not(windows))]
+fn attach_console() {}
+
+/// 执行命令行模式，返回进程退出码
+pub fn run(args: &[String]) -> i32 {
+    attach_console();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return EXIT_OK;
+    }
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let keymap = match load_keymap(&args) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_USAGE;
+        }
+    };
+
+    let black_key_mode = match args.black_keys {
+        BlackKeys::Sharp => BlackKeyMode::AutoSharp,
+        BlackKeys::Keep | BlackKeys::Drop => BlackKeyMode::SupportBlackKey,
+    };
+    let options = AnalyzerOptions {
+        min_note: args.min_note,
+        max_note: args.max_note,
+        black_key_mode,
+        trim_long_notes: args.trim_long_notes,
+        ..AnalyzerOptions::default()
+    };
+    let analysis = match midi_analyzer::analyze_midi_file(&args.file, &options) {
+        Ok(analysis) => analysis,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_PARSE_ERROR;
+        }
+    };
+
+    // 与前端播放相同：移调后只保留范围内且有映射的音符
+    let events = keypress_simulator::key_events(
+        &analysis.events,
+        &keymap,
+        &KeyEventOptions {
+            transpose: args.transpose,
+            min_note: args.min_note,
+            max_note: args.max_note,
+            drop_black_keys: args.black_keys == BlackKeys::Drop,
+        },
+    );
+    let summary = Summary {
+        file: &args.file,
+        duration: analysis.events.iter().map(|e| e.end).fold(0.0, f64::max),
+        note_count: analysis
+            .events
+            .iter()
+            .filter(|e| e.type_ == "note_on")
+            .count(),
+        playable_count: events.len(),
+        min_note: analysis.analysis.min_note,
+        max_note: analysis.analysis.max_note,
+        min_note_name: &analysis.analysis.min_note_name,
+        max_note_name: &analysis.analysis.max_note_name,
+        under_min_count: analysis.analysis.under_min_count,
+        over_max_count: analysis.analysis.over_max_count,
+        tracks: analysis.tracks.iter().map(|t| t.name.as_str()).collect(),
+    };
+    match serde_json::to_string_pretty(&summary) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("{}", e),
+    }
+
+    if !args.play {
+        return EXIT_OK;
+    }
+    if events.is_empty() {
+        eprintln!("No playable notes in range");
+        return EXIT_PLAYBACK_ERROR;
+    }
+    play(events, args.delay)
+}
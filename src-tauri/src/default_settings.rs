@@ -0,0 +1,93 @@
+//! 解析和播放选项的默认值，保存在应用配置目录
+//! parse_midi、start_playback 等命令的调用方没有给出某个选项时使用这里的值，前端不必自己维护一份默认值
+
+use crate::humanize::HumanizeConfig;
+use crate::keypress_simulator::PlaybackSettings;
+use crate::midi_analyzer::AnalyzerOptions;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// 可以设置默认值的选项，未保存过时为出厂值
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DefaultSettings {
+    // 音轨选择跟具体文件有关，不作为默认值保存
+    pub analyzer: AnalyzerOptions,
+    pub playback: PlaybackSettings,
+    pub press_sounding: bool,
+    pub humanize: Option<HumanizeConfig>,
+}
+
+impl DefaultSettings {
+    /// 与调用命令时相同的检查
+    pub fn validate(&self) -> Result<(), String> {
+        self.analyzer.validate()?;
+        self.playback.validate()?;
+        if let Some(humanize) = &self.humanize {
+            humanize.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// 默认值的读写，通过 Tauri `.manage()` 注册
+pub struct DefaultSettingsStore {
+    path: PathBuf,
+    settings: Mutex<DefaultSettings>,
+}
+
+impl DefaultSettingsStore {
+    /// 从 path 读取，文件不存在、损坏或取值无效时使用出厂值
+    pub fn load(path: PathBuf) -> Self {
+        let settings = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str::<DefaultSettings>(&content)
+                .map_err(|e| e.to_string())
+                .and_then(|settings| settings.validate().map(|_| settings))
+                .unwrap_or_else(|e| {
+                    eprintln!("Ignoring invalid default settings: {}", e);
+                    DefaultSettings::default()
+                }),
+            Err(_) => DefaultSettings::default(),
+        };
+        Self {
+            path,
+            settings: Mutex::new(settings),
+        }
+    }
+
+    pub fn get(&self) -> DefaultSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// 检查后保存，无效时不修改已有的默认值
+    pub fn set(&self, mut settings: DefaultSettings) -> Result<(), String> {
+        settings.analyzer.track_selection = None;
+        settings.validate()?;
+        self.save(&settings)?;
+        *self.settings.lock().unwrap() = settings;
+        Ok(())
+    }
+
+    /// 恢复出厂值并删除配置文件
+    pub fn reset(&self) -> Result<DefaultSettings, String> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to delete default settings: {}", e)),
+        }
+        let settings = DefaultSettings::default();
+        *self.settings.lock().unwrap() = settings.clone();
+        Ok(settings)
+    }
+
+    fn save(&self, settings: &DefaultSettings) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create config directory: {}", e))?;
+        }
+        let json = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+        fs::write(&self.path, json).map_err(|e| format!("Failed to save default settings: {}", e))
+    }
+}
@@ -0,0 +1,107 @@
+//! 试运行报告：把一次试运行（见 keypress_simulator::dry_run）的调度结果写成 JSON，方便用户分享排查卡顿
+//! 报告只导出、不导入；已有字段的名称和含义保持不变，结构变化时递增 REPORT_SCHEMA_VERSION
+//! 报告不含时间戳等每次都不同的内容，同样的事件和设置总是得到同样的文件
+
+use crate::error::CommandError;
+use crate::keypress_simulator::{self, DryRun, DryRunSummary, KeyEvent, PlaybackSettings};
+use serde::Serialize;
+use std::io::Write;
+
+// 版本 1：settings、summary、warnings 和逐事件的 events
+// 版本 2：summary 增加 stuck_key_risk，settings 增加 keep_alive_seconds
+pub const REPORT_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunReport {
+    pub schema_version: u32,
+    pub settings: PlaybackSettings, // 试运行使用的设置
+    #[serde(flatten)]
+    pub dry_run: DryRun,
+}
+
+pub fn build(
+    events: &[KeyEvent],
+    settings: PlaybackSettings,
+) -> Result<DryRunReport, CommandError> {
+    let dry_run = keypress_simulator::dry_run(events, &settings)?;
+    Ok(DryRunReport {
+        schema_version: REPORT_SCHEMA_VERSION,
+        settings,
+        dry_run,
+    })
+}
+
+/// 试运行并把报告写入 out，返回报告的统计
+pub fn write(
+    out: &mut dyn Write,
+    events: &[KeyEvent],
+    settings: PlaybackSettings,
+) -> Result<DryRunSummary, CommandError> {
+    let report = build(events, settings)?;
+    serde_json::to_writer_pretty(out, &report)
+        .map_err(|e| CommandError::Other(format!("Failed to write dry run report: {}", e)))?;
+    Ok(report.dry_run.summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 当前版本的报告，必须与 write 的输出逐字节相同
+    const SNAPSHOT: &str = include_str!("../tests/fixtures/reports/v2.json");
+
+    // 覆盖报告的各种字段：和弦、轻点、修饰键冲突、同键连按、保留键和超速丢弃
+    fn events() -> Vec<KeyEvent> {
+        [
+            (0.0, "a", 0.5, Some(72)),
+            (0.0, "d", 0.5, Some(64)),
+            (0.5, "g", 0.0, None),
+            (1.0, "shift+a", 0.5, Some(74)),
+            (1.0, "b", 0.5, Some(60)),
+            (1.5, "c", 1.0, None),
+            (2.0, "c", 0.5, None),
+            (2.2, "e", 0.0, None),
+            (2.21, "e", 0.1, None),
+            (2.5, "f", 0.25, None),
+            (3.0, "h", 0.1, None),
+            (3.02, "j", 0.1, None),
+            (3.04, "k", 0.1, None),
+        ]
+        .into_iter()
+        .map(|(time, key, duration, note)| KeyEvent {
+            time,
+            key: key.to_string(),
+            duration,
+            group: None,
+            note,
+            chord: None,
+        })
+        .collect()
+    }
+
+    fn settings() -> PlaybackSettings {
+        PlaybackSettings {
+            max_presses_per_second: 3,
+            reserved_keys: vec!["f".to_string()],
+            ..PlaybackSettings::default()
+        }
+    }
+
+    fn report() -> String {
+        let mut out = Vec::new();
+        write(&mut out, &events(), settings()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // 报告格式的快照，见 tests/fixtures/reports；不一致时说明格式变了，要递增 REPORT_SCHEMA_VERSION
+    #[test]
+    fn report_matches_the_snapshot() {
+        assert_eq!(report(), SNAPSHOT);
+    }
+
+    #[test]
+    fn snapshot_is_the_current_schema() {
+        let snapshot: serde_json::Value = serde_json::from_str(SNAPSHOT).unwrap();
+        assert_eq!(snapshot["schema_version"], REPORT_SCHEMA_VERSION);
+    }
+}
@@ -0,0 +1,304 @@
+//! 两人合奏：两个实例各演奏同一首歌的一个声部，同时开始
+//! 声部按事件的分组标签划分：group 为 "upper" / "lower" 的事件分属高、低声部；
+//! 歌曲里没有这两种标签时按音高在 SPLIT_NOTE 处分开，没有音高的事件归高声部
+//! 两边必须使用同一份导出的歌曲文件，划分完全由事件决定，结果一致
+//!
+//! 同步流程：
+//! 1. 领奏方开启遥控服务，用 schedule_playback 预约开始并给出 duet_role；
+//!    预约的开始时间、领奏方的系统时间和歌曲指纹通过遥控接口 GET /api/duet 公开
+//! 2. 跟奏方用 follow_duet 轮询领奏方，拿到开始时间后按估计的时钟偏差换算为本机时间，预约自己的开始
+//!
+//! 时钟偏差按一次请求的往返估计（假定去程和回程耗时相同），几百毫秒以内的误差可以接受
+
+use crate::keypress_simulator::KeyEvent;
+use crate::playback_stats;
+use crate::song_library;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 没有声部标签时高、低声部的分界：音高不低于它的属于高声部（60 为中央 C）
+pub const SPLIT_NOTE: u8 = 60;
+
+pub const UPPER_GROUP: &str = "upper";
+pub const LOWER_GROUP: &str = "lower";
+
+/// 跟奏方轮询领奏方的间隔和最长等待时间
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+pub const MAX_FOLLOW_WAIT: Duration = Duration::from_secs(30 * 60);
+
+// 单次请求的连接和读写超时
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 本实例演奏的声部
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuetRole {
+    Upper,
+    Lower,
+    #[default]
+    Full,
+}
+
+impl DuetRole {
+    fn name(self) -> &'static str {
+        match self {
+            DuetRole::Upper => UPPER_GROUP,
+            DuetRole::Lower => LOWER_GROUP,
+            DuetRole::Full => "full",
+        }
+    }
+}
+
+// 事件所属的声部；tagged 为 true 时按分组标签，否则按音高
+fn part_of(event: &KeyEvent, tagged: bool) -> Option<DuetRole> {
+    if tagged {
+        return match event.group.as_deref() {
+            Some(UPPER_GROUP) => Some(DuetRole::Upper),
+            Some(LOWER_GROUP) => Some(DuetRole::Lower),
+            _ => None,
+        };
+    }
+    Some(match event.note {
+        Some(note) if note < SPLIT_NOTE => DuetRole::Lower,
+        _ => DuetRole::Upper,
+    })
+}
+
+/// 只保留 role 声部的事件，Full 时原样返回
+/// 有 "upper" / "lower" 标签时没有这两种标签的事件两边都不演奏
+pub fn filter_events(events: Vec<KeyEvent>, role: DuetRole) -> Result<Vec<KeyEvent>, String> {
+    if role == DuetRole::Full {
+        return Ok(events);
+    }
+    let tagged = events.iter().any(|event| {
+        matches!(
+            event.group.as_deref(),
+            Some(UPPER_GROUP) | Some(LOWER_GROUP)
+        )
+    });
+    let part: Vec<KeyEvent> = events
+        .into_iter()
+        .filter(|event| part_of(event, tagged) == Some(role))
+        .collect();
+    if part.is_empty() {
+        return Err(format!(
+            "The {} part of this song has no events",
+            role.name()
+        ));
+    }
+    Ok(part)
+}
+
+/// 划分之前的完整事件序列的指纹，两边用来确认演奏的是同一首歌
+pub fn fingerprint(events: &[KeyEvent]) -> String {
+    let json = serde_json::to_vec(events).unwrap_or_default();
+    song_library::file_hash(&json)
+}
+
+/// 领奏方通过 GET /api/duet 公开的信息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuetAnnouncement {
+    pub start_at_unix_ms: Option<u64>, // 预约的开始时间，领奏方的时钟；还没有预约或已开始时为 None
+    pub server_unix_ms: u64,           // 领奏方回复时的系统时间
+    pub role: Option<DuetRole>,
+    pub fingerprint: Option<String>,
+}
+
+/// 领奏方时钟比本机快多少毫秒：假定回复在请求往返的正中生成
+pub fn clock_offset_ms(server_unix_ms: u64, sent_unix_ms: u64, received_unix_ms: u64) -> i64 {
+    let midpoint = (sent_unix_ms as i128 + received_unix_ms as i128) / 2;
+    (server_unix_ms as i128 - midpoint) as i64
+}
+
+/// 领奏方的开始时间换算为本机时间
+pub fn local_start_ms(leader_start_ms: u64, offset_ms: i64) -> u64 {
+    (leader_start_ms as i128 - offset_ms as i128).max(0) as u64
+}
+
+/// 检查跟奏方能否与领奏方合奏，返回本机的开始时间（Unix 毫秒）
+/// 领奏方还没有预约时返回 Ok(None)
+pub fn arm_follower(
+    announcement: &DuetAnnouncement,
+    role: DuetRole,
+    fingerprint: &str,
+    sent_unix_ms: u64,
+    received_unix_ms: u64,
+) -> Result<Option<u64>, String> {
+    let Some(start) = announcement.start_at_unix_ms else {
+        return Ok(None);
+    };
+    let (Some(leader_role), Some(leader_fingerprint)) =
+        (announcement.role, announcement.fingerprint.as_deref())
+    else {
+        return Err("The leader's scheduled playback is not a duet (no duet_role)".to_string());
+    };
+    if leader_fingerprint != fingerprint {
+        return Err("The leader is playing a different song file".to_string());
+    }
+    if leader_role == DuetRole::Full {
+        return Err("The leader plays the full song, there is no part left".to_string());
+    }
+    if leader_role == role {
+        return Err(format!("The leader also plays the {} part", role.name()));
+    }
+    let offset = clock_offset_ms(announcement.server_unix_ms, sent_unix_ms, received_unix_ms);
+    Ok(Some(local_start_ms(start, offset)))
+}
+
+/// 跟奏轮询的结果；start_at_unix_ms 与 error 都没有时轮询被取消
+#[derive(Debug, Clone, Serialize)]
+pub struct FollowOutcome {
+    pub start_at_unix_ms: Option<u64>, // 本机预约的开始时间
+    pub error: Option<String>,
+}
+
+// 领奏方当前的合奏设置
+#[derive(Debug, Clone)]
+struct LeaderSession {
+    role: DuetRole,
+    fingerprint: String,
+}
+
+/// 合奏状态，通过 Tauri `.manage()` 注册：领奏方记下声部和歌曲指纹，跟奏方记下进行中的轮询
+#[derive(Default)]
+pub struct Duet {
+    leader: Mutex<Option<LeaderSession>>,
+    follow_cancel: Mutex<Option<Arc<AtomicBool>>>,
+}
+
+impl Duet {
+    /// 预约播放成功后调用，role 为 None 时不是合奏；fingerprint 见 fingerprint()
+    pub fn set_leader(&self, role: Option<DuetRole>, fingerprint: String) {
+        *self.leader.lock().unwrap() = role.map(|role| LeaderSession { role, fingerprint });
+    }
+
+    /// 遥控接口 GET /api/duet 的内容，scheduled_at 为控制器当前的预约时间
+    pub fn announcement(&self, scheduled_at: Option<u64>) -> DuetAnnouncement {
+        let leader = self.leader.lock().unwrap().clone();
+        DuetAnnouncement {
+            start_at_unix_ms: scheduled_at,
+            server_unix_ms: playback_stats::unix_ms(),
+            role: leader.as_ref().map(|leader| leader.role),
+            fingerprint: leader.map(|leader| leader.fingerprint),
+        }
+    }
+
+    /// 在后台轮询领奏方（地址如 "192.168.1.5:17380"），拿到本机的开始时间后调用 arm
+    /// 已有轮询时先取消它；done 在结束（预约成功、出错或取消）时调用一次
+    pub fn follow(
+        &self,
+        address: &str,
+        token: String,
+        role: DuetRole,
+        fingerprint: String,
+        arm: Box<dyn FnOnce(u64) -> Result<(), String> + Send>,
+        done: Box<dyn FnOnce(FollowOutcome) + Send>,
+    ) -> Result<(), String> {
+        if role == DuetRole::Full {
+            return Err("duet_role must be upper or lower to follow a leader".to_string());
+        }
+        let address = address
+            .to_socket_addrs()
+            .map_err(|e| format!("Invalid leader address {}: {}", address, e))?
+            .next()
+            .ok_or_else(|| format!("Invalid leader address {}", address))?;
+        self.cancel_follow();
+        let cancel = Arc::new(AtomicBool::new(false));
+        *self.follow_cancel.lock().unwrap() = Some(Arc::clone(&cancel));
+        thread::spawn(move || {
+            let started = Instant::now();
+            let mut unreachable = None;
+            let result = loop {
+                if cancel.load(Ordering::SeqCst) {
+                    break Ok(None);
+                }
+                if started.elapsed() > MAX_FOLLOW_WAIT {
+                    break Err(unreachable.unwrap_or_else(|| {
+                        "The leader did not schedule a start in time".to_string()
+                    }));
+                }
+                let sent = playback_stats::unix_ms();
+                let announcement = match fetch_announcement(address, &token) {
+                    Ok(announcement) => announcement,
+                    // 领奏方可能还没开启遥控服务，继续等
+                    Err(FetchError::Unreachable(e)) => {
+                        unreachable = Some(e);
+                        thread::sleep(POLL_INTERVAL);
+                        continue;
+                    }
+                    Err(FetchError::Invalid(e)) => break Err(e),
+                };
+                let received = playback_stats::unix_ms();
+                match arm_follower(&announcement, role, &fingerprint, sent, received) {
+                    // 请求期间被取消时不再预约
+                    Ok(Some(_)) if cancel.load(Ordering::SeqCst) => break Ok(None),
+                    Ok(Some(start)) => break arm(start).map(|_| Some(start)),
+                    Ok(None) => thread::sleep(POLL_INTERVAL),
+                    Err(e) => break Err(e),
+                }
+            };
+            done(match result {
+                Ok(start_at_unix_ms) => FollowOutcome {
+                    start_at_unix_ms,
+                    error: None,
+                },
+                Err(error) => FollowOutcome {
+                    start_at_unix_ms: None,
+                    error: Some(error),
+                },
+            });
+        });
+        Ok(())
+    }
+
+    pub fn cancel_follow(&self) {
+        if let Some(cancel) = self.follow_cancel.lock().unwrap().take() {
+            cancel.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+enum FetchError {
+    Unreachable(String), // 连接不上或超时，稍后重试
+    Invalid(String),     // 令牌错误或回复无法解析，重试也没用
+}
+
+// 向领奏方的遥控服务请求 /api/duet；用 HTTP/1.0，回复不会分块，读到连接关闭即可
+fn fetch_announcement(address: SocketAddr, token: &str) -> Result<DuetAnnouncement, FetchError> {
+    let unreachable = |e: std::io::Error| {
+        FetchError::Unreachable(format!("Failed to reach the leader at {}: {}", address, e))
+    };
+    let mut stream = TcpStream::connect_timeout(&address, REQUEST_TIMEOUT).map_err(unreachable)?;
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(unreachable)?;
+    write!(
+        stream,
+        "GET /api/duet HTTP/1.0\r\nHost: {}\r\nAuthorization: Bearer {}\r\n\r\n",
+        address, token
+    )
+    .map_err(unreachable)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(unreachable)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| FetchError::Invalid("Invalid response from the leader".to_string()))?;
+    let error = match head.split_whitespace().nth(1) {
+        Some("200") => None,
+        Some("401") => Some("The leader rejected the remote control token".to_string()),
+        status => Some(format!(
+            "The leader answered with status {}",
+            status.unwrap_or("unknown")
+        )),
+    };
+    if let Some(error) = error {
+        return Err(FetchError::Invalid(error));
+    }
+    serde_json::from_str(body)
+        .map_err(|e| FetchError::Invalid(format!("Invalid response from the leader: {}", e)))
+}
@@ -0,0 +1,69 @@
+use serde::Serialize;
+use std::fmt;
+
+/// 返回给前端的结构化错误，解析等库函数的入口也直接返回它
+/// 序列化为 `{ "kind": "...", "message": "..." }`，前端可按 kind 分别处理
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", content = "message", rename_all = "snake_case")]
+pub enum CommandError {
+    /// 缺少模拟输入所需的系统权限（macOS 辅助功能）
+    PermissionDenied(String),
+    /// 要打开的文件不存在
+    FileNotFound(String),
+    /// 没有读写目标文件或文件夹的权限
+    AccessDenied(String),
+    /// 文件不是 MIDI 文件或无法解析
+    InvalidFile(String),
+    /// 操作被用户取消
+    Cancelled(String),
+    /// 本应用的窗口在前台，按键会打进应用自己
+    SelfFocused(String),
+    /// 当前平台或桌面环境不支持该功能（如 Wayland 下列出窗口）
+    Unsupported(String),
+    /// 文件由更新版本的应用保存，需要升级应用才能打开
+    NewerSchema(String),
+    /// 参数取值无效，message 以参数名开头并给出收到的值
+    InvalidArgument(String),
+    /// 当前的播放状态下不能这样做，如没有进行中的播放或已有播放在进行
+    InvalidState(String),
+    Other(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::PermissionDenied(message)
+            | CommandError::FileNotFound(message)
+            | CommandError::AccessDenied(message)
+            | CommandError::InvalidFile(message)
+            | CommandError::Cancelled(message)
+            | CommandError::SelfFocused(message)
+            | CommandError::Unsupported(message)
+            | CommandError::NewerSchema(message)
+            | CommandError::InvalidArgument(message)
+            | CommandError::InvalidState(message)
+            | CommandError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        CommandError::Other(message)
+    }
+}
+
+impl From<uni_window::WindowQueryError> for CommandError {
+    fn from(error: uni_window::WindowQueryError) -> Self {
+        use uni_window::WindowQueryError;
+        let message = error.to_string();
+        match error {
+            WindowQueryError::Unsupported(_) => CommandError::Unsupported(message),
+            WindowQueryError::PermissionDenied(_) => CommandError::PermissionDenied(message),
+            WindowQueryError::Cancelled => CommandError::Cancelled(message),
+            WindowQueryError::TimedOut | WindowQueryError::Query(_) => CommandError::Other(message),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! 分块上传按键事件：几万个事件的数组一次通过 IPC 传给 start_playback 会序列化几 MB 的 JSON，界面明显卡顿
+//! 前端先 begin 拿到上传号，分块 upload（每块单独校验），commit 后把上传号作为句柄传给 start_playback
+//! 中途放弃或长时间不用的上传在下次 begin 时清理
+
+use crate::error::CommandError;
+use crate::keypress_simulator::{self, KeyEvent};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 一次上传的事件数上限
+pub const MAX_UPLOAD_EVENTS: usize = 2_000_000;
+/// 一个分块的事件数上限
+pub const MAX_CHUNK_EVENTS: usize = 50_000;
+
+// 未提交的上传多久没有收到分块就丢弃
+const PENDING_TTL: Duration = Duration::from_secs(60);
+// 提交后的句柄多久没有使用就丢弃
+const COMMITTED_TTL: Duration = Duration::from_secs(30 * 60);
+// 同时保留的上传数（含已提交的），超出时丢弃最久没有使用的
+const MAX_UPLOADS: usize = 8;
+
+struct Upload {
+    expected: usize,
+    events: Vec<KeyEvent>,
+    committed: bool,
+    touched: Instant,
+}
+
+impl Upload {
+    fn expired(&self, now: Instant) -> bool {
+        let ttl = if self.committed {
+            COMMITTED_TTL
+        } else {
+            PENDING_TTL
+        };
+        now.duration_since(self.touched) > ttl
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    uploads: HashMap<u64, Upload>,
+    next_id: u64,
+}
+
+/// 后端暂存的事件，通过 Tauri `.manage()` 注册
+#[derive(Default)]
+pub struct EventUploads {
+    inner: Mutex<Inner>,
+}
+
+impl EventUploads {
+    /// 开始一次上传，返回上传号
+    pub fn begin(&self, total_count: usize) -> Result<u64, CommandError> {
+        if !(1..=MAX_UPLOAD_EVENTS).contains(&total_count) {
+            return Err(CommandError::InvalidArgument(format!(
+                "total_count must be within 1-{}, got {}",
+                MAX_UPLOAD_EVENTS, total_count
+            )));
+        }
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.uploads.retain(|_, upload| !upload.expired(now));
+        while inner.uploads.len() >= MAX_UPLOADS {
+            let oldest = inner
+                .uploads
+                .iter()
+                .min_by_key(|(_, upload)| upload.touched)
+                .map(|(&id, _)| id);
+            if let Some(id) = oldest {
+                inner.uploads.remove(&id);
+            }
+        }
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.uploads.insert(
+            id,
+            Upload {
+                expected: total_count,
+                // 先只按一块预留，不让声称的 total_count 一开始就占满内存
+                events: Vec::with_capacity(total_count.min(MAX_CHUNK_EVENTS)),
+                committed: false,
+                touched: now,
+            },
+        );
+        Ok(id)
+    }
+
+    /// 追加一块事件，返回已收到的事件数
+    /// 分块中有无效事件时整块拒绝，错误信息给出事件在整首歌中的序号
+    pub fn append(&self, upload_id: u64, mut chunk: Vec<KeyEvent>) -> Result<usize, CommandError> {
+        if chunk.len() > MAX_CHUNK_EVENTS {
+            return Err(CommandError::InvalidArgument(format!(
+                "chunk must contain at most {} events, got {}",
+                MAX_CHUNK_EVENTS,
+                chunk.len()
+            )));
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let upload = pending(&mut inner, upload_id)?;
+        let received = upload.events.len();
+        if received + chunk.len() > upload.expected {
+            return Err(CommandError::InvalidArgument(format!(
+                "Upload {} expects {} events, got {}",
+                upload_id,
+                upload.expected,
+                received + chunk.len()
+            )));
+        }
+        for (i, event) in chunk.iter_mut().enumerate() {
+            keypress_simulator::validate_event(event).map_err(|e| {
+                CommandError::InvalidArgument(format!("Event {}: {}", received + i, e))
+            })?;
+        }
+        upload.events.extend(chunk);
+        upload.touched = Instant::now();
+        Ok(upload.events.len())
+    }
+
+    /// 结束上传，返回传给 start_playback 的句柄；收到的事件数必须与 begin 时一致
+    pub fn commit(&self, upload_id: u64) -> Result<u64, CommandError> {
+        let mut inner = self.inner.lock().unwrap();
+        let upload = pending(&mut inner, upload_id)?;
+        if upload.events.len() != upload.expected {
+            return Err(CommandError::InvalidArgument(format!(
+                "Upload {} is incomplete: {} of {} events",
+                upload_id,
+                upload.events.len(),
+                upload.expected
+            )));
+        }
+        upload.committed = true;
+        upload.touched = Instant::now();
+        Ok(upload_id)
+    }
+
+    /// 已提交的事件；句柄可以重复使用，直到过期或被 discard
+    pub fn events(&self, handle: u64) -> Result<Vec<KeyEvent>, CommandError> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.uploads.get_mut(&handle) {
+            Some(upload) if upload.committed && !upload.expired(Instant::now()) => {
+                upload.touched = Instant::now();
+                Ok(upload.events.clone())
+            }
+            Some(upload) if !upload.committed => Err(CommandError::InvalidArgument(format!(
+                "Upload {} has not been committed",
+                handle
+            ))),
+            _ => Err(unknown(handle)),
+        }
+    }
+
+    /// 丢弃上传或句柄，不存在时什么都不做
+    pub fn discard(&self, id: u64) {
+        self.inner.lock().unwrap().uploads.remove(&id);
+    }
+}
+
+// 尚未提交、也没有过期的上传
+fn pending(inner: &mut Inner, upload_id: u64) -> Result<&mut Upload, CommandError> {
+    let now = Instant::now();
+    match inner.uploads.get_mut(&upload_id) {
+        Some(upload) if upload.committed => Err(CommandError::InvalidArgument(format!(
+            "Upload {} is already committed",
+            upload_id
+        ))),
+        Some(upload) if !upload.expired(now) => Ok(upload),
+        _ => Err(unknown(upload_id)),
+    }
+}
+
+fn unknown(id: u64) -> CommandError {
+    CommandError::InvalidArgument(format!("Unknown or expired upload {}", id))
+}
@@ -0,0 +1,295 @@
+//! 导出任务：在后台线程上通过缓冲写入生成文件，命令立即返回任务编号
+//! 先写到同一文件夹中的临时文件，成功后再改名为目标文件，取消或失败时删除临时文件，不留下半个文件
+//! 写往同一路径的导出按开始的顺序依次进行，后开始的结果覆盖先开始的
+
+use crate::error::CommandError;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// 每写入这么多字节推送一次 export://progress，更小的导出不推送进度
+pub const PROGRESS_INTERVAL_BYTES: u64 = 1024 * 1024;
+
+const WRITE_BUFFER_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportProgress {
+    pub job_id: u64,
+    pub path: String,
+    pub bytes_written: u64,
+}
+
+/// 导出结束，error 与 cancelled 都没有时文件已写好
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportFinished {
+    pub job_id: u64,
+    pub path: String,
+    pub bytes_written: u64,
+    pub cancelled: bool,
+    pub error: Option<CommandError>,
+    // 导出附带的结果，如试运行报告的统计
+    pub output: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum ExportEvent {
+    Progress(ExportProgress),
+    Finished(ExportFinished),
+}
+
+impl ExportEvent {
+    /// 对应的前端事件名
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportEvent::Progress(_) => "export://progress",
+            ExportEvent::Finished(_) => "export://finished",
+        }
+    }
+}
+
+/// 导出事件回调，由 lib.rs 转发为 Tauri 事件
+pub type ExportSink = Arc<dyn Fn(ExportEvent) + Send + Sync>;
+
+/// 生成导出内容，写入给出的 writer；返回值放进 ExportFinished.output
+pub type ExportContent =
+    Box<dyn FnOnce(&mut dyn Write) -> Result<Option<Value>, CommandError> + Send>;
+
+fn write_error(path: &Path, error: &io::Error) -> CommandError {
+    let message = format!("Failed to write {}: {}", path.display(), error);
+    match error.kind() {
+        ErrorKind::PermissionDenied => CommandError::AccessDenied(message),
+        ErrorKind::NotFound => CommandError::FileNotFound(message),
+        _ => CommandError::Other(message),
+    }
+}
+
+// 统计写入的字节数、推送进度；取消后的写入返回错误，让生成内容的一方尽快停下
+struct ExportWriter<'a> {
+    inner: BufWriter<File>,
+    job: &'a ExportJob,
+    sink: &'a ExportSink,
+    written: u64,
+    next_progress: u64,
+}
+
+impl Write for ExportWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.job.cancelled.load(Ordering::SeqCst) {
+            // 不能用 Interrupted，write_all 遇到它会一直重试
+            return Err(io::Error::other("export cancelled"));
+        }
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        if self.written >= self.next_progress {
+            self.next_progress = self.written + PROGRESS_INTERVAL_BYTES;
+            (self.sink)(ExportEvent::Progress(ExportProgress {
+                job_id: self.job.id,
+                path: self.job.path.to_string_lossy().to_string(),
+                bytes_written: self.written,
+            }));
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ExportJob {
+    id: u64,
+    path: PathBuf,
+    temp_path: PathBuf,
+    cancelled: AtomicBool,
+}
+
+// 各目标路径上排队的任务，队首的任务正在写
+#[derive(Default)]
+struct PathQueues {
+    queues: Mutex<HashMap<PathBuf, VecDeque<u64>>>,
+    changed: Condvar,
+}
+
+impl PathQueues {
+    fn enqueue(&self, path: &Path, id: u64) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.entry(path.to_path_buf()).or_default().push_back(id);
+    }
+
+    // 等到轮到 id，期间取消则提前返回 false
+    fn wait_turn(&self, job: &ExportJob) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        loop {
+            if job.cancelled.load(Ordering::SeqCst) {
+                return false;
+            }
+            if queues
+                .get(&job.path)
+                .and_then(|queue| queue.front())
+                .is_some_and(|&front| front == job.id)
+            {
+                return true;
+            }
+            queues = self.changed.wait(queues).unwrap();
+        }
+    }
+
+    // 取消后叫醒排队中的任务；持锁通知，不会漏掉正要开始等待的任务
+    fn wake(&self) {
+        let _queues = self.queues.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    fn leave(&self, path: &Path, id: u64) {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(path) {
+            queue.retain(|&queued| queued != id);
+            if queue.is_empty() {
+                queues.remove(path);
+            }
+        }
+        drop(queues);
+        self.changed.notify_all();
+    }
+}
+
+/// 导出任务，通过 Tauri `.manage()` 注册
+#[derive(Default)]
+pub struct ExportWorker {
+    next_id: AtomicU64,
+    jobs: Arc<Mutex<HashMap<u64, Arc<ExportJob>>>>,
+    queues: Arc<PathQueues>,
+}
+
+impl ExportWorker {
+    /// 开始导出到 path，返回任务编号；结束时推送 export://finished
+    /// 无法在目标文件夹中创建文件（不存在、没有权限等）时直接返回错误
+    pub fn start(
+        &self,
+        path: &Path,
+        content: ExportContent,
+        sink: ExportSink,
+    ) -> Result<u64, CommandError> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| {
+                CommandError::InvalidArgument(format!("path is not a file: {}", path.display()))
+            })?
+            .to_string_lossy()
+            .to_string();
+        if path.is_dir() {
+            return Err(CommandError::InvalidArgument(format!(
+                "path is a folder: {}",
+                path.display()
+            )));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let temp_path = path.with_file_name(format!(".{}.{}.part", file_name, id));
+        let file = File::create(&temp_path).map_err(|e| write_error(path, &e))?;
+
+        let job = Arc::new(ExportJob {
+            id,
+            path: path.to_path_buf(),
+            temp_path,
+            cancelled: AtomicBool::new(false),
+        });
+        self.jobs.lock().unwrap().insert(id, Arc::clone(&job));
+        self.queues.enqueue(path, id);
+
+        let queues = Arc::clone(&self.queues);
+        let jobs = Arc::clone(&self.jobs);
+        let spawned = thread::Builder::new()
+            .name(format!("export-{}", id))
+            .spawn(move || {
+                let finished = run(&job, &queues, file, content, &sink);
+                queues.leave(&job.path, job.id);
+                jobs.lock().unwrap().remove(&job.id);
+                sink(ExportEvent::Finished(finished));
+            });
+        if let Err(e) = spawned {
+            self.jobs.lock().unwrap().remove(&id);
+            self.queues.leave(path, id);
+            let _ = fs::remove_file(path.with_file_name(format!(".{}.{}.part", file_name, id)));
+            return Err(CommandError::Other(format!(
+                "Failed to start export thread: {}",
+                e
+            )));
+        }
+        Ok(id)
+    }
+
+    /// 取消导出，任务不存在或已结束时返回 false
+    pub fn cancel(&self, job_id: u64) -> bool {
+        let Some(job) = self.jobs.lock().unwrap().remove(&job_id) else {
+            return false;
+        };
+        job.cancelled.store(true, Ordering::SeqCst);
+        self.queues.wake();
+        true
+    }
+}
+
+// 按顺序写入临时文件并改名；出错或取消时删除临时文件
+fn run(
+    job: &ExportJob,
+    queues: &PathQueues,
+    file: File,
+    content: ExportContent,
+    sink: &ExportSink,
+) -> ExportFinished {
+    let mut writer = ExportWriter {
+        inner: BufWriter::with_capacity(WRITE_BUFFER_BYTES, file),
+        job,
+        sink,
+        written: 0,
+        next_progress: PROGRESS_INTERVAL_BYTES,
+    };
+    let is_cancelled = || job.cancelled.load(Ordering::SeqCst);
+    // Ok(None) 表示已取消；改名之后再取消不影响结果
+    let outcome = if queues.wait_turn(job) {
+        // 生成内容时 panic 只让这次导出失败，排在后面的导出照常进行
+        panic::catch_unwind(AssertUnwindSafe(|| content(&mut writer)))
+            .unwrap_or_else(|_| Err(CommandError::Other("Export crashed".to_string())))
+            .and_then(|output| {
+                let file = writer
+                    .inner
+                    .into_inner()
+                    .map_err(|e| write_error(&job.path, e.error()))?;
+                file.sync_all().map_err(|e| write_error(&job.path, &e))?;
+                drop(file);
+                if is_cancelled() {
+                    return Ok(None);
+                }
+                fs::rename(&job.temp_path, &job.path).map_err(|e| write_error(&job.path, &e))?;
+                Ok(Some(output))
+            })
+    } else {
+        Ok(None)
+    };
+    let (cancelled, error, output) = match outcome {
+        Ok(Some(output)) => (false, None, output),
+        Ok(None) => (true, None, None),
+        // 取消后生成内容的一方看到的写入错误不算失败
+        Err(_) if is_cancelled() => (true, None, None),
+        Err(error) => (false, Some(error), None),
+    };
+    if cancelled || error.is_some() {
+        let _ = fs::remove_file(&job.temp_path);
+    }
+    ExportFinished {
+        job_id: job.id,
+        path: job.path.to_string_lossy().to_string(),
+        bytes_written: writer.written,
+        cancelled,
+        error,
+        output,
+    }
+}
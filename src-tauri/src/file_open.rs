@@ -0,0 +1,122 @@
+//! 通过文件关联打开 MIDI 文件：启动参数、macOS 的打开文件事件，以及单实例转发的参数
+
+use crate::error::CommandError;
+use crate::midi_analyzer::{ParseCache, ParseProgress};
+use crate::recent_files::RecentFiles;
+use crate::ParsedMidi;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{AppHandle, Emitter, Manager};
+
+#[derive(Serialize)]
+pub struct FileOpened {
+    path: String,
+    #[serde(flatten)]
+    parsed: ParsedMidi,
+}
+
+#[derive(Serialize)]
+pub struct FileOpenFailed {
+    path: String,
+    error: CommandError,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum FileOpenOutcome {
+    Opened(FileOpened),
+    Failed(FileOpenFailed),
+}
+
+/// 启动时打开的文件；前端可能还没开始监听事件，由 take_pending_file_open 取走
+#[derive(Default)]
+pub struct PendingFileOpen(Mutex<Option<FileOpenOutcome>>);
+
+impl PendingFileOpen {
+    pub fn set(&self, outcome: FileOpenOutcome) {
+        *self.0.lock().unwrap() = Some(outcome);
+    }
+
+    pub fn take(&self) -> Option<FileOpenOutcome> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// 参数中第一个不是选项的值，相对路径按 cwd 解析
+pub fn file_from_args(args: &[String], cwd: &Path) -> Option<PathBuf> {
+    args.iter()
+        .find(|arg| !arg.starts_with('-'))
+        .map(|arg| cwd.join(arg))
+}
+
+fn is_midi(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi"))
+}
+
+fn parse(
+    recent: &RecentFiles,
+    cache: &ParseCache,
+    path: &Path,
+) -> Result<ParsedMidi, CommandError> {
+    let display = path.display();
+    if !path.is_file() {
+        return Err(CommandError::FileNotFound(format!(
+            "File not found: {}",
+            display
+        )));
+    }
+    if !is_midi(path) {
+        return Err(CommandError::InvalidFile(format!(
+            "Not a MIDI file: {}",
+            display
+        )));
+    }
+    let file_path = path
+        .to_str()
+        .ok_or_else(|| CommandError::InvalidFile(format!("Invalid file path: {}", display)))?;
+    // 打开过的文件沿用上次的解析参数，否则使用默认设置
+    let settings = recent.settings_for(file_path).unwrap_or_default();
+    let mut ignore_progress = |_: ParseProgress| true;
+    crate::parse_midi_with(
+        recent,
+        cache,
+        file_path,
+        settings,
+        None,
+        false,
+        None,
+        &mut ignore_progress,
+    )
+}
+
+/// 解析文件并通知前端：成功时发送 app://file_opened，失败时发送 app://file_open_failed
+pub fn open(app: &AppHandle, path: PathBuf) -> FileOpenOutcome {
+    let display = path.to_string_lossy().to_string();
+    let outcome = match parse(
+        &app.state::<RecentFiles>(),
+        &app.state::<ParseCache>(),
+        &path,
+    ) {
+        Ok(parsed) => {
+            let opened = FileOpened {
+                path: display,
+                parsed,
+            };
+            let _ = app.emit("app://file_opened", &opened);
+            FileOpenOutcome::Opened(opened)
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            let failed = FileOpenFailed {
+                path: display,
+                error,
+            };
+            let _ = app.emit("app://file_open_failed", &failed);
+            FileOpenOutcome::Failed(failed)
+        }
+    };
+    outcome
+}
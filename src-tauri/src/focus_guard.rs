@@ -0,0 +1,157 @@
+use crate::keypress_simulator::{PlaybackController, PlaybackStatus};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+use uni_window::{ForegroundError, ForegroundWindow};
+
+// 前台窗口检查间隔
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// 本应用自己的窗口在前台时怎么办（按键会打进自己的搜索框等控件）
+/// 不需要设置目标窗口
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfFocusPolicy {
+    Off,
+    #[default]
+    Pause, // 播放中自动暂停并推送 playback://self_focused
+    Refuse, // 开始播放时拒绝，播放中同 Pause
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FocusGuardSettings {
+    pub target: Option<String>, // 目标窗口标题子串（不区分大小写）
+    pub auto_resume: bool,      // 焦点回到目标窗口后自动继续
+    pub self_focus: SelfFocusPolicy,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FocusChange {
+    pub focused: bool,
+    pub window: ForegroundWindow,
+}
+
+/// 焦点守卫
+/// 播放期间目标窗口失去焦点时自动暂停，防止按键打进聊天框等其他窗口
+#[derive(Default)]
+pub struct FocusGuard {
+    settings: Mutex<FocusGuardSettings>,
+    // 每次启动监视线程递增，旧线程发现不一致后自行退出
+    generation: AtomicU64,
+}
+
+impl FocusGuard {
+    pub fn set_target(&self, target: Option<String>, auto_resume: bool) {
+        let mut settings = self.settings.lock().unwrap();
+        settings.target = target.filter(|t| !t.trim().is_empty());
+        settings.auto_resume = auto_resume;
+    }
+
+    pub fn set_self_focus(&self, policy: SelfFocusPolicy) {
+        self.settings.lock().unwrap().self_focus = policy;
+    }
+
+    pub fn settings(&self) -> FocusGuardSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// 开始播放前调用：策略为 Refuse 且本应用窗口在前台时返回错误
+    /// 无法检测前台窗口时放行
+    pub fn check_start(&self) -> Result<(), String> {
+        if self.settings().self_focus != SelfFocusPolicy::Refuse {
+            return Ok(());
+        }
+        match uni_window::foreground_window() {
+            Ok(window) if is_self(&window) => Err(
+                "This app's window is in the foreground; focus the game window before playing"
+                    .to_string(),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// 播放开始后调用，启动前台窗口监视线程
+    /// 未设置目标窗口且不检查本应用窗口时不做任何事
+    pub fn watch(&self, app: AppHandle) {
+        let settings = self.settings();
+        if settings.target.is_none() && settings.self_focus == SelfFocusPolicy::Off {
+            return;
+        }
+
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+        thread::spawn(move || {
+            let guard = app.state::<FocusGuard>();
+            let controller = app.state::<PlaybackController>();
+            let mut paused_by_guard = false;
+
+            loop {
+                thread::sleep(CHECK_INTERVAL);
+
+                if guard.generation.load(Ordering::SeqCst) != generation {
+                    break;
+                }
+                let status = controller.status().status;
+                if status == PlaybackStatus::Idle {
+                    break;
+                }
+                // 每轮重新读取设置，播放中修改目标窗口也能生效
+                let settings = guard.settings();
+                let check_self = settings.self_focus != SelfFocusPolicy::Off;
+                if settings.target.is_none() && !check_self {
+                    break;
+                }
+
+                let window = match uni_window::foreground_window() {
+                    Ok(window) => window,
+                    Err(ForegroundError::Unsupported(reason)) => {
+                        eprintln!("Focus guard disabled: {}", reason);
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+
+                if check_self && is_self(&window) {
+                    if status == PlaybackStatus::Playing && controller.pause().is_ok() {
+                        paused_by_guard = true;
+                        let _ = app.emit(
+                            "playback://self_focused",
+                            FocusChange {
+                                focused: false,
+                                window,
+                            },
+                        );
+                    }
+                    continue;
+                }
+                // 没有目标窗口时只检查本应用窗口，不会自动继续
+                let Some(target) = &settings.target else {
+                    continue;
+                };
+                let focused = window.matches(target);
+                if !focused && status == PlaybackStatus::Playing {
+                    if controller.pause().is_ok() {
+                        paused_by_guard = true;
+                        let _ = app.emit("playback://focus_lost", FocusChange { focused, window });
+                    }
+                } else if focused && paused_by_guard {
+                    paused_by_guard = false;
+                    if settings.auto_resume && status == PlaybackStatus::Paused {
+                        let _ = controller.resume();
+                    }
+                    let _ = app.emit("playback://focus_regained", FocusChange { focused, window });
+                }
+            }
+        });
+    }
+}
+
+fn is_self(window: &ForegroundWindow) -> bool {
+    window.pid == std::process::id()
+}
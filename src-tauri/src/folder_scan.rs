@@ -0,0 +1,291 @@
+//! 批量扫描 MIDI 文件夹：有限个后台线程并行解析，每个文件完成时推送一条结果，全部完成或取消后推送汇总
+//! 内容相同的文件（按内容哈希）只解析一次，已在解析缓存中的直接使用；解析器 panic 的文件单独记为失败
+
+use crate::library_watcher::{self, MidiSummary};
+use crate::midi_analyzer::{self, KeyMap, RawMidi};
+use crate::song_library;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// 同时解析的文件数上限，再多只会让磁盘来回寻道
+pub const MAX_SCAN_THREADS: usize = 4;
+
+/// 一个文件的扫描结果
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanResult {
+    pub scan_id: u64,
+    pub path: String,
+    // 跨启动稳定的内容哈希（见 song_library::file_hash），无法读取时为 None
+    pub file_hash: Option<String>,
+    pub summary: Option<MidiSummary>,
+    pub error: Option<String>, // 无法读取、解析失败或解析器 panic
+    // 本次扫描中内容相同的第一个文件，此时 summary 沿用它的
+    pub duplicate_of: Option<String>,
+    pub cached: bool, // 没有重新解析（内容之前扫描过或已在解析缓存中）
+    pub done: usize,  // 已完成的文件数，含这一个
+    pub total: usize,
+}
+
+/// 扫描结束时的汇总
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanSummary {
+    pub scan_id: u64,
+    pub total: usize,
+    pub scanned: usize, // 完成的文件数，取消时少于 total
+    pub failed: usize,
+    pub duplicates: usize,
+    pub cancelled: bool,
+    pub elapsed_seconds: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum ScanEvent {
+    Result(ScanResult),
+    Finished(ScanSummary),
+}
+
+impl ScanEvent {
+    /// 对应的前端事件名
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScanEvent::Result(_) => "scan://result",
+            ScanEvent::Finished(_) => "scan://finished",
+        }
+    }
+}
+
+/// 扫描事件回调，由 lib.rs 转发为 Tauri 事件
+pub type ScanSink = Arc<dyn Fn(ScanEvent) + Send + Sync>;
+
+/// 按内容哈希查找已解析过的文件，由 lib.rs 接到 ParseCache::find_by_hash
+pub type CacheLookup = Arc<dyn Fn(u64) -> Option<Arc<RawMidi>> + Send + Sync>;
+
+// 按内容哈希保存的摘要；top_key 取决于按键映射，映射变了就清空
+#[derive(Default)]
+struct SummaryCache {
+    key_map: Option<KeyMap>,
+    summaries: HashMap<u64, MidiSummary>,
+}
+
+// 进行中的一次扫描
+struct ScanJob {
+    id: u64,
+    files: Vec<PathBuf>,
+    key_map: Option<KeyMap>,
+    next: AtomicUsize, // 下一个分派的文件
+    cancelled: AtomicBool,
+    results: Mutex<Vec<ScanResult>>,
+    // 本次扫描中各内容哈希第一次出现的文件
+    first_seen: Mutex<HashMap<u64, String>>,
+    // 仍在运行的工作线程数，归零时扫描结束
+    workers: Mutex<usize>,
+    finished: Condvar,
+    started: Instant,
+}
+
+impl ScanJob {
+    // 取消或分派完后返回 None
+    fn take_next(&self) -> Option<&Path> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return None;
+        }
+        let index = self.next.fetch_add(1, Ordering::SeqCst);
+        self.files.get(index).map(PathBuf::as_path)
+    }
+
+    fn wait(&self) {
+        let mut workers = self.workers.lock().unwrap();
+        while *workers > 0 {
+            workers = self.finished.wait(workers).unwrap();
+        }
+    }
+
+    fn summary(&self) -> ScanSummary {
+        let results = self.results.lock().unwrap();
+        ScanSummary {
+            scan_id: self.id,
+            total: self.files.len(),
+            scanned: results.len(),
+            failed: results.iter().filter(|r| r.error.is_some()).count(),
+            duplicates: results.iter().filter(|r| r.duplicate_of.is_some()).count(),
+            cancelled: self.cancelled.load(Ordering::SeqCst) && results.len() < self.files.len(),
+            elapsed_seconds: self.started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+// panic 的信息
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// 文件夹扫描，通过 Tauri `.manage()` 注册；同一时间只有一次扫描
+#[derive(Default)]
+pub struct FolderScanner {
+    next_id: AtomicU64,
+    current: Mutex<Option<Arc<ScanJob>>>,
+    cache: Arc<Mutex<SummaryCache>>,
+}
+
+impl FolderScanner {
+    /// 在后台扫描 dir 下的 MIDI 文件（不含子文件夹），返回本次扫描的编号
+    /// 已有扫描在进行时先取消它；key_map 给出时摘要中附带最常用的键
+    pub fn scan(
+        &self,
+        dir: &str,
+        key_map: Option<KeyMap>,
+        lookup: CacheLookup,
+        sink: ScanSink,
+    ) -> Result<u64, String> {
+        let entries =
+            fs::read_dir(dir).map_err(|e| format!("Failed to read folder {}: {}", dir, e))?;
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.is_file() && library_watcher::is_midi(path))
+            .collect();
+        files.sort();
+
+        self.cancel();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let threads = thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(MAX_SCAN_THREADS)
+            .min(files.len())
+            .max(1);
+        let job = Arc::new(ScanJob {
+            id,
+            files,
+            key_map,
+            next: AtomicUsize::new(0),
+            cancelled: AtomicBool::new(false),
+            results: Mutex::new(Vec::new()),
+            first_seen: Mutex::new(HashMap::new()),
+            workers: Mutex::new(threads),
+            finished: Condvar::new(),
+            started: Instant::now(),
+        });
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.key_map != job.key_map {
+                *cache = SummaryCache {
+                    key_map: job.key_map.clone(),
+                    summaries: HashMap::new(),
+                };
+            }
+        }
+        *self.current.lock().unwrap() = Some(Arc::clone(&job));
+
+        for _ in 0..threads {
+            let job = Arc::clone(&job);
+            let cache = Arc::clone(&self.cache);
+            let lookup = Arc::clone(&lookup);
+            let sink = Arc::clone(&sink);
+            thread::spawn(move || {
+                while let Some(path) = job.take_next() {
+                    let result = scan_file(&job, path, &cache, &lookup);
+                    sink(ScanEvent::Result(result));
+                }
+                let mut workers = job.workers.lock().unwrap();
+                *workers -= 1;
+                if *workers == 0 {
+                    drop(workers);
+                    job.finished.notify_all();
+                    sink(ScanEvent::Finished(job.summary()));
+                }
+            });
+        }
+        Ok(id)
+    }
+
+    /// 停止分派新文件，等正在解析的文件完成后返回已完成的结果；没有进行中的扫描时返回空列表
+    pub fn cancel(&self) -> Vec<ScanResult> {
+        let Some(job) = self.current.lock().unwrap().take() else {
+            return Vec::new();
+        };
+        job.cancelled.store(true, Ordering::SeqCst);
+        job.wait();
+        let results = job.results.lock().unwrap();
+        results.clone()
+    }
+}
+
+// 读取、去重并统计一个文件，记入 job.results
+fn scan_file(
+    job: &ScanJob,
+    path: &Path,
+    cache: &Mutex<SummaryCache>,
+    lookup: &CacheLookup,
+) -> ScanResult {
+    let display = path.to_string_lossy().to_string();
+    let bytes = fs::read(path);
+    let file_hash = bytes.as_deref().ok().map(song_library::file_hash);
+    let outcome = bytes
+        .map_err(|e| format!("Failed to read file: {}", e))
+        .and_then(|bytes| {
+            let hash = midi_analyzer::content_hash(&bytes);
+            let duplicate_of = {
+                let mut first_seen = job.first_seen.lock().unwrap();
+                match first_seen.get(&hash) {
+                    Some(first) => Some(first.clone()),
+                    None => {
+                        first_seen.insert(hash, display.clone());
+                        None
+                    }
+                }
+            };
+            if let Some(summary) = cache.lock().unwrap().summaries.get(&hash) {
+                return Ok((summary.clone(), duplicate_of, true));
+            }
+            let cached_raw = lookup(hash);
+            let cached = cached_raw.is_some();
+            // 个别畸形文件会让解析器 panic，只让这一个文件失败
+            let summary = panic::catch_unwind(AssertUnwindSafe(|| -> Result<_, String> {
+                let raw = match cached_raw {
+                    Some(raw) => raw,
+                    None => Arc::new(
+                        midi_analyzer::parse_midi_bytes(&bytes, None, &mut |_| true)
+                            .map_err(|e| e.to_string())?,
+                    ),
+                };
+                Ok(library_watcher::summarize_raw(&raw, job.key_map.as_ref()))
+            }))
+            .map_err(|payload| format!("Parser crashed: {}", panic_message(payload)))??;
+            cache
+                .lock()
+                .unwrap()
+                .summaries
+                .insert(hash, summary.clone());
+            Ok((summary, duplicate_of, cached))
+        });
+
+    let mut results = job.results.lock().unwrap();
+    let (summary, error, duplicate_of, cached) = match outcome {
+        Ok((summary, duplicate_of, cached)) => (Some(summary), None, duplicate_of, cached),
+        Err(e) => (None, Some(e), None, false),
+    };
+    let result = ScanResult {
+        scan_id: job.id,
+        path: display,
+        file_hash,
+        summary,
+        error,
+        duplicate_of,
+        cached,
+        done: results.len() + 1,
+        total: job.files.len(),
+    };
+    results.push(result.clone());
+    result
+}
@@ -0,0 +1,217 @@
+//! 前端（webview）与播放之间的连接：webview 重新加载或渲染进程崩溃时，期间推送的事件无人接收
+//! 播放事件由单独的线程转发，前端卡住或断开都不会拖慢播放线程；最近的进度和生命周期事件留在这里，
+//! 前端重新挂载后调用 resync_playback_state 取回完整状态
+//! 设置了 auto_pause_after_seconds 时，前端断开超过这么久自动暂停播放
+
+use crate::keypress_simulator::{
+    EventSink, PlaybackController, PlaybackEvent, PlaybackProgress, PlaybackSettings,
+    PlaybackStatus, QueueEntryInfo,
+};
+use serde::{Deserialize, Serialize};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// auto_pause_after_seconds 的上限（秒）
+pub const MAX_AUTO_PAUSE_SECONDS: f64 = 3600.0;
+
+// 等待转发的事件数上限，超出时丢弃新事件，前端重新同步时再取回状态
+const RELAY_CAPACITY: usize = 1024;
+
+/// 前端断开时的处理
+/// 按键发给游戏，不需要前端，默认继续播放
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DetachPolicy {
+    // 前端断开超过这么多秒时暂停播放，None 时不暂停
+    pub auto_pause_after_seconds: Option<f64>,
+}
+
+impl DetachPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(seconds) = self.auto_pause_after_seconds {
+            if !(1.0..=MAX_AUTO_PAUSE_SECONDS).contains(&seconds) {
+                return Err(format!(
+                    "auto_pause_after_seconds must be within 1-{}, got {}",
+                    MAX_AUTO_PAUSE_SECONDS, seconds
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 缓存的事件，name 为对应的前端事件名
+#[derive(Debug, Clone, Serialize)]
+pub struct BufferedEvent {
+    pub name: &'static str,
+    pub event: PlaybackEvent,
+}
+
+/// resync_playback_state 返回的完整状态
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaybackState {
+    pub progress: PlaybackProgress, // 状态、位置、速度、循环区间等
+    pub active_keys: Vec<String>,
+    pub queue: Vec<QueueEntryInfo>,
+    // 最近一次生命周期事件（如 finished 及其报告），前端断开期间结束的播放也能看到结果
+    pub last_event: Option<BufferedEvent>,
+    pub auto_paused: bool,     // 因前端断开而自动暂停，之后还没有继续
+    pub dropped_events: usize, // 转发不及而丢弃的事件数，启动以来累计
+}
+
+struct LinkState {
+    attached: bool,
+    // 最近一次断开的时刻，启动后页面加载完成之前也算断开
+    detached_since: Option<Instant>,
+    auto_paused: bool,
+    last_lifecycle: Option<BufferedEvent>,
+}
+
+/// 通过 Tauri `.manage()` 注册
+pub struct FrontendLink {
+    state: Mutex<LinkState>,
+    policy: Mutex<DetachPolicy>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Default for FrontendLink {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(LinkState {
+                attached: false,
+                detached_since: Some(Instant::now()),
+                auto_paused: false,
+                last_lifecycle: None,
+            }),
+            policy: Mutex::new(DetachPolicy::default()),
+            dropped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+// 进度和按键状态随时可以从控制器读取，不缓存；预备拍和倒计时过时即无意义
+fn is_lifecycle(event: &PlaybackEvent) -> bool {
+    !matches!(
+        event,
+        PlaybackEvent::StatusChanged(_)
+            | PlaybackEvent::KeysActive { .. }
+            | PlaybackEvent::Beat(_)
+            | PlaybackEvent::Countdown { .. }
+    )
+}
+
+impl FrontendLink {
+    /// 在单独的线程上调用 sink；返回的 sink 只把事件放进队列，不会阻塞
+    /// sink 中的 panic 被捕获并记录，之后的事件照常转发
+    pub fn relay(&self, sink: EventSink) -> EventSink {
+        let (tx, rx) = mpsc::sync_channel::<PlaybackEvent>(RELAY_CAPACITY);
+        thread::Builder::new()
+            .name("playback-events".to_string())
+            .spawn(move || {
+                for event in rx {
+                    let name = event.name();
+                    if panic::catch_unwind(AssertUnwindSafe(|| sink(event))).is_err() {
+                        log::error!("Playback event handler panicked on {}", name);
+                    }
+                }
+            })
+            .expect("failed to spawn playback event thread");
+        let dropped = Arc::clone(&self.dropped);
+        Arc::new(move |event: PlaybackEvent| {
+            if let Err(TrySendError::Full(event)) = tx.try_send(event) {
+                if dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    log::warn!(
+                        "Playback event queue is full, dropping events (first: {})",
+                        event.name()
+                    );
+                }
+            }
+        })
+    }
+
+    /// 记下事件，在转发之前调用
+    pub fn record(&self, event: &PlaybackEvent) {
+        if is_lifecycle(event) {
+            self.state.lock().unwrap().last_lifecycle = Some(BufferedEvent {
+                name: event.name(),
+                event: event.clone(),
+            });
+        }
+    }
+
+    /// 页面加载完成或前端重新同步时调用
+    pub fn attach(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.attached = true;
+        state.detached_since = None;
+    }
+
+    /// 页面开始（重新）加载时调用
+    pub fn detach(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.attached {
+            state.attached = false;
+            state.detached_since = Some(Instant::now());
+        }
+    }
+
+    pub fn policy(&self) -> DetachPolicy {
+        *self.policy.lock().unwrap()
+    }
+
+    pub fn set_policy(&self, policy: DetachPolicy) -> Result<(), String> {
+        policy.validate()?;
+        *self.policy.lock().unwrap() = policy;
+        Ok(())
+    }
+
+    /// 前端断开超过设置的时间且正在播放时暂停，由后台定期调用
+    pub fn check(&self, controller: &PlaybackController) {
+        let status = controller.status().status;
+        let mut state = self.state.lock().unwrap();
+        // 自动暂停的标记保留到播放继续或结束
+        if status != PlaybackStatus::Paused {
+            state.auto_paused = false;
+        }
+        let Some(limit) = self.policy().auto_pause_after_seconds else {
+            return;
+        };
+        let Some(since) = state.detached_since else {
+            return;
+        };
+        let detached = since.elapsed().as_secs_f64();
+        if detached < limit || status != PlaybackStatus::Playing {
+            return;
+        }
+        // 暂停会发出事件，不持锁调用
+        drop(state);
+        if controller.pause().is_ok() {
+            self.state.lock().unwrap().auto_paused = true;
+            log::warn!("Paused playback: no frontend attached for {:.0}s", detached);
+        }
+    }
+
+    /// 前端挂载时取回的完整状态，同时视为前端已连接
+    /// settings 用于估计队列中还没有准备好的条目的时长
+    pub fn resync(
+        &self,
+        controller: &PlaybackController,
+        settings: &PlaybackSettings,
+    ) -> PlaybackState {
+        self.attach();
+        let progress = controller.status();
+        let state = self.state.lock().unwrap();
+        PlaybackState {
+            auto_paused: state.auto_paused && progress.status == PlaybackStatus::Paused,
+            progress,
+            active_keys: controller.active_keys(),
+            queue: controller.queue_list(settings),
+            last_event: state.last_lifecycle.clone(),
+            dropped_events: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
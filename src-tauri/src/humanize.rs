@@ -0,0 +1,130 @@
+use crate::keypress_simulator::KeyEvent;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// 播放时的人性化设置
+/// 每次播放都会重新随机，循环播放时每一遍都不同
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HumanizeConfig {
+    pub timing_jitter_ms: f64,       // 起音时间随机偏移上限（±毫秒）
+    pub duration_variance: f64,      // 时长随机缩放比例（0.1 表示 ±10%）
+    pub hesitation_probability: f64, // 每个起音前插入停顿的概率（0-1）
+    pub hesitation_ms: f64,          // 停顿时长（毫秒）
+    pub seed: Option<u64>,           // 固定种子便于复现，默认使用系统熵
+}
+
+impl Default for HumanizeConfig {
+    fn default() -> Self {
+        Self {
+            timing_jitter_ms: 10.0,
+            duration_variance: 0.1,
+            hesitation_probability: 0.0,
+            hesitation_ms: 60.0,
+            seed: None,
+        }
+    }
+}
+
+impl HumanizeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=200.0).contains(&self.timing_jitter_ms) {
+            return Err(format!(
+                "timing_jitter_ms must be within 0-200, got {}",
+                self.timing_jitter_ms
+            ));
+        }
+        if !(0.0..=0.5).contains(&self.duration_variance) {
+            return Err(format!(
+                "duration_variance must be within 0-0.5, got {}",
+                self.duration_variance
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.hesitation_probability) {
+            return Err(format!(
+                "hesitation_probability must be within 0-1, got {}",
+                self.hesitation_probability
+            ));
+        }
+        if !(0.0..=2000.0).contains(&self.hesitation_ms) {
+            return Err(format!(
+                "hesitation_ms must be within 0-2000, got {}",
+                self.hesitation_ms
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// 在调度器内对事件做随机化，持有自己的随机数生成器
+pub struct Humanizer {
+    config: HumanizeConfig,
+    rng: StdRng,
+}
+
+impl Humanizer {
+    pub fn new(config: HumanizeConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self { config, rng }
+    }
+
+    /// 返回随机化后的事件列表，不同起音时刻之间的先后顺序保持不变
+    pub fn apply(&mut self, events: &[KeyEvent]) -> Vec<KeyEvent> {
+        // 不同的起音时刻（升序），同一时刻的和弦共享一次停顿判定
+        let mut onsets: Vec<f64> = events.iter().map(|e| e.time).collect();
+        onsets.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        onsets.dedup();
+
+        // 停顿会累加到之后的所有起音上
+        let mut shifts = Vec::with_capacity(onsets.len());
+        let mut shift = 0.0;
+        for _ in &onsets {
+            if self.config.hesitation_probability > 0.0
+                && self.rng.gen_bool(self.config.hesitation_probability)
+            {
+                shift += self.config.hesitation_ms / 1000.0;
+            }
+            shifts.push(shift);
+        }
+
+        let jitter = self.config.timing_jitter_ms / 1000.0;
+        let variance = self.config.duration_variance;
+
+        events
+            .iter()
+            .map(|event| {
+                let i = onsets.partition_point(|&t| t < event.time);
+
+                // 偏移不超过与相邻起音间距的 45%，保证不会越过相邻音符
+                let prev_gap = if i > 0 {
+                    event.time - onsets[i - 1]
+                } else {
+                    f64::INFINITY
+                };
+                let next_gap = onsets.get(i + 1).map_or(f64::INFINITY, |&t| t - event.time);
+                let limit = jitter.min(prev_gap * 0.45).min(next_gap * 0.45);
+                let offset = if limit > 0.0 {
+                    self.rng.gen_range(-limit..=limit)
+                } else {
+                    0.0
+                };
+
+                let scale = if variance > 0.0 {
+                    self.rng.gen_range(1.0 - variance..=1.0 + variance)
+                } else {
+                    1.0
+                };
+
+                KeyEvent {
+                    time: (event.time + offset + shifts[i]).max(0.0),
+                    duration: event.duration * scale,
+                    ..event.clone()
+                }
+            })
+            .collect()
+    }
+}
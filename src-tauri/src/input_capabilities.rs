@@ -0,0 +1,117 @@
+//! 按键发送后端的能力探测：只创建后端、不发送任何按键，判断当前平台和会话支持哪些发送方式
+//! 前端据此禁用不支持的选项，布局预设也用它提示需要但不支持的功能
+
+use crate::keypress_simulator::InputBackend;
+use enigo::{Enigo, Settings};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// 预设或播放选项可能依赖的发送功能
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputFeature {
+    RawKeycodes,           // 主键按物理键码发送（macOS 虚拟键码、uinput）
+    ScanCodes,             // 主键按扫描码发送（Windows，DirectInput 游戏需要）
+    Unicode,               // 按字符输入，文本模式需要
+    LayoutTranslation,     // 非 QWERTY 布局下换算物理位置
+    ModifierOnly,          // 只按修饰键本身（如 "rshift"）
+    SideSpecificModifiers, // 区分左右修饰键
+}
+
+/// 一个发送后端的探测结果
+#[derive(Debug, Clone, Serialize)]
+pub struct InputCapabilities {
+    pub backend: InputBackend, // 解析 Auto 之后的后端
+    pub available: bool,       // 后端能创建
+    pub error: Option<String>, // 不能创建时的原因
+    pub features: Vec<InputFeature>,
+}
+
+impl InputCapabilities {
+    pub fn supports(&self, feature: InputFeature) -> bool {
+        self.available && self.features.contains(&feature)
+    }
+}
+
+// 各后端在当前平台上的发送方式，与 uni-input 的实现对应
+fn platform_features(backend: InputBackend) -> Vec<InputFeature> {
+    use InputFeature::*;
+    match backend {
+        InputBackend::Uinput => vec![
+            RawKeycodes,
+            LayoutTranslation,
+            ModifierOnly,
+            SideSpecificModifiers,
+        ],
+        _ if cfg!(target_os = "windows") => vec![
+            ScanCodes,
+            Unicode,
+            LayoutTranslation,
+            ModifierOnly,
+            SideSpecificModifiers,
+        ],
+        _ if cfg!(target_os = "macos") => vec![
+            RawKeycodes,
+            Unicode,
+            LayoutTranslation,
+            ModifierOnly,
+            SideSpecificModifiers,
+        ],
+        // X11 下主键按字符发送，由系统按当前布局输入，布局转换不起作用
+        _ => vec![Unicode, ModifierOnly, SideSpecificModifiers],
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn create_uinput() -> Result<(), String> {
+    // 虚拟设备创建后立即销毁，不产生任何按键
+    uni_input::UinputKeyboard::new()
+        .map(drop)
+        .map_err(|e| format!("Failed to create uinput keyboard: {}", e))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create_uinput() -> Result<(), String> {
+    Err("The uinput backend is only available on Linux".to_string())
+}
+
+/// 探测 backend（Auto 先解析为具体后端）
+pub fn probe(backend: InputBackend) -> InputCapabilities {
+    let backend = backend.resolve();
+    let created = match backend {
+        InputBackend::Uinput => create_uinput(),
+        _ => Enigo::new(&Settings::default())
+            .map(drop)
+            .map_err(|e| format!("Failed to create Enigo instance: {:?}", e)),
+    };
+    InputCapabilities {
+        backend,
+        available: created.is_ok(),
+        error: created.err(),
+        features: platform_features(backend),
+    }
+}
+
+/// 探测结果按后端缓存，整个运行期间每个后端只探测一次，通过 Tauri `.manage()` 注册
+#[derive(Default)]
+pub struct CapabilityCache {
+    probed: Mutex<HashMap<InputBackend, InputCapabilities>>,
+}
+
+impl CapabilityCache {
+    pub fn get(&self, backend: InputBackend) -> InputCapabilities {
+        let backend = backend.resolve();
+        if let Some(capabilities) = self.probed.lock().unwrap().get(&backend) {
+            return capabilities.clone();
+        }
+        // 探测可能较慢（创建虚拟设备），不持锁；并发时重复探测一次无妨
+        let capabilities = probe(backend);
+        self.probed
+            .lock()
+            .unwrap()
+            .entry(backend)
+            .or_insert(capabilities)
+            .clone()
+    }
+}
@@ -1,115 +1,5846 @@
+use crate::error::CommandError;
+use crate::humanize::{HumanizeConfig, Humanizer};
+use crate::metronome::{self, Beat, BeatGrid, CountInConfig, Meter};
+use crate::midi_analyzer::{self, ChordGrouper, ChordGrouping, KeyMap, MidiEvent, TempoMap};
+use crate::playback_stats::{self, PlaybackReport, StatsRecorder};
+use crate::rate_limiter::{self, RateDecision, RateLimiter, DEFAULT_MAX_PRESSES_PER_SECOND};
+use crate::recorder;
+use crate::schedule_spill::{self, SpillFile, SpillRecord};
+use crate::session_log;
+use crate::song_clock::{self, Clock, SongClock, SpeedCurve, SystemClock};
+use crate::timer_resolution::{
+    TimerResolution, DEFAULT_TIMER_RESOLUTION_MS, MAX_TIMER_RESOLUTION_MS,
+};
 use enigo::{Enigo, Settings};
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Duration;
-use uni_input::SmartKeyboard;
+use std::time::{Duration, Instant};
+use uni_input::{
+    normalize_key_string, parse_key_string, text_char, LayoutTranslation, MainKey, Modifier,
+    ParsedKey, SmartKeyboard,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KeyEvent {
     pub time: f64,     // 时间（秒）
     pub key: String,   // 按键字符串，如 "a", "shift+a", "ctrl+c"
-    pub duration: f64, // 按键持续时间（秒）
+    pub duration: f64, // 按键持续时间（秒），0 表示轻点：按下后按 min_hold_ms 松开
+    // 所属的分组（如音轨 "track2"），播放中可以按分组静音
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    // MIDI 音高，修饰键冲突时优先保留和弦的最高音
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<u8>,
+    // 解析时分出的和弦序号；缺少时播放前按默认设置重新分组
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chord: Option<usize>,
 }
 
-// 播放状态管理
-lazy_static::lazy_static! {
-    static ref PLAYBACK_HANDLE: Arc<Mutex<Option<thread::JoinHandle<()>>>> = Arc::new(Mutex::new(None));
-    static ref SHOULD_STOP: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+// 时长可以为 0（轻点），不能为负数
+fn validate_duration(duration: f64) -> Result<(), String> {
+    if !duration.is_finite() || duration < 0.0 {
+        return Err(format!(
+            "Invalid duration: {} (must be 0 or more, 0 plays a tap of min_hold_ms)",
+            duration
+        ));
+    }
+    Ok(())
+}
+
+/// 检查前端传来或导入的单个事件，并把按键整理成规范写法；分块上传时逐块调用
+pub fn validate_event(event: &mut KeyEvent) -> Result<(), String> {
+    if !event.time.is_finite() || event.time < 0.0 {
+        return Err(format!("Invalid time: {}", event.time));
+    }
+    validate_duration(event.duration)?;
+    if event.key.trim().is_empty() {
+        return Err("Empty key".to_string());
+    }
+    event.key = normalize_key_string(&event.key)?;
+    Ok(())
+}
+
+/// MidiAnalysis 的事件转为按键事件时的选项，与前端播放前的处理相同
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyEventOptions {
+    pub transpose: i32, // 映射前整体移调的半音数
+    // 移调后超出范围的音符丢弃
+    pub min_note: u8,
+    pub max_note: u8,
+    pub drop_black_keys: bool,
+}
+
+impl Default for KeyEventOptions {
+    fn default() -> Self {
+        Self {
+            transpose: 0,
+            min_note: 48,
+            max_note: 83,
+            drop_black_keys: false,
+        }
+    }
+}
+
+/// 把解析出的 note_on 事件按 key_map 转为按键事件，没有映射的音符丢弃
+/// 带 key 的事件（drum_mode 的打击乐）直接使用该按键，不受移调、音域和黑键选项影响
+/// 分组为所在音轨（"track2"），和弦序号原样保留
+pub fn key_events(
+    events: &[MidiEvent],
+    key_map: &KeyMap,
+    options: &KeyEventOptions,
+) -> Vec<KeyEvent> {
+    events
+        .iter()
+        .filter(|event| event.type_ == "note_on")
+        .filter_map(|event| {
+            let (note, key) = match &event.key {
+                Some(key) => (event.note, key),
+                None => {
+                    let note = u8::try_from(event.note as i32 + options.transpose).ok()?;
+                    if note < options.min_note || note > options.max_note {
+                        return None;
+                    }
+                    if options.drop_black_keys && midi_analyzer::is_black_key(note) {
+                        return None;
+                    }
+                    (note, key_map.get(&note)?)
+                }
+            };
+            Some(KeyEvent {
+                time: event.time,
+                key: key.clone(),
+                // 没有时值的音符保持为 0，播放时与其他时长为 0 的事件一样轻点
+                duration: event.duration,
+                group: Some(format!("track{}", event.track)),
+                note: Some(note),
+                chord: event.chord,
+            })
+        })
+        .collect()
+}
+
+// 默认最短按住时长，太短的按键部分游戏识别不到
+const DEFAULT_MIN_HOLD_MS: f64 = 50.0;
+
+/// 应用退出时等待播放线程结束的最长时间
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// 测试按键前最长的等待时间
+const MAX_TEST_DELAY_SECS: f64 = 30.0;
+
+// 内存中保留的最近播放报告数量
+const MAX_REPORTS: usize = 10;
+
+// 播放期间推送当前按住按键的间隔（约 30 Hz）
+const KEYS_ACTIVE_INTERVAL: Duration = Duration::from_millis(33);
+
+// send_retries 的上限
+const MAX_SEND_RETRIES: u32 = 10;
+
+// 发送失败后第 n 次重试前等待 n 倍的这段时间
+const SEND_RETRY_BACKOFF: Duration = Duration::from_millis(2);
+
+// 修饰键冲突时默认错开的时间，要比发送修饰键时的内部等待长
+const DEFAULT_CONFLICT_STAGGER_MS: f64 = 20.0;
+
+// conflict_stagger_ms 的上限
+const MAX_CONFLICT_STAGGER_MS: f64 = 200.0;
+
+// gate 的下限，再短大多数游戏识别不到
+const MIN_GATE: f64 = 0.1;
+
+// keep_alive_seconds 的范围
+const MIN_KEEP_ALIVE_SECONDS: f64 = 1.0;
+const MAX_KEEP_ALIVE_SECONDS: f64 = 60.0;
+
+// 试运行中松开与另一个键的按下相隔不到这么久（毫秒）时视为容易丢失
+const STUCK_RISK_WINDOW_MS: f64 = 5.0;
+
+// 软停止时一个乐句的小节数
+const PHRASE_MEASURES: u32 = 4;
+
+// 软停止时多长的静音算作间隙
+const STOP_GAP_SECONDS: f64 = 0.25;
+
+/// 软停止默认最多等待的秒数，定时停止到点时也按这个等待
+pub const DEFAULT_SOFT_STOP_WAIT: f64 = 5.0;
+
+// 定时停止的上限（24 小时）
+const MAX_STOP_TIMER_SECS: f64 = 24.0 * 3600.0;
+
+// 预览窗口（±秒）的上限
+const MAX_PREVIEW_WINDOW: f64 = 10.0;
+
+// 预览时最多轻点的键数
+const MAX_PREVIEW_TAPS: usize = 4;
+
+// 预览轻点的按住时长
+const PREVIEW_TAP: Duration = Duration::from_millis(30);
+
+// 等待播放线程处理预览的超时
+const PREVIEW_TIMEOUT: Duration = Duration::from_secs(2);
+
+// 练习模式下和弦的各个键需要在这段时间内按齐
+const PRACTICE_CHORD_WINDOW: Duration = Duration::from_millis(400);
+
+// 预备拍按键的按住时长上限
+const CLICK_HOLD_SECONDS: f64 = 0.05;
+
+// 预备拍按键在 held 中的占位序号，不对应任何事件
+const CLICK_EVENT_INDEX: usize = usize::MAX;
+
+// 定时播放最多提前多久预约
+const MAX_SCHEDULE_AHEAD_MS: u64 = 24 * 60 * 60 * 1000;
+
+// 等待定时开始时每次最长的休眠，醒来后重新读取系统时间，使校时等调整生效
+const SCHEDULE_POLL_MS: u64 = 250;
+
+/// 所有修饰键（分左右），松开全部按键时一并释放
+pub const MODIFIER_KEYS: [&str; 8] = [
+    "lshift", "rshift", "lctrl", "rctrl", "lalt", "ralt", "lmeta", "rmeta",
+];
+
+// 等待播放线程松开全部按键的超时
+const RELEASE_ALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 看门狗的检查间隔
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// 播放线程超过预计回来的时刻这么久仍没有心跳时判定为卡住
+// 等待下一个事件时预计时刻就是该事件的时间，歌曲中的长休止不会误判
+const HUNG_THRESHOLD: Duration = Duration::from_secs(5);
+
+// 判定卡住并请求停止后，再过这么久仍未停下就放弃该线程
+const HUNG_STOP_GRACE: Duration = Duration::from_secs(3);
+
+/// 按键发送后端
+/// 播放逻辑只依赖这个 trait，真实环境用 Enigo，测试时换成 RecordingSender
+pub trait KeySender {
+    fn press(&mut self, key: &str) -> Result<(), String>;
+    fn release(&mut self, key: &str) -> Result<(), String>;
+}
+
+/// 基于 Enigo + uni-input SmartKeyboard 的发送后端
+pub struct EnigoSender {
+    enigo: Enigo,
+    layout: LayoutTranslation,
+}
+
+impl EnigoSender {
+    pub fn new(layout: LayoutTranslation) -> Result<Self, CommandError> {
+        let enigo = Enigo::new(&Settings::default()).map_err(|e| {
+            CommandError::Other(format!("Failed to create Enigo instance: {:?}", e))
+        })?;
+        Ok(Self { enigo, layout })
+    }
+}
+
+impl KeySender for EnigoSender {
+    fn press(&mut self, key: &str) -> Result<(), String> {
+        recorder::note_injected(key);
+        self.enigo.key_down_with_layout(key, self.layout)
+    }
+
+    fn release(&mut self, key: &str) -> Result<(), String> {
+        recorder::note_injected(key);
+        self.enigo.key_up_with_layout(key, self.layout)
+    }
+}
+
+/// 文本模式的发送后端：把每个按键字符串作为 Unicode 字符输入，用于在游戏的聊天框里打出歌曲
+/// 不使用键码表和布局转换，大写字母由系统按字符输入
+pub struct TextSender {
+    enigo: Enigo,
+}
+
+impl TextSender {
+    pub fn new() -> Result<Self, CommandError> {
+        let enigo = Enigo::new(&Settings::default()).map_err(|e| {
+            CommandError::Other(format!("Failed to create Enigo instance: {:?}", e))
+        })?;
+        Ok(Self { enigo })
+    }
+}
+
+impl KeySender for TextSender {
+    fn press(&mut self, key: &str) -> Result<(), String> {
+        recorder::note_injected(key);
+        self.enigo.key_down_text(key)
+    }
+
+    fn release(&mut self, key: &str) -> Result<(), String> {
+        recorder::note_injected(key);
+        self.enigo.key_up_text(key)
+    }
+}
+
+/// 基于 Linux /dev/uinput 虚拟键盘的发送后端，Wayland 下使用
+#[cfg(target_os = "linux")]
+pub struct UinputSender {
+    keyboard: uni_input::UinputKeyboard,
+    layout: LayoutTranslation,
+}
+
+#[cfg(target_os = "linux")]
+impl UinputSender {
+    pub fn new(layout: LayoutTranslation) -> Result<Self, CommandError> {
+        let keyboard = uni_input::UinputKeyboard::new()
+            .map_err(|e| CommandError::Other(format!("Failed to create uinput keyboard: {}", e)))?;
+        Ok(Self { keyboard, layout })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl KeySender for UinputSender {
+    fn press(&mut self, key: &str) -> Result<(), String> {
+        recorder::note_injected(key);
+        self.keyboard.key_down(key, self.layout)
+    }
+
+    fn release(&mut self, key: &str) -> Result<(), String> {
+        recorder::note_injected(key);
+        self.keyboard.key_up(key, self.layout)
+    }
+}
+
+/// RecordingSender 收到的一次按下或松开
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SentKey {
+    pub time: f64, // 从创建 RecordingSender 起经过的秒数，按它的时钟计算
+    pub key: String,
+    pub pressed: bool,
+}
+
+/// 只记录、不发送的后端，用于检查调度结果
+/// 与控制器共用一个 VirtualClock 时整首歌在几毫秒内播完，记录的时间就是按键的歌曲时间
+/// 克隆出的实例共用同一份记录
+#[derive(Clone)]
+pub struct RecordingSender {
+    clock: Arc<dyn Clock>,
+    start: Instant,
+    sent: Arc<Mutex<Vec<SentKey>>>,
+}
+
+impl RecordingSender {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            start: clock.now(),
+            clock,
+            sent: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// 作为 PlaybackController::new 的工厂，创建的后端都记入这一份记录
+    pub fn factory(&self) -> SenderFactory {
+        let sender = self.clone();
+        Arc::new(move |_| Ok(Box::new(sender.clone()) as Box<dyn KeySender>))
+    }
+
+    /// 到目前为止的记录，按发送的顺序
+    pub fn sent(&self) -> Vec<SentKey> {
+        self.sent.lock().clone()
+    }
+
+    /// 记录写成 "0.500 +a" / "0.750 -a" 的形式，时间精确到毫秒，便于整体比较
+    pub fn lines(&self) -> Vec<String> {
+        self.sent
+            .lock()
+            .iter()
+            .map(|sent| {
+                let sign = if sent.pressed { '+' } else { '-' };
+                format!("{:.3} {}{}", sent.time, sign, sent.key)
+            })
+            .collect()
+    }
+
+    fn record(&self, key: &str, pressed: bool) {
+        let time = self
+            .clock
+            .now()
+            .saturating_duration_since(self.start)
+            .as_secs_f64();
+        self.sent.lock().push(SentKey {
+            time,
+            key: key.to_string(),
+            pressed,
+        });
+    }
+}
+
+impl KeySender for RecordingSender {
+    fn press(&mut self, key: &str) -> Result<(), String> {
+        self.record(key, true);
+        Ok(())
+    }
+
+    fn release(&mut self, key: &str) -> Result<(), String> {
+        self.record(key, false);
+        Ok(())
+    }
+}
+
+/// 按键发送后端的选择
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputBackend {
+    /// Linux Wayland 会话用 uinput，其余情况用 Enigo
+    #[default]
+    Auto,
+    Enigo,
+    Uinput,
+}
+
+impl InputBackend {
+    /// 把 Auto 解析为具体后端
+    pub fn resolve(self) -> InputBackend {
+        match self {
+            InputBackend::Auto => {
+                let wayland = cfg!(target_os = "linux")
+                    && std::env::var("XDG_SESSION_TYPE")
+                        .map(|t| t.eq_ignore_ascii_case("wayland"))
+                        .unwrap_or(false);
+                if wayland {
+                    InputBackend::Uinput
+                } else {
+                    InputBackend::Enigo
+                }
+            }
+            backend => backend,
+        }
+    }
+}
+
+/// 创建发送后端所需的配置
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SenderConfig {
+    pub backend: InputBackend,
+    // 非 QWERTY 布局下把字符换算到对应的物理按键位置
+    pub layout: LayoutTranslation,
+    // 文本模式：按字符输入，忽略 layout
+    pub text_mode: bool,
+}
+
+/// 按配置创建发送后端
+pub fn create_sender(config: SenderConfig) -> Result<Box<dyn KeySender>, CommandError> {
+    if config.text_mode {
+        // uinput 只有物理键码，无法输入任意字符
+        if config.backend.resolve() == InputBackend::Uinput {
+            return Err(CommandError::Unsupported(
+                "Text mode is not available with the uinput backend".to_string(),
+            ));
+        }
+        return Ok(Box::new(TextSender::new()?));
+    }
+    match config.backend.resolve() {
+        InputBackend::Uinput => create_uinput_sender(config.layout),
+        _ => Ok(Box::new(EnigoSender::new(config.layout)?)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn create_uinput_sender(layout: LayoutTranslation) -> Result<Box<dyn KeySender>, CommandError> {
+    Ok(Box::new(UinputSender::new(layout)?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create_uinput_sender(_layout: LayoutTranslation) -> Result<Box<dyn KeySender>, CommandError> {
+    Err(CommandError::Unsupported(
+        "The uinput backend is only available on Linux".to_string(),
+    ))
+}
+
+/// 发送后端工厂
+/// 后端在播放线程内创建（部分平台的 Enigo 不能跨线程移动）
+pub type SenderFactory =
+    Arc<dyn Fn(SenderConfig) -> Result<Box<dyn KeySender>, CommandError> + Send + Sync>;
+
+/// 单次播放使用的发送后端（如 MIDI 输出），在播放线程内创建
+pub type SenderBuilder = Box<dyn FnOnce() -> Result<Box<dyn KeySender>, CommandError> + Send>;
+
+/// 播放事件回调，由 lib.rs 转发为 Tauri 事件
+pub type EventSink = Arc<dyn Fn(PlaybackEvent) + Send + Sync>;
+
+/// 每次成功按下一个键后在播放线程上调用，不能阻塞
+pub type PressHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackStatus {
+    Idle,
+    Scheduled, // 已预约，等待开始时间
+    Playing,
+    Paused,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaybackProgress {
+    pub status: PlaybackStatus,
+    pub position: f64,                     // 当前播放位置（秒）
+    pub duration: f64,                     // 总时长（秒）
+    pub sent: usize,                       // 已处理的事件数
+    pub total: usize,                      // 事件总数
+    pub queue_index: Option<usize>,        // 队列播放时当前条目的序号
+    pub queue_elapsed: Option<f64>,        // 队列播放时从第一首开始累计的时间（含条目间隔）
+    pub scheduled_at_unix_ms: Option<u64>, // 已预约时的开始时间（Unix 毫秒）
+    pub stopping_at: Option<f64>,          // 软停止时预计停下的歌曲时间
+    pub speed: f64,                        // 当前的速度倍率
+    pub muted_groups: Vec<String>,         // 已静音的事件分组
+    pub loop_region: Option<(f64, f64)>,   // 循环播放的区间（歌曲时间）
+}
+
+/// 软停止的停止位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopBoundary {
+    Measure,
+    Phrase,  // PHRASE_MEASURES 小节
+    NextGap, // 下一个长于 STOP_GAP_SECONDS 的静音处
+}
+
+/// 播放起点
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartAt {
+    Time(f64),    // 从指定时间（秒）开始
+    Index(usize), // 从指定事件序号开始
+}
+
+/// 按下失败时的处理方式；释放失败时无论哪种方式都至少重试一次
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SendFailurePolicy {
+    #[default]
+    Skip, // 放弃这个音符，计入统计
+    Retry, // 重试 send_retries 次后仍失败再放弃
+    Abort, // 停止播放并报告错误
+}
+
+/// 同时按下的键需要不同的修饰键状态时的处理方式
+/// 例如 "shift+a" 按住时按 "b"，游戏收到的是 shift+b
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModifierConflictPolicy {
+    #[default]
+    Stagger, // 先按高音，冲突的键错开 conflict_stagger_ms 再按，之前的键提前松开
+    Drop,   // 同时起音的冲突音符只保留高音
+    Ignore, // 不处理，按原样发送
+}
+
+/// 播放设置，由前端整体传入
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaybackSettings {
+    pub min_hold_ms: f64,            // 最短按住时长（毫秒）
+    pub max_hold_ms: Option<f64>,    // 最长按住时长（毫秒），不设置则不限制
+    pub max_presses_per_second: u32, // 每秒最多按下的键数，超出的按键直接丢弃
+    pub i_know_what_im_doing: bool,  // 跳过峰值速率的播放前检查
+    pub send_failure_policy: SendFailurePolicy,
+    pub send_retries: u32, // retry 时的重试次数
+    pub modifier_conflicts: ModifierConflictPolicy,
+    pub conflict_stagger_ms: f64,   // 冲突的键错开的时间（毫秒）
+    pub timer_resolution_ms: u32,   // 播放期间请求的系统计时器精度（仅 Windows），0 表示不修改
+    pub gate: f64, // 按住时长占音符时长的比例（0.1-1），小于 1 时断奏，在最短/最长按住时长之前生效
+    pub reserved_keys: Vec<String>, // 绝不按下的键，事件里出现时跳过，见 ReservedKeys
+    // 播放期间每隔这么多秒把这段时间里松开过、现在没有按住的键再松开一次，防止松开丢失后键卡住
+    // 有的游戏会误解多余的松开，默认关闭
+    pub keep_alive_seconds: Option<f64>,
+}
+
+impl Default for PlaybackSettings {
+    fn default() -> Self {
+        Self {
+            min_hold_ms: DEFAULT_MIN_HOLD_MS,
+            max_hold_ms: None,
+            max_presses_per_second: DEFAULT_MAX_PRESSES_PER_SECOND,
+            i_know_what_im_doing: false,
+            send_failure_policy: SendFailurePolicy::Skip,
+            send_retries: 2,
+            modifier_conflicts: ModifierConflictPolicy::Stagger,
+            conflict_stagger_ms: DEFAULT_CONFLICT_STAGGER_MS,
+            timer_resolution_ms: DEFAULT_TIMER_RESOLUTION_MS,
+            gate: 1.0,
+            reserved_keys: Vec::new(),
+            keep_alive_seconds: None,
+        }
+    }
+}
+
+impl PlaybackSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1.0..=1000.0).contains(&self.min_hold_ms) {
+            return Err(format!(
+                "min_hold_ms must be within 1-1000, got {}",
+                self.min_hold_ms
+            ));
+        }
+        if let Some(max) = self.max_hold_ms {
+            if !max.is_finite() || max < self.min_hold_ms {
+                return Err(format!(
+                    "max_hold_ms must be at least min_hold_ms ({}), got {}",
+                    self.min_hold_ms, max
+                ));
+            }
+        }
+        if !(1.0..=MAX_CONFLICT_STAGGER_MS).contains(&self.conflict_stagger_ms) {
+            return Err(format!(
+                "conflict_stagger_ms must be within 1-{}, got {}",
+                MAX_CONFLICT_STAGGER_MS, self.conflict_stagger_ms
+            ));
+        }
+        if !(MIN_GATE..=1.0).contains(&self.gate) {
+            return Err(format!(
+                "gate must be within {}-1, got {}",
+                MIN_GATE, self.gate
+            ));
+        }
+        if self.timer_resolution_ms > MAX_TIMER_RESOLUTION_MS {
+            return Err(format!(
+                "timer_resolution_ms must be within 0-{}, got {}",
+                MAX_TIMER_RESOLUTION_MS, self.timer_resolution_ms
+            ));
+        }
+        if !(1..=MAX_SEND_RETRIES).contains(&self.send_retries) {
+            return Err(format!(
+                "send_retries must be within 1-{}, got {}",
+                MAX_SEND_RETRIES, self.send_retries
+            ));
+        }
+        if let Some(seconds) = self.keep_alive_seconds {
+            if !(MIN_KEEP_ALIVE_SECONDS..=MAX_KEEP_ALIVE_SECONDS).contains(&seconds) {
+                return Err(format!(
+                    "keep_alive_seconds must be within {}-{}, got {}",
+                    MIN_KEEP_ALIVE_SECONDS, MAX_KEEP_ALIVE_SECONDS, seconds
+                ));
+            }
+        }
+        ReservedKeys::parse(&self.reserved_keys)?;
+        rate_limiter::validate_max_rate(self.max_presses_per_second)
+    }
+
+    // 按下失败后的重试次数
+    fn press_retries(&self) -> u32 {
+        match self.send_failure_policy {
+            SendFailurePolicy::Retry => self.send_retries,
+            SendFailurePolicy::Skip | SendFailurePolicy::Abort => 0,
+        }
+    }
+
+    // 释放失败后的重试次数，至少一次，尽量避免按键卡住
+    fn release_retries(&self) -> u32 {
+        self.press_retries().max(1)
+    }
+
+    /// 事件时长按 gate 缩短后夹到允许的按住时长范围内（秒）
+    pub fn hold_secs(&self, duration: f64) -> f64 {
+        self.clamp_hold(duration * self.gate)
+    }
+
+    fn clamp_hold(&self, hold: f64) -> f64 {
+        let hold = hold.max(self.min_hold_ms / 1000.0);
+        match self.max_hold_ms {
+            Some(max) => hold.min(max / 1000.0),
+            None => hold,
+        }
+    }
+}
+
+/// 单次播放的选项
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaybackOptions {
+    pub start_at: Option<StartAt>,
+    // 起点时仍在发声的音符是否按剩余时长补按
+    pub press_sounding: bool,
+    pub humanize: Option<HumanizeConfig>,
+    pub settings: PlaybackSettings,
+    // 第一个音符前的预备拍
+    pub count_in: Option<CountInConfig>,
+    // 练习模式：不发送按键，每组起音前等待用户自己按下对应的键
+    pub practice_mode: bool,
+    // 歌曲的速度和拍号，用于在小节边界软停止
+    pub meter: Option<Meter>,
+    // 从开始播放起经过这么多秒（实际时间）后停止
+    pub stop_after: Option<f64>,
+    // 暂停期间定时停止是否继续计时
+    pub timer_counts_pause: bool,
+    // (起始倍率, 结束倍率)：速度在整首歌中线性变化
+    pub speed_ramp: Option<(f64, f64)>,
+    // 小节边界（原速的歌曲时间），来自 MidiAnalysis::measures
+    pub measures: Option<Vec<f64>>,
+    // 循环播放第 a 到第 b 小节（含两端，从 1 开始），需要 measures
+    pub loop_region_measures: Option<(u32, u32)>,
+    // 文本模式：把按键作为字符输入，用于只能在聊天框里演奏的游戏
+    pub text_mode: bool,
+    // 文件标记的循环点：第一遍从头播放，之后从循环起点重复，不能与 loop_region_measures 同时使用
+    pub file_loop: Option<FileLoop>,
+}
+
+/// MIDI 文件自带的循环点，来自 MidiAnalysis 的 loop_start / loop_end（歌曲时间）
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FileLoop {
+    pub start: f64,
+    // 没有终点标记时为 None，循环到最后一个音结束
+    #[serde(default)]
+    pub end: Option<f64>,
+}
+
+impl PlaybackOptions {
+    /// 循环播放的歌曲时间区间：loop_region_measures 对应的小节，或文件的循环点
+    /// 歌曲时间不随速度缩放，改变速度后循环仍对齐同样的小节
+    /// 文件循环点没有终点时需先经过 validate_single 补上
+    pub fn loop_region(&self) -> Result<Option<(f64, f64)>, String> {
+        if let Some(file_loop) = self.file_loop {
+            if self.loop_region_measures.is_some() {
+                return Err(
+                    "Specify either loop_region_measures or file loop points, not both".to_string(),
+                );
+            }
+            let end = file_loop.end.unwrap_or(f64::INFINITY);
+            if !(file_loop.start.is_finite() && file_loop.start >= 0.0 && file_loop.start < end) {
+                return Err(format!(
+                    "Invalid file loop points: {:.3}s-{:.3}s",
+                    file_loop.start, end
+                ));
+            }
+            return Ok(Some((file_loop.start, end)));
+        }
+        let Some((first, last)) = self.loop_region_measures else {
+            return Ok(None);
+        };
+        let boundaries = self.measures.as_deref().unwrap_or_default();
+        let (from, to) = metronome::measure_span(boundaries, first, last)?;
+        if !(from.is_finite() && to.is_finite() && from < to) {
+            return Err(format!(
+                "Invalid measure boundaries for measures {}-{}: {:.3}s-{:.3}s",
+                first, last, from, to
+            ));
+        }
+        Ok(Some((from, to)))
+    }
+}
+
+/// 把起点解析为歌曲时间，越界时报错而不是静默地什么都不播
+fn resolve_start(
+    events: &[KeyEvent],
+    start_at: Option<StartAt>,
+    settings: &PlaybackSettings,
+) -> Result<f64, String> {
+    match start_at {
+        None => Ok(0.0),
+        Some(StartAt::Index(index)) => events
+            .get(index)
+            .map(|e| e.time)
+            .ok_or_else(|| format!("Start index {} out of range (0..{})", index, events.len())),
+        Some(StartAt::Time(time)) => {
+            let end = events_duration(events, settings);
+            if !time.is_finite() || time < 0.0 {
+                Err(format!("Invalid start time: {}", time))
+            } else if time > end {
+                Err(format!(
+                    "Start time {:.3}s is past the end of the song ({:.3}s)",
+                    time, end
+                ))
+            } else {
+                Ok(time)
+            }
+        }
+    }
+}
+
+/// 播放队列中的一首歌
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueEntry {
+    pub name: String,
+    pub events: Vec<KeyEvent>,
+    // 这一首之前的间隔（秒），替换队列的 gap_seconds
+    #[serde(default)]
+    pub gap_override: Option<f64>,
+    // 后台提前准备好的动作列表，条目的克隆共享同一份
+    #[serde(skip)]
+    preparation: Arc<PreparationSlot>,
+}
+
+#[derive(Debug, Default)]
+struct PreparationSlot {
+    state: Mutex<Preparation>,
+    // 后台准备完成时通知
+    done: Condvar,
+}
+
+/// 队列条目的准备状态，见 queue_list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrepareStatus {
+    Pending,
+    Preparing,
+    Ready,
+    Invalid, // 已准备好，但有无法解析的按键，见 prepare_error
+}
+
+// 准备结果对应的播放设置，设置不同时需要重新准备
+#[derive(Debug, Clone, PartialEq)]
+struct PrepareKey {
+    seamless: bool,
+    press_sounding: bool,
+    settings: String, // PlaybackSettings 的 JSON
+}
+
+#[derive(Debug, Default)]
+enum Preparation {
+    #[default]
+    Pending,
+    Preparing(PrepareKey),
+    Ready {
+        key: PrepareKey,
+        song: PreparedSong,
+        duration: f64,
+        error: Option<String>,
+    },
+}
+
+impl Preparation {
+    fn status(&self) -> (PrepareStatus, Option<String>) {
+        match self {
+            Preparation::Pending => (PrepareStatus::Pending, None),
+            Preparation::Preparing(_) => (PrepareStatus::Preparing, None),
+            Preparation::Ready { error: None, .. } => (PrepareStatus::Ready, None),
+            Preparation::Ready { error, .. } => (PrepareStatus::Invalid, error.clone()),
+        }
+    }
+}
+
+impl QueueEntry {
+    // 在后台线程中为 key 准备动作列表；已经准备好或正在准备时不重复
+    fn prepare_in_background(&self, key: PrepareKey, options: &PlaybackOptions) {
+        {
+            let mut preparation = self.preparation.state.lock();
+            match &*preparation {
+                Preparation::Preparing(current) | Preparation::Ready { key: current, .. }
+                    if *current == key =>
+                {
+                    return;
+                }
+                _ => *preparation = Preparation::Preparing(key.clone()),
+            }
+        }
+        let slot = Arc::clone(&self.preparation);
+        let events = self.events.clone();
+        let press_sounding = options.press_sounding;
+        let settings = options.settings.clone();
+        thread::spawn(move || {
+            let start = queue_entry_start(&events, key.seamless);
+            let error = events.iter().enumerate().find_map(|(index, event)| {
+                parse_key_string(&event.key).err().map(|e| {
+                    format!(
+                        "Event {} has an invalid key \"{}\": {}",
+                        index, event.key, e
+                    )
+                })
+            });
+            let duration = events_duration(&events, &settings);
+            let actions =
+                ActionQueue::new(build_actions(&events, start, press_sounding, &settings));
+            let mut preparation = slot.state.lock();
+            // 准备期间设置变了（已经开始按新的设置准备）时丢弃结果
+            if matches!(&*preparation, Preparation::Preparing(current) if *current == key) {
+                *preparation = Preparation::Ready {
+                    key,
+                    song: PreparedSong {
+                        events,
+                        actions,
+                        start,
+                    },
+                    duration,
+                    error,
+                };
+            }
+            slot.done.notify_all();
+        });
+    }
+
+    // 取出为 key 准备的结果，仍在后台准备时等它完成；没有开始准备时返回 None
+    fn take_prepared(&self, key: &PrepareKey) -> Option<PreparedSong> {
+        let mut preparation = self.preparation.state.lock();
+        while matches!(&*preparation, Preparation::Preparing(current) if current == key) {
+            self.preparation.done.wait(&mut preparation);
+        }
+        match &*preparation {
+            Preparation::Ready { key: current, .. } if current == key => {}
+            _ => return None,
+        }
+        match std::mem::take(&mut *preparation) {
+            Preparation::Ready { song, .. } => Some(song),
+            _ => None,
+        }
+    }
+}
+
+/// 队列播放的选项
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueOptions {
+    // 条目之间的间隔（秒），无缝衔接时不使用
+    pub gap_seconds: f64,
+    // 无缝衔接：跳过下一首开头的静音，第一个音符落在上一首最后一次松开的时刻
+    pub seamless: bool,
+    // 无缝衔接时与上一首重叠的秒数：上一首末尾只剩松开的部分提前这么多结束
+    pub overlap_seconds: f64,
+}
+
+impl QueueOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_queue_gap(self.gap_seconds)?;
+        if !self.overlap_seconds.is_finite() || self.overlap_seconds < 0.0 {
+            return Err(format!("Invalid queue overlap: {}", self.overlap_seconds));
+        }
+        Ok(())
+    }
+}
+
+fn validate_queue_gap(seconds: f64) -> Result<(), String> {
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(format!("Invalid queue gap: {}", seconds));
+    }
+    Ok(())
+}
+
+// 条目从哪里开始播放：无缝衔接时从第一个音符开始
+fn queue_entry_start(events: &[KeyEvent], seamless: bool) -> f64 {
+    if !seamless || events.is_empty() {
+        return 0.0;
+    }
+    events.iter().map(|e| e.time).fold(f64::INFINITY, f64::min)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueEntryInfo {
+    pub index: usize,
+    pub name: String,
+    pub event_count: usize,
+    pub duration: f64,
+    pub active: bool, // 是否正在播放
+    // 队列播放时下一首会在后台提前准备，准备好后衔接时不再停顿
+    pub prepared: PrepareStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prepare_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum PlaybackEvent {
+    StatusChanged(PlaybackProgress),
+    QueueEntryStarted {
+        index: usize,
+        name: String,
+    },
+    QueueEntryFinished {
+        index: usize,
+        name: String,
+        completed: bool,
+        report: PlaybackReport,
+    },
+    RateLimited {
+        position: f64, // 开始丢弃按键时的歌曲时间
+        max_presses_per_second: u32,
+    },
+    // 定时播放开始前每秒推送一次
+    Countdown {
+        start_at_unix_ms: u64,
+        remaining_seconds: u64,
+    },
+    // 当前按住的按键，播放期间定时推送，每次按下时也立即推送
+    KeysActive {
+        keys: Vec<String>,
+    },
+    // 预备拍的每一拍，设置了 continue_beats 时正式播放中也推送
+    Beat(Beat),
+    // 定时停止到点，之后按软停止结束播放
+    TimerStopped {
+        position: f64,
+    },
+    // 看门狗发现播放线程卡住（通常是发送后端阻塞），随后尝试停止
+    Hung {
+        position: f64,
+        stalled_seconds: f64, // 超过预计时刻多久没有心跳
+    },
+    Finished {
+        completed: bool,       // false 表示被用户停止或出错
+        error: Option<String>, // 无法开始播放时的错误（如输入后端不可用）
+        progress: PlaybackProgress,
+        report: PlaybackReport,
+    },
+}
+
+impl PlaybackEvent {
+    /// 对应的前端事件名
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlaybackEvent::StatusChanged(_) => "playback://status",
+            PlaybackEvent::QueueEntryStarted { .. } => "playback://queue_entry_started",
+            PlaybackEvent::QueueEntryFinished { .. } => "playback://queue_entry_finished",
+            PlaybackEvent::RateLimited { .. } => "playback://rate_limited",
+            PlaybackEvent::Countdown { .. } => "playback://countdown",
+            PlaybackEvent::KeysActive { .. } => "playback://keys_active",
+            PlaybackEvent::Beat(_) => "playback://beat",
+            PlaybackEvent::TimerStopped { .. } => "playback://timer_stopped",
+            PlaybackEvent::Hung { .. } => "playback://hung",
+            PlaybackEvent::Finished { .. } => "playback://finished",
+        }
+    }
+}
+
+// 播放线程与控制器之间共享的状态
+struct SessionState {
+    status: PlaybackStatus,
+    stop_requested: bool,
+    pause_requested: bool,
+    skip_requested: bool,
+    seek_request: Option<SeekRequest>,
+    // 替换当前歌曲剩余部分的新事件列表
+    replace_request: Option<Vec<KeyEvent>>,
+    // 本次播放对事件列表的检查条件，替换事件时沿用
+    event_checks: EventChecks,
+    // 正在打预备拍，此时单曲播放也可以跳过
+    counting_in: bool,
+    // 练习模式下用户按下、尚未处理的键；不在练习时为 None
+    practice_input: Option<VecDeque<(Instant, String)>>,
+    // 暂停时拖动进度条的预览请求，由播放线程处理
+    preview_request: Option<PreviewRequest>,
+    // 松开全部按键的请求，由播放线程用当前的发送后端处理
+    release_request: Option<ReleaseRequest>,
+    // 在边界处停止的请求：(边界类型, 最多等待的秒数)
+    soft_stop_request: Option<(StopBoundary, f64)>,
+    stopping_at: Option<f64>,
+    // 本次播放的速度和拍号，按小节停止时需要
+    meter: Option<Meter>,
+    // 定时停止的到期时间，不计暂停时由播放线程在恢复时顺延
+    stop_timer: Option<Instant>,
+    // 手动设置的速度，替换速度渐变
+    speed_request: Option<f64>,
+    speed: f64,
+    // 静音的分组，属于这些分组的事件不再按下
+    muted_groups: BTreeSet<String>,
+    loop_region: Option<(f64, f64)>,
+    position: f64,
+    duration: f64,
+    sent: usize,
+    total: usize,
+    queue_index: Option<usize>,
+    // 当前条目之前累计的队列时间减去条目的起点，加上 position 即为 queue_elapsed
+    queue_offset: f64,
+    scheduled_at: Option<u64>,
+}
+
+impl SessionState {
+    fn idle() -> Self {
+        Self {
+            status: PlaybackStatus::Idle,
+            stop_requested: false,
+            pause_requested: false,
+            skip_requested: false,
+            seek_request: None,
+            replace_request: None,
+            event_checks: EventChecks::default(),
+            counting_in: false,
+            practice_input: None,
+            preview_request: None,
+            release_request: None,
+            soft_stop_request: None,
+            stopping_at: None,
+            meter: None,
+            stop_timer: None,
+            speed_request: None,
+            speed: 1.0,
+            muted_groups: BTreeSet::new(),
+            loop_region: None,
+            position: 0.0,
+            duration: 0.0,
+            sent: 0,
+            total: 0,
+            queue_index: None,
+            queue_offset: 0.0,
+            scheduled_at: None,
+        }
+    }
+
+    fn progress(&self) -> PlaybackProgress {
+        PlaybackProgress {
+            status: self.status,
+            position: self.position,
+            duration: self.duration,
+            sent: self.sent,
+            total: self.total,
+            queue_index: self.queue_index,
+            queue_elapsed: self.queue_index.map(|_| self.queue_offset + self.position),
+            scheduled_at_unix_ms: self.scheduled_at,
+            stopping_at: self.stopping_at,
+            speed: self.speed,
+            muted_groups: self.muted_groups.iter().cloned().collect(),
+            loop_region: self.loop_region,
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<SessionState>,
+    // 停止/暂停/恢复时唤醒播放线程
+    signal: Condvar,
+    // 最近几次播放的统计报告，最新的在末尾
+    reports: Mutex<VecDeque<PlaybackReport>>,
+    // 播放队列；与 state 同时加锁时先锁 queue
+    queue: Mutex<Vec<QueueEntry>>,
+    // 正在播放的队列的准备设置，人性化时为 None（人性化需要播放线程的随机器，不提前准备）
+    queue_preparation: Mutex<Option<(PrepareKey, PlaybackOptions)>>,
+    // 播放线程是否正在执行任务（播放、测试按键或重建后端）
+    busy: Mutex<bool>,
+    // 任务完成时通知等待中的 stop/shutdown
+    done: Condvar,
+    // 当前按住的键 -> 按下它的事件序号，由播放线程维护
+    // 停止/暂停时据此释放按键，也用于向前端展示按住的键
+    held: Mutex<HashMap<String, usize>>,
+    press_hook: Mutex<Option<PressHook>>,
+    // 播放线程按下过的所有按键，松开全部按键时使用
+    pressed_keys: Mutex<BTreeSet<String>>,
+    // 调度使用的时间来源，测试时可换成虚拟时钟
+    clock: Arc<dyn Clock>,
+    watchdog: Mutex<Watchdog>,
+    // 看门狗每放弃一个卡住的播放线程加一，旧线程恢复后据此得知自己已被替换
+    worker_generation: AtomicU64,
+}
+
+// 播放线程的心跳，按实际时间计算
+#[derive(Default)]
+struct Watchdog {
+    // 播放线程预计回到调度循环的时刻，无限期等待（暂停、等待用户按键）或空闲时为 None
+    due: Option<Instant>,
+    // 判定为卡住并请求停止的时刻
+    hung_since: Option<Instant>,
+}
+
+impl Shared {
+    /// 在 signal 上最多等待 timeout；虚拟时钟直接推进时间，不实际等待
+    fn wait_for(&self, state: &mut MutexGuard<'_, SessionState>, timeout: Duration) {
+        if !self.clock.skip(timeout) {
+            self.heartbeat(Some(timeout));
+            self.signal.wait_for(state, timeout);
+            self.heartbeat(Some(Duration::ZERO));
+        }
+    }
+
+    /// 无限期等待 signal，看门狗不计时
+    fn wait(&self, state: &mut MutexGuard<'_, SessionState>) {
+        self.heartbeat(None);
+        self.signal.wait(state);
+        self.heartbeat(Some(Duration::ZERO));
+    }
+
+    /// 记录心跳：播放线程预计在 expected 之后回来，None 表示无限期等待
+    fn heartbeat(&self, expected: Option<Duration>) {
+        let mut watchdog = self.watchdog.lock();
+        watchdog.due = expected.and_then(|wait| Instant::now().checked_add(wait));
+        watchdog.hung_since = None;
+    }
+
+    // 队列播放中时在后台准备正在播放的下一首
+    fn prepare_upcoming(&self) {
+        let Some((key, options)) = self.queue_preparation.lock().clone() else {
+            return;
+        };
+        let Some(current) = self.state.lock().queue_index else {
+            return;
+        };
+        if let Some(next) = self.queue.lock().get(current + 1) {
+            next.prepare_in_background(key, &options);
+        }
+    }
+
+    fn is_abandoned(&self, generation: u64) -> bool {
+        self.worker_generation.load(Ordering::SeqCst) != generation
+    }
+
+    fn wait_until(&self, state: &mut MutexGuard<'_, SessionState>, deadline: Instant) {
+        self.wait_for(state, deadline.saturating_duration_since(self.clock.now()));
+    }
+
+    fn progress(&self) -> PlaybackProgress {
+        self.state.lock().progress()
+    }
+
+    fn active_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.held.lock().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    fn push_report(&self, report: PlaybackReport) {
+        let mut reports = self.reports.lock();
+        if reports.len() >= MAX_REPORTS {
+            reports.pop_front();
+        }
+        reports.push_back(report);
+    }
+
+    fn finish_job(&self) {
+        *self.busy.lock() = false;
+        *self.watchdog.lock() = Watchdog::default();
+        self.done.notify_all();
+    }
+
+    /// 等待当前任务完成，超时返回 false
+    fn wait_idle(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut busy = self.busy.lock();
+        while *busy {
+            match deadline {
+                None => self.done.wait(&mut busy),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.done.wait_for(&mut busy, deadline - now);
+                }
+            }
+        }
+        true
+    }
+}
+
+// 发给播放线程的任务
+// 每次播放只发一个，Play 比其他任务大也无妨
+#[allow(clippy::large_enum_variant)]
+enum WorkerCommand {
+    Play {
+        source: SessionSource,
+        options: PlaybackOptions,
+        sender_config: SenderConfig,
+        // 设置时本次播放改用该后端，不影响复用的按键后端
+        target: Option<SenderBuilder>,
+        // 设置时等到该时刻（Unix 毫秒）才开始
+        scheduled_at: Option<u64>,
+    },
+    TestKey {
+        key: String,
+        hold: f64,
+        delay: f64,
+        sender_config: SenderConfig,
+        reply: mpsc::Sender<Result<(), CommandError>>,
+    },
+    ResetBackend {
+        sender_config: SenderConfig,
+        reply: mpsc::Sender<Result<(), CommandError>>,
+    },
+    ReleaseKeys {
+        keys: Vec<String>,
+        sender_config: SenderConfig,
+        reply: mpsc::Sender<Result<usize, CommandError>>,
+    },
+}
+
+/// 播放控制器
+/// 持有常驻播放线程和共享状态，通过 Tauri `.manage()` 注册
+/// 发送后端在播放线程内首次使用时创建，之后的播放和测试按键都复用同一个实例
+pub struct PlaybackController {
+    shared: Arc<Shared>,
+    // 常驻播放线程的任务通道，首次使用时启动
+    worker: Mutex<Option<mpsc::Sender<WorkerCommand>>>,
+    sender_factory: SenderFactory,
+    // 当前的发送后端配置，下次播放或测试按键时生效
+    sender_config: Mutex<SenderConfig>,
+    event_sink: Option<EventSink>,
+}
+
+impl PlaybackController {
+    pub fn new(sender_factory: SenderFactory) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(SessionState::idle()),
+                signal: Condvar::new(),
+                reports: Mutex::new(VecDeque::new()),
+                queue: Mutex::new(Vec::new()),
+                queue_preparation: Mutex::new(None),
+                busy: Mutex::new(false),
+                done: Condvar::new(),
+                held: Mutex::new(HashMap::new()),
+                press_hook: Mutex::new(None),
+                pressed_keys: Mutex::new(BTreeSet::new()),
+                clock: Arc::new(SystemClock),
+                watchdog: Mutex::new(Watchdog::default()),
+                worker_generation: AtomicU64::new(0),
+            }),
+            worker: Mutex::new(None),
+            sender_factory,
+            sender_config: Mutex::new(SenderConfig::default()),
+            event_sink: None,
+        }
+    }
+
+    pub fn with_event_sink(mut self, sink: EventSink) -> Self {
+        self.event_sink = Some(sink);
+        self
+    }
+
+    pub fn with_press_hook(self, hook: PressHook) -> Self {
+        *self.shared.press_hook.lock() = Some(hook);
+        self
+    }
+
+    /// 换用其他时间来源（如 VirtualClock），需在开始播放前调用
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("with_clock must be called before playback starts")
+            .clock = clock;
+        self
+    }
+
+    pub fn set_backend(&self, backend: InputBackend) {
+        self.sender_config.lock().backend = backend;
+    }
+
+    pub fn set_layout_translation(&self, layout: LayoutTranslation) {
+        self.sender_config.lock().layout = layout;
+    }
+
+    pub fn sender_config(&self) -> SenderConfig {
+        *self.sender_config.lock()
+    }
+
+    /// 按当前配置创建发送后端的函数
+    /// 供实时输入等不经过播放线程的场景在自己的线程里调用
+    pub fn sender_builder(
+        &self,
+    ) -> impl FnOnce() -> Result<Box<dyn KeySender>, CommandError> + Send + 'static {
+        let factory = Arc::clone(&self.sender_factory);
+        let config = self.sender_config();
+        move || factory(config)
+    }
+
+    /// 开始播放按键序列
+    pub fn start(
+        &self,
+        events: Vec<KeyEvent>,
+        options: PlaybackOptions,
+    ) -> Result<(), CommandError> {
+        self.start_with_target(events, options, None)
+    }
+
+    /// 开始播放，target 不为空时本次播放发往该后端而不是键盘
+    /// 调度、状态事件和暂停/跳转等控制与普通播放完全相同
+    pub fn start_with_target(
+        &self,
+        mut events: Vec<KeyEvent>,
+        mut options: PlaybackOptions,
+        target: Option<SenderBuilder>,
+    ) -> Result<(), CommandError> {
+        let start =
+            validate_single(&mut events, &mut options).map_err(CommandError::InvalidArgument)?;
+        self.spawn_session(
+            SessionSource::Single { events, start },
+            options,
+            target,
+            None,
+        )
+    }
+
+    /// 预约在指定的系统时间（Unix 毫秒）开始播放
+    /// 等待期间状态为 Scheduled，每秒推送一次 Countdown，可用 cancel_scheduled 取消
+    pub fn schedule(
+        &self,
+        mut events: Vec<KeyEvent>,
+        mut options: PlaybackOptions,
+        start_at_unix_ms: u64,
+    ) -> Result<(), CommandError> {
+        let now = playback_stats::unix_ms();
+        if start_at_unix_ms <= now {
+            return Err(CommandError::InvalidArgument(
+                "Scheduled start time is in the past".to_string(),
+            ));
+        }
+        if start_at_unix_ms - now > MAX_SCHEDULE_AHEAD_MS {
+            return Err(CommandError::InvalidArgument(
+                "Scheduled start time must be within 24 hours".to_string(),
+            ));
+        }
+        let start =
+            validate_single(&mut events, &mut options).map_err(CommandError::InvalidArgument)?;
+        self.spawn_session(
+            SessionSource::Single { events, start },
+            options,
+            None,
+            Some(start_at_unix_ms),
+        )
+    }
+
+    /// 取消尚未开始的定时播放
+    pub fn cancel_scheduled(&self) -> Result<(), CommandError> {
+        if self.shared.state.lock().status != PlaybackStatus::Scheduled {
+            return Err(CommandError::InvalidState(
+                "No scheduled playback".to_string(),
+            ));
+        }
+        self.stop()
+    }
+
+    /// 按顺序播放队列中的全部条目，条目之间的间隔或衔接方式见 QueueOptions
+    /// options 的 settings、press_sounding 和 humanize 用于每一首，通常来自默认设置
+    pub fn start_queue(
+        &self,
+        queue: QueueOptions,
+        options: PlaybackOptions,
+    ) -> Result<(), CommandError> {
+        queue.validate().map_err(CommandError::InvalidArgument)?;
+        options
+            .settings
+            .validate()
+            .map_err(CommandError::InvalidArgument)?;
+        if let Some(humanize) = &options.humanize {
+            humanize.validate().map_err(CommandError::InvalidArgument)?;
+        }
+        if self.shared.queue.lock().is_empty() {
+            return Err(CommandError::InvalidState("Queue is empty".to_string()));
+        }
+        self.spawn_session(SessionSource::Queue(queue), options, None, None)
+    }
+
+    fn spawn_session(
+        &self,
+        source: SessionSource,
+        options: PlaybackOptions,
+        target: Option<SenderBuilder>,
+        scheduled_at: Option<u64>,
+    ) -> Result<(), CommandError> {
+        let sender_config = SenderConfig {
+            text_mode: options.text_mode,
+            ..self.sender_config()
+        };
+        let event_checks = EventChecks::of(&options);
+        let meter = options.meter;
+        let speed = options.speed_ramp.map_or(1.0, |(start, _)| start);
+        let loop_region = options
+            .loop_region()
+            .map_err(CommandError::InvalidArgument)?;
+        self.submit(
+            WorkerCommand::Play {
+                source,
+                options,
+                sender_config,
+                target,
+                scheduled_at,
+            },
+            || {
+                // 重置共享状态
+                let mut state = self.shared.state.lock();
+                *state = SessionState::idle();
+                state.event_checks = event_checks;
+                state.meter = meter;
+                state.speed = speed;
+                state.loop_region = loop_region;
+                state.status = match scheduled_at {
+                    Some(_) => PlaybackStatus::Scheduled,
+                    None => PlaybackStatus::Playing,
+                };
+                state.scheduled_at = scheduled_at;
+            },
+        )
+    }
+
+    /// 把任务交给播放线程；已有任务在执行时拒绝
+    /// prepare 在确认空闲后、任务发出前执行
+    fn submit(&self, command: WorkerCommand, prepare: impl FnOnce()) -> Result<(), CommandError> {
+        let mut worker = self.worker.lock();
+        {
+            let mut busy = self.shared.busy.lock();
+            if *busy {
+                return Err(CommandError::InvalidState(
+                    "Playback already in progress".to_string(),
+                ));
+            }
+            *busy = true;
+        }
+        self.shared.heartbeat(Some(Duration::ZERO));
+        prepare();
+
+        let command = match worker.as_ref() {
+            Some(tx) => match tx.send(command) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::SendError(command)) => command,
+            },
+            None => command,
+        };
+
+        // 播放线程尚未启动或已退出，重新启动后再发送
+        let tx = self.spawn_worker();
+        let result = tx
+            .send(command)
+            .map_err(|_| CommandError::Other("Playback thread is not available".to_string()));
+        if result.is_err() {
+            self.shared.finish_job();
+        }
+        *worker = Some(tx);
+        result
+    }
+
+    fn spawn_worker(&self) -> mpsc::Sender<WorkerCommand> {
+        let (tx, rx) = mpsc::channel();
+        let shared = Arc::clone(&self.shared);
+        let sender_factory = Arc::clone(&self.sender_factory);
+        let event_sink = self.event_sink.clone();
+        let generation = shared.worker_generation.load(Ordering::SeqCst);
+        thread::spawn(move || run_worker(shared, sender_factory, event_sink, rx, generation));
+        tx
+    }
+
+    /// 检查播放线程是否卡住，由定时器每隔 WATCHDOG_INTERVAL 调用，返回是否处于卡住状态
+    /// 心跳超过预计时刻 HUNG_THRESHOLD 时推送 Hung 并请求停止；HUNG_STOP_GRACE 内仍未停下时放弃该线程，
+    /// 推送 Finished 后新的播放使用新的线程。被放弃的线程恢复后会停止播放并退出，不再改动共享状态
+    pub fn check_watchdog(&self) -> bool {
+        if !*self.shared.busy.lock() {
+            return false;
+        }
+        let now = Instant::now();
+        let mut watchdog = self.shared.watchdog.lock();
+        let Some(due) = watchdog.due else {
+            return false;
+        };
+        let stalled = now.saturating_duration_since(due);
+        if stalled < HUNG_THRESHOLD {
+            return false;
+        }
+        match watchdog.hung_since {
+            None => {
+                watchdog.hung_since = Some(now);
+                drop(watchdog);
+                let position = self.shared.state.lock().position;
+                log::error!(
+                    target: session_log::TARGET,
+                    "Playback thread stalled for {:.1}s at {:.3}s, stopping",
+                    stalled.as_secs_f64(),
+                    position
+                );
+                if let Some(sink) = &self.event_sink {
+                    sink(PlaybackEvent::Hung {
+                        position,
+                        stalled_seconds: stalled.as_secs_f64(),
+                    });
+                }
+                self.request_stop();
+            }
+            Some(since) if now.saturating_duration_since(since) >= HUNG_STOP_GRACE => {
+                drop(watchdog);
+                self.abandon_worker();
+            }
+            Some(_) => {}
+        }
+        true
+    }
+
+    // 放弃卡住的播放线程：关闭它的任务通道并恢复空闲状态
+    // 它按住的键留在 held 中，之后的播放停止或跳转时一并松开
+    fn abandon_worker(&self) {
+        let mut worker = self.worker.lock();
+        self.shared.worker_generation.fetch_add(1, Ordering::SeqCst);
+        worker.take();
+        let progress = {
+            let mut state = self.shared.state.lock();
+            *state = SessionState::idle();
+            state.progress()
+        };
+        self.shared.finish_job();
+        drop(worker);
+        log::error!(target: session_log::TARGET, "Abandoned the stalled playback thread");
+        if let Some(sink) = &self.event_sink {
+            sink(PlaybackEvent::Finished {
+                completed: false,
+                error: Some("Playback thread stopped responding".to_string()),
+                progress,
+                report: StatsRecorder::new(0).finish(false),
+            });
+        }
+    }
+
+    /// 丢弃当前的发送后端并立即重新创建，用于后端状态异常时恢复
+    pub fn reset_input_backend(&self) -> Result<(), CommandError> {
+        let (reply, rx) = mpsc::channel();
+        let sender_config = self.sender_config();
+        self.submit(
+            WorkerCommand::ResetBackend {
+                sender_config,
+                reply,
+            },
+            || {},
+        )?;
+        rx.recv().unwrap_or_else(|_| Err(thread_exited()))
+    }
+
+    /// 测试单个按键：等待 delay 秒后按下并在 duration 秒后释放，按住时长与播放时一样按 settings 夹紧
+    /// 与正式播放使用同一个发送后端，播放进行中时拒绝执行
+    pub fn test_keypress(
+        &self,
+        key: String,
+        duration: f64,
+        delay: f64,
+        settings: &PlaybackSettings,
+    ) -> Result<(), CommandError> {
+        if !(0.0..=MAX_TEST_DELAY_SECS).contains(&delay) {
+            return Err(CommandError::InvalidArgument(format!(
+                "delay must be within 0-{}s, got {}",
+                MAX_TEST_DELAY_SECS, delay
+            )));
+        }
+        validate_duration(duration).map_err(CommandError::InvalidArgument)?;
+        let key = normalize_key_string(&key).map_err(CommandError::InvalidArgument)?;
+
+        let (reply, rx) = mpsc::channel();
+        let sender_config = self.sender_config();
+        // 测试期间播放线程处于忙碌状态，无法开始播放
+        self.submit(
+            WorkerCommand::TestKey {
+                key,
+                hold: settings.hold_secs(duration),
+                delay,
+                sender_config,
+                reply,
+            },
+            || {},
+        )?;
+        rx.recv().unwrap_or_else(|_| Err(thread_exited()))
+    }
+
+    /// 松开程序可能按下的所有键：keys（通常是当前的按键映射）、播放以来按下过的键
+    /// 和所有修饰键，带修饰键的按键只释放主键；返回成功发送的释放数
+    /// 播放进行中时交给播放线程在两个动作之间执行，不会与播放的按键交错
+    pub fn release_all_keys(&self, keys: Vec<String>) -> Result<usize, CommandError> {
+        let (reply, rx) = mpsc::channel();
+        {
+            let mut state = self.shared.state.lock();
+            if state.status != PlaybackStatus::Idle {
+                state.release_request = Some(ReleaseRequest { keys, reply });
+                drop(state);
+                self.shared.signal.notify_all();
+                return rx.recv_timeout(RELEASE_ALL_TIMEOUT).map_err(|_| {
+                    CommandError::Other("Playback thread did not release the keys".to_string())
+                })?;
+            }
+        }
+        // 刚结束的播放或测试按键还在收尾时稍等
+        self.shared.wait_idle(Some(RELEASE_ALL_TIMEOUT));
+        let sender_config = self.sender_config();
+        self.submit(
+            WorkerCommand::ReleaseKeys {
+                keys,
+                sender_config,
+                reply,
+            },
+            || {},
+        )?;
+        let result = rx.recv().unwrap_or_else(|_| Err(thread_exited()));
+        // 等任务收尾，紧接着开始的播放不会被拒绝
+        self.shared.wait_idle(Some(RELEASE_ALL_TIMEOUT));
+        result
+    }
+
+    /// 停止播放，等待当前任务结束（结束前会释放所有按住的键）
+    pub fn stop(&self) -> Result<(), CommandError> {
+        self.request_stop();
+        self.shared.wait_idle(None);
+        Ok(())
+    }
+
+    /// 播放到下一个边界后停止并释放按键，期间状态中的 stopping_at 为预计停止位置
+    /// max_wait_seconds 内没有边界、暂停中或练习模式下直接停止
+    pub fn stop_at_boundary(
+        &self,
+        boundary: StopBoundary,
+        max_wait_seconds: f64,
+    ) -> Result<(), CommandError> {
+        if !max_wait_seconds.is_finite() || max_wait_seconds < 0.0 {
+            return Err(CommandError::InvalidArgument(format!(
+                "Invalid max_wait_seconds: {}",
+                max_wait_seconds
+            )));
+        }
+        let paused = {
+            let mut state = self.shared.state.lock();
+            check_started(state.status)?;
+            if state.meter.is_none() && boundary != StopBoundary::NextGap {
+                return Err(CommandError::InvalidState(
+                    "Measure data was not provided for this playback".to_string(),
+                ));
+            }
+            if state.status != PlaybackStatus::Paused {
+                state.soft_stop_request = Some((boundary, max_wait_seconds));
+            }
+            state.status == PlaybackStatus::Paused
+        };
+        if paused {
+            return self.stop();
+        }
+        self.shared.signal.notify_all();
+        Ok(())
+    }
+
+    /// 设置播放速度倍率，会替换 speed_ramp，对本次播放的剩余部分（含后续队列条目）有效
+    pub fn set_playback_speed(&self, speed: f64) -> Result<(), CommandError> {
+        song_clock::validate_speed(speed).map_err(CommandError::InvalidArgument)?;
+        {
+            let mut state = self.shared.state.lock();
+            check_started(state.status)?;
+            state.speed_request = Some(speed);
+            state.speed = speed;
+        }
+        self.shared.signal.notify_all();
+        Ok(())
+    }
+
+    /// 静音或取消静音一个事件分组，返回当前的静音分组
+    /// 已按下的键照常释放，下次播放时全部取消静音
+    pub fn set_group_muted(&self, group: &str, muted: bool) -> Result<Vec<String>, CommandError> {
+        let mut state = self.shared.state.lock();
+        check_started(state.status)?;
+        if muted {
+            state.muted_groups.insert(group.to_string());
+        } else {
+            state.muted_groups.remove(group);
+        }
+        Ok(state.muted_groups.iter().cloned().collect())
+    }
+
+    /// 修改或清除（None）定时停止，从现在起经过 seconds 秒后停止
+    pub fn set_stop_timer(&self, seconds: Option<f64>) -> Result<(), CommandError> {
+        if let Some(seconds) = seconds {
+            validate_stop_timer(seconds).map_err(CommandError::InvalidArgument)?;
+        }
+        {
+            let mut state = self.shared.state.lock();
+            check_started(state.status)?;
+            state.stop_timer =
+                seconds.map(|s| self.shared.clock.now() + Duration::from_secs_f64(s));
+        }
+        self.shared.signal.notify_all();
+        Ok(())
+    }
+
+    /// 应用退出时的停止流程
+    /// 与 stop 相同，但最多等待 timeout，返回播放是否已按时结束
+    /// 播放线程本身随任务通道关闭而退出
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        self.request_stop();
+        // 超时就不再等待，进程退出时线程会一并结束
+        let finished = self.shared.wait_idle(Some(timeout));
+        self.worker.lock().take();
+        finished
+    }
+
+    fn request_stop(&self) {
+        {
+            let mut state = self.shared.state.lock();
+            state.stop_requested = true;
+        }
+        self.shared.signal.notify_all();
+    }
+
+    /// 暂停播放（释放当前按住的键）
+    pub fn pause(&self) -> Result<(), CommandError> {
+        {
+            let mut state = self.shared.state.lock();
+            check_started(state.status)?;
+            state.pause_requested = true;
+        }
+        self.shared.signal.notify_all();
+        Ok(())
+    }
+
+    /// 从暂停处继续播放
+    pub fn resume(&self) -> Result<(), CommandError> {
+        {
+            let mut state = self.shared.state.lock();
+            check_started(state.status)?;
+            state.pause_requested = false;
+        }
+        self.shared.signal.notify_all();
+        Ok(())
+    }
+
+    pub fn status(&self) -> PlaybackProgress {
+        self.shared.progress()
+    }
+
+    /// 暂停时预览 position 前后 window_seconds 秒内的事件，position 成为新的继续播放位置
+    /// tap 为 true 时轻点 position 处正在发声的键（最多 MAX_PREVIEW_TAPS 个）
+    pub fn preview_at(
+        &self,
+        position: f64,
+        window_seconds: f64,
+        tap: bool,
+    ) -> Result<Vec<KeyEvent>, CommandError> {
+        if !position.is_finite() || position < 0.0 {
+            return Err(CommandError::InvalidArgument(format!(
+                "Invalid preview position: {}",
+                position
+            )));
+        }
+        if !(0.0..=MAX_PREVIEW_WINDOW).contains(&window_seconds) {
+            return Err(CommandError::InvalidArgument(format!(
+                "window_seconds must be within 0-{}, got {}",
+                MAX_PREVIEW_WINDOW, window_seconds
+            )));
+        }
+        let (reply, rx) = mpsc::channel();
+        {
+            let mut state = self.shared.state.lock();
+            // 只在暂停时可用，正式播放中不会插入预览按键
+            if state.status != PlaybackStatus::Paused {
+                return Err(CommandError::InvalidState(
+                    "Preview is only available while paused".to_string(),
+                ));
+            }
+            state.preview_request = Some(PreviewRequest {
+                position,
+                window: window_seconds,
+                tap,
+                reply,
+            });
+        }
+        self.shared.signal.notify_all();
+        rx.recv_timeout(PREVIEW_TIMEOUT).map_err(|_| {
+            CommandError::Other("Playback thread did not answer the preview".to_string())
+        })
+    }
+
+    /// 练习模式下用户按下了 key（与事件中相同格式的按键字符串）
+    /// 不在练习或已暂停时忽略
+    pub fn practice_key(&self, key: String) {
+        {
+            let mut state = self.shared.state.lock();
+            if state.status != PlaybackStatus::Playing {
+                return;
+            }
+            let Some(input) = state.practice_input.as_mut() else {
+                return;
+            };
+            input.push_back((self.shared.clock.now(), key));
+        }
+        self.shared.signal.notify_all();
+    }
+
+    /// 跳转到指定位置（秒），播放和暂停时均可用
+    pub fn seek(&self, position: f64) -> Result<(), CommandError> {
+        if !position.is_finite() {
+            return Err(CommandError::InvalidArgument(format!(
+                "Invalid seek position: {}",
+                position
+            )));
+        }
+        self.request_seek(SeekRequest::Absolute(position))
+    }
+
+    /// 相对当前位置前进或后退（秒），超出范围时夹到开头或结尾
+    pub fn skip_relative(&self, seconds: f64) -> Result<(), CommandError> {
+        if !seconds.is_finite() {
+            return Err(CommandError::InvalidArgument(format!(
+                "Invalid skip amount: {}",
+                seconds
+            )));
+        }
+        self.request_seek(SeekRequest::Relative(seconds))
+    }
+
+    fn request_seek(&self, request: SeekRequest) -> Result<(), CommandError> {
+        {
+            let mut state = self.shared.state.lock();
+            check_started(state.status)?;
+            // 连续的相对跳转累加，避免快速连点时丢失
+            state.seek_request = match (state.seek_request, request) {
+                (Some(SeekRequest::Relative(a)), SeekRequest::Relative(b)) => {
+                    Some(SeekRequest::Relative(a + b))
+                }
+                (Some(SeekRequest::Absolute(a)), SeekRequest::Relative(b)) => {
+                    Some(SeekRequest::Absolute(a + b))
+                }
+                _ => Some(request),
+            };
+        }
+        self.shared.signal.notify_all();
+        Ok(())
+    }
+
+    /// 替换正在播放的歌曲中尚未播放的部分，歌曲时钟不变
+    /// 当前位置之前的事件被丢弃；仍按住的键在新列表中也在发声时保持按住，否则立即释放
+    /// 新列表按开始播放时的练习模式、文本模式和速率设置检查，与 start 相同
+    pub fn update_events(&self, mut events: Vec<KeyEvent>) -> Result<(), CommandError> {
+        {
+            let mut state = self.shared.state.lock();
+            check_started(state.status)?;
+            validate_events(&mut events, &state.event_checks)
+                .map_err(CommandError::InvalidArgument)?;
+            state.replace_request = Some(events);
+        }
+        self.shared.signal.notify_all();
+        Ok(())
+    }
+
+    /// 结束当前队列条目，直接进入下一首
+    pub fn skip_to_next(&self) -> Result<(), CommandError> {
+        {
+            let mut state = self.shared.state.lock();
+            if state.status == PlaybackStatus::Idle
+                || (state.queue_index.is_none() && !state.counting_in)
+            {
+                return Err(CommandError::InvalidState(
+                    "No queue playback in progress".to_string(),
+                ));
+            }
+            state.skip_requested = true;
+            state.pause_requested = false;
+        }
+        self.shared.signal.notify_all();
+        Ok(())
+    }
+
+    /// 添加到队列末尾，返回条目序号
+    /// gap_override 为这一首之前的间隔，不指定时使用队列的设置
+    pub fn queue_add(
+        &self,
+        name: String,
+        mut events: Vec<KeyEvent>,
+        gap_override: Option<f64>,
+    ) -> Result<usize, CommandError> {
+        if let Some(gap) = gap_override {
+            validate_queue_gap(gap).map_err(CommandError::InvalidArgument)?;
+        }
+        validate_each(&mut events).map_err(CommandError::InvalidArgument)?;
+        let mut queue = self.shared.queue.lock();
+        queue.push(QueueEntry {
+            name,
+            events,
+            gap_override,
+            preparation: Arc::default(),
+        });
+        let index = queue.len() - 1;
+        drop(queue);
+        // 正在播放的是前一首时，新加的这一首现在就开始准备
+        self.shared.prepare_upcoming();
+        Ok(index)
+    }
+
+    pub fn queue_remove(&self, index: usize) -> Result<(), CommandError> {
+        let mut queue = self.shared.queue.lock();
+        if index >= queue.len() {
+            return Err(CommandError::InvalidArgument(format!(
+                "Queue index {} out of range (0..{})",
+                index,
+                queue.len()
+            )));
+        }
+
+        let mut state = self.shared.state.lock();
+        match state.queue_index {
+            Some(current) if current == index => {
+                return Err(CommandError::InvalidState(
+                    "Cannot remove the entry that is currently playing".to_string(),
+                ));
+            }
+            // 删除前面的条目后，正在播放的条目前移一位
+            Some(current) if current > index => state.queue_index = Some(current - 1),
+            _ => {}
+        }
+        queue.remove(index);
+        drop(state);
+        drop(queue);
+        self.shared.prepare_upcoming();
+        Ok(())
+    }
+
+    /// 队列条目的信息；还没有准备好的条目按 settings 估计时长
+    pub fn queue_list(&self, settings: &PlaybackSettings) -> Vec<QueueEntryInfo> {
+        let queue = self.shared.queue.lock();
+        let current = self.shared.state.lock().queue_index;
+        queue
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let preparation = entry.preparation.state.lock();
+                let (prepared, prepare_error) = preparation.status();
+                let duration = match &*preparation {
+                    Preparation::Ready { duration, .. } => *duration,
+                    _ => events_duration(&entry.events, settings),
+                };
+                QueueEntryInfo {
+                    index,
+                    name: entry.name.clone(),
+                    event_count: entry.events.len(),
+                    duration,
+                    active: current == Some(index),
+                    prepared,
+                    prepare_error,
+                }
+            })
+            .collect()
+    }
+
+    /// 队列中的条目（含事件），用于保存恢复文件
+    pub fn queue_entries(&self) -> Vec<QueueEntry> {
+        self.shared.queue.lock().clone()
+    }
+
+    /// 清空队列；正在播放的条目会播完，之后队列结束
+    pub fn queue_clear(&self) {
+        let mut queue = self.shared.queue.lock();
+        queue.clear();
+        self.shared.state.lock().queue_index = None;
+    }
+
+    /// 当前按住的按键（已排序），未播放时为空
+    pub fn active_keys(&self) -> Vec<String> {
+        self.shared.active_keys()
+    }
+
+    /// 最近一次播放的统计报告
+    pub fn last_report(&self) -> Option<PlaybackReport> {
+        self.shared.reports.lock().back().cloned()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.shared.state.lock().status != PlaybackStatus::Idle
+    }
+}
+
+// 练习模式中同时起音的一组音符
+struct OnsetGroup {
+    time: f64,
+    keys: Vec<String>,      // 去重后的按键，用于提示
+    parsed: Vec<ParsedKey>, // 与 keys 一一对应，用于和用户的按键比较
+    notes: usize,           // 组内的事件数
+}
+
+// 字符主键统一用小写比较
+fn normalize_key(mut parsed: ParsedKey) -> ParsedKey {
+    if let Some(MainKey::Char(ch)) = parsed.main {
+        parsed.main = Some(MainKey::Char(ch.to_ascii_lowercase()));
+    }
+    parsed
+}
+
+/// 每个事件所属的和弦序号
+/// 所有事件都带有解析时的 chord 时直接使用，否则按默认分组设置重新分组（如录制的事件）
+fn chord_ids(events: &[KeyEvent]) -> Vec<usize> {
+    if let Some(chords) = events.iter().map(|e| e.chord).collect::<Option<Vec<_>>>() {
+        return chords;
+    }
+    let onsets: Vec<f64> = events.iter().map(|e| e.time).collect();
+    ChordGrouper::new(ChordGrouping::default(), TempoMap::default()).group(&onsets)
+}
+
+// 把 start 之后的事件按和弦分组
+fn onset_groups(events: &[KeyEvent], start: f64) -> Vec<OnsetGroup> {
+    let chords = chord_ids(events);
+    let mut sorted: Vec<usize> = (0..events.len())
+        .filter(|&i| events[i].time >= start)
+        .collect();
+    sorted.sort_by(|&a, &b| {
+        events[a]
+            .time
+            .partial_cmp(&events[b].time)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let mut groups: Vec<OnsetGroup> = Vec::new();
+    let mut current_chord = None;
+    for i in sorted {
+        let event = &events[i];
+        let group = match groups.last_mut() {
+            Some(group) if current_chord == Some(chords[i]) => group,
+            _ => {
+                current_chord = Some(chords[i]);
+                groups.push(OnsetGroup {
+                    time: event.time,
+                    keys: Vec::new(),
+                    parsed: Vec::new(),
+                    notes: 0,
+                });
+                groups.last_mut().unwrap()
+            }
+        };
+        group.notes += 1;
+        // 无法解析的按键不参与比较（开始播放和替换事件时已校验过，见 validate_events）
+        let Ok(parsed) = parse_key_string(&event.key).map(normalize_key) else {
+            continue;
+        };
+        if !group.parsed.contains(&parsed) {
+            group.keys.push(event.key.clone());
+            group.parsed.push(parsed);
+        }
+    }
+    groups
+}
+
+// 检查单曲播放的参数，返回起始时间
+fn validate_single(events: &mut [KeyEvent], options: &mut PlaybackOptions) -> Result<f64, String> {
+    options.settings.validate()?;
+    validate_events(events, &EventChecks::of(options))?;
+    let start = resolve_start(events, options.start_at, &options.settings)?;
+    // 文件循环点没有终点时循环到最后一个音松开
+    if let Some(file_loop) = &mut options.file_loop {
+        file_loop
+            .end
+            .get_or_insert_with(|| events_duration(events, &options.settings));
+    }
+    // 没有指定起点时从循环小节的开头开始；文件循环点的第一遍从头播放
+    let start = match options.loop_region()? {
+        Some((from, _)) if options.start_at.is_none() && options.file_loop.is_none() => from,
+        _ => start,
+    };
+    if let Some(humanize) = &options.humanize {
+        humanize.validate()?;
+    }
+    if let Some(count_in) = &options.count_in {
+        count_in.validate()?;
+    }
+    if let Some(meter) = &options.meter {
+        meter.validate()?;
+    }
+    if let Some(seconds) = options.stop_after {
+        validate_stop_timer(seconds)?;
+    }
+    if let Some((start, end)) = options.speed_ramp {
+        song_clock::validate_speed(start)?;
+        song_clock::validate_speed(end)?;
+    }
+    Ok(start)
+}
+
+// 取决于播放选项的事件检查，同一次播放中替换事件时沿用开始时的条件
+#[derive(Debug, Clone, Copy, Default)]
+struct EventChecks {
+    practice_mode: bool,
+    text_mode: bool,
+    // 跳过峰值速率检查（settings.i_know_what_im_doing）
+    skip_rate_check: bool,
+}
+
+impl EventChecks {
+    fn of(options: &PlaybackOptions) -> Self {
+        Self {
+            practice_mode: options.practice_mode,
+            text_mode: options.text_mode,
+            skip_rate_check: options.settings.i_know_what_im_doing,
+        }
+    }
+}
+
+// 逐个检查事件并整理按键写法，播放和按住保持等都只见到规范写法
+fn validate_each(events: &mut [KeyEvent]) -> Result<(), String> {
+    for (index, event) in events.iter_mut().enumerate() {
+        validate_event(event).map_err(|e| format!("Event {}: {}", index, e))?;
+    }
+    Ok(())
+}
+
+// 开始播放（validate_single）和 update_events 共用的逐个事件的检查
+fn validate_events(events: &mut [KeyEvent], checks: &EventChecks) -> Result<(), String> {
+    // 规范写法总能解析，练习模式可以直接比较用户的按键和事件中的按键
+    validate_each(events)?;
+    if checks.text_mode {
+        for (index, event) in events.iter().enumerate() {
+            text_char(&event.key)
+                .map_err(|e| format!("Event {} cannot be typed in text mode: {}", index, e))?;
+        }
+    }
+    rate_limiter::preflight(events, checks.skip_rate_check)
+}
+
+fn validate_stop_timer(seconds: f64) -> Result<(), String> {
+    if !seconds.is_finite() || seconds <= 0.0 || seconds > MAX_STOP_TIMER_SECS {
+        return Err(format!("Invalid stop timer: {}", seconds));
+    }
+    Ok(())
+}
+
+// 播放线程没有回复就退出了
+fn thread_exited() -> CommandError {
+    CommandError::Other("Playback thread exited unexpectedly".to_string())
+}
+
+// 暂停、跳转等只对已经开始的播放有效
+fn check_started(status: PlaybackStatus) -> Result<(), CommandError> {
+    match status {
+        PlaybackStatus::Idle => Err(CommandError::InvalidState(
+            "No playback in progress".to_string(),
+        )),
+        PlaybackStatus::Scheduled => Err(CommandError::InvalidState(
+            "Scheduled playback has not started yet".to_string(),
+        )),
+        PlaybackStatus::Playing | PlaybackStatus::Paused => Ok(()),
+    }
+}
+
+impl Drop for PlaybackController {
+    fn drop(&mut self) {
+        self.shutdown(SHUTDOWN_TIMEOUT);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ActionKind {
+    Press,
+    Release,
+}
+
+/// 按下动作上记录的修饰键冲突处理结果，执行时计入统计
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ModifierConflict {
+    Staggered(f64), // 推迟了这么多秒
+    CutShort,       // 与之冲突的键被提前松开，本身按时按下
+    Dropped,
+}
+
+// 调度单元：按键事件拆成按下和释放两个动作
+#[derive(Debug, Clone)]
+struct Action {
+    time: f64,
+    kind: ActionKind,
+    key: String,
+    event_index: usize,
+    conflict: Option<ModifierConflict>,
+}
+
+fn events_duration(events: &[KeyEvent], settings: &PlaybackSettings) -> f64 {
+    events
+        .iter()
+        .map(|e| e.time + settings.hold_secs(e.duration))
+        .fold(0.0, f64::max)
+}
+
+/// 把按键事件展开为按时间排序的按下/释放动作
+/// start 之前的事件会被跳过；press_sounding 时在 start 处补按仍在发声的音符
+fn build_actions(
+    events: &[KeyEvent],
+    start: f64,
+    press_sounding: bool,
+    settings: &PlaybackSettings,
+) -> Vec<Action> {
+    let mut actions = Vec::with_capacity(events.len() * 2);
+    let next_onsets = next_same_key_onsets(events);
+
+    for (i, event) in events.iter().enumerate() {
+        // 缩短后的时长不超过同一个键的下一次起音，之后再按最短/最长按住时长夹紧
+        let gated = (event.duration * settings.gate).min(next_onsets[i] - event.time);
+        let hold = settings.clamp_hold(gated);
+        let end = event.time + hold;
+
+        let press_time = if event.time >= start {
+            event.time
+        } else if press_sounding && end > start {
+            start
+        } else {
+            continue;
+        };
+
+        actions.push(Action {
+            time: press_time,
+            kind: ActionKind::Press,
+            key: event.key.clone(),
+            event_index: i,
+            conflict: None,
+        });
+        actions.push(Action {
+            time: end,
+            kind: ActionKind::Release,
+            key: event.key.clone(),
+            event_index: i,
+            conflict: None,
+        });
+    }
+
+    actions.sort_by(|a, b| action_order(events, a, b));
+    resolve_modifier_conflicts(&mut actions, events, settings);
+    actions
+}
+
+/// 试运行中一个事件的调度结果
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunEvent {
+    pub index: usize,
+    pub key: String, // 规范写法
+    pub time: f64,   // 事件本身的时间
+    // 计划按下和松开的歌曲时间，不会按下时为 None
+    pub press_at: Option<f64>,
+    pub release_at: Option<f64>,
+    pub warnings: Vec<String>,
+}
+
+/// 试运行的整体统计
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunSummary {
+    pub event_count: usize,
+    pub presses: usize,             // 会按下的键数
+    pub taps: usize,                // 其中时长为 0、按 min_hold_ms 轻点的
+    pub reserved_skipped: usize,    // 保留键跳过的
+    pub conflicts: usize,           // 有修饰键冲突的，包括因此丢弃的
+    pub conflict_dropped: usize,    // 因修饰键冲突丢弃的
+    pub rate_limited: usize,        // 超出 max_presses_per_second 丢弃的
+    pub retriggered: usize,         // 同一个键仍按住时重新按下的
+    pub peak_rate: usize,           // 事件本身任意 1 秒内的最多按下数，播放前检查用它
+    pub scheduled_peak_rate: usize, // 实际会按下的键任意 1 秒内的最多数
+    // 按实际会按下的键统计
+    pub modifier_churn: midi_analyzer::ModifierChurn,
+    pub duration: f64,
+    // 松开与另一个键的按下相隔不到 STUCK_RISK_WINDOW_MS 的事件数，这样的松开最容易丢失而让键卡住
+    pub stuck_key_risk: usize,
+}
+
+/// 试运行的结果：按播放时的规则调度但不发送任何按键
+/// 不做人性化，也不考虑起点、循环和速度，结果只取决于事件和设置
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRun {
+    pub summary: DryRunSummary,
+    pub warnings: Vec<String>, // 整首歌的警告，如播放前检查会拒绝
+    pub events: Vec<DryRunEvent>,
+}
+
+/// 按播放时的规则调度 events，记录每个事件何时按下、松开以及被跳过的原因
+/// 速率限制按原速的歌曲时间模拟
+pub fn dry_run(events: &[KeyEvent], settings: &PlaybackSettings) -> Result<DryRun, CommandError> {
+    settings.validate().map_err(CommandError::InvalidArgument)?;
+    let mut events = events.to_vec();
+    for (i, event) in events.iter_mut().enumerate() {
+        validate_event(event)
+            .map_err(|e| CommandError::InvalidArgument(format!("Event {}: {}", i, e)))?;
+    }
+    let reserved_keys =
+        ReservedKeys::parse(&settings.reserved_keys).map_err(CommandError::InvalidArgument)?;
+    let mut report: Vec<DryRunEvent> = events
+        .iter()
+        .enumerate()
+        .map(|(index, event)| DryRunEvent {
+            index,
+            key: event.key.clone(),
+            time: event.time,
+            press_at: None,
+            release_at: None,
+            warnings: Vec::new(),
+        })
+        .collect();
+
+    let mut warnings = Vec::new();
+    let peak_rate = rate_limiter::peak_rate(&events);
+    if peak_rate > rate_limiter::ABSOLUTE_MAX_PRESSES_PER_SECOND as usize {
+        warnings.push(format!(
+            "Peak rate of {} presses/second exceeds the limit of {}; playback refuses to start \
+             unless i_know_what_im_doing is set",
+            peak_rate,
+            rate_limiter::ABSOLUTE_MAX_PRESSES_PER_SECOND
+        ));
+    }
+
+    let mut summary = DryRunSummary {
+        event_count: events.len(),
+        presses: 0,
+        taps: 0,
+        reserved_skipped: 0,
+        conflicts: 0,
+        conflict_dropped: 0,
+        rate_limited: 0,
+        retriggered: 0,
+        peak_rate,
+        scheduled_peak_rate: 0,
+        modifier_churn: midi_analyzer::churn_of_presses(&[]),
+        duration: events_duration(&events, settings),
+        stuck_key_risk: 0,
+    };
+    let mut rate_limiter = RateLimiter::new(settings.max_presses_per_second);
+    let origin = Instant::now();
+    let mut held: HashMap<String, usize> = HashMap::new();
+    let mut pressed: Vec<(f64, u8)> = Vec::new();
+    for action in build_actions(&events, 0.0, false, settings) {
+        let index = action.event_index;
+        if action.kind == ActionKind::Release {
+            // 与播放相同，只松开由本事件按下的键
+            if held.get(&action.key) == Some(&index) {
+                held.remove(&action.key);
+                report[index].release_at = Some(action.time);
+            }
+            continue;
+        }
+        let entry = &mut report[index];
+        if reserved_keys.blocks(&action.key) {
+            summary.reserved_skipped += 1;
+            entry.warnings.push("Skipped: reserved key".to_string());
+            continue;
+        }
+        if let Some(conflict) = action.conflict {
+            summary.conflicts += 1;
+            entry.warnings.push(match conflict {
+                ModifierConflict::Staggered(delay) => {
+                    format!("Modifier conflict: delayed by {:.1}ms", delay * 1000.0)
+                }
+                ModifierConflict::CutShort => {
+                    "Modifier conflict: released held keys early".to_string()
+                }
+                ModifierConflict::Dropped => "Modifier conflict: dropped".to_string(),
+            });
+            if conflict == ModifierConflict::Dropped {
+                summary.conflict_dropped += 1;
+                continue;
+            }
+        }
+        let at = origin + Duration::from_secs_f64(action.time.max(0.0));
+        if let RateDecision::Dropped { burst_started } = rate_limiter.check(at) {
+            summary.rate_limited += 1;
+            entry.warnings.push(format!(
+                "Skipped: rate limit of {}/s",
+                settings.max_presses_per_second
+            ));
+            if burst_started {
+                warnings.push(format!(
+                    "Key rate exceeds {}/s at {:.3}s, presses are dropped",
+                    settings.max_presses_per_second, action.time
+                ));
+            }
+            continue;
+        }
+        entry.press_at = Some(action.time);
+        summary.presses += 1;
+        if events[index].duration == 0.0 {
+            summary.taps += 1;
+        }
+        pressed.push((action.time, modifier_mask(&action.key)));
+        if let Some(previous) = held.insert(action.key.clone(), index) {
+            // 之前的音在这里被松开再重新按下
+            summary.retriggered += 1;
+            report[previous].release_at = Some(action.time);
+            report[index].warnings.push(format!(
+                "Retriggers {} while event {} still holds it",
+                action.key, previous
+            ));
+        }
+    }
+    summary.scheduled_peak_rate =
+        rate_limiter::peak_rate_of(pressed.iter().map(|&(time, _)| time).collect());
+    summary.modifier_churn = midi_analyzer::churn_of_presses(&pressed);
+    summary.stuck_key_risk = mark_stuck_key_risk(&mut report);
+    Ok(DryRun {
+        summary,
+        warnings,
+        events: report,
+    })
+}
+
+// 松开前后 STUCK_RISK_WINDOW_MS 内有别的键按下的事件加一条警告，返回这样的事件数
+fn mark_stuck_key_risk(report: &mut [DryRunEvent]) -> usize {
+    let window = STUCK_RISK_WINDOW_MS / 1000.0;
+    let mut presses: Vec<(f64, usize)> = report
+        .iter()
+        .filter_map(|event| event.press_at.map(|time| (time, event.index)))
+        .collect();
+    presses.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut risky = 0;
+    for i in 0..report.len() {
+        let Some(release) = report[i].release_at else {
+            continue;
+        };
+        let from = presses.partition_point(|&(time, _)| time < release - window);
+        let nearest = presses[from..]
+            .iter()
+            .take_while(|&&(time, _)| time <= release + window)
+            .filter(|&&(_, index)| report[index].key != report[i].key)
+            .min_by(|a, b| (a.0 - release).abs().total_cmp(&(b.0 - release).abs()));
+        if let Some(&(time, index)) = nearest {
+            risky += 1;
+            let message = format!(
+                "Release is {:.1}ms from the press of {} (event {}), it may get lost",
+                (time - release).abs() * 1000.0,
+                report[index].key,
+                index
+            );
+            report[i].warnings.push(message);
+        }
+    }
+    risky
+}
+
+/// 循环终点前留出的松开间隔（秒），松开不会和跳回起点后的第一批按下挤在一起
+const LOOP_RELEASE_GAP: f64 = 0.01;
+
+/// 因跨过循环终点而提前松开的事件序号
+/// 第一遍从起点播到终点，之后各遍从循环起点开始，循环起点之前按下的音只在第一遍出现
+#[derive(Debug, Default)]
+struct LoopTruncations {
+    first_pass: Vec<usize>,
+    repeats: Vec<usize>,
+}
+
+/// 在循环终点 to 之前按下、之后才松开的音改为在终点前 LOOP_RELEASE_GAP 松开并逐个记录
+/// 否则到达终点时被跳转截断，或松开落到跳回之后；按下离终点太近时取按下和终点的中点
+fn truncate_at_loop_end(
+    actions: &mut [Action],
+    events: &[KeyEvent],
+    (from, to): (f64, f64),
+) -> LoopTruncations {
+    let mut truncations = LoopTruncations::default();
+    if !to.is_finite() {
+        return truncations;
+    }
+    let mut pressed: HashMap<usize, f64> = HashMap::new();
+    for action in actions.iter_mut() {
+        match action.kind {
+            ActionKind::Press => {
+                pressed.insert(action.event_index, action.time);
+            }
+            ActionKind::Release => {
+                let Some(press) = pressed.remove(&action.event_index) else {
+                    continue;
+                };
+                if press >= to || action.time <= to - LOOP_RELEASE_GAP {
+                    continue;
+                }
+                let release = (to - LOOP_RELEASE_GAP).max((press + to) / 2.0);
+                let event = &events[action.event_index];
+                let repeats = press >= from;
+                log::warn!(
+                    target: session_log::TARGET,
+                    "Event {} ({}) at {:.3}s crosses the loop end {:.3}s: released at {:.3}s instead of {:.3}s{}",
+                    action.event_index,
+                    event.key,
+                    press,
+                    to,
+                    release,
+                    action.time,
+                    if repeats { "" } else { " (first pass only)" }
+                );
+                action.time = release;
+                truncations.first_pass.push(action.event_index);
+                if repeats {
+                    truncations.repeats.push(action.event_index);
+                }
+            }
+        }
+    }
+    if !truncations.first_pass.is_empty() {
+        actions.sort_by(|a, b| action_order(events, a, b));
+    }
+    truncations
+}
+
+// 每个事件之后同一个键下一次起音的时间，没有时为无穷大
+fn next_same_key_onsets(events: &[KeyEvent]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..events.len()).collect();
+    order.sort_by(|&a, &b| {
+        events[a]
+            .time
+            .partial_cmp(&events[b].time)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let mut next = vec![f64::INFINITY; events.len()];
+    let mut upcoming: HashMap<&str, f64> = HashMap::new();
+    for &i in order.iter().rev() {
+        if let Some(&time) = upcoming.get(events[i].key.as_str()) {
+            next[i] = time;
+        }
+        upcoming.insert(&events[i].key, events[i].time);
+    }
+    next
+}
+
+// 同一时刻先释放再按下，保证同键连续触发时能重新按下；同时按下的键高音在前
+fn action_order(events: &[KeyEvent], a: &Action, b: &Action) -> std::cmp::Ordering {
+    let note = |action: &Action| events[action.event_index].note;
+    a.time
+        .partial_cmp(&b.time)
+        .unwrap_or(std::cmp::Ordering::Equal)
+        .then_with(|| (a.kind == ActionKind::Press).cmp(&(b.kind == ActionKind::Press)))
+        .then_with(|| note(b).cmp(&note(a)))
+}
+
+/// 动作数达到这个数时才考虑按模式压缩调度队列
+const PATTERN_MIN_ACTIONS: usize = 4096;
+
+/// 平均每个模式至少重复这么多次才压缩，重复少时模式表本身就不省内存
+const PATTERN_MIN_REPEATS: usize = 8;
+
+// 每隔这么多个时刻记一次展开后的序号，查找时从最近的记录往后数
+const PATTERN_CHECKPOINT: usize = 64;
+
+// 比较模式时 PatternStep 的内容：是否按下、按键、事件偏移、冲突类型和推迟秒数的位
+type StepIdentity = (bool, u32, u32, u8, u64);
+
+// 模式中的一个动作；事件序号相对所在时刻的第一个事件
+#[derive(Debug, Clone, Copy)]
+struct PatternStep {
+    kind: ActionKind,
+    key: u32, // PatternedActions.keys 中的序号
+    event_offset: u32,
+    conflict: Option<ModifierConflict>,
+}
+
+impl PatternStep {
+    // 查找相同模式时用的键，冲突推迟的秒数按位比较
+    fn identity(&self) -> StepIdentity {
+        let (tag, seconds) = encode_conflict(self.conflict);
+        let press = self.kind == ActionKind::Press;
+        (press, self.key, self.event_offset, tag, seconds.to_bits())
+    }
+}
+
+// 冲突处理结果的 (类型, 推迟秒数)，用于比较模式和写入暂存文件
+fn encode_conflict(conflict: Option<ModifierConflict>) -> (u8, f64) {
+    match conflict {
+        None => (0, 0.0),
+        Some(ModifierConflict::Staggered(seconds)) => (1, seconds),
+        Some(ModifierConflict::CutShort) => (2, 0.0),
+        Some(ModifierConflict::Dropped) => (3, 0.0),
+    }
+}
+
+fn decode_conflict(tag: u8, seconds: f64) -> Option<ModifierConflict> {
+    match tag {
+        1 => Some(ModifierConflict::Staggered(seconds)),
+        2 => Some(ModifierConflict::CutShort),
+        3 => Some(ModifierConflict::Dropped),
+        _ => None,
+    }
+}
+
+// 同一时刻的一组动作对模式的一次引用
+#[derive(Debug, Clone, Copy)]
+struct PatternInstance {
+    time: f64,
+    first_event: u32,
+    pattern: u32,
+}
+
+// 按模式压缩的动作序列：伴奏里反复出现的和弦只存一份，每个时刻只记时间和模式编号
+#[derive(Debug)]
+struct PatternedActions {
+    keys: Vec<String>,
+    patterns: Vec<Vec<PatternStep>>,
+    instances: Vec<PatternInstance>,
+    // 第 k * PATTERN_CHECKPOINT 个时刻的第一个动作在展开后序列中的序号
+    checkpoints: Vec<u32>,
+    len: usize,
+}
+
+impl PatternedActions {
+    // 按时刻切分动作并合并相同的模式；序号超出 u32 时返回 None
+    fn build(actions: &[Action]) -> Option<Self> {
+        u32::try_from(actions.len()).ok()?;
+        let mut keys = Vec::new();
+        let mut key_ids: HashMap<&str, u32> = HashMap::new();
+        let mut patterns = Vec::new();
+        let mut pattern_ids: HashMap<Vec<StepIdentity>, u32> = HashMap::new();
+        let mut instances = Vec::new();
+        let mut checkpoints = Vec::new();
+        // 每个时刻复用的缓冲，只有新模式才复制一份
+        let mut steps: Vec<PatternStep> = Vec::new();
+        let mut identity: Vec<StepIdentity> = Vec::new();
+
+        let mut start = 0;
+        while start < actions.len() {
+            // 时间按位相同才算同一时刻，展开后与原序列逐位一致
+            let time = actions[start].time;
+            let end = start
+                + actions[start..]
+                    .iter()
+                    .take_while(|a| a.time.to_bits() == time.to_bits())
+                    .count();
+            let frame = &actions[start..end];
+            let first_event = frame.iter().map(|a| a.event_index).min().unwrap_or(0);
+            steps.clear();
+            steps.extend(frame.iter().map(|action| {
+                let next_id = keys.len() as u32;
+                let key = *key_ids.entry(action.key.as_str()).or_insert_with(|| {
+                    keys.push(action.key.clone());
+                    next_id
+                });
+                PatternStep {
+                    kind: action.kind,
+                    key,
+                    event_offset: (action.event_index - first_event) as u32,
+                    conflict: action.conflict,
+                }
+            }));
+            identity.clear();
+            identity.extend(steps.iter().map(PatternStep::identity));
+            let pattern = match pattern_ids.get(identity.as_slice()) {
+                Some(&pattern) => pattern,
+                None => {
+                    let pattern = patterns.len() as u32;
+                    pattern_ids.insert(identity.clone(), pattern);
+                    patterns.push(steps.clone());
+                    pattern
+                }
+            };
+            if instances.len() % PATTERN_CHECKPOINT == 0 {
+                checkpoints.push(start as u32);
+            }
+            instances.push(PatternInstance {
+                time,
+                first_event: u32::try_from(first_event).ok()?,
+                pattern,
+            });
+            start = end;
+        }
+        // 倍增扩容可能空出将近一半，整首歌播放期间都保留着
+        instances.shrink_to_fit();
+        checkpoints.shrink_to_fit();
+        Some(Self {
+            keys,
+            patterns,
+            instances,
+            checkpoints,
+            len: actions.len(),
+        })
+    }
+
+    fn pattern_len(&self, instance: usize) -> usize {
+        self.patterns[self.instances[instance].pattern as usize].len()
+    }
+
+    // 展开后第 index 个动作所在的时刻和该时刻第一个动作的序号
+    fn locate(&self, index: usize) -> Option<(usize, usize)> {
+        if index >= self.len {
+            return None;
+        }
+        let checkpoint = self.checkpoints.partition_point(|&s| s as usize <= index) - 1;
+        let mut instance = checkpoint * PATTERN_CHECKPOINT;
+        let mut start = self.checkpoints[checkpoint] as usize;
+        while start + self.pattern_len(instance) <= index {
+            start += self.pattern_len(instance);
+            instance += 1;
+        }
+        Some((instance, start))
+    }
+
+    // 第 instance 个时刻的第一个动作在展开后序列中的序号
+    fn start_of(&self, instance: usize) -> usize {
+        if instance >= self.instances.len() {
+            return self.len;
+        }
+        let checkpoint = instance / PATTERN_CHECKPOINT;
+        (checkpoint * PATTERN_CHECKPOINT..instance)
+            .fold(self.checkpoints[checkpoint] as usize, |start, i| {
+                start + self.pattern_len(i)
+            })
+    }
+
+    fn get(&self, index: usize) -> Option<Action> {
+        let (instance, start) = self.locate(index)?;
+        let instance = &self.instances[instance];
+        let step = self.patterns[instance.pattern as usize][index - start];
+        Some(Action {
+            time: instance.time,
+            kind: step.kind,
+            key: self.keys[step.key as usize].clone(),
+            event_index: (instance.first_event + step.event_offset) as usize,
+            conflict: step.conflict,
+        })
+    }
+}
+
+// 写入磁盘的动作序列，按键表和汇总信息留在内存中；读失败时按序列已结束处理
+#[derive(Debug)]
+struct SpilledActions {
+    file: SpillFile,
+    keys: Vec<String>,
+    presses: usize,
+    last_time: Option<f64>,
+    last_press_time: Option<f64>,
+}
+
+impl SpilledActions {
+    fn write(actions: &[Action]) -> std::io::Result<Self> {
+        let mut keys = Vec::new();
+        let mut key_ids: HashMap<&str, u32> = HashMap::new();
+        let records = actions.iter().map(|action| {
+            let next_id = keys.len() as u32;
+            let key = *key_ids.entry(action.key.as_str()).or_insert_with(|| {
+                keys.push(action.key.clone());
+                next_id
+            });
+            let (conflict_tag, conflict_value) = encode_conflict(action.conflict);
+            SpillRecord {
+                time: action.time,
+                event_index: action.event_index as u64,
+                key,
+                press: action.kind == ActionKind::Press,
+                conflict_tag,
+                conflict_value,
+            }
+        });
+        let file = SpillFile::create(records)?;
+        let mut presses = actions.iter().filter(|a| a.kind == ActionKind::Press);
+        Ok(Self {
+            file,
+            keys,
+            presses: presses.clone().count(),
+            last_time: actions.last().map(|a| a.time),
+            last_press_time: presses.next_back().map(|a| a.time),
+        })
+    }
+
+    fn record(&self, index: usize) -> Option<SpillRecord> {
+        self.file.get(index).unwrap_or_else(|e| {
+            log::error!(target: session_log::TARGET, "Failed to read spilled schedule: {}", e);
+            None
+        })
+    }
+
+    fn get(&self, index: usize) -> Option<Action> {
+        let record = self.record(index)?;
+        Some(Action {
+            time: record.time,
+            kind: if record.press {
+                ActionKind::Press
+            } else {
+                ActionKind::Release
+            },
+            key: self.keys[record.key as usize].clone(),
+            event_index: record.event_index as usize,
+            conflict: decode_conflict(record.conflict_tag, record.conflict_value),
+        })
+    }
+}
+
+/// 调度队列：普通的动作列表，重复度高时为按模式压缩的形式，很大时可以写入磁盘，播放时逐个展开
+/// 各种形式展开后的动作序列完全相同
+#[derive(Debug)]
+enum ActionQueue {
+    Plain(Vec<Action>),
+    Patterned(PatternedActions),
+    Spilled(Box<SpilledActions>),
+}
+
+impl ActionQueue {
+    /// 动作足够多且同样的时刻模式大量重复时压缩；不能压缩且超过暂存阈值时写入磁盘，否则原样保存
+    /// 强制暂存时不压缩，直接写入磁盘
+    fn new(actions: Vec<Action>) -> Self {
+        let force_spill = schedule_spill::settings().force;
+        if actions.len() >= PATTERN_MIN_ACTIONS && !force_spill {
+            match PatternedActions::build(&actions) {
+                Some(patterned)
+                    if patterned.patterns.len() * PATTERN_MIN_REPEATS
+                        <= patterned.instances.len() =>
+                {
+                    log::debug!(
+                        target: session_log::TARGET,
+                        "Action queue compressed: {} actions as {} patterns at {} times",
+                        actions.len(),
+                        patterned.patterns.len(),
+                        patterned.instances.len()
+                    );
+                    return ActionQueue::Patterned(patterned);
+                }
+                _ => {}
+            }
+        }
+        if !schedule_spill::should_spill(actions.len()) {
+            return ActionQueue::Plain(actions);
+        }
+        match SpilledActions::write(&actions) {
+            Ok(spilled) => {
+                log::debug!(
+                    target: session_log::TARGET,
+                    "Action queue spilled to disk: {} actions",
+                    spilled.file.count()
+                );
+                ActionQueue::Spilled(Box::new(spilled))
+            }
+            Err(e) => {
+                log::warn!(
+                    target: session_log::TARGET,
+                    "Failed to spill {} actions to disk, keeping them in memory: {}",
+                    actions.len(),
+                    e
+                );
+                ActionQueue::Plain(actions)
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ActionQueue::Plain(actions) => actions.len(),
+            ActionQueue::Patterned(patterned) => patterned.len,
+            ActionQueue::Spilled(spilled) => spilled.file.count(),
+        }
+    }
+
+    fn get(&self, index: usize) -> Option<Cow<'_, Action>> {
+        match self {
+            ActionQueue::Plain(actions) => actions.get(index).map(Cow::Borrowed),
+            ActionQueue::Patterned(patterned) => patterned.get(index).map(Cow::Owned),
+            ActionQueue::Spilled(spilled) => spilled.get(index).map(Cow::Owned),
+        }
+    }
+
+    // 第 index 个动作的时间，不展开按键
+    fn time(&self, index: usize) -> Option<f64> {
+        match self {
+            ActionQueue::Plain(actions) => actions.get(index).map(|a| a.time),
+            ActionQueue::Patterned(patterned) => patterned
+                .locate(index)
+                .map(|(instance, _)| patterned.instances[instance].time),
+            ActionQueue::Spilled(spilled) => spilled.record(index).map(|r| r.time),
+        }
+    }
+
+    fn last_time(&self) -> Option<f64> {
+        match self {
+            ActionQueue::Spilled(spilled) => spilled.last_time,
+            _ => self.len().checked_sub(1).and_then(|index| self.time(index)),
+        }
+    }
+
+    /// 第一个时间不早于 position 的动作的序号
+    fn partition_point(&self, position: f64) -> usize {
+        match self {
+            ActionQueue::Plain(actions) => actions.partition_point(|a| a.time < position),
+            ActionQueue::Patterned(patterned) => {
+                patterned.start_of(patterned.instances.partition_point(|i| i.time < position))
+            }
+            ActionQueue::Spilled(spilled) => {
+                spilled.file.partition_point(position).unwrap_or_else(|e| {
+                    log::error!(target: session_log::TARGET, "Failed to read spilled schedule: {}", e);
+                    spilled.file.count()
+                })
+            }
+        }
+    }
+
+    fn presses(&self) -> usize {
+        match self {
+            ActionQueue::Plain(actions) => actions
+                .iter()
+                .filter(|a| a.kind == ActionKind::Press)
+                .count(),
+            ActionQueue::Patterned(patterned) => patterned
+                .instances
+                .iter()
+                .map(|instance| {
+                    patterned.patterns[instance.pattern as usize]
+                        .iter()
+                        .filter(|step| step.kind == ActionKind::Press)
+                        .count()
+                })
+                .sum(),
+            ActionQueue::Spilled(spilled) => spilled.presses,
+        }
+    }
+
+    fn last_press_time(&self) -> Option<f64> {
+        match self {
+            ActionQueue::Plain(actions) => actions
+                .iter()
+                .rev()
+                .find(|a| a.kind == ActionKind::Press)
+                .map(|a| a.time),
+            ActionQueue::Patterned(patterned) => patterned
+                .instances
+                .iter()
+                .rev()
+                .find(|instance| {
+                    patterned.patterns[instance.pattern as usize]
+                        .iter()
+                        .any(|step| step.kind == ActionKind::Press)
+                })
+                .map(|instance| instance.time),
+            ActionQueue::Spilled(spilled) => spilled.last_press_time,
+        }
+    }
+}
+
+/// 按键需要的修饰键（不分左右）
+pub fn modifier_mask(key: &str) -> u8 {
+    parse_key_string(key).map_or(0, |parsed| parsed_modifier_mask(&parsed))
+}
+
+fn parsed_modifier_mask(parsed: &ParsedKey) -> u8 {
+    parsed.modifiers.iter().fold(0, |mask, modifier| {
+        mask | match modifier {
+            Modifier::Shift(_) => 1,
+            Modifier::Control(_) => 2,
+            Modifier::Alt(_) => 4,
+            Modifier::Meta(_) => 8,
+        }
+    })
+}
+
+/// 游戏里另有用途、播放时绝不能按下的键（如移动用的 w/a/s/d）
+/// 按下的键主键相同、且带有保留键的全部修饰键（不分左右）时视为保留键：保留 "w" 也会挡住 "shift+w"
+/// 只有修饰键的保留键（如 "alt"）挡住所有带这些修饰键的按键
+#[derive(Debug, Clone, Default)]
+pub struct ReservedKeys(Vec<(Option<MainKey>, u8)>);
+
+impl ReservedKeys {
+    /// 逐个检查按键字符串，错误信息指出是第几个
+    pub fn parse(keys: &[String]) -> Result<Self, String> {
+        keys.iter()
+            .enumerate()
+            .map(|(i, key)| {
+                normalize_key_string(key)
+                    .and_then(|key| parse_key_string(&key))
+                    .map(|parsed| {
+                        let parsed = normalize_key(parsed);
+                        (parsed.main, parsed_modifier_mask(&parsed))
+                    })
+                    .map_err(|e| format!("reserved_keys[{}]: invalid key \"{}\": {}", i, key, e))
+            })
+            .collect::<Result<_, _>>()
+            .map(ReservedKeys)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 无法解析的按键不视为保留键，发送时另行报错
+    pub fn blocks(&self, key: &str) -> bool {
+        if self.0.is_empty() {
+            return false;
+        }
+        let Ok(parsed) = parse_key_string(key).map(normalize_key) else {
+            return false;
+        };
+        let mask = parsed_modifier_mask(&parsed);
+        self.0.iter().any(|&(main, reserved_mask)| {
+            (main.is_none() || main == parsed.main) && mask & reserved_mask == reserved_mask
+        })
+    }
+}
+
+/// 处理修饰键冲突：按下某个键时，仍按住的键带着它不需要的修饰键
+/// （同一和弦里的，或之前起音、修饰键还按着的）
+/// 冲突的键在这次按下前松开；同一和弦内的按策略错开或丢弃，保留排在前面的高音
+/// actions 需已排序，处理后仍保持有序
+fn resolve_modifier_conflicts(
+    actions: &mut Vec<Action>,
+    events: &[KeyEvent],
+    settings: &PlaybackSettings,
+) {
+    let policy = settings.modifier_conflicts;
+    if policy == ModifierConflictPolicy::Ignore {
+        return;
+    }
+    let stagger = settings.conflict_stagger_ms / 1000.0;
+    let min_hold = settings.min_hold_ms / 1000.0;
+    let chords = chord_ids(events);
+    // 按住中的键：(事件序号, 修饰键, 按下时刻)
+    let mut held: Vec<(usize, u8, f64)> = Vec::new();
+    let mut i = 0;
+    while i < actions.len() {
+        let action = &actions[i];
+        let index = action.event_index;
+        if action.kind == ActionKind::Release {
+            held.retain(|&(other, _, _)| other != index);
+            i += 1;
+            continue;
+        }
+        if action.conflict == Some(ModifierConflict::Dropped) {
+            i += 1;
+            continue;
+        }
+        let time = action.time;
+        let mask = modifier_mask(&action.key);
+        let conflicting: Vec<(usize, f64)> = held
+            .iter()
+            .filter(|&&(_, other_mask, _)| other_mask & !mask != 0)
+            .map(|&(other, _, pressed)| (other, pressed))
+            .collect();
+        if conflicting.is_empty() {
+            held.push((index, mask, time));
+            i += 1;
+            continue;
+        }
+
+        let simultaneous = conflicting
+            .iter()
+            .any(|&(other, _)| chords[other] == chords[index]);
+        if policy == ModifierConflictPolicy::Drop && simultaneous {
+            // 没有按下，对应的释放动作什么都不会做
+            actions[i].conflict = Some(ModifierConflict::Dropped);
+            i += 1;
+            continue;
+        }
+
+        // 之前的键至少按住 stagger，然后在这次按下之前松开
+        let press_at = conflicting
+            .iter()
+            .map(|&(_, pressed)| pressed + stagger)
+            .fold(time, f64::max);
+        for &(other, _) in &conflicting {
+            if let Some(j) = find_release(actions, i + 1, other) {
+                if actions[j].time > press_at {
+                    move_action(actions, events, i + 1, j, press_at);
+                }
+            }
+        }
+        if actions[i].conflict.is_none() {
+            actions[i].conflict = Some(if press_at > time {
+                ModifierConflict::Staggered(press_at - time)
+            } else {
+                ModifierConflict::CutShort
+            });
+        }
+        if let Some(j) = find_release(actions, i + 1, index) {
+            if actions[j].time < press_at + min_hold {
+                move_action(actions, events, i + 1, j, press_at + min_hold);
+            }
+        }
+        // 移到提前的释放之后，再处理一次
+        move_action(actions, events, i, i, press_at);
+    }
+}
+
+fn find_release(actions: &[Action], from: usize, event_index: usize) -> Option<usize> {
+    actions[from..]
+        .iter()
+        .position(|a| a.kind == ActionKind::Release && a.event_index == event_index)
+        .map(|offset| from + offset)
+}
+
+// 修改 actions[index] 的时间，并在 actions[from..] 中重新放到有序的位置
+fn move_action(
+    actions: &mut Vec<Action>,
+    events: &[KeyEvent],
+    from: usize,
+    index: usize,
+    time: f64,
+) {
+    let mut action = actions.remove(index);
+    action.time = time;
+    let position = from
+        + actions[from..].partition_point(|other| {
+            action_order(events, other, &action) != std::cmp::Ordering::Greater
+        });
+    actions.insert(position, action);
+}
+
+enum Flow {
+    Continue,
+    Seek(f64),
+    Replace(Vec<KeyEvent>),
+    Skip,
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SeekRequest {
+    Absolute(f64),
+    Relative(f64),
+}
+
+struct PreviewRequest {
+    position: f64,
+    window: f64,
+    tap: bool,
+    reply: mpsc::Sender<Vec<KeyEvent>>,
+}
+
+// 松开全部按键的请求，keys 为调用方额外给出的按键
+struct ReleaseRequest {
+    keys: Vec<String>,
+    reply: mpsc::Sender<Result<usize, CommandError>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SongOutcome {
+    Completed,
+    Skipped,
+    Stopped,
+}
+
+enum SessionSource {
+    Single { events: Vec<KeyEvent>, start: f64 },
+    Queue(QueueOptions),
+}
+
+// 常驻播放线程主体，依次执行收到的任务，通道关闭后退出
+fn run_worker(
+    shared: Arc<Shared>,
+    sender_factory: SenderFactory,
+    event_sink: Option<EventSink>,
+    commands: mpsc::Receiver<WorkerCommand>,
+    generation: u64,
+) {
+    // 复用的发送后端及创建它时的配置
+    let mut input: Option<(SenderConfig, Box<dyn KeySender>)> = None;
+
+    for command in commands {
+        let _job = JobGuard(&shared, generation);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_command(
+                &shared,
+                &sender_factory,
+                &event_sink,
+                &mut input,
+                command,
+                generation,
+            )
+        }));
+        if let Err(payload) = result {
+            recover_from_panic(&shared, &event_sink, &mut input, payload.as_ref());
+        }
+    }
+}
+
+// 执行一个任务
+fn run_command(
+    shared: &Arc<Shared>,
+    sender_factory: &SenderFactory,
+    event_sink: &Option<EventSink>,
+    input: &mut Option<(SenderConfig, Box<dyn KeySender>)>,
+    command: WorkerCommand,
+    generation: u64,
+) {
+    match command {
+        WorkerCommand::Play {
+            source,
+            options,
+            sender_config,
+            target,
+            scheduled_at,
+        } => {
+            let session = Session {
+                source,
+                options,
+                scheduled_at,
+            };
+            match target {
+                Some(build) => match build() {
+                    Ok(mut sender) => {
+                        run_session(shared, Ok(sender.as_mut()), event_sink, session, generation)
+                    }
+                    Err(e) => run_session(shared, Err(e), event_sink, session, generation),
+                },
+                None => {
+                    let sender = acquire_sender(input, sender_factory, sender_config);
+                    run_session(shared, sender, event_sink, session, generation);
+                }
+            }
+        }
+        WorkerCommand::TestKey {
+            key,
+            hold,
+            delay,
+            sender_config,
+            reply,
+        } => {
+            shared.heartbeat(Some(Duration::from_secs_f64(delay)));
+            thread::sleep(Duration::from_secs_f64(delay));
+            shared.heartbeat(Some(Duration::from_secs_f64(hold)));
+            shared.pressed_keys.lock().insert(key.clone());
+            let result = acquire_sender(input, sender_factory, sender_config).and_then(|sender| {
+                sender.press(&key).map_err(CommandError::Other)?;
+                thread::sleep(Duration::from_secs_f64(hold));
+                sender.release(&key).map_err(CommandError::Other)
+            });
+            let _ = reply.send(result);
+        }
+        WorkerCommand::ResetBackend {
+            sender_config,
+            reply,
+        } => {
+            *input = None;
+            let result = acquire_sender(input, sender_factory, sender_config).map(|_| ());
+            let _ = reply.send(result);
+        }
+        WorkerCommand::ReleaseKeys {
+            keys,
+            sender_config,
+            reply,
+        } => {
+            let result = acquire_sender(input, sender_factory, sender_config)
+                .map(|sender| release_everything(shared, sender, keys));
+            let _ = reply.send(result);
+        }
+    }
+}
+
+/// 松开 keys、按住中和按下过的所有键以及所有修饰键，返回成功发送的释放数
+/// 按住的键从 held 中清除，之后到期的释放动作不会重复发送
+fn release_everything(shared: &Shared, sender: &mut dyn KeySender, keys: Vec<String>) -> usize {
+    let held: Vec<String> = shared.held.lock().drain().map(|(key, _)| key).collect();
+    let pressed: Vec<String> = shared.pressed_keys.lock().iter().cloned().collect();
+    let targets = release_targets(keys.iter().chain(&held).chain(&pressed));
+    let sent = targets
+        .iter()
+        .filter(|key| match send_with_retry(1, || sender.release(key)) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Failed to release key: {}", e);
+                false
+            }
+        })
+        .count();
+    log::debug!(
+        target: session_log::TARGET,
+        "Released all keys: {} of {} releases sent",
+        sent,
+        targets.len()
+    );
+    sent
+}
+
+// 需要发送释放的键：各按键的主键去重后加上所有修饰键，无法解析的按键跳过
+fn release_targets<'k>(keys: impl Iterator<Item = &'k String>) -> Vec<String> {
+    let mut mains = BTreeSet::new();
+    for key in keys {
+        match parse_key_string(key) {
+            Ok(parsed) if parsed.main.is_some() => {
+                if let Some(main) = key.rsplit('+').next() {
+                    mains.insert(main.trim().to_string());
+                }
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Skipping key {:?} when releasing all keys: {}", key, e),
+        }
+    }
+    mains
+        .into_iter()
+        .chain(MODIFIER_KEYS.iter().map(|key| key.to_string()))
+        .collect()
+}
+
+// 补发松开的键：released 的主键和修饰键分别松开，跳过 held 中仍按着的主键和修饰键（不分左右）
+// 字符主键不分大小写，"A" 和 "a" 是同一个键，按小写松开
+fn keep_alive_targets<'k>(
+    held: impl Iterator<Item = &'k String>,
+    released: impl Iterator<Item = &'k String>,
+) -> BTreeSet<String> {
+    let mut mains_in_use = Vec::new();
+    let mut modifiers_in_use = 0;
+    for parsed in held.filter_map(|key| parse_key_string(key).ok()) {
+        modifiers_in_use |= parsed_modifier_mask(&parsed);
+        mains_in_use.extend(normalize_key(parsed).main);
+    }
+    let mut targets = BTreeSet::new();
+    for parsed in released.filter_map(|key| parse_key_string(key).ok()) {
+        for &modifier in &parsed.modifiers {
+            let alone = ParsedKey {
+                modifiers: vec![modifier],
+                main: None,
+            };
+            if parsed_modifier_mask(&alone) & modifiers_in_use == 0 {
+                targets.insert(alone.to_string());
+            }
+        }
+        let main_only = normalize_key(ParsedKey {
+            modifiers: Vec::new(),
+            main: parsed.main,
+        });
+        if main_only
+            .main
+            .is_some_and(|main| !mains_in_use.contains(&main))
+        {
+            targets.insert(main_only.to_string());
+        }
+    }
+    targets
+}
+
+// 每个任务结束时清除 busy，任务中途 panic 导致播放线程退出时也一样
+// 这样控制器不会一直认为播放仍在进行，下次提交任务时会重新启动播放线程
+// 记录线程的 generation，被看门狗放弃后不再改动共享状态
+struct JobGuard<'a>(&'a Shared, u64);
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.is_abandoned(self.1) {
+            return;
+        }
+        if thread::panicking() {
+            *self.0.state.lock() = SessionState::idle();
+            self.0.held.lock().clear();
+        }
+        self.0.finish_job();
+    }
+}
+
+/// 任务 panic 后恢复：尽量松开按住的键，丢弃可能已损坏的发送后端，回到空闲状态
+fn recover_from_panic(
+    shared: &Shared,
+    event_sink: &Option<EventSink>,
+    input: &mut Option<(SenderConfig, Box<dyn KeySender>)>,
+    payload: &(dyn Any + Send),
+) {
+    let reason = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown error".to_string());
+    let error = format!("Playback thread panicked: {}", reason);
+    eprintln!("{}", error);
+    log::error!(target: session_log::TARGET, "{}", error);
+
+    let keys: Vec<String> = shared.held.lock().drain().map(|(key, _)| key).collect();
+    if let Some((_, sender)) = input.as_mut() {
+        for key in &keys {
+            // 后端本身可能就是 panic 的来源，这里的 panic 不能再让线程退出
+            let released = panic::catch_unwind(AssertUnwindSafe(|| sender.release(key)));
+            if !matches!(released, Ok(Ok(()))) {
+                eprintln!("Failed to release key after panic: {}", key);
+            }
+        }
+    }
+    *input = None;
+
+    let (was_active, progress) = {
+        let mut state = shared.state.lock();
+        let was_active = state.status != PlaybackStatus::Idle;
+        *state = SessionState::idle();
+        (was_active, state.progress())
+    };
+    if !was_active {
+        return;
+    }
+    let report = StatsRecorder::new(0).finish(false);
+    shared.push_report(report.clone());
+    if let Some(sink) = event_sink {
+        sink(PlaybackEvent::Finished {
+            completed: false,
+            error: Some(error),
+            progress,
+            report,
+        });
+    }
+}
+
+/// position 之后第一个长于 STOP_GAP_SECONDS 的静音开始的时刻，没有时为最后一个音符结束的时刻
+fn next_gap(events: &[KeyEvent], position: f64, settings: &PlaybackSettings) -> f64 {
+    let mut notes: Vec<(f64, f64)> = events
+        .iter()
+        .map(|e| (e.time, e.time + settings.hold_secs(e.duration)))
+        .filter(|&(_, end)| end > position)
+        .collect();
+    notes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    // 到目前为止仍在发声的音符最晚结束的时刻
+    let mut sounding_until = position;
+    for (start, end) in notes {
+        if start >= position && start - sounding_until >= STOP_GAP_SECONDS {
+            break;
+        }
+        sounding_until = sounding_until.max(end);
+    }
+    sounding_until
+}
+
+/// 发送失败后最多重试 retries 次，每次重试前等待的时间递增
+fn send_with_retry(
+    retries: u32,
+    mut send: impl FnMut() -> Result<(), String>,
+) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        match send() {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= retries => return Err(e),
+            Err(_) => {
+                attempt += 1;
+                thread::sleep(SEND_RETRY_BACKOFF * attempt);
+            }
+        }
+    }
+}
+
+/// 取得复用的发送后端；尚未创建或配置已变化时重新创建
+fn acquire_sender<'a>(
+    input: &'a mut Option<(SenderConfig, Box<dyn KeySender>)>,
+    sender_factory: &SenderFactory,
+    config: SenderConfig,
+) -> Result<&'a mut dyn KeySender, CommandError> {
+    if input.as_ref().map(|(current, _)| *current) != Some(config) {
+        // 先释放旧实例，再创建新实例
+        *input = None;
+        *input = Some((config, sender_factory(config)?));
+    }
+    match input {
+        Some((_, sender)) => Ok(sender.as_mut()),
+        None => Err(backend_unavailable()),
+    }
+}
+
+fn backend_unavailable() -> CommandError {
+    CommandError::Other("Input backend is not available".to_string())
+}
+
+// 一次播放任务的内容
+struct Session {
+    source: SessionSource,
+    options: PlaybackOptions,
+    scheduled_at: Option<u64>,
+}
+
+/// 等到预约的开始时间，期间被停止时返回 false
+/// 按系统时间而不是一次长时间休眠计算，系统时间被调整后仍在正确的时刻开始
+/// 等待期间也处理松开全部按键的请求，没有可用的发送后端时回复错误
+fn wait_for_scheduled_start(
+    shared: &Shared,
+    mut sender: Option<&mut dyn KeySender>,
+    event_sink: &Option<EventSink>,
+    start_at_unix_ms: u64,
+) -> bool {
+    let mut announced = None;
+    loop {
+        let remaining_ms = start_at_unix_ms.saturating_sub(playback_stats::unix_ms());
+        let remaining_seconds = remaining_ms.div_ceil(1000);
+        if remaining_ms > 0 && announced != Some(remaining_seconds) {
+            announced = Some(remaining_seconds);
+            if let Some(sink) = event_sink {
+                sink(PlaybackEvent::Countdown {
+                    start_at_unix_ms,
+                    remaining_seconds,
+                });
+            }
+        }
+
+        let mut state = shared.state.lock();
+        if state.stop_requested {
+            return false;
+        }
+        if let Some(request) = state.release_request.take() {
+            drop(state);
+            let result = match sender.as_deref_mut() {
+                Some(sender) => Ok(release_everything(shared, sender, request.keys)),
+                None => Err(backend_unavailable()),
+            };
+            let _ = request.reply.send(result);
+            continue;
+        }
+        if remaining_ms == 0 {
+            return true;
+        }
+        let wait = Duration::from_millis(remaining_ms.min(SCHEDULE_POLL_MS));
+        shared.heartbeat(Some(wait));
+        shared.signal.wait_for(&mut state, wait);
+    }
 }
 
-/// 开始播放按键序列
-pub fn start_playback(events: Vec<KeyEvent>) -> Result<(), String> {
-    // 检查是否已有播放在进行
-    {
-        let handle = PLAYBACK_HANDLE.lock().unwrap();
-        if handle.is_some() {
-            return Err("Playback already in progress".to_string());
+// 执行一次播放任务
+fn run_session(
+    shared: &Arc<Shared>,
+    mut sender: Result<&mut dyn KeySender, CommandError>,
+    event_sink: &Option<EventSink>,
+    session: Session,
+    generation: u64,
+) {
+    let Session {
+        source,
+        options,
+        scheduled_at,
+    } = session;
+    if let Some(start_at) = scheduled_at {
+        let available: Option<&mut dyn KeySender> = match &mut sender {
+            Ok(sender) => Some(&mut **sender),
+            Err(_) => None,
+        };
+        if !wait_for_scheduled_start(shared, available, event_sink, start_at) {
+            if shared.is_abandoned(generation) {
+                return;
+            }
+            // 开始前被取消，不留下播放报告
+            let progress = {
+                let mut state = shared.state.lock();
+                *state = SessionState::idle();
+                state.progress()
+            };
+            if let Some(sink) = event_sink {
+                sink(PlaybackEvent::Finished {
+                    completed: false,
+                    error: None,
+                    progress,
+                    report: StatsRecorder::new(0).finish(false),
+                });
+            }
+            return;
+        }
+        {
+            let mut state = shared.state.lock();
+            state.status = PlaybackStatus::Playing;
+            state.scheduled_at = None;
+        }
+        if let Some(sink) = event_sink {
+            sink(PlaybackEvent::StatusChanged(shared.progress()));
+        }
+    }
+
+    if let Some(seconds) = options.stop_after {
+        // 定时播放从实际开始时计时
+        let deadline = shared.clock.now() + Duration::from_secs_f64(seconds);
+        shared.state.lock().stop_timer = Some(deadline);
+    }
+
+    let (completed, error, report) = match sender {
+        Ok(sender) => {
+            // 播放期间提高系统计时器精度，结束时恢复
+            let timer = TimerResolution::acquire(options.settings.timer_resolution_ms);
+            let mut scheduler =
+                Scheduler::new(Arc::clone(shared), sender, event_sink.clone(), options);
+            scheduler.timer_resolution = timer.period_ms();
+            scheduler.generation = generation;
+            match source {
+                SessionSource::Single { events, start } => {
+                    let (outcome, report) = scheduler.play(&events, start);
+                    (outcome == SongOutcome::Completed, scheduler.error, report)
+                }
+                SessionSource::Queue(queue) => {
+                    let (completed, report) = scheduler.play_queue(queue);
+                    (completed, scheduler.error, report)
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            log::error!(target: session_log::TARGET, "Playback could not start: {}", e);
+            let total = match &source {
+                SessionSource::Single { events, .. } => events.len(),
+                SessionSource::Queue(_) => 0,
+            };
+            let report = StatsRecorder::new(total).finish(false);
+            shared.push_report(report.clone());
+            (false, Some(e.to_string()), report)
+        }
+    };
+
+    // 被看门狗放弃的线程不再改动状态，Finished 已由看门狗推送
+    if shared.is_abandoned(generation) {
+        return;
+    }
+
+    // 播放完成，恢复空闲状态
+    let progress = {
+        let mut state = shared.state.lock();
+        state.status = PlaybackStatus::Idle;
+        state.queue_index = None;
+        state.progress()
+    };
+    if let Some(sink) = event_sink {
+        sink(PlaybackEvent::Finished {
+            completed,
+            error,
+            progress,
+            report,
+        });
+    }
+}
+
+// 播放线程内的调度器
+struct Scheduler<'a> {
+    shared: Arc<Shared>,
+    sender: &'a mut dyn KeySender,
+    clock: SongClock,
+    // set_playback_speed 设置的速度，之后的歌曲也不再使用 speed_ramp
+    manual_speed: Option<f64>,
+    event_sink: Option<EventSink>,
+    stats: StatsRecorder,
+    options: PlaybackOptions,
+    // 人性化随机器跨歌曲保留，重复播放时每一遍都重新随机
+    humanizer: Option<Humanizer>,
+    rate_limiter: RateLimiter,
+    // 下次定时推送按住按键的时刻
+    next_keys_tick: Instant,
+    // 需要推送节拍时的节拍网格
+    beats: Option<BeatGrid>,
+    // 按 abort 策略停止播放时的错误
+    error: Option<String>,
+    // 当前歌曲的事件（人性化之后），用于预览
+    events: Vec<KeyEvent>,
+    // 软停止的停止位置（歌曲时间）
+    stop_at: Option<f64>,
+    // 循环播放的区间（歌曲时间），到达终点时跳回起点
+    loop_region: Option<(f64, f64)>,
+    // 本次播放实际生效的计时器精度（毫秒）
+    timer_resolution: Option<u32>,
+    // 无缝衔接队列时提前结束的秒数：歌曲末尾只剩松开的部分最多缩短这么多
+    tail_trim: f64,
+    // 上一首自然播完时结束的歌曲时间，无缝衔接时据此安排下一首
+    completed_at: Option<f64>,
+    // 所在播放线程的 generation，被看门狗放弃后尽快停止且不再推送事件
+    generation: u64,
+    // 发送前的最后一道检查，事件里出现保留键时跳过
+    reserved_keys: ReservedKeys,
+    // 开启 keep_alive_seconds 时下次补发松开的时刻，以及上次补发以来松开过的键
+    next_keep_alive: Option<Instant>,
+    recent_releases: BTreeSet<String>,
+}
+
+/// 准备好、可以直接开始播放的一首歌
+#[derive(Debug)]
+struct PreparedSong {
+    events: Vec<KeyEvent>, // 人性化之后的事件
+    actions: ActionQueue,
+    start: f64,
+}
+
+impl<'a> Scheduler<'a> {
+    fn new(
+        shared: Arc<Shared>,
+        sender: &'a mut dyn KeySender,
+        event_sink: Option<EventSink>,
+        options: PlaybackOptions,
+    ) -> Self {
+        // 练习模式按用户的节奏推进，随机化没有意义
+        let humanizer = if options.practice_mode {
+            None
+        } else {
+            options.humanize.clone().map(Humanizer::new)
+        };
+        let rate_limiter = RateLimiter::new(options.settings.max_presses_per_second);
+        let clock = SongClock::new(Arc::clone(&shared.clock));
+        let next_keys_tick = shared.clock.now();
+        // 开始前已经检查过
+        let loop_region = options.loop_region().ok().flatten();
+        let reserved_keys =
+            ReservedKeys::parse(&options.settings.reserved_keys).unwrap_or_default();
+        let next_keep_alive = options
+            .settings
+            .keep_alive_seconds
+            .map(|seconds| next_keys_tick + Duration::from_secs_f64(seconds));
+        Self {
+            shared,
+            sender,
+            clock,
+            manual_speed: None,
+            event_sink,
+            stats: StatsRecorder::new(0),
+            options,
+            humanizer,
+            rate_limiter,
+            next_keys_tick,
+            beats: None,
+            error: None,
+            events: Vec::new(),
+            stop_at: None,
+            loop_region,
+            timer_resolution: None,
+            tail_trim: 0.0,
+            completed_at: None,
+            generation: 0,
+            reserved_keys,
+            next_keep_alive,
+            recent_releases: BTreeSet::new(),
+        }
+    }
+
+    /// 播放一首歌，返回结束方式和统计报告
+    /// 从歌曲时间 start 开始，起点立即播放
+    fn play(&mut self, events: &[KeyEvent], start: f64) -> (SongOutcome, PlaybackReport) {
+        let song = self.prepare(events, start);
+        self.play_prepared(song, None)
+    }
+
+    /// 人性化并生成动作列表，队列播放时可以提前为下一首准备
+    fn prepare(&mut self, events: &[KeyEvent], start: f64) -> PreparedSong {
+        let events = match &mut self.humanizer {
+            Some(humanizer) => humanizer.apply(events),
+            None => events.to_vec(),
+        };
+        let mut actions = build_actions(
+            &events,
+            start,
+            self.options.press_sounding,
+            &self.options.settings,
+        );
+        self.truncate_at_loop_end(&mut actions, &events);
+        let actions = ActionQueue::new(actions);
+        PreparedSong {
+            events,
+            actions,
+            start,
+        }
+    }
+
+    /// 设置了循环区间时缩短跨过循环终点的音，见 truncate_at_loop_end
+    fn truncate_at_loop_end(&self, actions: &mut [Action], events: &[KeyEvent]) {
+        let Some(region) = self.loop_region else {
+            return;
+        };
+        let truncations = truncate_at_loop_end(actions, events, region);
+        if !truncations.first_pass.is_empty() {
+            log::info!(
+                target: session_log::TARGET,
+                "Loop {:.3}s-{:.3}s: {} notes shortened on the first pass, {} on repeats",
+                region.0,
+                region.1,
+                truncations.first_pass.len(),
+                truncations.repeats.len()
+            );
+        }
+    }
+
+    /// 播放准备好的歌曲
+    /// anchor 为歌曲时间 start 对应的时刻（无缝衔接队列时使用，不打预备拍），为 None 时从现在开始
+    fn play_prepared(
+        &mut self,
+        song: PreparedSong,
+        anchor: Option<Instant>,
+    ) -> (SongOutcome, PlaybackReport) {
+        let PreparedSong {
+            events,
+            actions,
+            start,
+        } = song;
+        self.events = events.clone();
+        self.completed_at = None;
+
+        let total = actions.presses();
+        {
+            let mut state = self.shared.state.lock();
+            state.position = start;
+            state.duration = actions.last_time().unwrap_or(start);
+            state.sent = 0;
+            state.total = total;
+        }
+        self.update_speed_curve();
+
+        log::debug!(
+            target: session_log::TARGET,
+            "Session start: {} events, {} presses from {:.3}s, press_sounding={}, settings={:?}, humanize={:?}",
+            events.len(),
+            total,
+            start,
+            self.options.press_sounding,
+            self.options.settings,
+            self.options.humanize
+        );
+
+        self.stats = StatsRecorder::new(total);
+        self.stats.set_timer_resolution(self.timer_resolution);
+        match anchor {
+            Some(anchor) => self.clock.reset_at(start, anchor),
+            None => self.reset_clock(start),
+        }
+        self.emit_status();
+
+        let outcome = if anchor.is_none() && !self.count_in(start) {
+            SongOutcome::Stopped
+        } else if self.options.practice_mode {
+            self.practice(&events, start)
+        } else {
+            self.run(actions)
+        };
+
+        let stats = std::mem::replace(&mut self.stats, StatsRecorder::new(0));
+        let report = stats.finish(outcome == SongOutcome::Completed);
+        log::debug!(
+            target: session_log::TARGET,
+            "Session end ({:?}): {}",
+            outcome,
+            serde_json::to_string(&report).unwrap_or_default()
+        );
+        self.shared.push_report(report.clone());
+        (outcome, report)
+    }
+
+    /// 练习模式：不发送任何按键，按起音分组，每组通过 KeysActive 提示要按的键
+    /// 用户在 PRACTICE_CHORD_WINDOW 内按齐一组的键后进入下一组，按错的键计入 mistakes
+    fn practice(&mut self, events: &[KeyEvent], start: f64) -> SongOutcome {
+        let mut groups = onset_groups(events, start);
+        self.shared.state.lock().practice_input = Some(VecDeque::new());
+        let mut outcome = SongOutcome::Completed;
+        let mut index = 0;
+
+        while index < groups.len() {
+            let time = groups[index].time;
+            self.shared.state.lock().position = time;
+            self.emit(PlaybackEvent::KeysActive {
+                keys: groups[index].keys.clone(),
+            });
+            self.emit_status();
+
+            match self.wait_for_keys(&groups[index]) {
+                Flow::Continue => {
+                    let notes = groups[index].notes;
+                    self.stats.record_played(notes);
+                    self.shared.state.lock().sent += notes;
+                    index += 1;
+                }
+                Flow::Seek(position) => {
+                    log::debug!(target: session_log::TARGET, "Seek to {:.3}s", position);
+                    index = groups.partition_point(|g| g.time < position);
+                }
+                Flow::Replace(events) => {
+                    groups = onset_groups(&events, time);
+                    index = 0;
+                    let remaining: usize = groups.iter().map(|g| g.notes).sum();
+                    let mut state = self.shared.state.lock();
+                    state.total = state.sent + remaining;
+                    state.duration = groups.last().map_or(time, |g| g.time);
+                }
+                Flow::Skip => {
+                    outcome = SongOutcome::Skipped;
+                    break;
+                }
+                Flow::Stop => {
+                    outcome = SongOutcome::Stopped;
+                    break;
+                }
+            }
+        }
+
+        self.shared.state.lock().practice_input = None;
+        self.emit(PlaybackEvent::KeysActive { keys: Vec::new() });
+        outcome
+    }
+
+    // 等待用户按齐一组的键，期间响应停止、暂停等请求
+    fn wait_for_keys(&mut self, group: &OnsetGroup) -> Flow {
+        let shared = Arc::clone(&self.shared);
+        let mut state = shared.state.lock();
+        // 已按下的组内按键 -> 按下时刻
+        let mut matched: HashMap<usize, Instant> = HashMap::new();
+        if group.parsed.is_empty() {
+            return Flow::Continue;
+        }
+
+        loop {
+            // 相对跳转以这组起音为准，而不是等待了多久
+            self.reset_clock(group.time);
+            state = match self.handle_requests(&shared, state) {
+                Ok(state) => state,
+                Err(flow) => return flow,
+            };
+            while let Some((at, key)) = state.practice_input.as_mut().and_then(|q| q.pop_front()) {
+                let pressed = parse_key_string(&key).ok().map(normalize_key);
+                match group
+                    .parsed
+                    .iter()
+                    .position(|k| Some(k) == pressed.as_ref())
+                {
+                    Some(i) => {
+                        matched.insert(i, at);
+                    }
+                    None => {
+                        self.stats.record_mistake();
+                        log::debug!(
+                            target: session_log::TARGET,
+                            "Practice mistake at {:.3}s: pressed {}, expected {:?}",
+                            group.time,
+                            key,
+                            group.keys
+                        );
+                    }
+                }
+                matched.retain(|_, pressed_at| {
+                    at.duration_since(*pressed_at) <= PRACTICE_CHORD_WINDOW
+                });
+                if matched.len() == group.parsed.len() {
+                    return Flow::Continue;
+                }
+            }
+            match state.stop_timer {
+                Some(deadline) => {
+                    shared.wait_until(&mut state, deadline);
+                }
+                None => {
+                    shared.wait(&mut state);
+                }
+            }
+        }
+    }
+
+    /// 在 start 之前打预备拍，返回 false 表示期间被停止
+    /// 跳过时立即从 start 开始；预备拍期间的跳转和替换事件留给正式播放处理
+    fn count_in(&mut self, start: f64) -> bool {
+        let Some(config) = self.options.count_in.clone() else {
+            return true;
+        };
+        // 有弱起时网格对齐第一个完整小节，弱起的几拍由歌曲本身填上
+        let grid = BeatGrid::new(&config, config.grid_origin(start));
+        let beats = config.count_in_beats() as i64;
+        let first = grid.time_of(-beats);
+        let clicks: Vec<f64> = (-beats..0)
+            .map(|index| grid.time_of(index))
+            .take_while(|&time| time < start - 1e-9)
+            .collect();
+        self.beats = Some(grid);
+        self.reset_clock(first);
+        self.shared.state.lock().counting_in = true;
+        log::debug!(
+            target: session_log::TARGET,
+            "Count-in: {} of {} beats of {:.3}s",
+            clicks.len(),
+            beats,
+            config.beat_seconds()
+        );
+
+        let click = if config.silent {
+            None
+        } else {
+            config.click_key.clone()
+        };
+        let hold = (config.beat_seconds() / 2.0).min(CLICK_HOLD_SECONDS);
+        let mut finished = true;
+        let mut interrupted = false;
+        'beats: for &time in &clicks {
+            for (at, press) in [(time, true), (time + hold, false)] {
+                match self.wait_until(at) {
+                    Flow::Continue => {}
+                    Flow::Stop => {
+                        finished = false;
+                        break 'beats;
+                    }
+                    Flow::Skip => {
+                        log::debug!(target: session_log::TARGET, "Count-in skipped");
+                        interrupted = true;
+                        break 'beats;
+                    }
+                    Flow::Seek(position) => {
+                        self.shared.state.lock().seek_request =
+                            Some(SeekRequest::Absolute(position));
+                        interrupted = true;
+                        break 'beats;
+                    }
+                    Flow::Replace(events) => {
+                        self.shared.state.lock().replace_request = Some(events);
+                        interrupted = true;
+                        break 'beats;
+                    }
+                }
+                if let Some(key) = &click {
+                    self.click(key, press);
+                }
+            }
+        }
+
+        // 暂停时 release_all 已经松开，这里只处理仍按住的情况
+        if let Some(key) = &click {
+            self.click(key, false);
+        }
+        self.shared.state.lock().counting_in = false;
+        if !config.continue_beats {
+            self.beats = None;
+        }
+        if interrupted {
+            self.reset_clock(start);
+        }
+        finished
+    }
+
+    // 预备拍的按键记在 held 里，暂停和停止时会一并松开
+    fn click(&mut self, key: &str, press: bool) {
+        if press {
+            self.shared
+                .held
+                .lock()
+                .insert(key.to_string(), CLICK_EVENT_INDEX);
+            if let Err(e) = self.press_key(key) {
+                eprintln!("Failed to send count-in key: {}", e);
+            }
+        } else if self.shared.held.lock().remove(key).is_some() {
+            self.release_key(key);
+        }
+    }
+
+    /// 依次播放队列条目；队列在播放过程中可以被修改
+    /// 每首开始播放时在后台准备下一首（检查按键、生成动作列表），衔接处不必再做准备工作
+    fn play_queue(&mut self, queue: QueueOptions) -> (bool, PlaybackReport) {
+        let key = PrepareKey {
+            seamless: queue.seamless,
+            press_sounding: self.options.press_sounding,
+            settings: serde_json::to_string(&self.options.settings).unwrap_or_default(),
+        };
+        *self.shared.queue_preparation.lock() = self
+            .humanizer
+            .is_none()
+            .then(|| (key.clone(), self.options.clone()));
+        let result = self.play_queue_entries(queue, &key);
+        *self.shared.queue_preparation.lock() = None;
+        result
+    }
+
+    // 无缝衔接时按上一首结束的时刻排定下一首的时钟
+    fn play_queue_entries(
+        &mut self,
+        queue: QueueOptions,
+        key: &PrepareKey,
+    ) -> (bool, PlaybackReport) {
+        let mut index = 0;
+        let mut last_report: Option<PlaybackReport> = None;
+        // 已经累计的队列时间
+        let mut elapsed = 0.0;
+        // 无缝衔接时上一首结束的时刻
+        let mut boundary: Option<Instant> = None;
+
+        loop {
+            let (entry, has_next) = {
+                let entries = self.shared.queue.lock();
+                (entries.get(index).cloned(), entries.len() > index + 1)
+            };
+            let Some(entry) = entry else {
+                break;
+            };
+
+            // 第一首之前没有间隔
+            let mut anchor = None;
+            if let Some(report) = &last_report {
+                if queue.seamless {
+                    let gap = entry.gap_override.unwrap_or(0.0);
+                    let end = boundary.unwrap_or_else(|| self.shared.clock.now());
+                    anchor = Some(end + Duration::from_secs_f64(gap));
+                    elapsed += gap;
+                } else {
+                    let gap = entry.gap_override.unwrap_or(queue.gap_seconds);
+                    if gap > 0.0 {
+                        self.reset_clock(0.0);
+                        if let Flow::Stop = self.wait_until(gap) {
+                            return (false, report.clone());
+                        }
+                    }
+                    elapsed += gap;
+                }
+            }
+
+            // 人性化时不会提前准备，每首在这里重新随机
+            let prepared = if self.humanizer.is_none() {
+                // 等待后台准备完成期间看门狗不计时
+                self.shared.heartbeat(None);
+                let song = entry.take_prepared(key);
+                self.shared.heartbeat(Some(Duration::ZERO));
+                song
+            } else {
+                None
+            };
+            let song = match prepared {
+                Some(song) => song,
+                None => self.prepare(
+                    &entry.events,
+                    queue_entry_start(&entry.events, queue.seamless),
+                ),
+            };
+            // 只在有下一首时提前结束，最后一首完整播完
+            self.tail_trim = if queue.seamless && has_next {
+                queue.overlap_seconds
+            } else {
+                0.0
+            };
+
+            let start = song.start;
+            {
+                let mut state = self.shared.state.lock();
+                state.queue_index = Some(index);
+                state.queue_offset = elapsed - start;
+            }
+            self.shared.prepare_upcoming();
+            self.emit(PlaybackEvent::QueueEntryStarted {
+                index,
+                name: entry.name.clone(),
+            });
+
+            let (outcome, report) = self.play_prepared(song, anchor);
+            self.emit(PlaybackEvent::QueueEntryFinished {
+                index,
+                name: entry.name,
+                completed: outcome == SongOutcome::Completed,
+                report: report.clone(),
+            });
+            last_report = Some(report);
+
+            if outcome == SongOutcome::Stopped {
+                return (false, last_report.unwrap());
+            }
+
+            // 跳过时以跳过的位置为结束点，从现在开始衔接
+            let end = self
+                .completed_at
+                .unwrap_or_else(|| self.shared.state.lock().position);
+            elapsed += (end - start).max(0.0);
+            boundary = self.completed_at.map(|end| self.clock.instant_at(end));
+
+            // 播放期间可能删除了前面的条目或清空了队列，以最新位置为准
+            let Some(current) = self.shared.state.lock().queue_index else {
+                break;
+            };
+            index = current + 1;
+        }
+
+        let report = last_report.unwrap_or_else(|| StatsRecorder::new(0).finish(true));
+        (true, report)
+    }
+
+    /// 执行全部动作
+    fn run(&mut self, mut actions: ActionQueue) -> SongOutcome {
+        let mut outcome = SongOutcome::Completed;
+        let mut song_end = actions.last_time().unwrap_or(0.0);
+        let mut tail_cut = self.tail_cut(&actions, song_end);
+        let mut end = song_end;
+        let mut index = 0;
+
+        loop {
+            // 下一个动作在循环终点之后（或已经没有动作）时，等到终点就跳回起点
+            // 循环起点在歌曲结尾之后时不再循环
+            let next = actions.time(index).unwrap_or(f64::INFINITY);
+            let loop_back = self
+                .loop_region
+                .filter(|&(from, to)| next >= to && from < song_end);
+            // 剩下的只有松开时，到提前结束的位置就结束
+            let cut = tail_cut.filter(|&cut| loop_back.is_none() && next > cut);
+            if loop_back.is_none() && cut.is_none() && index >= actions.len() {
+                break;
+            }
+            let target = cut.unwrap_or(loop_back.map_or(next, |(_, to)| to));
+            match self.wait_until(target) {
+                Flow::Continue if self.stop_at.is_some_and(|stop_at| target >= stop_at) => {
+                    break;
+                }
+                Flow::Continue if cut.is_some() => {
+                    end = target;
+                    break;
+                }
+                Flow::Continue => {
+                    if let Some((from, to)) = loop_back {
+                        log::debug!(
+                            target: session_log::TARGET,
+                            "Loop from {:.3}s back to {:.3}s",
+                            to,
+                            from
+                        );
+                        index = self.seek(&actions, from);
+                        continue;
+                    }
+                }
+                Flow::Seek(position) => {
+                    log::debug!(target: session_log::TARGET, "Seek to {:.3}s", position);
+                    // 跳转到末尾等同于播放结束
+                    if position >= song_end {
+                        break;
+                    }
+                    index = self.seek(&actions, position);
+                    continue;
+                }
+                Flow::Replace(events) => {
+                    let position = self.song_time();
+                    actions = self.replace_events(&events, position);
+                    song_end = actions.last_time().unwrap_or(position);
+                    tail_cut = self.tail_cut(&actions, song_end);
+                    end = song_end;
+                    index = 0;
+                    continue;
+                }
+                Flow::Skip => {
+                    outcome = SongOutcome::Skipped;
+                    break;
+                }
+                Flow::Stop => {
+                    outcome = SongOutcome::Stopped;
+                    break;
+                }
+            }
+
+            // 压缩的队列在这里逐个展开
+            let Some(action) = actions.get(index) else {
+                break;
+            };
+            if let Err(e) = self.apply(&action) {
+                log::error!(target: session_log::TARGET, "Playback aborted: {}", e);
+                self.error = Some(e);
+                outcome = SongOutcome::Stopped;
+                break;
+            }
+            index += 1;
+
+            let mut state = self.shared.state.lock();
+            state.position = action.time;
+            state.speed = self.clock.speed_at(action.time);
+            if action.kind == ActionKind::Press {
+                state.sent += 1;
+            }
+        }
+
+        // 软停止：到达停止位置或歌曲在此之前结束，都按停止处理（队列也不再继续）
+        if let Some(stop_at) = self.stop_at.take() {
+            outcome = SongOutcome::Stopped;
+            let mut state = self.shared.state.lock();
+            state.position = state.position.max(stop_at.min(song_end));
+            state.stopping_at = None;
+        }
+        if outcome == SongOutcome::Completed {
+            self.completed_at = Some(end);
+        }
+        self.release_all();
+        outcome
+    }
+
+    /// 开启 tail_trim 时提前结束的位置：歌曲末尾往前 tail_trim 秒，但不早于最后一次按下
+    fn tail_cut(&self, actions: &ActionQueue, song_end: f64) -> Option<f64> {
+        if self.tail_trim <= 0.0 {
+            return None;
         }
+        let last_press = actions.last_press_time().unwrap_or(f64::NEG_INFINITY);
+        Some((song_end - self.tail_trim).max(last_press))
     }
 
-    // 重置停止标志
-    {
-        let mut should_stop = SHOULD_STOP.lock().unwrap();
-        *should_stop = false;
+    /// 跳转到 position，返回之后第一个待执行动作的序号
+    /// 跳转前释放所有按住的键，跳过的音符不会补按
+    fn seek(&mut self, actions: &ActionQueue, position: f64) -> usize {
+        self.release_all();
+        self.reset_clock(position);
+        {
+            let mut state = self.shared.state.lock();
+            state.position = position;
+        }
+        // 立即推送新位置，前端不必等下一次进度更新
+        self.emit_status();
+        actions.partition_point(position)
     }
 
-    // 在新线程中执行播放
-    let handle = thread::spawn(move || {
-        // 创建 Enigo 实例
-        let mut enigo = match Enigo::new(&Settings::default()) {
-            Ok(e) => e,
-            Err(e) => {
-                eprintln!("Failed to create Enigo instance: {:?}", e);
-                return;
+    /// 用新的事件列表替换剩余动作，返回从 position 开始的动作
+    fn replace_events(&mut self, events: &[KeyEvent], position: f64) -> ActionQueue {
+        let humanized;
+        let events = match &mut self.humanizer {
+            Some(humanizer) => {
+                humanized = humanizer.apply(events);
+                &humanized
+            }
+            None => events,
+        };
+        self.events = events.to_vec();
+        let mut actions = build_actions(events, position, true, &self.options.settings);
+        self.truncate_at_loop_end(&mut actions, events);
+
+        // 在 position 仍在发声的音符：对应的键已按住就接管它（不重新按下），否则丢弃
+        let (kept, stale) = {
+            let held = self.shared.held.lock();
+            let mut kept: HashMap<String, usize> = HashMap::new();
+            for action in &actions {
+                if action.kind == ActionKind::Press
+                    && events[action.event_index].time < position
+                    && held.contains_key(&action.key)
+                {
+                    kept.entry(action.key.clone()).or_insert(action.event_index);
+                }
             }
+            let stale: Vec<String> = held
+                .keys()
+                .filter(|key| !kept.contains_key(*key))
+                .cloned()
+                .collect();
+            (kept, stale)
+        };
+        actions.retain(|action| {
+            let sounding = events[action.event_index].time < position;
+            !sounding
+                || (action.kind == ActionKind::Release
+                    && kept.get(&action.key) == Some(&action.event_index))
+        });
+
+        for key in &stale {
+            self.shared.held.lock().remove(key);
+            self.release_key(key);
+        }
+        self.shared.held.lock().extend(kept);
+
+        let presses = actions
+            .iter()
+            .filter(|a| a.kind == ActionKind::Press)
+            .count();
+        {
+            let mut state = self.shared.state.lock();
+            state.total = state.sent + presses;
+            state.duration = actions.last().map_or(position, |a| a.time.max(position));
+        }
+        // 渐变跨越新的歌曲长度
+        self.update_speed_curve();
+        log::debug!(
+            target: session_log::TARGET,
+            "Events replaced at {:.3}s: {} presses remaining, {} keys released",
+            position,
+            presses,
+            stale.len()
+        );
+        self.emit_active_keys();
+        self.emit_status();
+        ActionQueue::new(actions)
+    }
+
+    fn song_time(&self) -> f64 {
+        self.clock.now()
+    }
+
+    /// 按手动速度或 speed_ramp 设置时钟的速度曲线，渐变的长度取当前歌曲的结束时间
+    fn update_speed_curve(&mut self) {
+        let mut state = self.shared.state.lock();
+        let curve = match (self.manual_speed, self.options.speed_ramp) {
+            (Some(speed), _) => SpeedCurve::constant(speed),
+            (None, Some((start, end))) => SpeedCurve::ramp(start, end, state.duration),
+            (None, None) => SpeedCurve::constant(1.0),
         };
+        self.clock.set_curve(curve);
+        state.speed = self.clock.speed_at(state.position);
+    }
 
-        let start_time = std::time::Instant::now();
+    /// 让歌曲时间从 position 开始计时
+    fn reset_clock(&mut self, position: f64) {
+        self.clock.reset(position);
+        if let Some(grid) = &mut self.beats {
+            grid.reset(position);
+        }
+    }
 
-        for event in events {
-            // 检查是否需要停止
+    /// 处理停止、跳过、跳转和替换请求，暂停时在这里等待恢复
+    /// 返回 Err 时调用方应立即返回该 Flow
+    fn handle_requests<'s>(
+        &mut self,
+        shared: &'s Shared,
+        mut state: MutexGuard<'s, SessionState>,
+    ) -> Result<MutexGuard<'s, SessionState>, Flow> {
+        loop {
+            if state.stop_requested || shared.is_abandoned(self.generation) {
+                return Err(Flow::Stop);
+            }
+            if state.skip_requested {
+                state.skip_requested = false;
+                return Err(Flow::Skip);
+            }
+            if let Some(request) = state.seek_request.take() {
+                let position = match request {
+                    SeekRequest::Absolute(position) => position,
+                    SeekRequest::Relative(delta) => self.song_time() + delta,
+                };
+                return Err(Flow::Seek(position.max(0.0)));
+            }
+            if let Some(events) = state.replace_request.take() {
+                return Err(Flow::Replace(events));
+            }
+            if state
+                .stop_timer
+                .is_some_and(|deadline| shared.clock.now() >= deadline)
             {
-                let should_stop = SHOULD_STOP.lock().unwrap();
-                if *should_stop {
-                    break;
+                state.stop_timer = None;
+                let position = self.song_time();
+                log::debug!(
+                    target: session_log::TARGET,
+                    "Stop timer expired at {:.3}s",
+                    position
+                );
+                drop(state);
+                self.emit(PlaybackEvent::TimerStopped { position });
+                state = shared.state.lock();
+                if state.pause_requested {
+                    return Err(Flow::Stop);
+                }
+                let boundary = match state.meter {
+                    Some(_) => StopBoundary::Measure,
+                    None => StopBoundary::NextGap,
+                };
+                // 用户已经要求的软停止优先
+                state
+                    .soft_stop_request
+                    .get_or_insert((boundary, DEFAULT_SOFT_STOP_WAIT));
+                continue;
+            }
+            if let Some((boundary, max_wait)) = state.soft_stop_request.take() {
+                let now = self.song_time();
+                let stop_at = match state.meter {
+                    _ if self.options.practice_mode || state.counting_in => None,
+                    Some(meter) if boundary == StopBoundary::Measure => {
+                        Some(meter.next_boundary(now, 1))
+                    }
+                    Some(meter) if boundary == StopBoundary::Phrase => {
+                        Some(meter.next_boundary(now, PHRASE_MEASURES))
+                    }
+                    _ => Some(next_gap(&self.events, now, &self.options.settings)),
+                };
+                match stop_at {
+                    Some(stop_at) if stop_at - now <= max_wait => {
+                        log::debug!(
+                            target: session_log::TARGET,
+                            "Stopping at {:?} boundary {:.3}s",
+                            boundary,
+                            stop_at
+                        );
+                        self.stop_at = Some(stop_at);
+                        state.stopping_at = Some(stop_at);
+                        drop(state);
+                        self.emit_status();
+                        state = shared.state.lock();
+                        continue;
+                    }
+                    // 等不到边界时退化为立即停止
+                    _ => return Err(Flow::Stop),
                 }
             }
+            if let Some(speed) = state.speed_request.take() {
+                log::debug!(
+                    target: session_log::TARGET,
+                    "Speed set to {} at {:.3}s",
+                    speed,
+                    self.song_time()
+                );
+                self.manual_speed = Some(speed);
+                self.clock.set_curve(SpeedCurve::constant(speed));
+                drop(state);
+                self.emit_status();
+                state = shared.state.lock();
+                continue;
+            }
+            if let Some(request) = state.preview_request.take() {
+                drop(state);
+                let position = self.preview(request);
+                state = shared.state.lock();
+                // 预览位置成为继续播放的位置，交给跳转处理
+                state.seek_request = Some(SeekRequest::Absolute(position));
+                continue;
+            }
+            if let Some(request) = state.release_request.take() {
+                drop(state);
+                let sent = release_everything(shared, self.sender, request.keys);
+                let _ = request.reply.send(Ok(sent));
+                self.emit_active_keys();
+                state = shared.state.lock();
+                continue;
+            }
+            if !state.pause_requested {
+                return Ok(state);
+            }
 
-            // 等待到事件时间
-            let target_time = Duration::from_secs_f64(event.time);
-            let elapsed = start_time.elapsed();
+            if state.status != PlaybackStatus::Paused {
+                state.status = PlaybackStatus::Paused;
+                drop(state);
 
-            if target_time > elapsed {
-                let wait_time = target_time - elapsed;
-                thread::sleep(wait_time);
+                self.release_all();
+                self.emit_status();
+                state = shared.state.lock();
             }
 
-            // 再次检查是否需要停止
+            let paused_at = shared.clock.now();
+            while state.pause_requested
+                && !state.stop_requested
+                && !state.skip_requested
+                && state.seek_request.is_none()
+                && state.replace_request.is_none()
+                && state.preview_request.is_none()
+                && state.release_request.is_none()
             {
-                let should_stop = SHOULD_STOP.lock().unwrap();
-                if *should_stop {
-                    break;
+                match state.stop_timer {
+                    Some(deadline) if self.options.timer_counts_pause => {
+                        if shared.clock.now() >= deadline {
+                            break;
+                        }
+                        shared.wait_until(&mut state, deadline);
+                    }
+                    _ => {
+                        shared.wait(&mut state);
+                    }
                 }
             }
 
-            // 模拟按键 (调用 uni-input 的 SmartKeyboard trait)
-            if let Err(e) = enigo.simulate_keypress_smart(&event.key) {
-                eprintln!("Failed to simulate keypress: {}", e);
+            // 暂停期间不计入歌曲时间
+            let paused_for = shared.clock.now().saturating_duration_since(paused_at);
+            self.clock.shift(paused_for);
+            if !self.options.timer_counts_pause {
+                if let Some(deadline) = &mut state.stop_timer {
+                    *deadline += paused_for;
+                }
             }
+            if state.pause_requested {
+                // 被停止/跳过/跳转唤醒，回到循环开头处理，保持暂停状态
+                continue;
+            }
+
+            state.status = PlaybackStatus::Playing;
+            drop(state);
+            self.emit_status();
+            state = shared.state.lock();
         }
+    }
 
-        // 播放完成，清理句柄
-        let mut handle = PLAYBACK_HANDLE.lock().unwrap();
-        *handle = None;
-    });
+    /// 等待到指定的歌曲时间，期间响应停止和暂停
+    fn wait_until(&mut self, target: f64) -> Flow {
+        let shared = Arc::clone(&self.shared);
+        let mut state = shared.state.lock();
+
+        loop {
+            state = match self.handle_requests(&shared, state) {
+                Ok(state) => state,
+                Err(flow) => return flow,
+            };
+            // 软停止时最多等到停止位置
+            let target = self.stop_at.map_or(target, |stop_at| target.min(stop_at));
+
+            let now = self.song_time();
+            if self
+                .beats
+                .as_ref()
+                .is_some_and(|grid| grid.next_time() <= now)
+            {
+                drop(state);
+                self.emit_due_beats(now);
+                state = shared.state.lock();
+                continue;
+            }
+            if now >= target {
+                return Flow::Continue;
+            }
 
-    // 保存线程句柄
-    {
-        let mut playback_handle = PLAYBACK_HANDLE.lock().unwrap();
-        *playback_handle = Some(handle);
+            // 等待期间按固定频率推送按住的键
+            let now_instant = shared.clock.now();
+            if now_instant >= self.next_keys_tick {
+                self.emit_active_keys();
+                self.next_keys_tick = now_instant + KEYS_ACTIVE_INTERVAL;
+            }
+            if self.next_keep_alive.is_some_and(|at| now_instant >= at) {
+                drop(state);
+                self.keep_alive();
+                state = shared.state.lock();
+                continue;
+            }
+            let until_tick = self.next_keys_tick.saturating_duration_since(now_instant);
+            let until_keep_alive = self.next_keep_alive.map_or(Duration::MAX, |at| {
+                at.saturating_duration_since(now_instant)
+            });
+            let next_beat = self
+                .beats
+                .as_ref()
+                .map_or(f64::INFINITY, BeatGrid::next_time);
+            let until_timer = state.stop_timer.map_or(Duration::MAX, |deadline| {
+                deadline.saturating_duration_since(now_instant)
+            });
+            // 歌曲时间按速度曲线换算为实际等待时间
+            let wait = self
+                .clock
+                .wall_until(target.min(next_beat))
+                .min(until_tick)
+                .min(until_keep_alive)
+                .min(until_timer);
+            shared.wait_for(&mut state, wait);
+        }
     }
 
-    Ok(())
+    /// 执行一个动作；返回 Err 表示按 abort 策略应停止播放
+    fn apply(&mut self, action: &Action) -> Result<(), String> {
+        match action.kind {
+            ActionKind::Press => {
+                if self.reserved_keys.blocks(&action.key) {
+                    self.stats.record_skip();
+                    log::warn!(
+                        target: session_log::TARGET,
+                        "Skipped key {} at {:.3}s: reserved",
+                        action.key,
+                        action.time
+                    );
+                    return Ok(());
+                }
+                if let Some(conflict) = action.conflict {
+                    self.stats.record_modifier_conflict();
+                    log::warn!(
+                        target: session_log::TARGET,
+                        "Modifier conflict for key {} at {:.3}s: {}",
+                        action.key,
+                        action.time,
+                        match conflict {
+                            ModifierConflict::Staggered(delay) =>
+                                format!("delayed by {:.1}ms", delay * 1000.0),
+                            ModifierConflict::CutShort => "released held keys early".to_string(),
+                            ModifierConflict::Dropped => "dropped".to_string(),
+                        }
+                    );
+                    if conflict == ModifierConflict::Dropped {
+                        self.stats.record_skip();
+                        return Ok(());
+                    }
+                }
+                if self.is_muted(action.event_index) {
+                    log::debug!(
+                        target: session_log::TARGET,
+                        "Skipped key {} at {:.3}s: group muted",
+                        action.key,
+                        action.time
+                    );
+                    return Ok(());
+                }
+                if let RateDecision::Dropped { burst_started } =
+                    self.rate_limiter.check(self.shared.clock.now())
+                {
+                    self.stats.record_rate_limited();
+                    log::debug!(
+                        target: session_log::TARGET,
+                        "Skipped key {} at {:.3}s: rate limit",
+                        action.key,
+                        action.time
+                    );
+                    if burst_started {
+                        eprintln!(
+                            "Key rate exceeded {}/s, dropping presses",
+                            self.options.settings.max_presses_per_second
+                        );
+                        self.emit(PlaybackEvent::RateLimited {
+                            position: action.time,
+                            max_presses_per_second: self.options.settings.max_presses_per_second,
+                        });
+                    }
+                    return Ok(());
+                }
+
+                // 同一个键仍被之前的音符按住，先释放再重新按下
+                let previous = self.shared.held.lock().remove(&action.key);
+                if previous.is_some() {
+                    self.release_key(&action.key);
+                }
+
+                let actual = self.song_time();
+                let late_ms = (actual - action.time) * 1000.0;
+                if late_ms > session_log::LATE_WARNING_MS {
+                    log::debug!(
+                        target: session_log::TARGET,
+                        "Late key {} at {:.3}s: {:.1}ms behind schedule",
+                        action.key,
+                        action.time,
+                        late_ms
+                    );
+                }
+                let send_started = self.shared.clock.now();
+                let result = self.press_key(&action.key);
+                let send_secs = self
+                    .shared
+                    .clock
+                    .now()
+                    .saturating_duration_since(send_started)
+                    .as_secs_f64();
+                self.stats.record_press(
+                    &action.key,
+                    action.time,
+                    actual,
+                    send_secs,
+                    result.is_ok(),
+                );
+                let tap = self
+                    .events
+                    .get(action.event_index)
+                    .is_some_and(|event| event.duration == 0.0);
+                if tap && result.is_ok() {
+                    self.stats.record_tap();
+                }
+
+                match result {
+                    Ok(()) => {
+                        self.shared
+                            .held
+                            .lock()
+                            .insert(action.key.clone(), action.event_index);
+                        self.emit_active_keys();
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to simulate keypress: {}", e);
+                        log::debug!(
+                            target: session_log::TARGET,
+                            "Failed key {} at {:.3}s: {}",
+                            action.key,
+                            action.time,
+                            e
+                        );
+                        if self.options.settings.send_failure_policy == SendFailurePolicy::Abort {
+                            return Err(format!("Failed to press key {}: {}", action.key, e));
+                        }
+                    }
+                }
+            }
+            ActionKind::Release => {
+                // 只释放由本事件按下的键，避免提前截断后续同键音符
+                let released = {
+                    let mut held = self.shared.held.lock();
+                    let owned = held.get(&action.key) == Some(&action.event_index);
+                    if owned {
+                        held.remove(&action.key);
+                    }
+                    owned
+                };
+                if released {
+                    self.release_key(&action.key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_muted(&self, event_index: usize) -> bool {
+        let Some(group) = self.events.get(event_index).and_then(|e| e.group.as_ref()) else {
+            return false;
+        };
+        self.shared.state.lock().muted_groups.contains(group)
+    }
+
+    fn release_all(&mut self) {
+        let keys: Vec<String> = self
+            .shared
+            .held
+            .lock()
+            .drain()
+            .map(|(key, _)| key)
+            .collect();
+        if keys.is_empty() {
+            return;
+        }
+        for key in keys {
+            self.release_key(&key);
+        }
+        self.emit_active_keys();
+    }
+
+    /// 返回 position 前后 window 内的事件，需要时轻点 position 处正在发声的键
+    fn preview(&mut self, request: PreviewRequest) -> f64 {
+        let PreviewRequest {
+            position,
+            window,
+            tap,
+            reply,
+        } = request;
+        let mut nearby: Vec<KeyEvent> = self
+            .events
+            .iter()
+            .filter(|e| (e.time - position).abs() <= window)
+            .cloned()
+            .collect();
+        nearby.sort_by(|a, b| {
+            a.time
+                .partial_cmp(&b.time)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        if tap {
+            let settings = &self.options.settings;
+            let mut keys: Vec<String> = Vec::new();
+            for event in &nearby {
+                let sounding = event.time <= position
+                    && position < event.time + settings.hold_secs(event.duration);
+                if sounding && !keys.contains(&event.key) {
+                    keys.push(event.key.clone());
+                }
+            }
+            keys.truncate(MAX_PREVIEW_TAPS);
+            for key in &keys {
+                if let Err(e) = self.press_key(key) {
+                    eprintln!("Failed to preview key: {}", e);
+                }
+            }
+            thread::sleep(PREVIEW_TAP);
+            for key in &keys {
+                self.release_key(key);
+            }
+        }
+
+        let _ = reply.send(nearby);
+        position
+    }
+
+    /// 按下一个键，按 send_failure_policy 重试
+    fn press_key(&mut self, key: &str) -> Result<(), String> {
+        let retries = self.options.settings.press_retries();
+        send_with_retry(retries, || self.sender.press(key))?;
+        self.shared.pressed_keys.lock().insert(key.to_string());
+        if let Some(hook) = &*self.shared.press_hook.lock() {
+            hook(key);
+        }
+        Ok(())
+    }
+
+    /// 释放一个键，失败时至少重试一次
+    fn release_key(&mut self, key: &str) {
+        let retries = self.options.settings.release_retries();
+        if let Err(e) = send_with_retry(retries, || self.sender.release(key)) {
+            eprintln!("Failed to release key: {}", e);
+            log::debug!(target: session_log::TARGET, "Failed to release key {}: {}", key, e);
+        }
+        if self.next_keep_alive.is_some() {
+            self.recent_releases.insert(key.to_string());
+        }
+    }
+
+    /// 把上次补发以来松开过、现在没有按住的键再松开一次，计入统计
+    /// 按主键和各修饰键分别松开；按住的键用到的主键或修饰键跳过，不会松开正在按住的键
+    fn keep_alive(&mut self) {
+        let Some(seconds) = self.options.settings.keep_alive_seconds else {
+            return;
+        };
+        self.next_keep_alive = Some(self.shared.clock.now() + Duration::from_secs_f64(seconds));
+        let released = std::mem::take(&mut self.recent_releases);
+        let targets = keep_alive_targets(self.shared.held.lock().keys(), released.iter());
+        if targets.is_empty() {
+            return;
+        }
+        let mut sent = 0;
+        for key in &targets {
+            match self.sender.release(key) {
+                Ok(()) => sent += 1,
+                Err(e) => log::debug!(
+                    target: session_log::TARGET,
+                    "Keep-alive release of {} failed: {}",
+                    key,
+                    e
+                ),
+            }
+        }
+        self.stats.record_keep_alive(sent);
+        log::debug!(
+            target: session_log::TARGET,
+            "Keep-alive: re-sent {} of {} releases",
+            sent,
+            targets.len()
+        );
+    }
+
+    fn emit(&self, event: PlaybackEvent) {
+        if self.shared.is_abandoned(self.generation) {
+            return;
+        }
+        if let Some(sink) = &self.event_sink {
+            sink(event);
+        }
+    }
+
+    fn emit_status(&self) {
+        self.emit(PlaybackEvent::StatusChanged(self.shared.progress()));
+    }
+
+    fn emit_due_beats(&mut self, now: f64) {
+        while let Some(grid) = &mut self.beats {
+            if grid.next_time() > now {
+                break;
+            }
+            let beat = grid.advance();
+            self.emit(PlaybackEvent::Beat(beat));
+        }
+    }
+
+    fn emit_active_keys(&self) {
+        self.emit(PlaybackEvent::KeysActive {
+            keys: self.shared.active_keys(),
+        });
+    }
 }
 
-/// 停止播放
-pub fn stop_playback() -> Result<(), String> {
-    // 设置停止标志
-    {
-        let mut should_stop = SHOULD_STOP.lock().unwrap();
-        *should_stop = true;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song_clock::VirtualClock;
+
+    fn event(time: f64, key: &str, duration: f64) -> KeyEvent {
+        KeyEvent {
+            time,
+            key: key.to_string(),
+            duration,
+            group: None,
+            note: None,
+            chord: None,
+        }
     }
 
-    // 等待线程结束（最多等待1秒）
-    let handle = {
-        let mut playback_handle = PLAYBACK_HANDLE.lock().unwrap();
-        playback_handle.take()
-    };
+    // 虚拟时钟上的控制器和记录按键的后端
+    fn controller() -> (PlaybackController, RecordingSender) {
+        let clock = Arc::new(VirtualClock::default());
+        let sender = RecordingSender::new(clock.clone());
+        let controller = PlaybackController::new(sender.factory()).with_clock(clock);
+        (controller, sender)
+    }
 
-    if let Some(handle) = handle {
-        let _ = handle.join();
+    // 按下 key 后让播放线程停在按下处，直到 open；用来在确定的位置发出控制命令
+    struct Gate {
+        reached: mpsc::Receiver<()>,
+        open: mpsc::Sender<()>,
     }
 
-    Ok(())
+    impl Gate {
+        fn wait(&self) {
+            self.reached
+                .recv_timeout(Duration::from_secs(5))
+                .expect("playback did not reach the gate");
+        }
+
+        fn open(&self) {
+            let _ = self.open.send(());
+        }
+    }
+
+    fn gated_controller(key: &'static str) -> (PlaybackController, RecordingSender, Gate) {
+        let (controller, sender) = controller();
+        let (reached_tx, reached) = mpsc::channel();
+        let (open, open_rx) = mpsc::channel();
+        let open_rx = Mutex::new(open_rx);
+        let controller = controller.with_press_hook(Arc::new(move |pressed: &str| {
+            if pressed == key {
+                let _ = reached_tx.send(());
+                let _ = open_rx.lock().recv();
+            }
+        }));
+        (controller, sender, Gate { reached, open })
+    }
+
+    // 按实际时间最多等 5 秒
+    fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn wait_idle(controller: &PlaybackController) {
+        wait_for("playback to finish", || !controller.is_active());
+    }
+
+    fn wait_status(controller: &PlaybackController, status: PlaybackStatus) {
+        wait_for("playback status", || controller.status().status == status);
+    }
+
+    fn stop_requested(controller: &PlaybackController) -> bool {
+        controller.shared.state.lock().stop_requested
+    }
+
+    #[test]
+    fn start_presses_and_releases_on_time() {
+        let (controller, sender) = controller();
+        let events = vec![
+            event(0.0, "a", 0.25),
+            event(0.5, "b", 0.5),
+            event(0.75, "c", 0.0), // 轻点，按 min_hold_ms（50ms）松开
+        ];
+        controller
+            .start(events, PlaybackOptions::default())
+            .unwrap();
+        wait_idle(&controller);
+        assert_eq!(
+            sender.lines(),
+            ["0.000 +a", "0.250 -a", "0.500 +b", "0.750 +c", "0.800 -c", "1.000 -b"]
+        );
+        let report = controller.last_report().unwrap();
+        assert_eq!(report.sent, 3);
+        assert_eq!(controller.status().status, PlaybackStatus::Idle);
+        assert!(controller.active_keys().is_empty());
+    }
+
+    #[test]
+    fn start_rejects_a_second_playback() {
+        let (controller, _sender, gate) = gated_controller("a");
+        controller
+            .start(vec![event(0.0, "a", 0.25)], PlaybackOptions::default())
+            .unwrap();
+        gate.wait();
+        assert!(controller
+            .start(vec![event(0.0, "b", 0.25)], PlaybackOptions::default())
+            .is_err());
+        gate.open();
+        wait_idle(&controller);
+    }
+
+    #[test]
+    fn stop_releases_held_keys() {
+        let (controller, sender, gate) = gated_controller("a");
+        let events = vec![event(0.0, "a", 1.0), event(2.0, "b", 0.25)];
+        controller
+            .start(events, PlaybackOptions::default())
+            .unwrap();
+        gate.wait();
+        thread::scope(|scope| {
+            let stopping = scope.spawn(|| controller.stop());
+            wait_for("stop request", || stop_requested(&controller));
+            gate.open();
+            stopping.join().unwrap().unwrap();
+        });
+        assert_eq!(sender.lines(), ["0.000 +a", "0.000 -a"]);
+        assert_eq!(controller.status().status, PlaybackStatus::Idle);
+        assert!(controller.active_keys().is_empty());
+    }
+
+    #[test]
+    fn pause_releases_keys_and_resume_continues() {
+        let (controller, sender, gate) = gated_controller("a");
+        let events = vec![event(0.0, "a", 1.0), event(2.0, "b", 0.25)];
+        controller
+            .start(events, PlaybackOptions::default())
+            .unwrap();
+        gate.wait();
+        controller.pause().unwrap();
+        gate.open();
+        wait_status(&controller, PlaybackStatus::Paused);
+        assert!(controller.active_keys().is_empty());
+        assert_eq!(sender.lines(), ["0.000 +a", "0.000 -a"]);
+
+        controller.resume().unwrap();
+        wait_idle(&controller);
+        assert_eq!(
+            sender.lines(),
+            ["0.000 +a", "0.000 -a", "2.000 +b", "2.250 -b"]
+        );
+    }
+
+    #[test]
+    fn seek_moves_the_position() {
+        let (controller, sender, gate) = gated_controller("a");
+        let events = vec![
+            event(0.0, "a", 0.25),
+            event(1.0, "b", 0.25),
+            event(3.0, "c", 0.25),
+        ];
+        controller
+            .start(events, PlaybackOptions::default())
+            .unwrap();
+        gate.wait();
+        controller.seek(2.5).unwrap();
+        gate.open();
+        wait_idle(&controller);
+        // 跳转时松开按住的键，b 被跳过，c 在跳转后 0.5 秒按下
+        assert_eq!(
+            sender.lines(),
+            ["0.000 +a", "0.000 -a", "0.500 +c", "0.750 -c"]
+        );
+    }
+
+    #[test]
+    fn update_events_is_checked_like_start() {
+        let (controller, sender, gate) = gated_controller("a");
+        let options = PlaybackOptions {
+            text_mode: true,
+            ..Default::default()
+        };
+        let events = vec![event(0.0, "a", 0.25), event(1.0, "b", 0.25)];
+        controller.start(events, options).unwrap();
+        gate.wait();
+        let error = controller
+            .update_events(vec![event(1.0, "ctrl+c", 0.25)])
+            .unwrap_err();
+        assert!(
+            matches!(&error, CommandError::InvalidArgument(message) if message.contains("text mode")),
+            "{}",
+            error
+        );
+        controller
+            .update_events(vec![event(1.0, "shift+c", 0.25)])
+            .unwrap();
+        gate.open();
+        wait_idle(&controller);
+        assert_eq!(
+            sender.lines(),
+            ["0.000 +a", "0.000 -a", "1.000 +shift+c", "1.250 -shift+c"]
+        );
+    }
+
+    // 开始和替换时都整理成规范写法，后端、按住保持和释放都只见到一种写法
+    // 替换后 ctrl+c 不再发声，立即松开
+    #[test]
+    fn start_and_update_normalize_keys() {
+        let (controller, sender, gate) = gated_controller("ctrl+c");
+        let events = vec![event(0.0, "CTRL-c", 0.25), event(1.0, "b", 0.25)];
+        controller
+            .start(events, PlaybackOptions::default())
+            .unwrap();
+        gate.wait();
+        controller
+            .update_events(vec![event(1.0, "cmd + Up", 0.25)])
+            .unwrap();
+        gate.open();
+        wait_idle(&controller);
+        assert_eq!(
+            sender.lines(),
+            [
+                "0.000 +ctrl+c",
+                "0.000 -ctrl+c",
+                "1.000 +meta+up",
+                "1.250 -meta+up"
+            ]
+        );
+    }
+
+    #[test]
+    fn update_events_in_practice_mode_needs_parsable_keys() {
+        let (controller, sender) = controller();
+        let options = PlaybackOptions {
+            practice_mode: true,
+            ..Default::default()
+        };
+        controller
+            .start(vec![event(0.0, "a", 0.25)], options)
+            .unwrap();
+        let error = controller
+            .update_events(vec![event(1.0, "nosuchkey", 0.25)])
+            .unwrap_err();
+        assert!(
+            matches!(&error, CommandError::InvalidArgument(message) if message.contains("Event 0: Invalid main key 'nosuchkey'")),
+            "{}",
+            error
+        );
+        controller.stop().unwrap();
+        assert!(sender.sent().is_empty());
+    }
+
+    // 按下 "z" 时 panic 的后端，其余按键照常记录
+    struct PanickingSender(RecordingSender);
+
+    impl KeySender for PanickingSender {
+        fn press(&mut self, key: &str) -> Result<(), String> {
+            if key == "z" {
+                panic!("sender exploded");
+            }
+            self.0.press(key)
+        }
+
+        fn release(&mut self, key: &str) -> Result<(), String> {
+            self.0.release(key)
+        }
+    }
+
+    #[test]
+    fn panicking_sender_leaves_the_controller_usable() {
+        let clock = Arc::new(VirtualClock::default());
+        let sender = RecordingSender::new(clock.clone());
+        let recording = sender.clone();
+        let factory: SenderFactory = Arc::new(move |_| {
+            Ok(Box::new(PanickingSender(recording.clone())) as Box<dyn KeySender>)
+        });
+        let controller = PlaybackController::new(factory).with_clock(clock);
+        let events = vec![event(0.0, "a", 1.0), event(0.5, "z", 0.25)];
+        controller
+            .start(events, PlaybackOptions::default())
+            .unwrap();
+        wait_idle(&controller);
+        // panic 时仍按住的 a 被松开
+        assert_eq!(sender.lines(), ["0.000 +a", "0.500 -a"]);
+        assert_eq!(controller.status().status, PlaybackStatus::Idle);
+        assert!(controller.active_keys().is_empty());
+        assert!(!controller.last_report().unwrap().completed);
+
+        // 之后还能正常开始下一次播放
+        controller
+            .start(vec![event(0.0, "b", 0.25)], PlaybackOptions::default())
+            .unwrap();
+        wait_idle(&controller);
+        assert_eq!(sender.sent().len(), 4);
+        assert_eq!(controller.last_report().unwrap().sent, 1);
+    }
+
+    // 记录每次创建后端时的配置，后端本身是 RecordingSender
+    fn config_recording_controller() -> (
+        PlaybackController,
+        RecordingSender,
+        Arc<Mutex<Vec<SenderConfig>>>,
+    ) {
+        let clock = Arc::new(VirtualClock::default());
+        let sender = RecordingSender::new(clock.clone());
+        let configs = Arc::new(Mutex::new(Vec::new()));
+        let (recording, created) = (sender.clone(), configs.clone());
+        let factory: SenderFactory = Arc::new(move |config| {
+            created.lock().push(config);
+            Ok(Box::new(recording.clone()) as Box<dyn KeySender>)
+        });
+        let controller = PlaybackController::new(factory).with_clock(clock);
+        (controller, sender, configs)
+    }
+
+    fn text_mode() -> PlaybackOptions {
+        PlaybackOptions {
+            text_mode: true,
+            ..Default::default()
+        }
+    }
+
+    // 文本模式创建文本后端，字符（包括 shift 加字母）原样交给它
+    #[test]
+    fn text_mode_sends_characters_through_a_text_sender() {
+        let (controller, sender, configs) = config_recording_controller();
+        let events = vec![event(0.0, "a", 0.25), event(0.5, "shift+b", 0.25)];
+        controller.start(events, text_mode()).unwrap();
+        wait_idle(&controller);
+        assert_eq!(
+            sender.lines(),
+            ["0.000 +a", "0.250 -a", "0.500 +shift+b", "0.750 -shift+b"]
+        );
+        let configs = configs.lock();
+        assert_eq!(configs.len(), 1);
+        assert!(configs[0].text_mode);
+    }
+
+    // 文本模式下 shift 以外的修饰键在开始前就被拒绝，不会创建后端
+    #[test]
+    fn text_mode_rejects_other_modifiers() {
+        let (controller, _, configs) = config_recording_controller();
+        let error = controller
+            .start(vec![event(0.0, "ctrl+a", 0.25)], text_mode())
+            .unwrap_err();
+        assert!(
+            matches!(&error, CommandError::InvalidArgument(message) if message.contains("text mode")),
+            "{}",
+            error
+        );
+        assert!(configs.lock().is_empty());
+        assert!(!controller.is_active());
+    }
+
+    // 按键模式接受任意修饰键组合，按物理按键发送
+    #[test]
+    fn key_mode_sends_modifier_combinations_through_a_key_sender() {
+        let (controller, sender, configs) = config_recording_controller();
+        let events = vec![event(0.0, "ctrl+a", 0.25)];
+        controller
+            .start(events, PlaybackOptions::default())
+            .unwrap();
+        wait_idle(&controller);
+        assert_eq!(sender.lines(), ["0.000 +ctrl+a", "0.250 -ctrl+a"]);
+        assert!(!configs.lock()[0].text_mode);
+    }
+
+    // 同一模式的后端在播放之间复用，切换模式时重新创建
+    #[test]
+    fn switching_modes_recreates_the_sender() {
+        let (controller, _, configs) = config_recording_controller();
+        for options in [
+            PlaybackOptions::default(),
+            PlaybackOptions::default(),
+            text_mode(),
+        ] {
+            controller
+                .start(vec![event(0.0, "a", 0.25)], options)
+                .unwrap();
+            wait_idle(&controller);
+        }
+        let modes: Vec<bool> = configs.lock().iter().map(|c| c.text_mode).collect();
+        assert_eq!(modes, [false, true]);
+    }
+
+    #[test]
+    fn text_mode_is_unavailable_with_uinput() {
+        let config = SenderConfig {
+            backend: InputBackend::Uinput,
+            text_mode: true,
+            ..Default::default()
+        };
+        assert!(create_sender(config).is_err());
+    }
+
+    // 第 i 个和弦在 i * 0.25 + jitter(i) 秒按下 chords[i % chords.len()] 中的键
+    fn chord_song(
+        count: usize,
+        chords: &[&[&str]],
+        jitter: impl Fn(usize) -> f64,
+    ) -> Vec<KeyEvent> {
+        (0..count)
+            .flat_map(|i| {
+                let time = i as f64 * 0.25 + jitter(i);
+                chords[i % chords.len()]
+                    .iter()
+                    .enumerate()
+                    .map(move |(voice, key)| KeyEvent {
+                        note: Some(60 + voice as u8 * 4),
+                        ..event(time, key, 0.2)
+                    })
+            })
+            .collect()
+    }
+
+    // 动作的全部内容，浮点数按位比较
+    fn action_bits(action: &Action) -> (u64, bool, String, usize, u8, u64) {
+        let (tag, seconds) = encode_conflict(action.conflict);
+        (
+            action.time.to_bits(),
+            action.kind == ActionKind::Press,
+            action.key.clone(),
+            action.event_index,
+            tag,
+            seconds.to_bits(),
+        )
+    }
+
+    // 压缩后的队列逐个展开、查找和汇总都要与原来的动作列表完全一致
+    fn assert_patterned_matches_naive(events: &[KeyEvent]) {
+        let naive = build_actions(events, 0.0, false, &PlaybackSettings::default());
+        let queue = ActionQueue::new(naive.clone());
+        assert!(matches!(queue, ActionQueue::Patterned(_)), "not compressed");
+        assert_queue_matches(&queue, &naive);
+    }
+
+    // queue 展开后与 naive 逐位一致，查找和汇总的结果也相同
+    fn assert_queue_matches(queue: &ActionQueue, naive: &[Action]) {
+        assert_eq!(queue.len(), naive.len());
+        for (i, action) in naive.iter().enumerate() {
+            assert_eq!(
+                action_bits(&queue.get(i).unwrap()),
+                action_bits(action),
+                "action {}",
+                i
+            );
+            assert_eq!(queue.time(i).map(f64::to_bits), Some(action.time.to_bits()));
+        }
+        assert!(queue.get(naive.len()).is_none());
+        for action in naive.iter().step_by(7) {
+            for position in [action.time, action.time + 0.01] {
+                assert_eq!(
+                    queue.partition_point(position),
+                    naive.partition_point(|a| a.time < position),
+                    "partition_point({})",
+                    position
+                );
+            }
+        }
+        let mut presses = naive.iter().filter(|a| a.kind == ActionKind::Press);
+        assert_eq!(queue.presses(), presses.clone().count());
+        assert_eq!(queue.last_press_time(), presses.next_back().map(|a| a.time));
+        assert_eq!(queue.last_time(), naive.last().map(|a| a.time));
+    }
+
+    #[test]
+    fn patterned_queue_matches_the_naive_schedule() {
+        let chords: &[&[&str]] = &[&["a", "d", "g"], &["s", "f", "h"]];
+        assert_patterned_matches_naive(&chord_song(3000, chords, |_| 0.0));
+    }
+
+    // 修饰键冲突推迟的按下也要原样展开
+    #[test]
+    fn patterned_queue_keeps_modifier_conflicts() {
+        let chords: &[&[&str]] = &[&["b", "c", "shift+a"], &["e", "ctrl+d"]];
+        let events = chord_song(3000, chords, |_| 0.0);
+        let naive = build_actions(&events, 0.0, false, &PlaybackSettings::default());
+        assert!(naive.iter().any(|a| a.conflict.is_some()));
+        assert_patterned_matches_naive(&events);
+    }
+
+    // 人性化之后时间不再整齐，只要重复得足够多仍然压缩
+    #[test]
+    fn patterned_queue_matches_with_uneven_timing() {
+        let chords: &[&[&str]] = &[&["a", "d", "g"]];
+        let jitter = |i: usize| [0.0, 0.003, -0.002, 0.0011][i % 4];
+        assert_patterned_matches_naive(&chord_song(3000, chords, jitter));
+    }
+
+    // 动作序列占用的内存（字节），按键字符串按容量计
+    fn naive_bytes(actions: &[Action]) -> usize {
+        actions
+            .iter()
+            .map(|action| std::mem::size_of::<Action>() + action.key.capacity())
+            .sum()
+    }
+
+    fn patterned_bytes(patterned: &PatternedActions) -> usize {
+        let keys: usize = patterned
+            .keys
+            .iter()
+            .map(|key| std::mem::size_of::<String>() + key.capacity())
+            .sum();
+        let patterns: usize = patterned
+            .patterns
+            .iter()
+            .map(|steps| {
+                std::mem::size_of::<Vec<PatternStep>>()
+                    + steps.capacity() * std::mem::size_of::<PatternStep>()
+            })
+            .sum();
+        keys + patterns
+            + patterned.instances.capacity() * std::mem::size_of::<PatternInstance>()
+            + patterned.checkpoints.capacity() * std::mem::size_of::<u32>()
+    }
+
+    // 10 万个事件的伴奏：压缩后不到原来的 10%，压缩比生成原来的动作列表更快
+    #[test]
+    fn patterned_queue_is_small_and_quick_for_a_long_accompaniment() {
+        let chords: &[&[&str]] = &[&["a", "d", "g"], &["s", "f", "h"], &["q", "e", "t"]];
+        let events = chord_song(33_334, chords, |_| 0.0);
+        assert!(events.len() >= 100_000);
+
+        let started = Instant::now();
+        let naive = build_actions(&events, 0.0, false, &PlaybackSettings::default());
+        let naive_time = started.elapsed();
+        let naive_size = naive_bytes(&naive);
+
+        let started = Instant::now();
+        let queue = ActionQueue::new(naive);
+        let queue_time = started.elapsed();
+        let ActionQueue::Patterned(patterned) = &queue else {
+            panic!("not compressed");
+        };
+        let patterned_size = patterned_bytes(patterned);
+
+        eprintln!(
+            "naive {} bytes in {:?}, patterned {} bytes in {:?}",
+            naive_size, naive_time, patterned_size, queue_time
+        );
+        assert!(
+            patterned_size * 10 < naive_size,
+            "patterned {} bytes, naive {} bytes",
+            patterned_size,
+            naive_size
+        );
+        assert!(
+            queue_time < naive_time,
+            "compressing took {:?}, building the naive schedule {:?}",
+            queue_time,
+            naive_time
+        );
+        if !cfg!(debug_assertions) {
+            assert!(
+                queue_time < Duration::from_millis(100),
+                "compressing took {:?}",
+                queue_time
+            );
+        }
+    }
+
+    // 设置 force 时 ActionQueue::new 不压缩，直接这样写入磁盘
+    // 这里直接构造，不改全局的暂存设置，以免影响并行运行的其他测试
+    #[test]
+    fn spilled_queue_matches_the_naive_schedule() {
+        schedule_spill::init_for_tests();
+        let chords: &[&[&str]] = &[&["b", "c", "shift+a"], &["e", "ctrl+d"], &["a", "d", "g"]];
+        let events = chord_song(3000, chords, |i| [0.0, 0.003][i % 2]);
+        let naive = build_actions(&events, 0.0, false, &PlaybackSettings::default());
+        assert!(naive.len() > schedule_spill::SPILL_WINDOW * 2);
+        assert!(naive.iter().any(|a| a.conflict.is_some()));
+        let queue = ActionQueue::Spilled(Box::new(SpilledActions::write(&naive).unwrap()));
+        assert_queue_matches(&queue, &naive);
+    }
+
+    // 和弦几乎不重复的歌曲不压缩
+    #[test]
+    fn unrepeated_schedule_stays_plain() {
+        let letters: Vec<String> = ('a'..='z').map(String::from).collect();
+        let mut seed = 12345u32;
+        let mut events = Vec::new();
+        for i in 0..2000 {
+            let mut chord: Vec<&str> = Vec::new();
+            while chord.len() < 4 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let key = letters[(seed >> 16) as usize % letters.len()].as_str();
+                if !chord.contains(&key) {
+                    chord.push(key);
+                }
+            }
+            events.extend(chord.iter().map(|key| event(i as f64 * 0.25, key, 0.2)));
+        }
+        let naive = build_actions(&events, 0.0, false, &PlaybackSettings::default());
+        assert!(naive.len() >= PATTERN_MIN_ACTIONS);
+        assert!(matches!(ActionQueue::new(naive), ActionQueue::Plain(_)));
+    }
+
+    // 循环区间 (from, to) 内缩短后的 (事件序号, 松开时刻)
+    fn loop_releases(
+        events: &[KeyEvent],
+        region: (f64, f64),
+    ) -> (Vec<(usize, f64)>, LoopTruncations) {
+        let mut actions = build_actions(events, 0.0, false, &PlaybackSettings::default());
+        let truncations = truncate_at_loop_end(&mut actions, events, region);
+        assert!(actions.windows(2).all(|w| w[0].time <= w[1].time));
+        let releases = actions
+            .iter()
+            .filter(|a| a.kind == ActionKind::Release)
+            .map(|a| (a.event_index, (a.time * 1e4).round() / 1e4))
+            .collect();
+        (releases, truncations)
+    }
+
+    // 跨过循环终点的长音在终点前 10ms 松开，终点前按下的短音不受影响
+    #[test]
+    fn pad_across_the_loop_end_is_released_before_it() {
+        let events = vec![
+            event(1.0, "a", 4.0),
+            event(2.0, "b", 1.5),
+            event(3.0, "c", 2.0),
+        ];
+        let (releases, truncations) = loop_releases(&events, (0.5, 4.0));
+        assert_eq!(releases, [(1, 3.5), (0, 3.99), (2, 3.99)]);
+        assert_eq!(truncations.first_pass, [0, 2]);
+        assert_eq!(truncations.repeats, [0, 2]);
+    }
+
+    // 循环起点之前按下的长音只在第一遍缩短
+    #[test]
+    fn pad_before_the_loop_start_is_first_pass_only() {
+        let events = vec![event(0.0, "a", 5.0), event(2.5, "b", 2.0)];
+        let (releases, truncations) = loop_releases(&events, (2.0, 4.0));
+        assert_eq!(releases, [(1, 3.99), (0, 3.99)]);
+        assert_eq!(truncations.first_pass, [1, 0]);
+        assert_eq!(truncations.repeats, [1]);
+    }
+
+    // 紧贴终点按下的音在按下和终点的中点松开；恰好在间隔处、终点之后按下的音不变
+    #[test]
+    fn notes_at_the_loop_boundary() {
+        let events = vec![
+            event(1.0, "a", 2.99),  // 恰好在终点前 10ms 松开
+            event(3.995, "b", 1.0), // 离终点只有 5ms
+            event(4.0, "c", 1.0),   // 在终点按下，属于下一遍之外
+        ];
+        let (releases, truncations) = loop_releases(&events, (0.0, 4.0));
+        assert_eq!(releases, [(0, 3.99), (1, 3.9975), (2, 5.0)]);
+        assert_eq!(truncations.first_pass, [1]);
+    }
+
+    #[test]
+    fn open_ended_loop_changes_nothing() {
+        let events = vec![event(0.0, "a", 10.0)];
+        let (releases, truncations) = loop_releases(&events, (0.0, f64::INFINITY));
+        assert_eq!(releases, [(0, 10.0)]);
+        assert!(truncations.first_pass.is_empty());
+    }
+
+    // 文件循环点：第一遍的长音和每一遍的长音都在跳回之前松开，不会带进下一遍
+    #[test]
+    fn looping_playback_does_not_leak_pad_presses() {
+        let (controller, sender) = controller();
+        let events = vec![
+            event(0.5, "a", 3.0),
+            event(1.0, "b", 0.5),
+            event(1.5, "c", 2.0),
+        ];
+        let options = PlaybackOptions {
+            file_loop: Some(FileLoop {
+                start: 1.0,
+                end: Some(2.0),
+            }),
+            ..PlaybackOptions::default()
+        };
+        controller.start(events, options).unwrap();
+        wait_for("three passes", || sender.lines().len() >= 14);
+        controller.stop().unwrap();
+        wait_idle(&controller);
+        assert_eq!(
+            sender.lines()[..14],
+            [
+                "0.500 +a", "1.000 +b", "1.500 -b", "1.500 +c", "1.990 -a", "1.990 -c", "2.000 +b",
+                "2.500 -b", "2.500 +c", "2.990 -c", "3.000 +b", "3.500 -b", "3.500 +c", "3.990 -c",
+            ]
+        );
+        assert!(controller.active_keys().is_empty());
+    }
+
+    // 时长为 0 的事件按 min_hold_ms 轻点，gate 和 max_hold_ms 不会让它更短
+    #[test]
+    fn zero_duration_holds_for_min_hold() {
+        assert_eq!(PlaybackSettings::default().hold_secs(0.0), 0.05);
+        let settings = PlaybackSettings {
+            min_hold_ms: 80.0,
+            max_hold_ms: Some(200.0),
+            gate: 0.5,
+            ..PlaybackSettings::default()
+        };
+        assert_eq!(settings.hold_secs(0.0), 0.08);
+        assert!(validate_event(&mut event(0.0, "a", 0.0)).is_ok());
+        let error = validate_event(&mut event(0.0, "a", -0.01)).unwrap_err();
+        assert!(error.contains("must be 0 or more"), "{}", error);
+    }
+
+    // 试运行中每个事件的 (按下毫秒, 松开毫秒)
+    type DryRunMs = Vec<(Option<u32>, Option<u32>)>;
+
+    fn dry_run_ms(events: &[KeyEvent]) -> (DryRunMs, DryRunSummary) {
+        let ms = |time: Option<f64>| time.map(|time| (time * 1000.0).round() as u32);
+        let dry = dry_run(events, &PlaybackSettings::default()).unwrap();
+        let times = dry
+            .events
+            .iter()
+            .map(|event| (ms(event.press_at), ms(event.release_at)))
+            .collect();
+        (times, dry.summary)
+    }
+
+    // 同一个键上间隔不到 min_hold_ms 的轻点：每次按下前松开上一次，最后一次按满 min_hold_ms
+    #[test]
+    fn zero_duration_burst_on_one_key_retriggers_in_order() {
+        let events: Vec<KeyEvent> = (0..4).map(|i| event(i as f64 * 0.016, "a", 0.0)).collect();
+        let (controller, sender) = controller();
+        controller
+            .start(events.clone(), PlaybackOptions::default())
+            .unwrap();
+        wait_idle(&controller);
+        assert_eq!(
+            sender.lines(),
+            [
+                "0.000 +a", "0.016 -a", "0.016 +a", "0.032 -a", "0.032 +a", "0.048 -a", "0.048 +a",
+                "0.098 -a",
+            ]
+        );
+        let report = controller.last_report().unwrap();
+        assert_eq!((report.sent, report.taps), (4, 4));
+
+        // 试运行与实际播放一致
+        let (times, summary) = dry_run_ms(&events);
+        assert_eq!(
+            times,
+            [
+                (Some(0), Some(16)),
+                (Some(16), Some(32)),
+                (Some(32), Some(48)),
+                (Some(48), Some(98)),
+            ]
+        );
+        assert_eq!((summary.taps, summary.retriggered), (4, 3));
+    }
+
+    // 同一时刻同一个键的两次轻点都发出，第二次按满 min_hold_ms
+    #[test]
+    fn same_time_taps_on_one_key_are_both_sent() {
+        let events = vec![
+            event(0.0, "a", 0.0),
+            event(0.0, "a", 0.0),
+            event(0.1, "a", 0.0),
+        ];
+        let (controller, sender) = controller();
+        controller
+            .start(events.clone(), PlaybackOptions::default())
+            .unwrap();
+        wait_idle(&controller);
+        assert_eq!(
+            sender.lines(),
+            ["0.000 +a", "0.000 -a", "0.000 +a", "0.050 -a", "0.100 +a", "0.150 -a"]
+        );
+        let (times, summary) = dry_run_ms(&events);
+        assert_eq!(
+            times,
+            [
+                (Some(0), Some(0)),
+                (Some(0), Some(50)),
+                (Some(100), Some(150))
+            ]
+        );
+        assert_eq!((summary.taps, summary.retriggered), (3, 1));
+    }
+
+    #[test]
+    fn queue_list_estimates_durations_with_the_given_settings() {
+        let (controller, _) = controller();
+        controller
+            .queue_add("tap".to_string(), vec![event(1.0, "a", 0.0)], None)
+            .unwrap();
+        let settings = PlaybackSettings {
+            min_hold_ms: 200.0,
+            ..PlaybackSettings::default()
+        };
+        let duration = |settings: &PlaybackSettings| controller.queue_list(settings)[0].duration;
+        assert!((duration(&PlaybackSettings::default()) - 1.05).abs() < 1e-9);
+        assert!((duration(&settings) - 1.2).abs() < 1e-9);
+    }
+
+    // 负的时长与试运行一样拒绝，不会被夹紧成轻点
+    #[test]
+    fn start_and_queue_reject_negative_durations() {
+        let (controller, sender) = controller();
+        let events = vec![event(0.0, "a", 0.25), event(0.5, "b", -0.1)];
+        for error in [
+            controller
+                .start(events.clone(), PlaybackOptions::default())
+                .unwrap_err(),
+            controller
+                .queue_add("song".to_string(), events.clone(), None)
+                .unwrap_err(),
+        ] {
+            assert!(
+                matches!(&error, CommandError::InvalidArgument(message) if message.starts_with("Event 1: Invalid duration")),
+                "{}",
+                error
+            );
+        }
+        assert!(dry_run(&events, &PlaybackSettings::default()).is_err());
+        assert!(controller
+            .queue_list(&PlaybackSettings::default())
+            .is_empty());
+        assert!(!controller.is_active());
+        assert!(sender.sent().is_empty());
+    }
+
+    #[test]
+    fn keep_alive_skips_keys_still_held() {
+        let held = ["ctrl+c".to_string(), "A".to_string()];
+        let released = ["shift+c", "ctrl+x", "b", "rshift", "a", "alt+A"].map(String::from);
+        // c 和 a 还按着，ctrl 也还按着；rshift 与 shift 分别松开
+        assert_eq!(
+            keep_alive_targets(held.iter(), released.iter())
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["alt", "b", "rshift", "shift", "x"]
+        );
+        // 只按着右 shift 时左右 shift 都不补发
+        let held = ["rshift".to_string()];
+        assert_eq!(
+            keep_alive_targets(held.iter(), released.iter())
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["a", "alt", "b", "c", "ctrl", "x"]
+        );
+    }
+
+    #[test]
+    fn controls_need_a_playback() {
+        let (controller, sender) = controller();
+        assert!(matches!(
+            controller.pause(),
+            Err(CommandError::InvalidState(_))
+        ));
+        assert!(matches!(
+            controller.resume(),
+            Err(CommandError::InvalidState(_))
+        ));
+        assert!(matches!(
+            controller.seek(1.0),
+            Err(CommandError::InvalidState(_))
+        ));
+        assert!(matches!(
+            controller.start(
+                vec![event(0.0, "a", 0.25)],
+                PlaybackOptions {
+                    stop_after: Some(-1.0),
+                    ..PlaybackOptions::default()
+                }
+            ),
+            Err(CommandError::InvalidArgument(_))
+        ));
+        assert!(controller.stop().is_ok());
+        assert!(sender.sent().is_empty());
+    }
+
+    #[test]
+    fn shutdown_stops_the_worker_within_the_timeout() {
+        let (controller, sender, gate) = gated_controller("a");
+        let events = vec![event(0.0, "a", 1.0), event(2.0, "b", 0.25)];
+        controller
+            .start(events, PlaybackOptions::default())
+            .unwrap();
+        gate.wait();
+        let finished = thread::scope(|scope| {
+            let shutting_down = scope.spawn(|| controller.shutdown(SHUTDOWN_TIMEOUT));
+            wait_for("stop request", || stop_requested(&controller));
+            gate.open();
+            shutting_down.join().unwrap()
+        });
+        assert!(finished);
+        assert_eq!(sender.lines(), ["0.000 +a", "0.000 -a"]);
+        assert!(controller.active_keys().is_empty());
+        // 任务通道已关闭，播放线程随之退出
+        assert!(controller.worker.lock().is_none());
+    }
+
+    #[test]
+    fn shutdown_gives_up_on_a_stuck_worker() {
+        let (controller, sender, gate) = gated_controller("a");
+        controller
+            .start(vec![event(0.0, "a", 1.0)], PlaybackOptions::default())
+            .unwrap();
+        gate.wait();
+        let started = Instant::now();
+        assert!(!controller.shutdown(Duration::from_millis(50)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // 卡住的线程恢复后仍按停止请求松开按键
+        gate.open();
+        wait_for("keys to be released", || sender.sent().len() == 2);
+        assert_eq!(sender.lines(), ["0.000 +a", "0.000 -a"]);
+    }
+
+    #[test]
+    fn drop_stops_playback_and_releases_keys() {
+        let (controller, sender, gate) = gated_controller("a");
+        let events = vec![event(0.0, "a", 1.0), event(2.0, "b", 0.25)];
+        controller
+            .start(events, PlaybackOptions::default())
+            .unwrap();
+        gate.wait();
+        let shared = Arc::clone(&controller.shared);
+        let dropping = thread::spawn(move || drop(controller));
+        wait_for("stop request", || shared.state.lock().stop_requested);
+        gate.open();
+        dropping.join().unwrap();
+        assert_eq!(sender.lines(), ["0.000 +a", "0.000 -a"]);
+        assert!(shared.held.lock().is_empty());
+    }
 }
@@ -1,9 +1,104 @@
-mod keypress_simulator;
-mod midi_analyzer;
+//! OpenGamesAutoPlay 的后端
+//!
+//! 解析和播放可以不经过 Tauri 单独使用，这几个模块不依赖 Tauri：
+//! - `midi_analyzer`：`analyze_midi_file(path, &AnalyzerOptions)` 读取并分析 MIDI 文件；
+//!   需要进度、缓存或用不同选项重复分析时用 `read_midi_file` / `ParseCache` 加 `analyze_raw`
+//! - `keypress_simulator`：`key_events` 把分析结果按按键映射转为 `KeyEvent`，
+//!   `PlaybackController` 按 `PlaybackOptions` 播放
+//! - `error`：入口函数返回的 `CommandError`，按 kind 区分参数错误、文件错误和取消
+//!
+//! 选项和结果都带 serde 派生，可以直接读写 JSON。
+//! 下面的 `#[tauri::command]` 只负责 IPC 参数转换和应用状态，逻辑都在上述模块中
+
+mod audio_feedback;
+mod cli;
+mod default_settings;
+mod dry_run_report;
+pub mod duet;
+pub mod error;
+mod event_upload;
+mod export_worker;
+mod file_open;
+mod focus_guard;
+mod folder_scan;
+mod frontend_link;
+pub mod humanize;
+mod input_capabilities;
+pub mod keypress_simulator;
+mod library_watcher;
+mod live_input;
+mod live_recording;
+pub mod metronome;
+pub mod midi_analyzer;
+mod midi_output;
 mod mouse_simulator;
+mod notifications;
+pub mod playback_stats;
+mod presets;
+mod profiles;
+mod rate_limiter;
+mod recent_files;
+mod recorder;
+mod remote_server;
+mod schedule_spill;
+mod session_log;
+mod session_recovery;
+pub mod song_clock;
+mod song_file;
+mod song_library;
+mod timer_resolution;
+#[cfg(desktop)]
+mod tray;
+
+use audio_feedback::{AudioFeedback, AudioFeedbackSettings, AudioFeedbackStatus};
+use default_settings::{DefaultSettings, DefaultSettingsStore};
+use duet::{Duet, DuetRole, FollowOutcome};
+use error::CommandError;
+use event_upload::EventUploads;
+use export_worker::{ExportContent, ExportEvent, ExportWorker};
+use file_open::{FileOpenOutcome, PendingFileOpen};
+use focus_guard::{FocusGuard, SelfFocusPolicy};
+use folder_scan::{FolderScanner, ScanEvent, ScanResult};
+use frontend_link::{DetachPolicy, FrontendLink, PlaybackState};
+use humanize::HumanizeConfig;
+use input_capabilities::{CapabilityCache, InputCapabilities};
+use keypress_simulator::{
+    FileLoop, InputBackend, KeySender, PlaybackController, PlaybackEvent, PlaybackOptions,
+    PlaybackProgress, PlaybackSettings, QueueEntryInfo, QueueOptions, StartAt, StopBoundary,
+    DEFAULT_SOFT_STOP_WAIT,
+};
+use library_watcher::{LibraryEvent, LibraryWatcher};
+use live_input::{LiveInput, LiveMappingSettings, LiveStatus};
+use live_recording::{LiveRecordingOptions, LiveRecordingResult};
+use metronome::{CountInConfig, Meter};
+use midi_analyzer::{AnalyzerOptions, ParseCache};
+use midi_output::MidiOutputSender;
+use notifications::PlaybackNotifier;
+use presets::{LayoutCoverage, LayoutSpec, PresetInfo, PresetSettings, PresetStore};
+use profiles::{ProfileDetector, ProfileMatch, ProfileRule};
+use recent_files::{FileSettings, RecentFile, RecentFiles};
+use recorder::{Recorder, RecordingOptions};
+use remote_server::{RemoteServer, RemoteSettings, RemoteStatus};
+use schedule_spill::SpillSettings;
+use serde::Serialize;
+use session_recovery::{RecoveredSession, SessionRecovery};
+use song_file::ImportedSong;
+use song_library::{LibraryEntry, LibraryFilters, SearchPage, SongLibrary};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tauri::webview::PageLoadEvent;
+use tauri::{AppHandle, Emitter, Manager, RunEvent, State, WindowEvent};
+use tauri_plugin_log::{Target, TargetKind};
+use uni_input::{InputPermission, LayoutTranslation};
+use uni_window::{TopLevelWindow, WindowInfo};
 
-use std::sync::Mutex;
-use uni_window::WindowInfo;
+/// 应用级设置
+#[derive(Default)]
+struct AppSettings {
+    // 播放中关闭窗口时，先询问前端而不是直接停止并退出
+    confirm_exit_during_playback: bool,
+}
 
 // Define Global Locked Window State
 lazy_static::lazy_static! {
@@ -15,6 +110,43 @@ fn get_windows() -> Result<Vec<WindowInfo>, String> {
     uni_window::enumerate_windows().map_err(|e| e.to_string())
 }
 
+// 点选窗口的最长等待时间
+const WINDOW_PICK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// 正在进行的窗口点选的取消标志，cancel_window_pick 置位
+#[derive(Default)]
+struct WindowPickCancel(AtomicBool);
+
+/// 当前桌面上可见的顶层窗口（标题和进程名），从前到后排列
+/// 选中的标题用于 set_target_window 和配置规则的 window_title
+/// Wayland 下返回 unsupported 错误，macOS 缺少屏幕录制权限时返回 permission_denied 错误
+#[tauri::command]
+fn list_windows() -> Result<Vec<TopLevelWindow>, CommandError> {
+    Ok(uni_window::list_windows()?)
+}
+
+/// 等待用户点击（或把鼠标移上去按回车）选中一个窗口并返回它
+/// 点到本应用的窗口时继续等待，Esc 或 cancel_window_pick 取消，最多等待 30 秒
+#[tauri::command]
+async fn pick_window_under_cursor(app: AppHandle) -> Result<TopLevelWindow, CommandError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let cancel = app.state::<WindowPickCancel>();
+        cancel.0.store(false, Ordering::SeqCst);
+        Ok(uni_window::pick_window_under_cursor(
+            WINDOW_PICK_TIMEOUT,
+            &cancel.0,
+        )?)
+    })
+    .await
+    .map_err(|e| CommandError::Other(e.to_string()))?
+}
+
+/// 取消正在进行的窗口点选，对应的 pick_window_under_cursor 返回 cancelled 错误
+#[tauri::command]
+fn cancel_window_pick(cancel: State<'_, WindowPickCancel>) {
+    cancel.0.store(true, Ordering::SeqCst);
+}
+
 #[tauri::command]
 fn lock_window(window: WindowInfo) {
     let mut locked = LOCKED_WINDOW.lock().unwrap();
@@ -38,42 +170,1307 @@ fn try_activate_locked_window() -> Result<(), String> {
     if let Some(ref window) = *locked {
         #[cfg(target_os = "windows")]
         uni_window::activate_window(window.id).map_err(|e| e.to_string())?;
-        
+
         #[cfg(target_os = "macos")]
         uni_window::activate_window_by_pid(window.pid).map_err(|e| e.to_string())?;
-        
+
         // Wait a bit for window to actually activate
         std::thread::sleep(std::time::Duration::from_millis(500));
     }
     Ok(())
 }
 
+/// 缺少输入权限时提前报错，而不是启动一个静默失败的播放线程
+fn ensure_input_permission() -> Result<(), CommandError> {
+    match uni_input::check_input_permission(false) {
+        InputPermission::Denied => Err(CommandError::PermissionDenied(
+            "Accessibility permission is required to simulate key presses. \
+             Grant it in System Settings > Privacy & Security > Accessibility."
+                .to_string(),
+        )),
+        InputPermission::Granted | InputPermission::NotApplicable => Ok(()),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum PermissionStatus {
+    Granted,
+    Denied,
+    NotApplicable,
+}
+
+/// 查询模拟输入权限；prompt 为 true 时在未授权的情况下弹出系统授权提示
 #[tauri::command]
-fn parse_midi(
+fn check_input_permission(prompt: Option<bool>) -> PermissionStatus {
+    match uni_input::check_input_permission(prompt.unwrap_or(false)) {
+        InputPermission::Granted => PermissionStatus::Granted,
+        InputPermission::Denied => PermissionStatus::Denied,
+        InputPermission::NotApplicable => PermissionStatus::NotApplicable,
+    }
+}
+
+/// 打开系统隐私设置中的辅助功能面板（仅 macOS 有效）
+#[tauri::command]
+fn open_input_permission_settings() -> Result<(), String> {
+    uni_input::open_permission_settings()
+}
+
+/// parse_midi 的返回值：分析结果加上该文件上次使用的设置（首次打开时为 null）
+#[derive(Serialize)]
+struct ParsedMidi {
+    #[serde(flatten)]
+    analysis: midi_analyzer::MidiAnalysis,
+    remembered_settings: Option<FileSettings>,
+}
+
+// 歌曲标题：优先使用第一个音轨的名称，没有时使用文件名
+fn midi_title(file_path: &str, analysis: &midi_analyzer::MidiAnalysis) -> String {
+    library_watcher::track_title(&analysis.tracks)
+        .or_else(|| {
+            std::path::Path::new(file_path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+        })
+        .unwrap_or_else(|| file_path.to_string())
+}
+
+/// 正在进行的解析的取消计数，cancel_parse 递增后，之前开始的解析都会中止
+#[derive(Default)]
+struct ParseCancel(AtomicU64);
+
+/// 在阻塞线程池中解析 MIDI 文件，期间发送 parse://progress 事件
+/// 音符范围等选项无效时返回 invalid_argument 错误，不会开始解析
+/// 给出 preview 时只快速解析开头 preview 秒（analysis.partial 为 true），不记入最近文件
+#[tauri::command]
+async fn parse_midi(
+    app: AppHandle,
+    file_path: String,
+    // 解析选项，各字段见 AnalyzerOptions，如 {min_note: 48, max_note: 83, black_key_mode: "auto_sharp"}
+    // 不给出时使用 set_default_settings 保存的默认值；给出时整体替换默认值，缺少的字段取出厂值
+    options: Option<AnalyzerOptions>,
+    key_map: Option<midi_analyzer::KeyMapSpec>, // 当前的音高到按键映射或按音域分层的映射列表，给出时统计 analysis.modifier_churn 和 key_usage
+    trace: Option<bool>, // 在 analysis.pipeline 中记录每个处理步骤增删改了多少音符
+    preview: Option<f64>,
+) -> Result<ParsedMidi, CommandError> {
+    let options = options.unwrap_or_else(|| app.state::<DefaultSettingsStore>().get().analyzer);
+    options.validate().map_err(CommandError::InvalidArgument)?;
+    let settings = FileSettings {
+        analyzer: options,
+        tracks: Vec::new(),
+    };
+    midi_analyzer::validate_preview(preview).map_err(CommandError::InvalidArgument)?;
+    let key_map = key_map
+        .map(|spec| spec.resolve())
+        .transpose()
+        .map_err(CommandError::InvalidArgument)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let cancel = app.state::<ParseCancel>();
+        let generation = cancel.0.load(Ordering::SeqCst);
+        let mut on_progress = |progress: midi_analyzer::ParseProgress| {
+            let _ = app.emit(
+                "parse://progress",
+                serde_json::json!({ "file_path": file_path, "progress": progress }),
+            );
+            cancel.0.load(Ordering::SeqCst) == generation
+        };
+        parse_midi_with(
+            &app.state::<RecentFiles>(),
+            &app.state::<ParseCache>(),
+            &file_path,
+            settings,
+            key_map.as_ref(),
+            trace.unwrap_or(false),
+            preview,
+            &mut on_progress,
+        )
+    })
+    .await
+    .map_err(|e| CommandError::Other(e.to_string()))?
+}
+
+/// 建议能放下 available_keys 个键的音域：覆盖音符最多（旋律音加权）的连续音域、覆盖率和居中所需的移调
+/// 使用解析时缓存的音符，刚解析过的文件不会重新读取
+#[tauri::command]
+async fn suggest_note_range(
+    app: AppHandle,
+    file_path: String,
+    available_keys: usize,
+    black_key_mode: Option<String>, // auto_sharp 时音域只按白键计数
+) -> Result<midi_analyzer::NoteRangeSuggestion, CommandError> {
+    let black_key_mode = match black_key_mode {
+        Some(mode) => mode.parse().map_err(CommandError::InvalidArgument)?,
+        None => midi_analyzer::BlackKeyMode::default(),
+    };
+    tauri::async_runtime::spawn_blocking(move || {
+        let raw = app
+            .state::<ParseCache>()
+            .read(&file_path, None, &mut |_| true)?;
+        midi_analyzer::suggest_note_range(&raw, available_keys, black_key_mode)
+    })
+    .await
+    .map_err(|e| CommandError::Other(e.to_string()))?
+}
+
+/// 比较几个布局对这首歌的覆盖率，使用解析时缓存的音符，不生成完整的事件列表
+/// layouts 的每项是 { name }（预设名）或 { name, settings }（未保存的布局）；options 是布局之外的解析选项，缺省时与 parse_midi 的默认值相同
+/// 每项的 unsupported_features 按当前发送后端的探测结果给出
+#[tauri::command]
+async fn evaluate_layouts(
+    app: AppHandle,
+    file_path: String,
+    layouts: Vec<LayoutSpec>,
+    options: Option<AnalyzerOptions>,
+) -> Result<Vec<LayoutCoverage>, CommandError> {
+    if layouts.is_empty() {
+        return Err(CommandError::InvalidArgument(
+            "layouts must not be empty".to_string(),
+        ));
+    }
+    let store = preset_store(&app)?;
+    let layouts = layouts
+        .into_iter()
+        .map(|spec| store.resolve(spec))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(CommandError::InvalidArgument)?;
+    let options = options.unwrap_or_else(|| app.state::<DefaultSettingsStore>().get().analyzer);
+    tauri::async_runtime::spawn_blocking(move || {
+        let raw = app
+            .state::<ParseCache>()
+            .read(&file_path, None, &mut |_| true)?;
+        let backend = app.state::<PlaybackController>().sender_config().backend;
+        let capabilities = app.state::<CapabilityCache>().get(backend);
+        layouts
+            .into_iter()
+            .map(|(name, settings)| {
+                presets::evaluate_layout(&raw, &options, name, &settings, Some(&capabilities))
+                    .map_err(CommandError::InvalidArgument)
+            })
+            .collect()
+    })
+    .await
+    .map_err(|e| CommandError::Other(e.to_string()))?
+}
+
+/// 中止正在进行的解析，对应的 parse_midi 返回 cancelled 错误
+#[tauri::command]
+fn cancel_parse(cancel: State<'_, ParseCancel>) {
+    cancel.0.fetch_add(1, Ordering::SeqCst);
+}
+
+/// 按 settings 中的解析参数分析文件并记入最近文件
+/// 预览解析不记入最近文件，之后的完整解析照常记录和回填设置
+/// key_map 是全局的按键设置，不随文件记录；没有时不统计修饰键切换
+/// 分层映射时每个 note_on 事件带上 key_layer
+#[allow(clippy::too_many_arguments)]
+fn parse_midi_with(
+    recent: &RecentFiles,
+    cache: &ParseCache,
     file_path: &str,
-    min_note: u8,
-    max_note: u8,
-    black_key_mode: &str,
-    trim_long_notes: bool,
-) -> Result<midi_analyzer::MidiAnalysis, String> {
-    midi_analyzer::analyze_midi_file(
-        file_path,
-        min_note,
-        max_note,
-        black_key_mode,
-        trim_long_notes,
+    settings: FileSettings,
+    key_map: Option<&midi_analyzer::ResolvedKeyMap>,
+    trace: bool,
+    preview: Option<f64>,
+    progress: midi_analyzer::ProgressCallback,
+) -> Result<ParsedMidi, CommandError> {
+    settings
+        .analyzer
+        .validate()
+        .map_err(CommandError::InvalidArgument)?;
+    let raw = cache.read(file_path, preview, progress)?;
+    settings
+        .analyzer
+        .validate_tracks(&raw)
+        .map_err(CommandError::InvalidArgument)?;
+    let mut analysis = midi_analyzer::analyze_raw(
+        &raw,
+        &settings.analyzer,
+        key_map.map(|key_map| &key_map.keys),
+        trace,
+    );
+    if let Some(key_map) = key_map {
+        midi_analyzer::annotate_key_layers(&mut analysis, key_map);
+    }
+    let remembered_settings = if preview.is_some() {
+        recent.settings_for(file_path)
+    } else {
+        recent.record_open(file_path, midi_title(file_path, &analysis), settings)
+    };
+    Ok(ParsedMidi {
+        analysis,
+        remembered_settings,
+    })
+}
+
+// 在导出线程上生成并写入文件，返回任务编号；进度和结果通过 export://progress、export://finished 推送
+fn start_export(app: &AppHandle, path: &str, content: ExportContent) -> Result<u64, CommandError> {
+    let app = app.clone();
+    app.state::<ExportWorker>().start(
+        std::path::Path::new(path),
+        content,
+        Arc::new(move |event: ExportEvent| {
+            let _ = app.emit(event.name(), &event);
+        }),
+    )
+}
+
+/// 把按键序列（和可选的 MIDI 分析结果）导出为带 schema_version 的 JSON 文件
+/// 在后台写入，返回导出任务编号，完成时发送 export://finished
+#[tauri::command]
+fn export_song(
+    app: AppHandle,
+    path: String,
+    title: Option<String>,
+    events: Vec<keypress_simulator::KeyEvent>,
+    analysis: Option<midi_analyzer::MidiAnalysis>,
+) -> Result<u64, CommandError> {
+    start_export(
+        &app,
+        &path,
+        Box::new(move |out| {
+            song_file::write(out, title, events, analysis)?;
+            Ok(None)
+        }),
+    )
+}
+
+/// 按 settings（默认为保存的默认设置）试运行按键序列，不发送按键，把调度结果写成 JSON 报告
+/// 报告包括每个事件计划的按下和松开时间、跳过的原因、峰值速率、修饰键切换和设置快照
+/// 在后台试运行并写入，返回导出任务编号；export://finished 的 output 为报告的统计
+#[tauri::command]
+fn export_dry_run_report(
+    app: AppHandle,
+    defaults: State<'_, DefaultSettingsStore>,
+    path: String,
+    events: Vec<keypress_simulator::KeyEvent>,
+    settings: Option<PlaybackSettings>,
+) -> Result<u64, CommandError> {
+    let settings = settings.unwrap_or_else(|| defaults.get().playback);
+    start_export(
+        &app,
+        &path,
+        Box::new(move |out| {
+            let summary = dry_run_report::write(out, &events, settings)?;
+            let summary = serde_json::to_value(summary).map_err(|e| e.to_string())?;
+            Ok(Some(summary))
+        }),
     )
 }
 
+/// 取消导出，已写的临时文件被删除；任务不存在或已结束时返回 false
+#[tauri::command]
+fn cancel_export(exports: State<'_, ExportWorker>, job_id: u64) -> bool {
+    exports.cancel(job_id)
+}
+
+/// 导入 export_song 导出的文件，旧版本文件升级为当前格式，warnings 说明补上默认值的数据
+/// 更新版本的应用保存的文件返回 newer_schema 错误
+#[tauri::command]
+fn import_song(path: String) -> Result<ImportedSong, CommandError> {
+    song_file::load(std::path::Path::new(&path))
+}
+
+/// 保存前端的工作状态（当前文件、设置、循环区间等），连同播放队列立即写入恢复文件
+/// 之后后台每隔一段时间自动保存，内容没有变化时不写
+#[tauri::command]
+fn save_session_state(
+    controller: State<'_, PlaybackController>,
+    recovery: State<'_, SessionRecovery>,
+    state: serde_json::Value,
+) -> Result<(), String> {
+    recovery.update(state, controller.queue_entries())
+}
+
+/// 读取上次留下的恢复文件，启动时调用以询问是否恢复
+/// 没有、损坏或早于 max_age_hours（默认 72）小时的恢复文件返回 None
+#[tauri::command]
+fn load_session_state(
+    recovery: State<'_, SessionRecovery>,
+    max_age_hours: Option<f64>,
+) -> Result<Option<RecoveredSession>, String> {
+    let max_age_hours = max_age_hours.unwrap_or(session_recovery::DEFAULT_MAX_AGE_HOURS);
+    if !max_age_hours.is_finite() || max_age_hours < 0.0 {
+        return Err(format!("Invalid max_age_hours: {}", max_age_hours));
+    }
+    Ok(recovery.load(max_age_hours))
+}
+
+/// 删除恢复文件，用户选择不恢复时调用
+#[tauri::command]
+fn discard_session_state(recovery: State<'_, SessionRecovery>) -> Result<(), String> {
+    recovery.discard()
+}
+
+// 后台定期保存恢复文件，队列在两次推送之间变化时也能留下
+fn spawn_session_autosave(app: AppHandle) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(session_recovery::AUTOSAVE_INTERVAL);
+        let queue = app.state::<PlaybackController>().queue_entries();
+        if let Err(e) = app.state::<SessionRecovery>().flush(queue) {
+            eprintln!("{}", e);
+        }
+    });
+}
+
+// 定时检查播放线程是否卡住
+fn spawn_playback_watchdog(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(keypress_simulator::WATCHDOG_INTERVAL).await;
+            app.state::<PlaybackController>().check_watchdog();
+            app.state::<FrontendLink>()
+                .check(&app.state::<PlaybackController>());
+        }
+    });
+}
+
+/// 开始分块上传 total_count 个按键事件，返回上传号
+#[tauri::command]
+fn begin_event_upload(
+    uploads: State<'_, EventUploads>,
+    total_count: usize,
+) -> Result<u64, CommandError> {
+    uploads.begin(total_count)
+}
+
+/// 追加一块事件（最多 event_upload::MAX_CHUNK_EVENTS 个），返回已收到的事件数
+#[tauri::command]
+fn upload_event_chunk(
+    uploads: State<'_, EventUploads>,
+    upload_id: u64,
+    chunk: Vec<keypress_simulator::KeyEvent>,
+) -> Result<usize, CommandError> {
+    uploads.append(upload_id, chunk)
+}
+
+/// 结束上传，返回传给 start_playback 的 events_handle
+#[tauri::command]
+fn commit_events(uploads: State<'_, EventUploads>, upload_id: u64) -> Result<u64, CommandError> {
+    uploads.commit(upload_id)
+}
+
+/// 提前释放上传或句柄，不调用时过一段时间自动清理
+#[tauri::command]
+fn discard_events(uploads: State<'_, EventUploads>, upload_id: u64) {
+    uploads.discard(upload_id)
+}
+
+/// 取走前端加载完成前通过文件关联打开的文件（成功时含解析结果，失败时含 error）
+#[tauri::command]
+fn take_pending_file_open(pending: State<'_, PendingFileOpen>) -> Option<FileOpenOutcome> {
+    pending.take()
+}
+
+/// 最近打开的文件，最近的在前；已不存在的文件 missing 为 true
+#[tauri::command]
+fn get_recent_files(recent: State<'_, RecentFiles>) -> Vec<RecentFile> {
+    recent.list()
+}
+
 #[tauri::command]
-fn start_playback(events: Vec<keypress_simulator::KeyEvent>) -> Result<(), String> {
+fn clear_recent_files(recent: State<'_, RecentFiles>) -> Result<(), String> {
+    recent.clear()
+}
+
+/// 监视 MIDI 文件夹，文件变化时发送 library://added、library://removed、library://changed 事件
+#[tauri::command]
+fn watch_midi_folder(
+    app: AppHandle,
+    watcher: State<'_, LibraryWatcher>,
+    dir: String,
+    key_map: Option<midi_analyzer::KeyMapSpec>, // 给出时摘要中附带最常用的键（top_key）
+) -> Result<(), String> {
+    let key_map = key_map.map(|spec| spec.resolve()).transpose()?;
+    watcher.watch(
+        &dir,
+        key_map.map(|key_map| key_map.keys),
+        Arc::new(move |event: LibraryEvent| {
+            let _ = app.emit(event.name(), &event);
+        }),
+    )
+}
+
+/// 在后台扫描 MIDI 文件夹，返回扫描编号；每个文件完成时发送 scan://result，结束时发送 scan://finished
+/// 已有扫描在进行时先取消它；扫描结果同时写入歌曲库
+#[tauri::command]
+async fn scan_midi_folder(
+    app: AppHandle,
+    dir: String,
+    key_map: Option<midi_analyzer::KeyMapSpec>, // 给出时摘要中附带最常用的键（top_key）
+) -> Result<u64, String> {
+    let key_map = key_map.map(|spec| spec.resolve()).transpose()?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let lookup_app = app.clone();
+        let sink_app = app.clone();
+        let scan_dir = dir.clone();
+        app.state::<FolderScanner>().scan(
+            &dir,
+            key_map.map(|key_map| key_map.keys),
+            Arc::new(move |hash| lookup_app.state::<ParseCache>().find_by_hash(hash)),
+            Arc::new(move |event: ScanEvent| {
+                let library = sink_app.state::<SongLibrary>();
+                match &event {
+                    ScanEvent::Result(result) => library.record_scan(result),
+                    ScanEvent::Finished(summary) => library.finish_scan(&scan_dir, summary),
+                }
+                let _ = sink_app.emit(event.name(), &event);
+            }),
+        )
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// 取消进行中的扫描：不再开始新的文件，等正在解析的完成后返回已完成的结果
+#[tauri::command]
+async fn cancel_scan(app: AppHandle) -> Result<Vec<ScanResult>, String> {
+    tauri::async_runtime::spawn_blocking(move || app.state::<FolderScanner>().cancel())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// 在歌曲库中搜索，返回一页按标题排序的结果；query 为空时只按过滤条件
+#[tauri::command]
+fn library_search(
+    library: State<'_, SongLibrary>,
+    query: Option<String>,
+    filters: Option<LibraryFilters>,
+) -> SearchPage {
+    library.search(&query.unwrap_or_default(), &filters.unwrap_or_default())
+}
+
+/// 替换一首歌的标签，file_hash 见扫描结果
+#[tauri::command]
+fn library_tag(
+    library: State<'_, SongLibrary>,
+    file_hash: String,
+    tags: Vec<String>,
+) -> Result<LibraryEntry, String> {
+    library.set_tags(&file_hash, tags)
+}
+
+#[tauri::command]
+fn library_get(library: State<'_, SongLibrary>, file_hash: String) -> Option<LibraryEntry> {
+    library.get(&file_hash)
+}
+
+#[tauri::command]
+fn unwatch_midi_folder(watcher: State<'_, LibraryWatcher>) {
+    watcher.unwatch();
+}
+
+/// 记住前端对某个文件调整后的设置，下次打开时随 parse_midi 返回
+#[tauri::command]
+fn update_recent_file_settings(
+    recent: State<'_, RecentFiles>,
+    file_path: String,
+    settings: FileSettings,
+) -> Result<(), String> {
+    recent.update_settings(&file_path, settings)
+}
+
+/// 本应用窗口在前台时的处理方式，默认播放中自动暂停
+#[tauri::command]
+fn set_self_focus_policy(guard: State<'_, FocusGuard>, policy: SelfFocusPolicy) {
+    guard.set_self_focus(policy);
+}
+
+/// 设置焦点守卫的目标窗口，返回当前标题匹配的窗口列表
+/// 传入空字符串会清除目标并返回全部窗口
+#[tauri::command]
+fn set_target_window(
+    guard: State<'_, FocusGuard>,
+    title_substring: String,
+    auto_resume: bool,
+) -> Result<Vec<WindowInfo>, String> {
+    let pattern = title_substring.trim().to_lowercase();
+    if !pattern.is_empty() {
+        if let Err(e @ uni_window::ForegroundError::Unsupported(_)) =
+            uni_window::foreground_window()
+        {
+            return Err(e.to_string());
+        }
+    }
+
+    guard.set_target(Some(title_substring), auto_resume);
+
+    let windows = uni_window::enumerate_windows().map_err(|e| e.to_string())?;
+    Ok(windows
+        .into_iter()
+        .filter(|w| {
+            w.title.to_lowercase().contains(&pattern)
+                || w.app_name.to_lowercase().contains(&pattern)
+        })
+        .collect())
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+fn start_playback(
+    app: AppHandle,
+    controller: State<'_, PlaybackController>,
+    guard: State<'_, FocusGuard>,
+    recorder: State<'_, Recorder>,
+    uploads: State<'_, EventUploads>,
+    events: Option<Vec<keypress_simulator::KeyEvent>>,
+    events_handle: Option<u64>, // commit_events 返回的句柄，大歌曲不必一次传整个事件数组
+    song_path: Option<String>,  // export_song 导出的文件，直接播放其中的事件
+    start_at_time: Option<f64>,
+    start_at_index: Option<usize>,
+    press_sounding: Option<bool>,
+    humanize: Option<HumanizeConfig>,
+    settings: Option<PlaybackSettings>,
+    title: Option<String>, // 托盘菜单和通知中显示的曲目名
+    count_in: Option<CountInConfig>,
+    practice_mode: Option<bool>,
+    meter: Option<Meter>,    // 速度和拍号，来自 MidiAnalysis，按小节软停止时需要
+    stop_after: Option<f64>, // 定时停止：开始后经过的实际秒数
+    timer_counts_pause: Option<bool>, // 暂停期间定时停止是否继续计时，默认不计
+    speed_ramp: Option<(f64, f64)>, // (起始倍率, 结束倍率)，整首歌中线性变化
+    measures: Option<Vec<f64>>, // 小节边界，来自 parse_midi 的 measures（include_measures 开启时）
+    loop_region_measures: Option<(u32, u32)>, // 循环播放第 a 到第 b 小节（含两端）
+    text_mode: Option<bool>, // 把按键作为字符输入到聊天框，只允许 shift 修饰
+    use_file_loop_points: Option<bool>, // 按文件标记的循环点循环：第一遍从头播放，之后从 loop_start 重复
+    loop_start: Option<f64>, // 来自 parse_midi 的 loop_start / loop_end；song_path 时可省略，使用文件中的分析结果
+    loop_end: Option<f64>,
+    duet_role: Option<DuetRole>, // 合奏时只演奏一个声部，默认 full，见 duet 模块
+) -> Result<(), CommandError> {
+    let mut file_loop = loop_start.map(|start| FileLoop {
+        start,
+        end: loop_end,
+    });
+    let (events, title) = match (events, events_handle, song_path) {
+        (Some(events), None, None) => (events, title),
+        (None, Some(handle), None) => (uploads.events(handle)?, title),
+        (None, None, Some(path)) => {
+            let document = song_file::load(std::path::Path::new(&path))?.document;
+            if let Some(analysis) = document.analysis.as_ref().filter(|_| file_loop.is_none()) {
+                file_loop = analysis.loop_start.map(|start| FileLoop {
+                    start,
+                    end: analysis.loop_end,
+                });
+            }
+            (document.events, title.or(document.title))
+        }
+        _ => {
+            return Err(CommandError::InvalidArgument(
+                "Specify exactly one of events, events_handle or song_path".to_string(),
+            ))
+        }
+    };
+    let events = duet::filter_events(events, duet_role.unwrap_or_default())?;
+    let file_loop = match (use_file_loop_points.unwrap_or(false), file_loop) {
+        (false, _) => None,
+        (true, Some(file_loop)) => Some(file_loop),
+        (true, None) => {
+            return Err(CommandError::InvalidArgument(
+                "use_file_loop_points needs loop_start; the song has no loop points".to_string(),
+            ))
+        }
+    };
+    let start_at = match (start_at_time, start_at_index) {
+        (Some(_), Some(_)) => {
+            return Err(CommandError::InvalidArgument(
+                "Specify either start_at_time or start_at_index, not both".to_string(),
+            ))
+        }
+        (Some(time), None) => Some(StartAt::Time(time)),
+        (None, Some(index)) => Some(StartAt::Index(index)),
+        (None, None) => None,
+    };
+    let defaults = app.state::<DefaultSettingsStore>().get();
+    let options = PlaybackOptions {
+        start_at,
+        press_sounding: press_sounding.unwrap_or(defaults.press_sounding),
+        humanize: humanize.or_else(|| defaults.humanize.clone()),
+        settings: settings.unwrap_or_else(|| defaults.playback.clone()),
+        count_in,
+        practice_mode: practice_mode.unwrap_or(false),
+        meter,
+        stop_after,
+        timer_counts_pause: timer_counts_pause.unwrap_or(false),
+        speed_ramp,
+        measures,
+        loop_region_measures,
+        text_mode: text_mode.unwrap_or(false),
+        file_loop,
+    };
+    let practice = options.practice_mode;
+
+    apply_detected_profile(&app)?;
+    ensure_input_permission()?;
     try_activate_locked_window()?;
-    keypress_simulator::start_playback(events)
+    // 问题报告里的播放日志带上当时的默认值
+    log::debug!(
+        target: session_log::TARGET,
+        "Default settings: {}",
+        serde_json::to_string(&defaults).unwrap_or_default()
+    );
+    // 练习模式不发送按键，不怕打进自己的窗口
+    if !practice {
+        guard.check_start().map_err(CommandError::SelfFocused)?;
+    }
+    controller.start(events, options)?;
+    if practice {
+        // 用录制功能的全局键盘监听接收用户的按键，播放结束时在事件回调中停止
+        let handle = app.clone();
+        recorder.start_practice(controller.sender_config().layout, move |key| {
+            handle.state::<PlaybackController>().practice_key(key);
+        });
+    }
+    set_now_playing(&app, title);
+    // 练习时用户会看着本应用的提示，焦点守卫没有意义
+    if !practice {
+        guard.watch(app);
+    }
+    Ok(())
 }
 
+/// 预约在指定的系统时间（Unix 毫秒）开始播放，用于多人同时开始演奏
+/// 等待期间推送 playback://countdown，可用 cancel_scheduled 取消
+/// 给出 duet_role 时作为合奏的领奏方，开始时间通过遥控接口 /api/duet 提供给跟奏方（见 follow_duet）
 #[tauri::command]
-fn stop_playback() -> Result<(), String> {
-    keypress_simulator::stop_playback()
+#[allow(clippy::too_many_arguments)]
+fn schedule_playback(
+    app: AppHandle,
+    controller: State<'_, PlaybackController>,
+    guard: State<'_, FocusGuard>,
+    sync: State<'_, Duet>,
+    events: Vec<keypress_simulator::KeyEvent>,
+    start_at_unix_ms: u64,
+    press_sounding: Option<bool>,
+    humanize: Option<HumanizeConfig>,
+    settings: Option<PlaybackSettings>,
+    title: Option<String>,
+    count_in: Option<CountInConfig>,
+    duet_role: Option<DuetRole>,
+) -> Result<(), CommandError> {
+    let defaults = app.state::<DefaultSettingsStore>().get();
+    let options = PlaybackOptions {
+        press_sounding: press_sounding.unwrap_or(defaults.press_sounding),
+        humanize: humanize.or(defaults.humanize),
+        settings: settings.unwrap_or(defaults.playback),
+        count_in,
+        ..Default::default()
+    };
+    let fingerprint = duet::fingerprint(&events);
+    let events = duet::filter_events(events, duet_role.unwrap_or_default())?;
+
+    apply_detected_profile(&app)?;
+    ensure_input_permission()?;
+    try_activate_locked_window()?;
+    guard.check_start().map_err(CommandError::SelfFocused)?;
+    controller.schedule(events, options, start_at_unix_ms)?;
+    sync.set_leader(duet_role, fingerprint);
+    set_now_playing(&app, title);
+    guard.watch(app);
+    Ok(())
+}
+
+/// 作为合奏的跟奏方：在后台轮询领奏方的遥控服务（leader_address 如 "192.168.1.5:17380"，token 为其令牌），
+/// 领奏方预约后按估计的时钟偏差预约本机在同一时刻开始，只演奏 duet_role 声部
+/// 两边必须播放同一份歌曲文件（events 或 export_song 导出的 song_path）；结果通过 duet://follow 推送
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+fn follow_duet(
+    app: AppHandle,
+    sync: State<'_, Duet>,
+    leader_address: String,
+    token: String,
+    duet_role: DuetRole,
+    events: Option<Vec<keypress_simulator::KeyEvent>>,
+    song_path: Option<String>,
+    press_sounding: Option<bool>,
+    humanize: Option<HumanizeConfig>,
+    settings: Option<PlaybackSettings>,
+    title: Option<String>,
+    count_in: Option<CountInConfig>,
+) -> Result<(), CommandError> {
+    let (events, title) = match (events, song_path) {
+        (Some(events), None) => (events, title),
+        (None, Some(path)) => {
+            let document = song_file::load(std::path::Path::new(&path))?.document;
+            (document.events, title.or(document.title))
+        }
+        _ => {
+            return Err(CommandError::InvalidArgument(
+                "Specify exactly one of events or song_path".to_string(),
+            ))
+        }
+    };
+    let defaults = app.state::<DefaultSettingsStore>().get();
+    let options = PlaybackOptions {
+        press_sounding: press_sounding.unwrap_or(defaults.press_sounding),
+        humanize: humanize.or(defaults.humanize),
+        settings: settings.unwrap_or(defaults.playback),
+        count_in,
+        ..Default::default()
+    };
+    let fingerprint = duet::fingerprint(&events);
+    let events = duet::filter_events(events, duet_role)?;
+    ensure_input_permission()?;
+
+    let arm_app = app.clone();
+    let done_app = app.clone();
+    sync.follow(
+        &leader_address,
+        token,
+        duet_role,
+        fingerprint,
+        // 与 start_playback 相同：预约前套用检测到的游戏预设并检查焦点
+        Box::new(move |start_at_unix_ms| {
+            apply_detected_profile(&arm_app)?;
+            try_activate_locked_window()?;
+            arm_app.state::<FocusGuard>().check_start()?;
+            arm_app
+                .state::<PlaybackController>()
+                .schedule(events, options, start_at_unix_ms)
+                .map_err(|e| e.to_string())?;
+            set_now_playing(&arm_app, title);
+            arm_app.state::<FocusGuard>().watch(arm_app.clone());
+            Ok(())
+        }),
+        Box::new(move |outcome: FollowOutcome| {
+            let _ = done_app.emit("duet://follow", &outcome);
+        }),
+    )?;
+    Ok(())
+}
+
+/// 停止轮询领奏方；已经预约的开始用 cancel_scheduled 取消
+#[tauri::command]
+fn cancel_duet_follow(sync: State<'_, Duet>) {
+    sync.cancel_follow();
+}
+
+#[tauri::command]
+fn cancel_scheduled(controller: State<'_, PlaybackController>) -> Result<(), CommandError> {
+    controller.cancel_scheduled()
+}
+
+// 托盘菜单和通知中显示的曲目名
+fn set_now_playing(app: &AppHandle, title: Option<String>) {
+    app.state::<PlaybackNotifier>().set_title(title.clone());
+    #[cfg(desktop)]
+    if let Some(tray) = app.try_state::<tray::Tray>() {
+        tray.set_title(title);
+    }
+}
+
+/// 把按键序列以 MIDI 音符发往输出端口试听，调度与正式播放相同
+/// pitch_map_inverse 是按键字符串到音符的映射
+#[tauri::command]
+fn start_playback_midi(
+    controller: State<'_, PlaybackController>,
+    defaults: State<'_, DefaultSettingsStore>,
+    events: Vec<keypress_simulator::KeyEvent>,
+    port_name: String,
+    pitch_map_inverse: HashMap<String, u8>,
+    settings: Option<PlaybackSettings>,
+) -> Result<(), CommandError> {
+    let options = PlaybackOptions {
+        settings: settings.unwrap_or_else(|| defaults.get().playback),
+        ..Default::default()
+    };
+    controller.start_with_target(
+        events,
+        options,
+        Some(Box::new(move || {
+            let sender = MidiOutputSender::new(&port_name, pitch_map_inverse)
+                .map_err(CommandError::Other)?;
+            Ok(Box::new(sender) as Box<dyn KeySender>)
+        })),
+    )
+}
+
+#[tauri::command]
+fn list_midi_outputs() -> Result<Vec<String>, String> {
+    midi_output::list_midi_outputs()
+}
+
+#[tauri::command]
+fn queue_add(
+    controller: State<'_, PlaybackController>,
+    events: Vec<keypress_simulator::KeyEvent>,
+    name: String,
+    gap_override: Option<f64>, // 这一首之前的间隔（秒），替换队列的间隔设置
+) -> Result<usize, CommandError> {
+    controller.queue_add(name, events, gap_override)
+}
+
+#[tauri::command]
+fn queue_remove(
+    controller: State<'_, PlaybackController>,
+    index: usize,
+) -> Result<(), CommandError> {
+    controller.queue_remove(index)
+}
+
+#[tauri::command]
+fn queue_list(
+    controller: State<'_, PlaybackController>,
+    defaults: State<'_, DefaultSettingsStore>,
+) -> Vec<QueueEntryInfo> {
+    controller.queue_list(&defaults.get().playback)
+}
+
+#[tauri::command]
+fn queue_clear(controller: State<'_, PlaybackController>) {
+    controller.queue_clear()
+}
+
+#[tauri::command]
+fn start_queue(
+    app: AppHandle,
+    gap_seconds: f64,
+    seamless: Option<bool>, // 无缝衔接，下一首的第一个音符紧接上一首最后一次松开
+    overlap_seconds: Option<f64>, // 无缝衔接时与上一首末尾重叠的秒数
+) -> Result<(), CommandError> {
+    start_queue_playback(
+        &app,
+        QueueOptions {
+            gap_seconds,
+            seamless: seamless.unwrap_or(false),
+            overlap_seconds: overlap_seconds.unwrap_or(0.0),
+        },
+    )
+}
+
+/// 开始播放队列，前端命令和遥控服务共用
+fn start_queue_playback(app: &AppHandle, options: QueueOptions) -> Result<(), CommandError> {
+    ensure_input_permission()?;
+    try_activate_locked_window()?;
+    app.state::<FocusGuard>()
+        .check_start()
+        .map_err(CommandError::SelfFocused)?;
+    // 与 start_playback 相同，队列中的每一首按默认设置播放
+    let defaults = app.state::<DefaultSettingsStore>().get();
+    let playback = PlaybackOptions {
+        press_sounding: defaults.press_sounding,
+        humanize: defaults.humanize,
+        settings: defaults.playback,
+        ..Default::default()
+    };
+    app.state::<PlaybackController>()
+        .start_queue(options, playback)?;
+    app.state::<FocusGuard>().watch(app.clone());
+    Ok(())
+}
+
+#[tauri::command]
+fn skip_to_next(controller: State<'_, PlaybackController>) -> Result<(), CommandError> {
+    controller.skip_to_next()
+}
+
+/// duration 为 0 时轻点，按住时长与播放时一样按默认设置（set_default_settings）的 min_hold_ms 等夹紧
+#[tauri::command]
+async fn test_keypress(
+    controller: State<'_, PlaybackController>,
+    defaults: State<'_, DefaultSettingsStore>,
+    key: String,
+    duration: f64,
+    delay: f64,
+) -> Result<(), CommandError> {
+    ensure_input_permission()?;
+    let settings = defaults.get().playback;
+    controller.test_keypress(key, duration, delay, &settings)
+}
+
+/// 选择按键发送后端："auto" | "enigo" | "uinput"
+#[tauri::command]
+fn set_input_backend(controller: State<'_, PlaybackController>, backend: InputBackend) {
+    controller.set_backend(backend);
+}
+
+#[tauri::command]
+fn get_input_backend(controller: State<'_, PlaybackController>) -> InputBackend {
+    controller.sender_config().backend
+}
+
+/// 探测发送后端支持的功能（不发送任何按键），backend 省略时为当前选择的后端
+/// 结果在本次运行中缓存
+#[tauri::command]
+fn probe_input_capabilities(
+    controller: State<'_, PlaybackController>,
+    capabilities: State<'_, CapabilityCache>,
+    backend: Option<InputBackend>,
+) -> InputCapabilities {
+    capabilities.get(backend.unwrap_or(controller.sender_config().backend))
+}
+
+/// 丢弃并重新创建按键发送后端，用于后端卡在异常状态时恢复
+#[tauri::command]
+async fn reset_input_backend(
+    controller: State<'_, PlaybackController>,
+) -> Result<(), CommandError> {
+    controller.reset_input_backend()
+}
+
+/// 松开程序可能按下的所有键，用于按键卡住时恢复，没有播放时也可以调用
+/// keys 为当前的按键映射，与播放以来按下过的键和所有修饰键合并；返回发送的释放数
+#[tauri::command]
+async fn release_all_keys(
+    controller: State<'_, PlaybackController>,
+    keys: Option<Vec<String>>,
+) -> Result<usize, CommandError> {
+    controller.release_all_keys(keys.unwrap_or_default())
+}
+
+/// 设置键盘布局转换："none" | "azerty" | "qwertz" | "dvorak"
+#[tauri::command]
+fn set_layout_translation(controller: State<'_, PlaybackController>, layout: LayoutTranslation) {
+    controller.set_layout_translation(layout);
+}
+
+#[tauri::command]
+fn get_layout_translation(controller: State<'_, PlaybackController>) -> LayoutTranslation {
+    controller.sender_config().layout
+}
+
+/// 开始录制用户按键，keys 为空时记录全部按键
+#[tauri::command]
+fn start_recording(
+    controller: State<'_, PlaybackController>,
+    recorder: State<'_, Recorder>,
+    options: Option<RecordingOptions>,
+) -> Result<(), String> {
+    recorder.start(
+        options.unwrap_or_default(),
+        controller.sender_config().layout,
+    )
+}
+
+/// 结束录制，返回可直接播放的按键列表
+#[tauri::command]
+fn stop_recording(
+    recorder: State<'_, Recorder>,
+) -> Result<Vec<keypress_simulator::KeyEvent>, String> {
+    recorder.stop()
+}
+
+#[tauri::command]
+fn is_recording(recorder: State<'_, Recorder>) -> bool {
+    recorder.is_recording()
+}
+
+#[tauri::command]
+fn list_midi_inputs() -> Result<Vec<String>, String> {
+    live_input::list_midi_inputs()
+}
+
+/// 开始实时 MIDI 输入，设备断开时发送 live_input://error 事件
+/// 运行期间定期和实时移调变化时发送 live://status 事件
+#[tauri::command]
+fn start_live_input(
+    app: AppHandle,
+    controller: State<'_, PlaybackController>,
+    live: State<'_, LiveInput>,
+    port_name: String,
+    mapping_settings: LiveMappingSettings,
+) -> Result<(), CommandError> {
+    ensure_input_permission()?;
+    let status_app = app.clone();
+    live.start(
+        &port_name,
+        mapping_settings,
+        controller.sender_builder(),
+        Arc::new(move |message: String| {
+            let _ = app.emit("live_input://error", message);
+        }),
+        Arc::new(move |status: LiveStatus| {
+            let _ = status_app.emit("live://status", status);
+        }),
+    )?;
+    Ok(())
+}
+
+/// 实时输入移调 delta_semitones 个半音（如 ±12 换八度），返回新的移调
+/// 已按住的音仍按原来的键松开
+#[tauri::command]
+fn live_transpose(live: State<'_, LiveInput>, delta_semitones: i32) -> Result<i32, String> {
+    live.transpose_by(delta_semitones)
+}
+
+/// 把实时输入的移调设为 semitones，没有运行时也可以预先设置
+#[tauri::command]
+fn live_set_transpose(live: State<'_, LiveInput>, semitones: i32) -> Result<i32, String> {
+    live.set_transpose(semitones)
+}
+
+#[tauri::command]
+fn stop_live_input(live: State<'_, LiveInput>) -> Result<(), String> {
+    live.stop()
+}
+
+/// 开始录制实时输入，options 省略时最多保留最近 30 分钟
+#[tauri::command]
+fn start_live_recording(
+    live: State<'_, LiveInput>,
+    options: Option<LiveRecordingOptions>,
+) -> Result<(), String> {
+    live.start_recording(options.unwrap_or_default())
+}
+
+/// 停止录制，返回实际发送的按键事件；给出 midi_path 时把收到的原始音符写成 MIDI 文件
+#[tauri::command]
+fn stop_live_recording(
+    live: State<'_, LiveInput>,
+    midi_path: Option<String>,
+) -> Result<LiveRecordingResult, String> {
+    live.stop_recording(midi_path.as_deref())
+}
+
+/// 解析和播放选项的默认值，命令没有给出某个选项时使用
+#[tauri::command]
+fn get_default_settings(defaults: State<'_, DefaultSettingsStore>) -> DefaultSettings {
+    defaults.get()
+}
+
+/// 保存默认值，取值范围与调用命令时的检查相同，无效时不修改
+#[tauri::command]
+fn set_default_settings(
+    defaults: State<'_, DefaultSettingsStore>,
+    settings: DefaultSettings,
+) -> Result<(), CommandError> {
+    defaults
+        .set(settings)
+        .map_err(CommandError::InvalidArgument)
+}
+
+/// 恢复出厂默认值，返回恢复后的设置
+#[tauri::command]
+fn reset_default_settings(
+    defaults: State<'_, DefaultSettingsStore>,
+) -> Result<DefaultSettings, String> {
+    defaults.reset()
+}
+
+// 用户预设保存在应用配置目录的 presets 子目录
+fn preset_store(app: &AppHandle) -> Result<PresetStore, String> {
+    let dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to resolve config directory: {}", e))?;
+    Ok(PresetStore::new(dir.join("presets")))
+}
+
+/// 保存设置预设，同名预设已存在时需要 overwrite 为 true
+#[tauri::command]
+fn save_preset(
+    app: AppHandle,
+    name: String,
+    settings: PresetSettings,
+    overwrite: Option<bool>,
+) -> Result<(), String> {
+    preset_store(&app)?.save(&name, settings, overwrite.unwrap_or(false))
+}
+
+#[tauri::command]
+fn load_preset(app: AppHandle, name: String) -> Result<PresetSettings, String> {
+    preset_store(&app)?.load(&name)
+}
+
+/// 列出内置预设和用户预设，内置预设在前
+#[tauri::command]
+fn list_presets(app: AppHandle) -> Result<Vec<PresetInfo>, String> {
+    Ok(preset_store(&app)?.list())
+}
+
+#[tauri::command]
+fn delete_preset(app: AppHandle, name: String) -> Result<(), String> {
+    preset_store(&app)?.delete(&name)
+}
+
+/// 设置按前台窗口标题选择预设的规则，按顺序匹配，第一个匹配的生效
+/// auto_apply 时点击播放会先应用检测到的预设中由后端负责的部分（键盘布局、目标窗口）
+#[tauri::command]
+fn set_profile_rules(
+    app: AppHandle,
+    detector: State<'_, ProfileDetector>,
+    rules: Vec<ProfileRule>,
+    auto_apply: bool,
+) {
+    detector.set_rules(app.clone(), rules, auto_apply);
+}
+
+/// 检查前台窗口并返回匹配的预设，没有匹配时返回 null
+#[tauri::command]
+fn detect_active_profile(
+    app: AppHandle,
+    detector: State<'_, ProfileDetector>,
+) -> Result<Option<ProfileMatch>, String> {
+    detector.update(&app).map_err(|e| e.to_string())
+}
+
+// 开启自动应用时，把检测到的预设中的键盘布局和目标窗口应用到后端
+// 按键映射由前端根据 profile://changed 事件应用
+fn apply_detected_profile(app: &AppHandle) -> Result<(), String> {
+    let detector = app.state::<ProfileDetector>();
+    if !detector.auto_apply() {
+        return Ok(());
+    }
+    let Some(profile) = detector.current() else {
+        return Ok(());
+    };
+    let preset = preset_store(app)?.load(&profile.preset)?;
+    app.state::<PlaybackController>()
+        .set_layout_translation(preset.layout);
+    let guard = app.state::<FocusGuard>();
+    guard.set_target(preset.target_window, guard.settings().auto_resume);
+    Ok(())
+}
+
+/// 按设置启动或关闭遥控服务，返回服务状态（含实际使用的令牌）
+#[tauri::command]
+async fn set_remote_control(
+    app: AppHandle,
+    remote: State<'_, RemoteServer>,
+    settings: RemoteSettings,
+) -> Result<RemoteStatus, String> {
+    remote.apply(app.clone(), settings).await
+}
+
+#[tauri::command]
+fn get_remote_control_status(remote: State<'_, RemoteServer>) -> RemoteStatus {
+    remote.status()
+}
+
+/// 检测系统当前键盘布局，无法识别时返回 null
+#[tauri::command]
+fn detect_keyboard_layout() -> Option<LayoutTranslation> {
+    uni_input::detect_keyboard_layout()
+}
+
+/// 播放到下一个小节、乐句或静音处再停止，max_wait_seconds（默认 5 秒）内没有边界时立即停止
+#[tauri::command]
+fn stop_at_boundary(
+    controller: State<'_, PlaybackController>,
+    boundary: StopBoundary,
+    max_wait_seconds: Option<f64>,
+) -> Result<(), CommandError> {
+    controller.stop_at_boundary(boundary, max_wait_seconds.unwrap_or(DEFAULT_SOFT_STOP_WAIT))
+}
+
+/// 设置播放速度倍率（0.25-4），替换 speed_ramp
+#[tauri::command]
+fn set_playback_speed(
+    controller: State<'_, PlaybackController>,
+    speed: f64,
+) -> Result<(), CommandError> {
+    controller.set_playback_speed(speed)
+}
+
+/// 播放中静音或取消静音一个事件分组（KeyEvent.group），返回当前的静音分组
+#[tauri::command]
+fn set_group_muted(
+    controller: State<'_, PlaybackController>,
+    group: String,
+    muted: bool,
+) -> Result<Vec<String>, CommandError> {
+    controller.set_group_muted(&group, muted)
+}
+
+/// 修改定时停止，seconds 从现在起计算；传 null 取消
+#[tauri::command]
+fn set_stop_timer(
+    controller: State<'_, PlaybackController>,
+    seconds: Option<f64>,
+) -> Result<(), CommandError> {
+    controller.set_stop_timer(seconds)
+}
+
+#[tauri::command]
+fn stop_playback(controller: State<'_, PlaybackController>) -> Result<(), CommandError> {
+    controller.stop()
+}
+
+#[tauri::command]
+fn pause_playback(controller: State<'_, PlaybackController>) -> Result<(), CommandError> {
+    controller.pause()
+}
+
+#[tauri::command]
+fn resume_playback(controller: State<'_, PlaybackController>) -> Result<(), CommandError> {
+    controller.resume()
+}
+
+#[tauri::command]
+fn seek_playback(
+    controller: State<'_, PlaybackController>,
+    position: f64,
+) -> Result<(), CommandError> {
+    controller.seek(position)
+}
+
+/// 暂停时拖动进度条：返回 position 前后 window_seconds 秒内的事件，并从 position 继续播放
+/// tap_preview 为 true 时轻点该位置正在发声的键
+#[tauri::command]
+fn preview_at(
+    controller: State<'_, PlaybackController>,
+    position: f64,
+    window_seconds: f64,
+    tap_preview: Option<bool>,
+) -> Result<Vec<keypress_simulator::KeyEvent>, CommandError> {
+    controller.preview_at(position, window_seconds, tap_preview.unwrap_or(false))
+}
+
+/// 编辑器改动后替换正在播放的事件列表，从当前位置继续，不重新开始播放
+#[tauri::command]
+fn update_playback_events(
+    controller: State<'_, PlaybackController>,
+    events: Vec<keypress_simulator::KeyEvent>,
+) -> Result<(), CommandError> {
+    controller.update_events(events)
+}
+
+#[tauri::command]
+fn skip_relative(
+    controller: State<'_, PlaybackController>,
+    seconds: f64,
+) -> Result<(), CommandError> {
+    controller.skip_relative(seconds)
+}
+
+/// 当前按住的按键，与 playback://keys_active 事件内容相同
+#[tauri::command]
+fn get_active_keys(controller: State<'_, PlaybackController>) -> Vec<String> {
+    controller.active_keys()
+}
+
+#[tauri::command]
+fn get_playback_status(controller: State<'_, PlaybackController>) -> PlaybackProgress {
+    controller.status()
+}
+
+/// 前端重新挂载（刷新、崩溃恢复）后取回断开期间错过的播放状态
+#[tauri::command]
+fn resync_playback_state(
+    controller: State<'_, PlaybackController>,
+    link: State<'_, FrontendLink>,
+    defaults: State<'_, DefaultSettingsStore>,
+) -> PlaybackState {
+    link.resync(&controller, &defaults.get().playback)
+}
+
+/// 前端断开时是否自动暂停播放，默认不暂停
+#[tauri::command]
+fn set_frontend_detach_policy(
+    link: State<'_, FrontendLink>,
+    policy: DetachPolicy,
+) -> Result<(), String> {
+    link.set_policy(policy)
+}
+
+#[tauri::command]
+fn get_frontend_detach_policy(link: State<'_, FrontendLink>) -> DetachPolicy {
+    link.policy()
+}
+
+#[tauri::command]
+fn get_last_playback_report(
+    controller: State<'_, PlaybackController>,
+) -> Option<playback_stats::PlaybackReport> {
+    controller.last_report()
 }
 
 #[tauri::command]
@@ -92,6 +1489,94 @@ async fn pick_mouse_coordinate() -> Result<(i32, i32), String> {
     mouse_simulator::pick_coordinate().await
 }
 
+/// 设置日志级别，设为 "debug" 时记录播放诊断（开始时的设置、延迟和失败的按键、结束时的统计）
+#[tauri::command]
+fn set_log_level(level: String) -> Result<(), String> {
+    session_log::set_level(&level)
+}
+
+/// 设置超大调度的磁盘暂存，新设置从下一次准备的调度起生效
+#[tauri::command]
+fn set_schedule_spill(settings: SpillSettings) -> Result<(), String> {
+    schedule_spill::set_settings(settings)
+}
+
+#[tauri::command]
+fn get_schedule_spill() -> SpillSettings {
+    schedule_spill::settings()
+}
+
+/// 播放诊断日志的最后 last_n_lines 行
+#[tauri::command]
+fn get_session_log(app: AppHandle, last_n_lines: usize) -> Result<Vec<String>, String> {
+    let dir = app
+        .path()
+        .app_log_dir()
+        .map_err(|e| format!("Failed to resolve log directory: {}", e))?;
+    session_log::tail(
+        &dir.join(format!("{}.log", session_log::FILE_NAME)),
+        last_n_lines,
+    )
+}
+
+/// 播放完成或出错时是否发桌面通知，默认开启
+#[tauri::command]
+fn set_playback_notifications(notifier: State<'_, PlaybackNotifier>, enabled: bool) {
+    notifier.set_enabled(enabled);
+}
+
+/// 每次按键时在本机播放提示音；没有音频设备时自动关闭，warning 中说明原因
+/// 打开设备可能需要一点时间，所以是异步命令
+#[tauri::command]
+async fn set_audio_feedback(
+    audio: State<'_, AudioFeedback>,
+    settings: AudioFeedbackSettings,
+) -> Result<AudioFeedbackStatus, String> {
+    audio.set_settings(settings)
+}
+
+#[tauri::command]
+fn get_audio_feedback(audio: State<'_, AudioFeedback>) -> AudioFeedbackStatus {
+    audio.status()
+}
+
+#[tauri::command]
+fn set_confirm_exit_during_playback(settings: State<'_, Mutex<AppSettings>>, enabled: bool) {
+    settings.lock().unwrap().confirm_exit_during_playback = enabled;
+}
+
+/// 前端确认退出后调用：停止播放并退出应用
+#[tauri::command]
+fn confirm_exit(app: AppHandle) {
+    stop_all_playback(&app);
+    app.exit(0);
+}
+
+/// 退出前停止所有播放，释放按住的键
+fn stop_all_playback(app: &AppHandle) {
+    if let Some(controller) = app.try_state::<PlaybackController>() {
+        if !controller.shutdown(keypress_simulator::SHUTDOWN_TIMEOUT) {
+            eprintln!("Playback thread did not stop in time");
+        }
+    }
+    if let Some(audio) = app.try_state::<AudioFeedback>() {
+        audio.shutdown();
+    }
+}
+
+/// 又启动了一个实例：聚焦已有窗口，参数里有文件时在这个实例中打开
+#[cfg(desktop)]
+fn on_second_instance(app: &AppHandle, argv: Vec<String>, cwd: String) {
+    tray::show_main_window(app);
+    let args = argv.get(1..).unwrap_or_default();
+    if let Some(file) = file_open::file_from_args(args, cwd.as_ref()) {
+        let app = app.clone();
+        std::thread::spawn(move || {
+            file_open::open(&app, file);
+        });
+    }
+}
+
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
 fn greet(name: &str) -> String {
@@ -100,27 +1585,298 @@ fn greet(name: &str) -> String {
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    tauri::Builder::default()
+    // 带 --parse 参数时以命令行模式运行，不启动界面
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if cli::requested(&args) {
+        std::process::exit(cli::run(&args));
+    }
+
+    let builder = tauri::Builder::default();
+    // 必须最先注册：第二个实例在这里把参数转发给已运行的实例后直接退出
+    // 锁由系统对象（Windows 命名互斥量、Linux D-Bus 名称、macOS 套接字）维护，进程崩溃后自动释放
+    #[cfg(desktop)]
+    let builder = builder.plugin(tauri_plugin_single_instance::init(on_second_instance));
+
+    builder
         .plugin(tauri_plugin_opener::init())
-        .plugin(tauri_plugin_log::Builder::default().build())
+        .plugin(
+            tauri_plugin_log::Builder::default()
+                // 插件本身不过滤级别，由 set_log_level 在运行时调整
+                .level(log::LevelFilter::Trace)
+                .max_file_size(2_000_000)
+                .targets([
+                    Target::new(TargetKind::Stdout),
+                    Target::new(TargetKind::LogDir { file_name: None })
+                        .filter(|metadata| metadata.target() != session_log::TARGET),
+                    // 播放诊断单独写到 playback.log，方便用户反馈问题时附上
+                    Target::new(TargetKind::LogDir {
+                        file_name: Some(session_log::FILE_NAME.to_string()),
+                    })
+                    .filter(|metadata| metadata.target() == session_log::TARGET),
+                ])
+                .build(),
+        )
         .plugin(tauri_plugin_os::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_window_state::Builder::default().build()) // Add this line
         .plugin(tauri_plugin_dialog::init()) // Add this line
         .plugin(tauri_plugin_global_shortcut::Builder::new().build())
+        .plugin(tauri_plugin_notification::init())
+        .setup(move |app| {
+            log::set_max_level(session_log::DEFAULT_LEVEL);
+            // 播放事件转发给前端和遥控客户端；转发在单独的线程上，前端断开或卡住不影响播放
+            let link = FrontendLink::default();
+            let handle = app.handle().clone();
+            let forward = link.relay(Arc::new(move |event: PlaybackEvent| {
+                if let Err(e) = handle.emit(event.name(), &event) {
+                    log::debug!("Failed to emit {}: {}", event.name(), e);
+                }
+                if let Some(remote) = handle.try_state::<RemoteServer>() {
+                    remote.publish(&event);
+                }
+                if let Some(notifier) = handle.try_state::<PlaybackNotifier>() {
+                    notifier.handle(&handle, &event);
+                }
+                #[cfg(desktop)]
+                if let Some(tray) = handle.try_state::<tray::Tray>() {
+                    tray.update(&event);
+                }
+                if let PlaybackEvent::Finished { .. } = event {
+                    if let Some(recorder) = handle.try_state::<Recorder>() {
+                        recorder.stop_practice();
+                    }
+                }
+            }));
+            app.manage(link);
+            let link_handle = app.handle().clone();
+            let audio_handle = app.handle().clone();
+            let controller = PlaybackController::new(Arc::new(keypress_simulator::create_sender))
+                .with_event_sink(Arc::new(move |event: PlaybackEvent| {
+                    link_handle.state::<FrontendLink>().record(&event);
+                    forward(event);
+                }))
+                .with_press_hook(Arc::new(move |key: &str| {
+                    if let Some(audio) = audio_handle.try_state::<AudioFeedback>() {
+                        audio.cue(key);
+                    }
+                }));
+            app.manage(controller);
+            app.manage(Mutex::new(AppSettings::default()));
+            app.manage(FocusGuard::default());
+            app.manage(Recorder::default());
+            app.manage(LiveInput::default());
+            app.manage(LibraryWatcher::default());
+            app.manage(FolderScanner::default());
+            app.manage(RemoteServer::default());
+            app.manage(PlaybackNotifier::default());
+            app.manage(AudioFeedback::default());
+            app.manage(ProfileDetector::default());
+            app.manage(CapabilityCache::default());
+            #[cfg(desktop)]
+            app.manage(tray::Tray::create(app.handle())?);
+            let data_dir = app.path().app_data_dir()?;
+            app.manage(RecentFiles::load(data_dir.join("recent_files.json")));
+            app.manage(SongLibrary::load(data_dir.join("song_library.json")));
+            app.manage(PendingFileOpen::default());
+            app.manage(ParseCancel::default());
+            app.manage(ParseCache::default());
+            app.manage(EventUploads::default());
+            app.manage(ExportWorker::default());
+            app.manage(Duet::default());
+            app.manage(WindowPickCancel::default());
+            app.manage(SessionRecovery::new(data_dir.join("session_recovery.json")));
+            schedule_spill::init(app.path().app_cache_dir()?.join("schedule_spill"));
+            let config_dir = app.path().app_config_dir()?;
+            app.manage(DefaultSettingsStore::load(
+                config_dir.join("default_settings.json"),
+            ));
+            spawn_session_autosave(app.handle().clone());
+            spawn_playback_watchdog(app.handle().clone());
+
+            // 启动时在后台探测当前后端，之后的查询直接使用缓存
+            let handle = app.handle().clone();
+            std::thread::spawn(move || {
+                let backend = handle.state::<PlaybackController>().sender_config().backend;
+                handle.state::<CapabilityCache>().get(backend);
+            });
+
+            // 双击 MIDI 文件启动时路径在启动参数里
+            let cwd = std::env::current_dir().unwrap_or_default();
+            if let Some(file) = file_open::file_from_args(&args, &cwd) {
+                let handle = app.handle().clone();
+                std::thread::spawn(move || {
+                    let outcome = file_open::open(&handle, file);
+                    handle.state::<PendingFileOpen>().set(outcome);
+                });
+            }
+            Ok(())
+        })
+        // 页面重新加载（包括渲染进程崩溃后恢复）期间前端收不到事件
+        .on_page_load(|webview, payload| {
+            if let Some(link) = webview.app_handle().try_state::<FrontendLink>() {
+                match payload.event() {
+                    PageLoadEvent::Started => link.detach(),
+                    PageLoadEvent::Finished => link.attach(),
+                }
+            }
+        })
+        .on_window_event(|window, event| {
+            if let WindowEvent::CloseRequested { api, .. } = event {
+                let app = window.app_handle();
+                let playing = app
+                    .try_state::<PlaybackController>()
+                    .is_some_and(|controller| controller.is_active());
+                let confirm = app
+                    .state::<Mutex<AppSettings>>()
+                    .lock()
+                    .unwrap()
+                    .confirm_exit_during_playback;
+
+                if playing && confirm {
+                    // 交给前端询问，用户确认后调用 confirm_exit
+                    api.prevent_close();
+                    let _ = app.emit("app://exit_requested", ());
+                } else {
+                    stop_all_playback(app);
+                }
+            }
+        })
         .invoke_handler(tauri::generate_handler![
             greet,
             parse_midi,
+            suggest_note_range,
+            evaluate_layouts,
+            cancel_parse,
+            take_pending_file_open,
+            get_recent_files,
+            clear_recent_files,
+            update_recent_file_settings,
+            watch_midi_folder,
+            unwatch_midi_folder,
+            scan_midi_folder,
+            cancel_scan,
+            library_search,
+            library_tag,
+            library_get,
+            set_remote_control,
+            get_remote_control_status,
             start_playback,
+            schedule_playback,
+            cancel_scheduled,
+            follow_duet,
+            cancel_duet_follow,
             stop_playback,
+            stop_at_boundary,
+            set_stop_timer,
+            set_group_muted,
+            set_playback_speed,
+            pause_playback,
+            resume_playback,
+            seek_playback,
+            update_playback_events,
+            preview_at,
+            skip_relative,
+            get_playback_status,
+            resync_playback_state,
+            set_frontend_detach_policy,
+            get_frontend_detach_policy,
+            get_active_keys,
+            get_last_playback_report,
+            test_keypress,
+            check_input_permission,
+            open_input_permission_settings,
+            set_input_backend,
+            get_input_backend,
+            probe_input_capabilities,
+            reset_input_backend,
+            release_all_keys,
+            save_session_state,
+            load_session_state,
+            discard_session_state,
+            set_layout_translation,
+            get_layout_translation,
+            detect_keyboard_layout,
+            start_recording,
+            stop_recording,
+            is_recording,
+            list_midi_inputs,
+            start_live_input,
+            stop_live_input,
+            live_transpose,
+            live_set_transpose,
+            start_live_recording,
+            stop_live_recording,
+            start_playback_midi,
+            list_midi_outputs,
+            get_default_settings,
+            set_default_settings,
+            reset_default_settings,
+            save_preset,
+            load_preset,
+            list_presets,
+            delete_preset,
+            set_profile_rules,
+            detect_active_profile,
+            queue_add,
+            queue_remove,
+            queue_list,
+            queue_clear,
+            start_queue,
+            skip_to_next,
             start_mouse_playback,
             stop_mouse_playback,
             pick_mouse_coordinate,
+            export_song,
+            import_song,
+            export_dry_run_report,
+            cancel_export,
+            begin_event_upload,
+            upload_event_chunk,
+            commit_events,
+            discard_events,
             get_windows,
+            list_windows,
+            pick_window_under_cursor,
+            cancel_window_pick,
             lock_window,
             unlock_window,
-            get_locked_window
+            get_locked_window,
+            set_confirm_exit_during_playback,
+            set_playback_notifications,
+            set_audio_feedback,
+            get_audio_feedback,
+            set_log_level,
+            set_schedule_spill,
+            get_schedule_spill,
+            get_session_log,
+            set_target_window,
+            set_self_focus_policy,
+            confirm_exit
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| match event {
+            RunEvent::Exit => {
+                stop_all_playback(app);
+                if let Some(watcher) = app.try_state::<LibraryWatcher>() {
+                    watcher.unwatch();
+                }
+                if let Some(remote) = app.try_state::<RemoteServer>() {
+                    remote.stop();
+                }
+                schedule_spill::cleanup();
+            }
+            // macOS 通过打开文件事件而不是启动参数传递文件，启动时的事件也可能早于前端加载
+            #[cfg(target_os = "macos")]
+            RunEvent::Opened { urls } => {
+                for path in urls.into_iter().filter_map(|url| url.to_file_path().ok()) {
+                    let app = app.clone();
+                    std::thread::spawn(move || {
+                        let outcome = file_open::open(&app, path);
+                        app.state::<PendingFileOpen>().set(outcome);
+                    });
+                }
+            }
+            _ => {}
+        });
 }
@@ -0,0 +1,229 @@
+use crate::midi_analyzer::{self, AnalyzerOptions, KeyMap, RawMidi, TrackInfo};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 最后一次变化后等待的静默时间，批量同步时合并为一次处理
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+// 持续有变化时最长的合并时间，避免一直不推送
+const MAX_BATCH_DELAY: Duration = Duration::from_secs(5);
+
+/// 新文件的简要信息
+#[derive(Debug, Clone, Serialize)]
+pub struct MidiSummary {
+    pub duration: f64, // 秒
+    pub note_count: usize,
+    pub top_key: Option<String>, // 按下次数最多的键，监视时给出了 key_map 才有
+    pub title: Option<String>,   // 第一个音轨的名称，见 track_title
+    // 任意 1 秒内开始的音符数的最大值，用作难度
+    pub peak_notes_per_second: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum LibraryEvent {
+    Added {
+        path: String,
+        summary: Option<MidiSummary>, // 文件尚未写完或无法解析时为 null
+    },
+    Removed {
+        path: String,
+    },
+    Changed {
+        path: String,
+        summary: Option<MidiSummary>,
+    },
+}
+
+impl LibraryEvent {
+    /// 对应的前端事件名
+    pub fn name(&self) -> &'static str {
+        match self {
+            LibraryEvent::Added { .. } => "library://added",
+            LibraryEvent::Removed { .. } => "library://removed",
+            LibraryEvent::Changed { .. } => "library://changed",
+        }
+    }
+}
+
+/// 库事件回调，由 lib.rs 转发为 Tauri 事件
+pub type LibrarySink = Arc<dyn Fn(LibraryEvent) + Send + Sync>;
+
+/// 与前端文件列表相同的过滤规则
+pub fn is_midi(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext == "mid" || ext == "midi")
+}
+
+fn summarize(path: &Path, key_map: Option<&KeyMap>) -> Option<MidiSummary> {
+    let raw = midi_analyzer::read_midi_file(path.to_str()?, None, &mut |_| true).ok()?;
+    Some(summarize_raw(&raw, key_map))
+}
+
+/// 歌曲标题：第一个有音符的音轨的名称，没有名称（或是默认的 "Track N"）时为 None
+pub fn track_title(tracks: &[TrackInfo]) -> Option<String> {
+    let track = tracks.first()?;
+    let name = track.name.trim();
+    (!name.is_empty() && name != format!("Track {}", track.id)).then(|| name.to_string())
+}
+
+// onsets 已排序
+fn peak_per_second(onsets: &[f64]) -> usize {
+    let mut start = 0;
+    let mut peak = 0;
+    for (end, &time) in onsets.iter().enumerate() {
+        while time - onsets[start] >= 1.0 {
+            start += 1;
+        }
+        peak = peak.max(end - start + 1);
+    }
+    peak
+}
+
+/// 按默认解析选项统计时长、音符数，给出 key_map 时附带最常用的键
+pub fn summarize_raw(raw: &RawMidi, key_map: Option<&KeyMap>) -> MidiSummary {
+    let analysis = midi_analyzer::analyze_raw(raw, &AnalyzerOptions::default(), key_map, false);
+    let mut onsets: Vec<f64> = analysis
+        .events
+        .iter()
+        .filter(|e| e.type_ == "note_on")
+        .map(|e| e.time)
+        .collect();
+    onsets.sort_by(f64::total_cmp);
+    MidiSummary {
+        duration: analysis.events.iter().map(|e| e.end).fold(0.0, f64::max),
+        note_count: onsets.len(),
+        top_key: analysis.top_keys.into_iter().next(),
+        title: track_title(&analysis.tracks),
+        peak_notes_per_second: peak_per_second(&onsets),
+    }
+}
+
+fn scan(dir: &Path) -> HashSet<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| is_midi(path))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// 合并后的变化按文件当前是否存在判断类型，不依赖各平台不同的事件种类
+fn flush(
+    pending: &mut HashSet<PathBuf>,
+    known: &mut HashSet<PathBuf>,
+    key_map: Option<&KeyMap>,
+    sink: &LibrarySink,
+) {
+    for path in pending.drain() {
+        let display = path.to_string_lossy().to_string();
+        let event = match (path.is_file(), known.contains(&path)) {
+            (true, false) => {
+                known.insert(path.clone());
+                LibraryEvent::Added {
+                    path: display,
+                    summary: summarize(&path, key_map),
+                }
+            }
+            (true, true) => LibraryEvent::Changed {
+                path: display,
+                summary: summarize(&path, key_map),
+            },
+            (false, true) => {
+                known.remove(&path);
+                LibraryEvent::Removed { path: display }
+            }
+            (false, false) => continue,
+        };
+        sink(event);
+    }
+}
+
+// 去抖线程；监听器释放后通道关闭，线程随之退出
+fn run_debouncer(
+    mut known: HashSet<PathBuf>,
+    rx: mpsc::Receiver<Vec<PathBuf>>,
+    key_map: Option<KeyMap>,
+    sink: LibrarySink,
+) {
+    let mut pending = HashSet::new();
+    let mut batch_started = Instant::now();
+
+    loop {
+        let received = if pending.is_empty() {
+            rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+        } else {
+            let remaining = MAX_BATCH_DELAY.saturating_sub(batch_started.elapsed());
+            rx.recv_timeout(DEBOUNCE.min(remaining))
+        };
+        match received {
+            Ok(paths) => {
+                if pending.is_empty() {
+                    batch_started = Instant::now();
+                }
+                pending.extend(paths.into_iter().filter(|path| is_midi(path)));
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                flush(&mut pending, &mut known, key_map.as_ref(), &sink)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// 监视 MIDI 文件夹，新增、删除或修改文件时推送事件
+/// 与前端的一次性扫描并存，只负责增量变化
+#[derive(Default)]
+pub struct LibraryWatcher {
+    watcher: Mutex<Option<RecommendedWatcher>>,
+}
+
+impl LibraryWatcher {
+    /// 开始监视 dir，已在监视其他文件夹时先停止
+    /// key_map 为当前的按键映射，给出时摘要中附带最常用的键
+    pub fn watch(
+        &self,
+        dir: &str,
+        key_map: Option<KeyMap>,
+        sink: LibrarySink,
+    ) -> Result<(), String> {
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            return Err(format!("Not a directory: {}", dir.display()));
+        }
+
+        // 先记下已有文件，之后的变化才区分新增和修改
+        let known = scan(&dir);
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(
+            move |result: notify::Result<notify::Event>| match result {
+                Ok(event) => {
+                    let _ = tx.send(event.paths);
+                }
+                Err(e) => eprintln!("Folder watch error: {}", e),
+            },
+        )
+        .map_err(|e| format!("Failed to create folder watcher: {}", e))?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(|e| format!("Failed to watch {}: {}", dir.display(), e))?;
+
+        // 替换旧的监听器，旧的去抖线程随通道关闭退出
+        *self.watcher.lock().unwrap() = Some(watcher);
+        thread::spawn(move || run_debouncer(known, rx, key_map, sink));
+        Ok(())
+    }
+
+    pub fn unwatch(&self) {
+        self.watcher.lock().unwrap().take();
+    }
+}
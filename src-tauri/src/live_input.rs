@@ -0,0 +1,428 @@
+use crate::error::CommandError;
+use crate::keypress_simulator::KeySender;
+use crate::live_recording::{LiveRecording, LiveRecordingOptions, LiveRecordingResult};
+use crate::midi_analyzer::{apply_black_key_mode, normalize_key_map, BlackKeyMode};
+use midir::{MidiInput, MidiInputConnection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CLIENT_NAME: &str = "OpenGamesAutoPlay";
+
+// 检查设备是否仍然连接的间隔
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 实时移调的上下限（半音）
+pub const MAX_LIVE_TRANSPOSE: i32 = 48;
+
+/// 实时输入出错时的回调，由 lib.rs 转发为 Tauri 事件
+pub type ErrorSink = Arc<dyn Fn(String) + Send + Sync>;
+
+/// 实时输入状态的回调，运行期间每次检查设备时和移调变化时调用
+pub type StatusSink = Arc<dyn Fn(LiveStatus) + Send + Sync>;
+
+/// 实时输入的当前状态
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveStatus {
+    pub port_name: String,
+    pub transpose: i32,       // 实时移调，叠加在映射设置的 transpose/octave 上
+    pub held_keys: usize,     // 当前按住的键数
+    pub dropped_notes: usize, // 本次会话中超出音域或没有映射而没有按下的音
+}
+
+/// 移调后超出 min_note-max_note 的音的处理
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutOfRangePolicy {
+    #[default]
+    Drop, // 不按
+    Fold, // 按八度移回音域内，音域不足一个八度时仍可能落空
+}
+
+/// 实时输入的音符映射设置，与分析器和前端播放使用同一套规则
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LiveMappingSettings {
+    pub note_to_key: HashMap<u8, String>,
+    pub min_note: u8,
+    pub max_note: u8,
+    pub transpose: i32,
+    pub octave: i32,
+    pub black_key_mode: BlackKeyMode,
+    pub out_of_range: OutOfRangePolicy,
+}
+
+impl Default for LiveMappingSettings {
+    fn default() -> Self {
+        Self {
+            note_to_key: HashMap::new(),
+            min_note: 48,
+            max_note: 83,
+            transpose: 0,
+            octave: 0,
+            black_key_mode: BlackKeyMode::SupportBlackKey,
+            out_of_range: OutOfRangePolicy::Drop,
+        }
+    }
+}
+
+impl LiveMappingSettings {
+    /// 音符对应的按键，live_transpose 为实时移调；超出范围（按 out_of_range 处理后）或未映射时返回 None
+    pub fn key_for(&self, note: u8, live_transpose: i32) -> Option<&str> {
+        let mut note = apply_black_key_mode(note, self.black_key_mode) as i32
+            + self.transpose
+            + self.octave * 12
+            + live_transpose;
+        let (min, max) = (self.min_note as i32, self.max_note as i32);
+        if self.out_of_range == OutOfRangePolicy::Fold && min <= max {
+            while note < min {
+                note += 12;
+            }
+            while note > max {
+                note -= 12;
+            }
+        }
+        if note < min || note > max {
+            return None;
+        }
+        self.note_to_key.get(&(note as u8)).map(String::as_str)
+    }
+}
+
+// at 为收到消息的时间，录制时使用
+enum NoteCommand {
+    On {
+        note: u8,
+        velocity: u8,
+        channel: u8,
+        at: Instant,
+    },
+    Off {
+        note: u8,
+        channel: u8,
+        at: Instant,
+    },
+}
+
+/// 解析 MIDI 消息中的音符开关，velocity 为 0 的 note on 视为 note off
+fn parse_note(message: &[u8]) -> Option<NoteCommand> {
+    let at = Instant::now();
+    match *message {
+        [status, note, velocity] if status & 0xF0 == 0x90 && velocity > 0 => {
+            Some(NoteCommand::On {
+                note,
+                velocity,
+                channel: status & 0x0F,
+                at,
+            })
+        }
+        [status, note, _] if status & 0xF0 == 0x90 || status & 0xF0 == 0x80 => {
+            Some(NoteCommand::Off {
+                note,
+                channel: status & 0x0F,
+                at,
+            })
+        }
+        _ => None,
+    }
+}
+
+pub fn list_midi_inputs() -> Result<Vec<String>, String> {
+    let input = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+    Ok(input
+        .ports()
+        .iter()
+        .filter_map(|port| input.port_name(port).ok())
+        .collect())
+}
+
+fn port_connected(port_name: &str) -> bool {
+    // 查询失败时不当作断开，避免误停
+    list_midi_inputs()
+        .map(|names| names.iter().any(|name| name == port_name))
+        .unwrap_or(true)
+}
+
+// 会话和状态推送共享的计数
+#[derive(Default)]
+struct LiveCounters {
+    held_keys: AtomicUsize,
+    dropped_notes: AtomicUsize,
+}
+
+// 按键线程：直接调用发送后端，不经过播放调度
+// 同一按键可能对应多个音符（如黑键转白键），按引用计数按下/释放
+// 按下时记下音符对应的键，松开时按这个键释放，期间改变移调也不会让键卡住
+// 正在录制时每个音符连同实际按下的键（没有按下时为 None）一起记入 recording
+fn run_keys(
+    mut sender: Box<dyn KeySender>,
+    settings: LiveMappingSettings,
+    transpose: Arc<AtomicI32>,
+    counters: Arc<LiveCounters>,
+    recording: Arc<Mutex<Option<LiveRecording>>>,
+    rx: mpsc::Receiver<NoteCommand>,
+) {
+    let mut notes: HashMap<u8, String> = HashMap::new();
+    let mut held: HashMap<String, usize> = HashMap::new();
+    let record = |record: &dyn Fn(&mut LiveRecording)| {
+        if let Some(recording) = recording.lock().unwrap().as_mut() {
+            record(recording);
+        }
+    };
+
+    for command in rx {
+        match command {
+            NoteCommand::On {
+                note,
+                velocity,
+                channel,
+                at,
+            } => {
+                let mapped = settings.key_for(note, transpose.load(Ordering::SeqCst));
+                let sent = mapped.filter(|_| !notes.contains_key(&note));
+                record(&|recording| {
+                    recording.note_on(at, note, velocity, channel, sent.map(str::to_string))
+                });
+                let Some(key) = mapped else {
+                    counters.dropped_notes.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+                if sent.is_none() {
+                    continue;
+                }
+                notes.insert(note, key.to_string());
+                let count = held.entry(key.to_string()).or_insert(0);
+                *count += 1;
+                if *count == 1 {
+                    if let Err(e) = sender.press(key) {
+                        eprintln!("Live input failed to press {}: {}", key, e);
+                    }
+                }
+                counters.held_keys.store(held.len(), Ordering::Relaxed);
+            }
+            NoteCommand::Off { note, channel, at } => {
+                record(&|recording| recording.note_off(at, note, channel));
+                let Some(key) = notes.remove(&note) else {
+                    continue;
+                };
+                if let Some(count) = held.get_mut(&key) {
+                    *count -= 1;
+                    if *count == 0 {
+                        held.remove(&key);
+                        if let Err(e) = sender.release(&key) {
+                            eprintln!("Live input failed to release {}: {}", key, e);
+                        }
+                    }
+                }
+                counters.held_keys.store(held.len(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    // 会话结束（停止或设备断开）时释放所有仍按住的键
+    for key in held.keys() {
+        let _ = sender.release(key);
+    }
+    counters.held_keys.store(0, Ordering::Relaxed);
+    record(&|recording| recording.release_all(Instant::now()));
+}
+
+struct Session {
+    // 连接关闭时 MIDI 回调持有的发送端随之释放，按键线程收尾退出
+    connection: MidiInputConnection<()>,
+    stopped: Arc<AtomicBool>,
+    port_name: String,
+    counters: Arc<LiveCounters>,
+    on_status: StatusSink,
+}
+
+impl Session {
+    fn close(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.connection.close();
+    }
+}
+
+impl Session {
+    fn status(&self, transpose: i32) -> LiveStatus {
+        LiveStatus {
+            port_name: self.port_name.clone(),
+            transpose,
+            held_keys: self.counters.held_keys.load(Ordering::Relaxed),
+            dropped_notes: self.counters.dropped_notes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 实时 MIDI 输入：把外接 MIDI 键盘的音符实时转换为游戏按键
+#[derive(Default)]
+pub struct LiveInput {
+    session: Arc<Mutex<Option<Session>>>,
+    // 实时移调跨会话保留，没有运行时也可以预先设置
+    transpose: Arc<AtomicI32>,
+    // 正在进行或会话已结束但还没取走的录制
+    recording: Arc<Mutex<Option<LiveRecording>>>,
+}
+
+impl LiveInput {
+    pub fn start<F>(
+        &self,
+        port_name: &str,
+        mut settings: LiveMappingSettings,
+        create_sender: F,
+        on_error: ErrorSink,
+        on_status: StatusSink,
+    ) -> Result<(), String>
+    where
+        F: FnOnce() -> Result<Box<dyn KeySender>, CommandError> + Send + 'static,
+    {
+        let mut session = self.session.lock().unwrap();
+        if session.is_some() {
+            return Err("Live input already running".to_string());
+        }
+        settings.note_to_key = normalize_key_map(&settings.note_to_key)?;
+
+        let input = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+        let port = input
+            .ports()
+            .into_iter()
+            .find(|port| input.port_name(port).ok().as_deref() == Some(port_name))
+            .ok_or_else(|| format!("MIDI input not found: {}", port_name))?;
+
+        // 发送后端在按键线程内创建，等创建结果返回后再连接设备
+        let (tx, rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let counters = Arc::new(LiveCounters::default());
+        let transpose = Arc::clone(&self.transpose);
+        let key_counters = Arc::clone(&counters);
+        let recording = Arc::clone(&self.recording);
+        thread::spawn(move || match create_sender() {
+            Ok(sender) => {
+                let _ = ready_tx.send(Ok(()));
+                run_keys(sender, settings, transpose, key_counters, recording, rx);
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e.to_string()));
+            }
+        });
+        ready_rx
+            .recv()
+            .map_err(|_| "Live input thread exited unexpectedly".to_string())??;
+
+        let connection = input
+            .connect(
+                &port,
+                "live-input",
+                move |_, message, _| {
+                    if let Some(command) = parse_note(message) {
+                        let _ = tx.send(command);
+                    }
+                },
+                (),
+            )
+            .map_err(|e| format!("Failed to connect to {}: {}", port_name, e))?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        *session = Some(Session {
+            connection,
+            stopped: Arc::clone(&stopped),
+            port_name: port_name.to_string(),
+            counters,
+            on_status,
+        });
+
+        // 部分平台不通知设备拔出，定期检查端口是否还在，顺带推送状态
+        let sessions = Arc::clone(&self.session);
+        let transpose = Arc::clone(&self.transpose);
+        let port_name = port_name.to_string();
+        thread::spawn(move || loop {
+            thread::sleep(DEVICE_POLL_INTERVAL);
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            if port_connected(&port_name) {
+                if let Some(session) = sessions.lock().unwrap().as_ref() {
+                    (session.on_status)(session.status(transpose.load(Ordering::SeqCst)));
+                }
+                continue;
+            }
+            let mut current = sessions.lock().unwrap();
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Some(session) = current.take() {
+                session.close();
+            }
+            on_error(format!("MIDI device disconnected: {}", port_name));
+            break;
+        });
+        Ok(())
+    }
+
+    /// 在当前的实时移调上加 delta 个半音，返回新的移调
+    /// 之后按下的音按新的移调映射，已按住的音仍按原来的键松开
+    pub fn transpose_by(&self, delta: i32) -> Result<i32, String> {
+        self.update_transpose(|current| current.checked_add(delta))
+    }
+
+    /// 把实时移调设为 semitones，返回新的移调
+    pub fn set_transpose(&self, semitones: i32) -> Result<i32, String> {
+        self.update_transpose(|_| Some(semitones))
+    }
+
+    fn update_transpose(&self, update: impl Fn(i32) -> Option<i32>) -> Result<i32, String> {
+        let valid = |value: &i32| (-MAX_LIVE_TRANSPOSE..=MAX_LIVE_TRANSPOSE).contains(value);
+        let previous = self
+            .transpose
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                update(current).filter(valid)
+            })
+            .map_err(|current| {
+                format!(
+                    "Live transpose must stay within -{0} to {0} semitones (currently {1})",
+                    MAX_LIVE_TRANSPOSE, current
+                )
+            })?;
+        let transpose = update(previous).unwrap_or(previous);
+        if let Some(session) = self.session.lock().unwrap().as_ref() {
+            (session.on_status)(session.status(transpose));
+        }
+        Ok(transpose)
+    }
+
+    /// 开始录制实时输入，需要实时输入正在运行
+    pub fn start_recording(&self, options: LiveRecordingOptions) -> Result<(), String> {
+        options.validate()?;
+        if self.session.lock().unwrap().is_none() {
+            return Err("Live input is not running".to_string());
+        }
+        let mut recording = self.recording.lock().unwrap();
+        if recording.is_some() {
+            return Err("Live input is already being recorded".to_string());
+        }
+        *recording = Some(LiveRecording::new(&options));
+        Ok(())
+    }
+
+    /// 停止录制并返回结果，给出 midi_path 时同时写出原始音符的 MIDI 文件
+    /// 实时输入已停止或设备已断开时返回到那时为止的录制
+    pub fn stop_recording(&self, midi_path: Option<&str>) -> Result<LiveRecordingResult, String> {
+        let recording = self
+            .recording
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| "Live input is not being recorded".to_string())?;
+        recording.finish(Instant::now(), midi_path)
+    }
+
+    pub fn stop(&self) -> Result<(), String> {
+        let session = self.session.lock().unwrap().take();
+        session
+            .map(Session::close)
+            .ok_or_else(|| "Live input is not running".to_string())
+    }
+}
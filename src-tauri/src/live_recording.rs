@@ -0,0 +1,250 @@
+//! 实时输入的录制：记下收到的音符和实际发送的按键，结束时返回按键事件，需要时把原始音符写成 MIDI 文件
+//! 缓冲区只保留最近 max_duration_seconds 秒，忘了停止也不会一直占用内存
+
+use crate::keypress_simulator::KeyEvent;
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// 默认保留的录制时长（秒）
+pub const DEFAULT_MAX_DURATION_SECONDS: f64 = 1800.0;
+
+/// max_duration_seconds 的上限（秒）
+pub const MAX_DURATION_SECONDS: f64 = 4.0 * 3600.0;
+
+// 写出的 MIDI 文件固定 120 BPM，每拍 480 tick，即每秒 960 tick
+const TICKS_PER_BEAT: u16 = 480;
+const TEMPO_MICROSECONDS: u32 = 500_000;
+const TICKS_PER_SECOND: f64 = 960.0;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LiveRecordingOptions {
+    // 只保留最近这么多秒，更早的音符丢弃并在结果中标记 truncated
+    pub max_duration_seconds: f64,
+}
+
+impl Default for LiveRecordingOptions {
+    fn default() -> Self {
+        Self {
+            max_duration_seconds: DEFAULT_MAX_DURATION_SECONDS,
+        }
+    }
+}
+
+impl LiveRecordingOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1.0..=MAX_DURATION_SECONDS).contains(&self.max_duration_seconds) {
+            return Err(format!(
+                "max_duration_seconds must be within 1-{}, got {}",
+                MAX_DURATION_SECONDS, self.max_duration_seconds
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// 没有发送给游戏的音（超出音域、没有映射，或同一音符仍按住时的重复按下）
+#[derive(Debug, Clone, Serialize)]
+pub struct DroppedNote {
+    pub time: f64, // 秒，与 events 同一时间轴
+    pub duration: f64,
+    pub note: u8,
+    pub velocity: u8,
+    pub channel: u8,
+}
+
+/// 一次录制的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveRecordingResult {
+    pub events: Vec<KeyEvent>, // 实际发送的按键，时间相对第一个音
+    pub dropped: Vec<DroppedNote>,
+    pub duration: f64,
+    pub truncated: bool,           // 超出 max_duration_seconds，开头的部分已丢弃
+    pub midi_path: Option<String>, // 写出了 MIDI 文件时的路径
+}
+
+#[derive(Debug)]
+struct RecordedNote {
+    start: Instant,
+    end: Option<Instant>, // 还按着时为 None
+    note: u8,
+    velocity: u8,
+    channel: u8,
+    key: Option<String>, // 实际按下的键，没有发送时为 None
+}
+
+/// 录制缓冲区，由按键线程在处理每个音符时写入
+pub struct LiveRecording {
+    max_duration: f64,
+    notes: VecDeque<RecordedNote>,
+    truncated: bool,
+}
+
+impl LiveRecording {
+    pub fn new(options: &LiveRecordingOptions) -> Self {
+        Self {
+            max_duration: options.max_duration_seconds,
+            notes: VecDeque::new(),
+            truncated: false,
+        }
+    }
+
+    /// 记录一个按下的音，key 为实际按下的键
+    pub fn note_on(
+        &mut self,
+        at: Instant,
+        note: u8,
+        velocity: u8,
+        channel: u8,
+        key: Option<String>,
+    ) {
+        while self
+            .notes
+            .front()
+            .is_some_and(|front| at.duration_since(front.start).as_secs_f64() > self.max_duration)
+        {
+            self.notes.pop_front();
+            self.truncated = true;
+        }
+        self.notes.push_back(RecordedNote {
+            start: at,
+            end: None,
+            note,
+            velocity,
+            channel,
+            key,
+        });
+    }
+
+    /// 松开同一通道上最近按下、还没松开的同一个音
+    pub fn note_off(&mut self, at: Instant, note: u8, channel: u8) {
+        if let Some(recorded) = self
+            .notes
+            .iter_mut()
+            .rev()
+            .find(|n| n.end.is_none() && n.note == note && n.channel == channel)
+        {
+            recorded.end = Some(at);
+        }
+    }
+
+    /// 会话结束时松开所有还按着的音
+    pub fn release_all(&mut self, at: Instant) {
+        for recorded in self.notes.iter_mut().filter(|n| n.end.is_none()) {
+            recorded.end = Some(at);
+        }
+    }
+
+    /// 结束录制；给出 midi_path 时把全部音符（包括没有发送的）写成 MIDI 文件
+    pub fn finish(
+        mut self,
+        at: Instant,
+        midi_path: Option<&str>,
+    ) -> Result<LiveRecordingResult, String> {
+        self.release_all(at);
+        let Some(origin) = self.notes.front().map(|n| n.start) else {
+            return Err("Nothing was recorded".to_string());
+        };
+        let seconds = |instant: Instant| instant.saturating_duration_since(origin).as_secs_f64();
+
+        let mut events = Vec::new();
+        let mut dropped = Vec::new();
+        let mut duration: f64 = 0.0;
+        for recorded in &self.notes {
+            let time = seconds(recorded.start);
+            let end = seconds(recorded.end.unwrap_or(at));
+            duration = duration.max(end);
+            match &recorded.key {
+                Some(key) => events.push(KeyEvent {
+                    time,
+                    key: key.clone(),
+                    duration: end - time,
+                    group: None,
+                    note: Some(recorded.note),
+                    chord: None,
+                }),
+                None => dropped.push(DroppedNote {
+                    time,
+                    duration: end - time,
+                    note: recorded.note,
+                    velocity: recorded.velocity,
+                    channel: recorded.channel,
+                }),
+            }
+        }
+
+        if let Some(path) = midi_path {
+            self.write_midi(path, origin, at)?;
+        }
+        Ok(LiveRecordingResult {
+            events,
+            dropped,
+            duration,
+            truncated: self.truncated,
+            midi_path: midi_path.map(str::to_string),
+        })
+    }
+
+    // 单音轨 SMF，保留原始的音高、力度和通道
+    fn write_midi(&self, path: &str, origin: Instant, at: Instant) -> Result<(), String> {
+        let ticks = |instant: Instant| {
+            (instant.saturating_duration_since(origin).as_secs_f64() * TICKS_PER_SECOND).round()
+                as u32
+        };
+        // (tick, 是否按下, 消息)，同一 tick 先松开再按下
+        let mut messages: Vec<(u32, bool, u8, MidiMessage)> = Vec::new();
+        for recorded in &self.notes {
+            let key = u7::new(recorded.note.min(127));
+            messages.push((
+                ticks(recorded.start),
+                true,
+                recorded.channel,
+                MidiMessage::NoteOn {
+                    key,
+                    vel: u7::new(recorded.velocity.clamp(1, 127)),
+                },
+            ));
+            messages.push((
+                ticks(recorded.end.unwrap_or(at)),
+                false,
+                recorded.channel,
+                MidiMessage::NoteOff {
+                    key,
+                    vel: u7::new(0),
+                },
+            ));
+        }
+        messages.sort_by_key(|&(tick, on, _, _)| (tick, on));
+
+        let mut track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(TEMPO_MICROSECONDS))),
+        }];
+        let mut last = 0;
+        for (tick, _, channel, message) in messages {
+            track.push(TrackEvent {
+                delta: u28::new(tick - last),
+                kind: TrackEventKind::Midi {
+                    channel: u4::new(channel & 0x0F),
+                    message,
+                },
+            });
+            last = tick;
+        }
+        track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(TICKS_PER_BEAT)),
+        ));
+        smf.tracks.push(track);
+        smf.save(path)
+            .map_err(|e| format!("Failed to write MIDI file {}: {}", path, e))
+    }
+}
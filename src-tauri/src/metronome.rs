@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+
+/// 预备拍设置，速度和拍号由前端从 MidiAnalysis 中取得
+/// 节拍从播放起点（有弱起时从第一个完整小节）开始计算，只使用开头的速度
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CountInConfig {
+    pub measures: u32,             // 预备拍小节数
+    pub bpm: f64,                  // 每分钟四分音符数
+    pub beats_per_measure: u32,    // 拍号分子
+    pub beat_unit: u32,            // 拍号分母
+    pub click_key: Option<String>, // 每拍按下的键
+    pub silent: bool,              // 不按键，只推送 playback://beat
+    pub continue_beats: bool,      // 正式播放时继续推送 playback://beat
+    // 弱起歌曲第一个完整小节的歌曲时间（MidiAnalysis::pickup 的 downbeat）
+    // 给出且在播放起点之后不到预备拍长度时，节拍以它为第 1 小节的第 1 拍，弱起占去预备拍的最后几拍
+    pub downbeat: Option<f64>,
+}
+
+impl Default for CountInConfig {
+    fn default() -> Self {
+        Self {
+            measures: 1,
+            bpm: 120.0,
+            beats_per_measure: 4,
+            beat_unit: 4,
+            click_key: None,
+            silent: false,
+            continue_beats: false,
+            downbeat: None,
+        }
+    }
+}
+
+impl CountInConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=16).contains(&self.measures) {
+            return Err(format!(
+                "measures must be within 1-16, got {}",
+                self.measures
+            ));
+        }
+        if !(self.bpm.is_finite() && self.bpm >= 10.0 && self.bpm <= 400.0) {
+            return Err(format!("bpm must be within 10-400, got {}", self.bpm));
+        }
+        if !(1..=32).contains(&self.beats_per_measure) {
+            return Err(format!(
+                "beats_per_measure must be within 1-32, got {}",
+                self.beats_per_measure
+            ));
+        }
+        if !self.beat_unit.is_power_of_two() || self.beat_unit > 32 {
+            return Err(format!(
+                "beat_unit must be a power of two up to 32, got {}",
+                self.beat_unit
+            ));
+        }
+        if let Some(downbeat) = self.downbeat {
+            if !(downbeat.is_finite() && downbeat >= 0.0) {
+                return Err(format!(
+                    "downbeat must be a time in seconds, got {}",
+                    downbeat
+                ));
+            }
+        }
+        if !self.silent && self.click_key.as_deref().is_none_or(str::is_empty) {
+            return Err("click_key is required unless silent is set".to_string());
+        }
+        Ok(())
+    }
+
+    /// 一拍的秒数，分母为 8 时一拍是八分音符
+    pub fn beat_seconds(&self) -> f64 {
+        60.0 / self.bpm * 4.0 / self.beat_unit as f64
+    }
+
+    pub fn count_in_beats(&self) -> u32 {
+        self.measures * self.beats_per_measure
+    }
+
+    /// 从 start 开始播放时节拍网格的原点：第一个完整小节在起点之后不到预备拍长度时取它，否则取 start
+    pub fn grid_origin(&self, start: f64) -> f64 {
+        let length = self.count_in_beats() as f64 * self.beat_seconds();
+        self.downbeat
+            .filter(|&downbeat| downbeat > start && downbeat - start < length)
+            .unwrap_or(start)
+    }
+}
+
+/// 推送给前端的节拍
+/// 预备拍的 measure 为 0 或负数，正式播放从第 1 小节开始
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Beat {
+    pub measure: i64,
+    pub beat: u32, // 小节内第几拍，从 1 开始
+    pub count_in: bool,
+    pub position: f64, // 歌曲时间
+}
+
+/// 以播放起点为原点的节拍网格，第 0 拍落在起点上
+pub struct BeatGrid {
+    origin: f64,
+    beat_seconds: f64,
+    beats_per_measure: u32,
+    next: i64,
+}
+
+impl BeatGrid {
+    pub fn new(config: &CountInConfig, origin: f64) -> Self {
+        Self {
+            origin,
+            beat_seconds: config.beat_seconds(),
+            beats_per_measure: config.beats_per_measure,
+            next: 0,
+        }
+    }
+
+    /// 第 index 拍的歌曲时间，预备拍的 index 为负
+    pub fn time_of(&self, index: i64) -> f64 {
+        self.origin + index as f64 * self.beat_seconds
+    }
+
+    /// 跳转后从 position 之后（含）的第一拍继续
+    pub fn reset(&mut self, position: f64) {
+        // 减去一点余量，避免浮点误差把正好落在拍上的位置算到下一拍
+        self.next = ((position - self.origin) / self.beat_seconds - 1e-9).ceil() as i64;
+    }
+
+    pub fn next_time(&self) -> f64 {
+        self.time_of(self.next)
+    }
+
+    /// 取出下一拍并前进
+    pub fn advance(&mut self) -> Beat {
+        let index = self.next;
+        self.next += 1;
+        let per_measure = self.beats_per_measure as i64;
+        Beat {
+            measure: index.div_euclid(per_measure) + 1,
+            beat: index.rem_euclid(per_measure) as u32 + 1,
+            count_in: index < 0,
+            position: self.time_of(index),
+        }
+    }
+}
+
+/// 歌曲开头的速度和拍号，由前端从 MidiAnalysis 中取得
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Meter {
+    pub bpm: f64,
+    pub time_signature: (u8, u8), // (分子, 分母)
+}
+
+impl Meter {
+    pub fn validate(&self) -> Result<(), String> {
+        let (beats, unit) = self.time_signature;
+        if !(self.bpm.is_finite() && self.bpm >= 10.0 && self.bpm <= 400.0) {
+            return Err(format!("bpm must be within 10-400, got {}", self.bpm));
+        }
+        if beats == 0 || !unit.is_power_of_two() || unit > 32 {
+            return Err(format!("Invalid time signature {}/{}", beats, unit));
+        }
+        Ok(())
+    }
+
+    /// 一小节的秒数
+    pub fn measure_seconds(&self) -> f64 {
+        let (beats, unit) = self.time_signature;
+        60.0 / self.bpm * 4.0 / unit as f64 * beats as f64
+    }
+
+    /// position 之后第一个 measures 小节整数倍的边界（歌曲从 0 秒开始计小节）
+    pub fn next_boundary(&self, position: f64, measures: u32) -> f64 {
+        let length = self.measure_seconds() * measures as f64;
+        ((position / length).floor() + 1.0) * length
+    }
+}
+
+/// 把第 first 到第 last 小节（从 1 开始，含两端）换算为歌曲时间的区间
+/// boundaries 是 MidiAnalysis::measures：第 i 项是第 i+1 小节的起点，最后一项是终点
+pub fn measure_span(boundaries: &[f64], first: u32, last: u32) -> Result<(f64, f64), String> {
+    let count = boundaries.len().saturating_sub(1);
+    if count == 0 {
+        return Err("No measure information; parse with include_measures".to_string());
+    }
+    if first == 0 || first > last || last as usize > count {
+        return Err(format!(
+            "Loop measures must be within 1-{}, got {}-{}",
+            count, first, last
+        ));
+    }
+    Ok((boundaries[first as usize - 1], boundaries[last as usize]))
+}
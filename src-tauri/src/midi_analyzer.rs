@@ -1,8 +1,15 @@
+use crate::error::CommandError;
+use crate::keypress_simulator::{modifier_mask, ReservedKeys};
 use midly::{MidiMessage, Smf, TrackEventKind};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs;
-use std::path::Path;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 // Black and white key pitch classes (matching Python implementation)
 const BLACK_PCS: [u8; 5] = [1, 3, 6, 8, 10]; // C#, D#, F#, G#, A#
@@ -36,6 +43,59 @@ fn nearest_white_pc(pc: u8) -> u8 {
     best_pc
 }
 
+/// How black keys are handled, serialized as "support_black_key" / "auto_sharp"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlackKeyMode {
+    #[default]
+    SupportBlackKey, // 保留黑键
+    AutoSharp, // 黑键移到最近的白键
+}
+
+impl BlackKeyMode {
+    const ALL: [BlackKeyMode; 2] = [BlackKeyMode::SupportBlackKey, BlackKeyMode::AutoSharp];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BlackKeyMode::SupportBlackKey => "support_black_key",
+            BlackKeyMode::AutoSharp => "auto_sharp",
+        }
+    }
+}
+
+impl FromStr for BlackKeyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == s)
+            .ok_or_else(|| {
+                let valid: Vec<&str> = Self::ALL.iter().map(|mode| mode.as_str()).collect();
+                format!(
+                    "black_key_mode must be one of {}, got \"{}\"",
+                    valid.join(", "),
+                    s
+                )
+            })
+    }
+}
+
+/// Apply the black key mode to a single note
+/// AutoSharp moves black keys to the nearest white key, SupportBlackKey keeps the note
+pub fn apply_black_key_mode(note: u8, black_key_mode: BlackKeyMode) -> u8 {
+    let pc = note % 12;
+    if black_key_mode != BlackKeyMode::AutoSharp || !BLACK_PCS.contains(&pc) {
+        return note;
+    }
+    // Keep the octave, only change the pitch class
+    (note - pc) + nearest_white_pc(pc)
+}
+
+pub fn is_black_key(note: u8) -> bool {
+    BLACK_PCS.contains(&(note % 12))
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MidiEvent {
     pub time: f64,
@@ -47,6 +107,15 @@ pub struct MidiEvent {
     pub velocity: u8,
     pub duration: f64,
     pub end: f64,
+    // 所属和弦的序号（按起音顺序），只有 note_on 有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chord: Option<usize>,
+    // 使用分层按键映射时，给出这个音按键的层序号，只有 note_on 有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_layer: Option<usize>,
+    // 按键已确定的音（drum_mode 的打击乐），不经 key_map 映射，也不随移调和音域变化
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -58,6 +127,49 @@ pub struct AnalysisResult {
     pub min_note_name: String,
     pub max_note_name: String,
     pub total_over_limit_count: usize,
+    #[serde(default = "default_bpm")]
+    pub bpm: f64, // 开头的速度（每分钟四分音符数）
+    #[serde(default = "default_time_signature")]
+    pub time_signature: (u8, u8), // 开头的拍号（分子, 分母），没有拍号事件时为 4/4
+    #[serde(default)]
+    pub phrase_gaps: usize, // 开启 phrase_gap 时在乐句边界插入或加长的换气间隙数
+    #[serde(default)]
+    pub thinned_runs: Vec<ThinnedRun>, // 开启 max_same_key_rate 时被抽稀的同键连音
+    #[serde(default)]
+    pub modifier_churn: Option<ModifierChurn>, // 给出 key_map 时统计
+    #[serde(default)]
+    pub warnings: Vec<AnalysisWarning>,
+    #[serde(default)]
+    pub tempo_flattening: Option<TempoFlattening>, // 开启 flatten_tempo 时，此时 bpm 是拉平后的速度
+}
+
+/// 拉平速度前后的速度
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TempoFlattening {
+    pub original_bpm: f64, // 原来开头的速度
+    pub original_min_bpm: f64,
+    pub original_max_bpm: f64,
+    pub tempo_changes: usize, // 原来的速度段数
+    pub flattened_bpm: f64,   // 保持总时长不变的平均速度
+}
+
+/// 需要用户确认的自动修改，带歌曲中的时间便于逐条检查
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AnalysisWarning {
+    pub kind: String, // 如 "modifier_substitution"
+    pub time: f64,
+    pub message: String,
+    // 发生在超出 target_max_rate 的哪一段（MidiAnalysis.input_rate.windows 的序号）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_window: Option<usize>,
+}
+
+fn default_bpm() -> f64 {
+    120.0
+}
+
+fn default_time_signature() -> (u8, u8) {
+    (4, 4)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -91,6 +203,115 @@ pub struct MidiAnalysis {
     pub events: Vec<MidiEvent>,
     pub analysis: AnalysisResult,
     pub tracks: Vec<TrackInfo>,
+    // 解析时开启 trace 才有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<PipelineTrace>,
+    // 预览解析只包含开头的一段，时值处理步骤也被跳过
+    #[serde(default)]
+    pub partial: bool,
+    // 开启 include_measures 时的小节边界（秒，原速）：第 i 项是第 i+1 小节的起点，最后一项是最后一小节的终点
+    // 开启 align_to_downbeat 且有弱起时从第一个完整小节开始，弱起部分不属于任何小节
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measures: Option<Vec<f64>>,
+    // 开启 align_to_downbeat 时检测到的弱起
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pickup: Option<Pickup>,
+    // 给出 target_max_rate 时的输入速率检查
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_rate: Option<InputRateReport>,
+    // 实际执行的处理步骤，按执行顺序
+    #[serde(default)]
+    pub pass_order: Vec<String>,
+    // 开启 include_raw_events 时可选处理步骤之前的事件，最多 MAX_RAW_EVENTS 个
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_events: Option<Vec<MidiEvent>>,
+    // 与 raw_events 一起给出，按全部音符统计
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_diff: Option<EventDiff>,
+    // 文件标记的循环点（秒，与 events 同一时间轴），见 loop_point_kind
+    // 只有起点时循环到最后一个音
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_start: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_end: Option<f64>,
+    // 给出 key_map 时按最终事件统计每个键的使用情况，没有映射的音不计入
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_usage: Option<BTreeMap<String, KeyUsage>>,
+    // key_usage 中按下次数最多的 TOP_KEYS 个键，次数相同时按总按住时长
+    #[serde(default)]
+    pub top_keys: Vec<String>,
+}
+
+/// 弱起：第一个音所在的小节不完整，播放从它之后的第一个完整小节开始计小节号
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Pickup {
+    pub start: f64,    // 第一个音的时间
+    pub downbeat: f64, // 第一个完整小节的起点，预备拍按它对齐（CountInConfig::downbeat）
+}
+
+/// 一个键在整首曲子中的使用情况
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct KeyUsage {
+    pub count: usize,       // 按下次数
+    pub held_seconds: f64,  // 按住的总时长
+    pub max_repeats: usize, // 按起音顺序连续按同一个键的最多次数
+}
+
+/// top_keys 的个数
+pub const TOP_KEYS: usize = 5;
+
+/// raw_events 中的事件数上限，超出时只保留开头的部分
+pub const MAX_RAW_EVENTS: usize = 200_000;
+
+/// 可选处理步骤前后的音符差异，按音符（而不是 note_on/note_off 事件）计数
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct EventDiff {
+    pub added: usize,        // 处理步骤新增的音（如移八度替换、错开的同音）
+    pub removed: usize,      // 被删除的音（如抽稀、复音数限制）
+    pub time_shifted: usize, // 起音时间变了的音
+    pub modified: usize,     // 起音时间不变，音高、时值或力度变了的音
+    pub truncated: bool,     // raw_events 超出 MAX_RAW_EVENTS 被截断
+}
+
+impl EventDiff {
+    fn between(raw: &[Note], processed: &[Note]) -> Self {
+        let before: HashMap<usize, &Note> = raw.iter().map(|n| (n.id, n)).collect();
+        let after: HashSet<usize> = processed.iter().map(|n| n.id).collect();
+        let mut diff = EventDiff {
+            removed: raw.iter().filter(|n| !after.contains(&n.id)).count(),
+            ..Default::default()
+        };
+        for note in processed {
+            match before.get(&note.id) {
+                None => diff.added += 1,
+                Some(old) if old.time != note.time => diff.time_shifted += 1,
+                Some(old) if *old != note => diff.modified += 1,
+                Some(_) => {}
+            }
+        }
+        diff
+    }
+}
+
+/// 按键速率超出 target_max_rate 的一段：从第一个超限的 1 秒窗口起到最后一个超限窗口结束
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RateWindow {
+    pub start: f64,
+    pub duration: f64,
+    pub rate: usize,       // 这段内 1 秒窗口的最多按键数，抽稀、复音数限制等处理之前
+    pub final_rate: usize, // 处理之后同一段内的最多按键数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measure: Option<usize>, // 开始处的小节号（从 1 开始），没有小节数据时为空
+}
+
+/// 输入速率的预检结果，只做提示，不修改音符
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InputRateReport {
+    pub target_max_rate: f64,
+    pub peak_rate: usize, // 处理之后全曲任意 1 秒内的最多按键数
+    pub peak_time: f64,   // 该窗口的起点
+    pub passed: bool,     // peak_rate 不超过 target_max_rate
+    pub windows: Vec<RateWindow>,
 }
 
 fn get_note_name(note: u8) -> String {
@@ -188,328 +409,2889 @@ fn optimize_transpose_suggestion(
     suggestions.first().map(|(t, o, _)| (*t, *o))
 }
 
-pub fn analyze_midi_file(
-    file_path: &str,
-    min_note: u8,
-    max_note: u8,
-    black_key_mode: &str,
-    trim_long_notes: bool,
-) -> Result<MidiAnalysis, String> {
-    let path = Path::new(file_path);
-    if !path.exists() {
-        return Err(format!("File not found: {}", file_path));
+/// 解析出的一个音符，处理步骤之间传递的单位
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Note {
+    pub id: usize, // 解析顺序，在步骤之间追踪同一个音符；步骤新增的音符需使用未用过的 id
+    pub time: f64,
+    pub duration: f64,
+    pub end: f64,
+    pub note: u8,
+    pub channel: u8,
+    pub track: usize,
+    pub velocity: u8,
+    // 打击乐映射（map_drums）给出的按键，音高只用于显示；旋律音为 None，按键由 key_map 决定
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl Note {
+    fn to_events(&self, chord: usize) -> [MidiEvent; 2] {
+        let event = |time, type_: &str, velocity, duration, chord| MidiEvent {
+            time,
+            type_: type_.to_string(),
+            note: self.note,
+            channel: self.channel,
+            track: self.track,
+            velocity,
+            duration,
+            end: self.end,
+            chord,
+            key_layer: None,
+            key: self.key.clone(),
+        };
+        [
+            event(
+                self.time,
+                "note_on",
+                self.velocity,
+                self.duration,
+                Some(chord),
+            ),
+            event(self.end, "note_off", 0, 0.0, None),
+        ]
     }
+}
 
-    let bytes = fs::read(path).map_err(|e| format!("Failed to read file: {}", e))?;
-    let smf = Smf::parse(&bytes).map_err(|e| format!("Failed to parse MIDI: {}", e))?;
+/// 处理步骤共用的解析选项和速度信息，步骤的统计结果也记在这里
+pub struct PassContext {
+    pub tempo: TempoMap,
+    pub chord_grouping: ChordGrouping,
+    pub phrase_gap: Option<PhraseGap>,
+    pub phrase_gaps: usize,
+    pub max_same_key_rate: Option<f64>,
+    pub thinned_runs: Vec<ThinnedRun>,
+    pub key_map: Option<KeyMap>,
+    pub warnings: Vec<AnalysisWarning>,
+    pub track_selection: Option<Vec<TrackSelection>>,
+    pub on_unison: Option<UnisonPolicy>,
+    pub same_key_overlap: Option<SameKeyOverlap>,
+    pub chord_spread: f64, // 秒
+    pub duration: f64,     // 原始的歌曲时长，拉平速度时保持不变
+    pub tempo_flattening: Option<TempoFlattening>,
+    pub max_polyphony: Option<usize>,
+    pub eviction: PolyphonyEviction,
+    pub target_max_rate: Option<f64>,
+    pub note_range: (u8, u8), // 没有按键映射时，速率只统计这个音域内的音
+    pub rate_windows: Vec<RateWindow>,
+    // include_raw_events 时记下可选处理步骤之前（拉平速度、选择音轨之后）的音符
+    pub raw_notes: Option<Vec<Note>>,
+    pub reserved_keys: ReservedKeys,
+    pub drum_keys: Option<KeyMap>, // drum_mode 时的打击乐映射（见 drum_key_table）
+}
 
-    let ticks_per_beat = match smf.header.timing {
-        midly::Timing::Metrical(t) => t.as_int() as f64,
-        midly::Timing::Timecode(_, _) => return Err("SMPTE timing not supported yet".to_string()),
-    };
+impl PassContext {
+    /// 与 start-end 重叠的第一个超速段的序号
+    pub fn rate_window_at(&self, start: f64, end: f64) -> Option<usize> {
+        self.rate_windows
+            .iter()
+            .position(|window| window.start <= end && start < window.start + window.duration)
+    }
+}
 
-    let mut events = Vec::new();
-    let mut tracks_info = Vec::new();
-    let mut tempo_changes = Vec::new(); // (tick, microseconds_per_beat)
+/// 解析后的一个处理步骤，读入音符列表并返回处理后的列表
+pub struct AnalyzerPass {
+    pub name: &'static str,
+    pub run: fn(Vec<Note>, &mut PassContext) -> Vec<Note>,
+}
 
-    // First pass: collect tempo changes from all tracks (usually track 0)
-    // And also track names and per-track note statistics
-    let mut track_notes: HashMap<usize, Vec<u8>> = HashMap::new();
+/// 全部处理步骤的名称，按默认顺序
+pub const PASS_NAMES: [&str; 13] = [
+    "flatten_tempo",
+    "map_drums",
+    "select_tracks",
+    "trim_long_notes",
+    "auto_sharp",
+    "avoid_reserved_keys",
+    "merge_unison",
+    "resolve_overlaps",
+    "measure_input_rate",
+    "limit_polyphony",
+    "reduce_modifier_churn",
+    "thin_same_key",
+    "phrase_gap",
+];
+
+// 拉平速度、打击乐映射和选择音轨必须最先执行，不能调整
+const FIXED_PASSES: [&str; 3] = ["flatten_tempo", "map_drums", "select_tracks"];
+
+// 按实际按下的键判断的步骤，需要在黑键映射（auto_sharp）之后
+const KEY_PASSES: [&str; 6] = [
+    "avoid_reserved_keys",
+    "merge_unison",
+    "resolve_overlaps",
+    "limit_polyphony",
+    "reduce_modifier_churn",
+    "thin_same_key",
+];
+
+/// 按解析选项组成的处理步骤，按顺序执行，未开启的选项不加入
+/// 拉平速度最先执行，之后按拍计算的步骤（如按拍数判定和弦）使用均匀的节拍
+/// drum_mode 的打击乐映射在移调之前，按原始音高查表
+/// 选择音轨和各音轨的移调随后执行，之后的步骤看到的是合并后的音符
+/// 不同音轨撞到同一个键的处理（on_unison）在黑键映射之后、其他按键相关的步骤之前
+/// 同一个键上剩下的重叠（same_key_overlap）紧随其后，不论是否来自同一音轨
+/// 给出 target_max_rate 时在这里统计超速段（不修改音符），之后抽稀和复音数限制的报告引用这些段
+/// 复音数限制紧随其后，按合并后实际同时按住的键计数
+/// 保留键的替换紧随黑键映射，之后的步骤看到的都是实际会按下的键
+/// has_key_map 为 false 时 avoid_reserved_keys 和 reduce_modifier_churn 没有映射可用，不加入
+/// 减少修饰键切换和抽稀同键连音在黑键映射之后执行，按实际会按下的键判断
+/// 移八度的替换在抽稀之前，换过去的音也会参与同键检查
+/// 调整时值的步骤（如 phrase_gap）放在最后，之后的步骤不会把插入的间隙抹掉
+/// 给出 pass_order 时按 reorder_passes 调整顺序
+pub fn build_pipeline(options: &AnalyzerOptions, has_key_map: bool) -> Vec<AnalyzerPass> {
+    let mut passes = default_pipeline(options, has_key_map);
+    if let Some(order) = &options.pass_order {
+        // validate 已按全部步骤检查过，少了 reduce_modifier_churn 也不会违反顺序约束
+        if let Err(e) = reorder_passes(&mut passes, order) {
+            eprintln!("Ignoring pass_order: {}", e);
+        }
+    }
+    passes
+}
 
-    for (i, track) in smf.tracks.iter().enumerate() {
-        let mut current_tick = 0;
-        let mut track_name = format!("Track {}", i);
-        let mut note_count = 0;
-        let mut notes_in_track = Vec::new();
+fn default_pipeline(options: &AnalyzerOptions, has_key_map: bool) -> Vec<AnalyzerPass> {
+    let mut passes = Vec::new();
+    if options.flatten_tempo {
+        passes.push(AnalyzerPass {
+            name: "flatten_tempo",
+            run: flatten_tempo_pass,
+        });
+    }
+    if options.drum_mode {
+        passes.push(AnalyzerPass {
+            name: "map_drums",
+            run: map_drums_pass,
+        });
+    }
+    if options.track_selection.is_some() {
+        passes.push(AnalyzerPass {
+            name: "select_tracks",
+            run: select_tracks_pass,
+        });
+    }
+    if options.trim_long_notes {
+        passes.push(AnalyzerPass {
+            name: "trim_long_notes",
+            run: trim_long_notes_pass,
+        });
+    }
+    if options.black_key_mode == BlackKeyMode::AutoSharp {
+        passes.push(AnalyzerPass {
+            name: "auto_sharp",
+            run: auto_sharp_pass,
+        });
+    }
+    if !options.reserved_keys.is_empty() && has_key_map {
+        passes.push(AnalyzerPass {
+            name: "avoid_reserved_keys",
+            run: avoid_reserved_keys_pass,
+        });
+    }
+    if options.on_unison.is_some() {
+        passes.push(AnalyzerPass {
+            name: "merge_unison",
+            run: merge_unison_pass,
+        });
+    }
+    if options.same_key_overlap.is_some() {
+        passes.push(AnalyzerPass {
+            name: "resolve_overlaps",
+            run: resolve_overlaps_pass,
+        });
+    }
+    if options.target_max_rate.is_some() {
+        passes.push(AnalyzerPass {
+            name: "measure_input_rate",
+            run: measure_input_rate_pass,
+        });
+    }
+    if options.max_polyphony.is_some() {
+        passes.push(AnalyzerPass {
+            name: "limit_polyphony",
+            run: limit_polyphony_pass,
+        });
+    }
+    if options.reduce_modifier_churn && has_key_map {
+        passes.push(AnalyzerPass {
+            name: "reduce_modifier_churn",
+            run: reduce_modifier_churn_pass,
+        });
+    }
+    if options.max_same_key_rate.is_some() {
+        passes.push(AnalyzerPass {
+            name: "thin_same_key",
+            run: thin_same_key_pass,
+        });
+    }
+    if options.phrase_gap.is_some() {
+        passes.push(AnalyzerPass {
+            name: "phrase_gap",
+            run: phrase_gap_pass,
+        });
+    }
+    passes
+}
 
-        for event in track {
-            current_tick += event.delta.as_int();
+/// 按 order 调整步骤顺序：order 中列出的步骤依次填入它们原来占的位置，没列出的步骤位置不变
+/// 列出但未开启的步骤忽略；出错时不修改 passes
+pub fn reorder_passes(passes: &mut Vec<AnalyzerPass>, order: &[String]) -> Result<(), String> {
+    for (i, name) in order.iter().enumerate() {
+        if !PASS_NAMES.contains(&name.as_str()) {
+            return Err(format!(
+                "pass_order[{}]: unknown pass \"{}\", expected one of {}",
+                i,
+                name,
+                PASS_NAMES.join(", ")
+            ));
+        }
+        if FIXED_PASSES.contains(&name.as_str()) {
+            return Err(format!(
+                "pass_order[{}]: {} always runs first and cannot be reordered",
+                i, name
+            ));
+        }
+        if order[..i].contains(name) {
+            return Err(format!(
+                "pass_order[{}]: {} is listed more than once",
+                i, name
+            ));
+        }
+    }
 
-            match event.kind {
-                TrackEventKind::Meta(midly::MetaMessage::Tempo(t)) => {
-                    tempo_changes.push((current_tick, t.as_int()));
-                }
-                TrackEventKind::Meta(midly::MetaMessage::TrackName(name)) => {
-                    if let Ok(n) = String::from_utf8(name.to_vec()) {
-                        track_name = n;
-                    }
-                }
-                TrackEventKind::Midi {
-                    message: MidiMessage::NoteOn { key, vel },
-                    ..
-                } => {
-                    if vel.as_int() > 0 {
-                        note_count += 1;
-                        notes_in_track.push(key.as_int());
-                    }
-                }
-                _ => {}
-            }
+    let listed = |pass: &AnalyzerPass| order.iter().any(|name| name == pass.name);
+    let slots: Vec<usize> = (0..passes.len()).filter(|&i| listed(&passes[i])).collect();
+    let mut moved = order
+        .iter()
+        .filter_map(|name| passes.iter().position(|pass| pass.name == name));
+    let mut indices: Vec<usize> = (0..passes.len()).collect();
+    for slot in slots {
+        if let Some(index) = moved.next() {
+            indices[slot] = index;
         }
+    }
 
-        if note_count > 0 {
-            // Calculate track analysis using provided min/max note
-            let limit_min = min_note;
-            let limit_max = max_note;
+    let names: Vec<&str> = indices.iter().map(|&i| passes[i].name).collect();
+    if let Some(sharp) = names.iter().position(|&name| name == "auto_sharp") {
+        if let Some(early) = names[..sharp].iter().find(|name| KEY_PASSES.contains(name)) {
+            return Err(format!(
+                "pass_order: {} must run after auto_sharp, which maps black keys",
+                early
+            ));
+        }
+    }
 
-            let max_note = notes_in_track.iter().max().copied();
-            let min_note = notes_in_track.iter().min().copied();
+    let mut taken: Vec<Option<AnalyzerPass>> = passes.drain(..).map(Some).collect();
+    passes.extend(indices.into_iter().filter_map(|i| taken[i].take()));
+    Ok(())
+}
 
-            let upper_over_limit = notes_in_track.iter().filter(|&&n| n > limit_max).count();
-            let lower_over_limit = notes_in_track.iter().filter(|&&n| n < limit_min).count();
+/// 合并的一个音轨及其移调
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TrackSelection {
+    pub index: usize, // 音轨序号，即 TrackInfo.id
+    #[serde(default)]
+    pub transpose: i8, // 半音数，在合并和音域判断之前生效
+}
 
-            let is_max_over_limit = max_note.map_or(false, |n| n > limit_max || n < limit_min);
-            let is_min_over_limit = min_note.map_or(false, |n| n < limit_min || n > limit_max);
+// 只保留选中音轨的音符并按各自的 transpose 移调，移出 MIDI 音高范围的音丢弃
+fn select_tracks_pass(notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(selection) = &context.track_selection else {
+        return notes;
+    };
+    let transposes: HashMap<usize, i8> = selection
+        .iter()
+        .map(|track| (track.index, track.transpose))
+        .collect();
+    notes
+        .into_iter()
+        .filter_map(|mut note| {
+            let transpose = *transposes.get(&note.track)?;
+            // 打击乐的按键已经确定，不移调
+            if note.key.is_none() {
+                note.note = transpose_note(note.note, transpose)?;
+            }
+            Some(note)
+        })
+        .collect()
+}
 
-            // 计算建议值（当前移调和转位都是0）
-            let current_transpose = 0;
-            let current_octave = 0;
+/// drum_mode 时按打击乐处理的通道（GM 的第 10 通道，从 0 数）
+pub const DRUM_CHANNEL: u8 = 9;
+
+// 打击乐没有有意义的时值，每一击按住这么久（秒）
+const DRUM_HIT_DURATION: f64 = 0.05;
+
+// 默认的 GM 打击乐映射：底鼓空格，军鼓 j，踩镲 k，嗵鼓 f/d/s，吊镲 l，叮叮镲 i
+const GM_DRUM_KEYS: [(u8, &str); 22] = [
+    (35, "space"), // Acoustic Bass Drum
+    (36, "space"), // Bass Drum 1
+    (37, "j"),     // Side Stick
+    (38, "j"),     // Acoustic Snare
+    (39, "j"),     // Hand Clap
+    (40, "j"),     // Electric Snare
+    (41, "f"),     // Low Floor Tom
+    (42, "k"),     // Closed Hi-Hat
+    (43, "f"),     // High Floor Tom
+    (44, "k"),     // Pedal Hi-Hat
+    (45, "d"),     // Low Tom
+    (46, "k"),     // Open Hi-Hat
+    (47, "d"),     // Low-Mid Tom
+    (48, "s"),     // Hi-Mid Tom
+    (49, "l"),     // Crash Cymbal 1
+    (50, "s"),     // High Tom
+    (51, "i"),     // Ride Cymbal 1
+    (52, "l"),     // Chinese Cymbal
+    (53, "i"),     // Ride Bell
+    (55, "l"),     // Splash Cymbal
+    (57, "l"),     // Crash Cymbal 2
+    (59, "i"),     // Ride Cymbal 2
+];
+
+/// drum_mode 使用的打击乐映射：GM 默认映射，drum_keys 中的项替换同一音高的按键，空字符串取消该音高的映射
+/// 按键整理成规范写法，写错的按键报出音高
+pub fn drum_key_table(overrides: &KeyMap) -> Result<KeyMap, String> {
+    let mut table: KeyMap = GM_DRUM_KEYS
+        .iter()
+        .map(|&(note, key)| (note, key.to_string()))
+        .collect();
+    let mut notes: Vec<u8> = overrides.keys().copied().collect();
+    notes.sort_unstable();
+    for note in notes {
+        if note > MAX_MIDI_NOTE {
+            return Err(format!(
+                "drum_keys note must be within 0-{}, got {}",
+                MAX_MIDI_NOTE, note
+            ));
+        }
+        let key = overrides[&note].trim();
+        if key.is_empty() {
+            table.remove(&note);
+            continue;
+        }
+        let key = uni_input::normalize_key_string(key)
+            .map_err(|e| format!("drum_keys note {}: {}", note, e))?;
+        table.insert(note, key);
+    }
+    Ok(table)
+}
 
-            let (suggested_max_transpose, suggested_max_octave) = if is_max_over_limit {
-                max_note
-                    .and_then(|n| {
-                        let diff = limit_max as i32 - n as i32;
-                        optimize_transpose_suggestion(diff, current_transpose, current_octave)
-                    })
-                    .map(|(t, o)| (Some(t), Some(o)))
-                    .unwrap_or((None, None))
-            } else {
-                (None, None)
+// 打击乐通道的音按打击乐映射定下按键，时值统一为 DRUM_HIT_DURATION，其他通道不变
+// 没有映射或映射到保留键的音删除，按音高或按键各记一条 warning
+fn map_drums_pass(notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(table) = &context.drum_keys else {
+        return notes;
+    };
+    // 删除的音数和其中最早的时间
+    let mut unmapped: BTreeMap<u8, (usize, f64)> = BTreeMap::new();
+    let mut reserved: BTreeMap<String, (usize, f64)> = BTreeMap::new();
+    let notes = notes
+        .into_iter()
+        .filter_map(|mut note| {
+            if note.channel != DRUM_CHANNEL {
+                return Some(note);
+            }
+            let dropped = match table.get(&note.note) {
+                None => unmapped.entry(note.note).or_insert((0, note.time)),
+                Some(key) if context.reserved_keys.blocks(key) => {
+                    reserved.entry(key.clone()).or_insert((0, note.time))
+                }
+                Some(key) => {
+                    note.key = Some(key.clone());
+                    note.duration = DRUM_HIT_DURATION;
+                    note.end = note.time + DRUM_HIT_DURATION;
+                    return Some(note);
+                }
             };
+            dropped.0 += 1;
+            dropped.1 = dropped.1.min(note.time);
+            None
+        })
+        .collect();
+    for (note, (dropped, time)) in unmapped {
+        context.warnings.push(AnalysisWarning {
+            kind: "drum_unmapped".to_string(),
+            time,
+            message: format!(
+                "{} percussion notes on note {} dropped: no drum key mapped",
+                dropped, note
+            ),
+            rate_window: None,
+        });
+    }
+    for (key, (dropped, time)) in reserved {
+        context.warnings.push(AnalysisWarning {
+            kind: "reserved_key_dropped".to_string(),
+            time,
+            message: format!(
+                "{} percussion notes dropped: their drum key {} is reserved",
+                dropped, key
+            ),
+            rate_window: None,
+        });
+    }
+    notes
+}
 
-            let (suggested_min_transpose, suggested_min_octave) = if is_min_over_limit {
-                min_note
-                    .and_then(|n| {
-                        let diff = limit_min as i32 - n as i32;
-                        optimize_transpose_suggestion(diff, current_transpose, current_octave)
-                    })
-                    .map(|(t, o)| (Some(t), Some(o)))
-                    .unwrap_or((None, None))
-            } else {
-                (None, None)
-            };
+// 步骤里判断"同一个键"的依据，通常是音高
+// 打击乐的音按按键归类，映射到同一个按键的旋律音与它归为一处，由同一套规则处理
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum KeySlot {
+    Pitch(u8),
+    Key(String),
+}
 
-            let analysis = TrackAnalysis {
-                max_note,
-                min_note,
-                max_note_name: max_note.map(get_note_name).unwrap_or_default(),
-                min_note_name: min_note.map(get_note_name).unwrap_or_default(),
-                max_note_group: max_note.map(get_note_group).unwrap_or_default(),
-                min_note_group: min_note.map(get_note_group).unwrap_or_default(),
-                upper_over_limit,
-                lower_over_limit,
-                is_max_over_limit,
-                is_min_over_limit,
-                suggested_max_transpose,
-                suggested_max_octave,
-                suggested_min_transpose,
-                suggested_min_octave,
-            };
+impl KeySlot {
+    fn label(&self) -> String {
+        match self {
+            KeySlot::Pitch(note) => get_note_name(*note),
+            KeySlot::Key(key) => format!("key {}", key),
+        }
+    }
+}
 
-            tracks_info.push(TrackInfo {
-                id: i,
-                name: track_name,
-                note_count,
-                analysis,
+// 每个音所在的 KeySlot；没有打击乐的音时与按音高归类相同
+fn key_slots(notes: &[Note], key_map: Option<&KeyMap>) -> Vec<KeySlot> {
+    let drum_keys: HashSet<&str> = notes
+        .iter()
+        .filter_map(|note| note.key.as_deref())
+        .collect();
+    notes
+        .iter()
+        .map(|note| {
+            let key = note.key.as_deref().or_else(|| {
+                key_map
+                    .and_then(|key_map| key_map.get(&note.note))
+                    .map(String::as_str)
+                    .filter(|key| drum_keys.contains(key))
             });
+            match key {
+                Some(key) => KeySlot::Key(key.to_string()),
+                None => KeySlot::Pitch(note.note),
+            }
+        })
+        .collect()
+}
+
+fn transpose_note(note: u8, transpose: i8) -> Option<u8> {
+    u8::try_from(note as i16 + transpose as i16)
+        .ok()
+        .filter(|&note| note <= MAX_MIDI_NOTE)
+}
+
+/// 两个音轨在重叠的时间里落到同一个键上时的处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnisonPolicy {
+    KeepMelody,  // 保留旋律音轨（平均音高最高的音轨）的音
+    KeepLongest, // 保留时值长的音，相同时保留旋律音轨的
+    // 两个都保留，后起音的（同时起音时为非旋律音轨的）推迟 chord_spread_ms，前一个在它起音时松开
+    KeepBothStaggered,
+}
 
-            track_notes.insert(i, notes_in_track);
+impl UnisonPolicy {
+    fn as_str(self) -> &'static str {
+        match self {
+            UnisonPolicy::KeepMelody => "keep_melody",
+            UnisonPolicy::KeepLongest => "keep_longest",
+            UnisonPolicy::KeepBothStaggered => "keep_both_staggered",
         }
     }
+}
 
-    // Sort tempo changes by tick
-    tempo_changes.sort_by_key(|k| k.0);
-    // Dedup tempo changes (keep last one for same tick)
-    let mut unique_tempo_changes: Vec<(u32, u32)> = Vec::new();
-    for tc in tempo_changes {
-        if let Some(last) = unique_tempo_changes.last_mut() {
-            if last.0 == tc.0 {
-                *last = tc;
+// 同一个键上来自不同音轨、时间重叠的音按 on_unison 处理，每处记一条 warning
+// 同一音轨内的重叠不是合并造成的，留给之后的步骤
+fn merge_unison_pass(mut notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(policy) = context.on_unison else {
+        return notes;
+    };
+    // 各音轨的平均音高，最高的视为旋律
+    let mut sums: HashMap<usize, (f64, usize)> = HashMap::new();
+    for note in &notes {
+        let entry = sums.entry(note.track).or_default();
+        entry.0 += note.note as f64;
+        entry.1 += 1;
+    }
+    let melody = sums
+        .iter()
+        .map(|(&track, &(sum, count))| (track, sum / count as f64))
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(track, _)| track);
+    let is_melody = |note: &Note| Some(note.track) == melody;
+
+    let mut by_key: HashMap<KeySlot, Vec<usize>> = HashMap::new();
+    for (i, slot) in key_slots(&notes, context.key_map.as_ref())
+        .into_iter()
+        .enumerate()
+    {
+        by_key.entry(slot).or_default().push(i);
+    }
+    let mut keys: Vec<KeySlot> = by_key.keys().cloned().collect();
+    keys.sort_unstable();
+
+    let mut removed = vec![false; notes.len()];
+    let mut found = Vec::new();
+    for key in keys {
+        let mut indices = by_key.remove(&key).unwrap_or_default();
+        indices.sort_by(|&a, &b| notes[a].time.total_cmp(&notes[b].time));
+        // 当前占着这个键的音
+        let mut active: Option<usize> = None;
+        for i in indices {
+            let overlap = active
+                .filter(|&a| notes[a].track != notes[i].track && notes[i].time < notes[a].end);
+            let Some(a) = overlap else {
+                if active.is_none_or(|a| notes[i].end > notes[a].end) {
+                    active = Some(i);
+                }
+                continue;
+            };
+            let (first, second) = if notes[a].time == notes[i].time && is_melody(&notes[i]) {
+                (i, a)
             } else {
-                unique_tempo_changes.push(tc);
-            }
-        } else {
-            unique_tempo_changes.push(tc);
+                (a, i)
+            };
+            let time = notes[second].time;
+            let tracks = (notes[first].track, notes[second].track);
+            let winner = match policy {
+                UnisonPolicy::KeepMelody if is_melody(&notes[second]) => Some(second),
+                UnisonPolicy::KeepMelody => Some(first),
+                UnisonPolicy::KeepLongest => {
+                    let (a, b) = (notes[first].duration, notes[second].duration);
+                    Some(if b > a || (b == a && is_melody(&notes[second])) {
+                        second
+                    } else {
+                        first
+                    })
+                }
+                UnisonPolicy::KeepBothStaggered => None,
+            };
+            let resolution = match winner {
+                Some(winner) => {
+                    removed[if winner == first { second } else { first }] = true;
+                    active = Some(winner);
+                    format!("kept track {}", notes[winner].track)
+                }
+                None => {
+                    let start = time.max(notes[first].time + context.chord_spread);
+                    let note = &mut notes[second];
+                    note.end = note.end.max(start + context.chord_spread);
+                    note.time = start;
+                    note.duration = note.end - note.time;
+                    let note = &mut notes[first];
+                    note.end = note.end.min(start);
+                    note.duration = note.end - note.time;
+                    active = Some(second);
+                    if start > time {
+                        format!(
+                            "delayed track {} by {:.0}ms",
+                            tracks.1,
+                            (start - time) * 1000.0
+                        )
+                    } else {
+                        format!("released track {} early", tracks.0)
+                    }
+                }
+            };
+            found.push(AnalysisWarning {
+                kind: "unison".to_string(),
+                time,
+                message: format!(
+                    "Tracks {} and {} both play {} ({}): {}",
+                    tracks.0,
+                    tracks.1,
+                    key.label(),
+                    policy.as_str(),
+                    resolution
+                ),
+                rate_window: None,
+            });
         }
     }
-    // Ensure there is a tempo at tick 0 (default 120 BPM = 500,000 microseconds per beat)
-    if unique_tempo_changes.is_empty() || unique_tempo_changes[0].0 > 0 {
-        unique_tempo_changes.insert(0, (0, 500_000));
-    }
+    found.sort_by(|a, b| a.time.total_cmp(&b.time));
+    context.warnings.extend(found);
+    notes
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !removed)
+        .map(|(note, _)| note)
+        .collect()
+}
 
-    // Helper to convert ticks to seconds
-    let tick_to_seconds = |tick: u32| -> f64 {
-        let mut time = 0.0;
-        let mut last_tick = 0;
-        let mut last_tempo = 500_000; // Default
+// 统计按键速率的滑动窗口长度（秒）
+const RATE_WINDOW: f64 = 1.0;
+
+// 计入按键速率的起音时间（已排序）：有按键映射时只算映射到的音，否则只算音域内的音；打击乐的音都算
+fn rate_onsets(notes: &[Note], context: &PassContext) -> Vec<f64> {
+    let (min_note, max_note) = context.note_range;
+    let mut times: Vec<f64> = notes
+        .iter()
+        .filter(|note| match &context.key_map {
+            _ if note.key.is_some() => true,
+            Some(key_map) => key_map.contains_key(&note.note),
+            None => (min_note..=max_note).contains(&note.note),
+        })
+        .map(|note| note.time)
+        .collect();
+    times.sort_by(f64::total_cmp);
+    times
+}
 
-        for (t_tick, t_tempo) in &unique_tempo_changes {
-            if *t_tick > tick {
-                break;
+// 从每个起音开始的 1 秒窗口内的起音数
+fn window_counts(times: &[f64]) -> Vec<usize> {
+    let mut end = 0;
+    times
+        .iter()
+        .enumerate()
+        .map(|(i, &time)| {
+            while end < times.len() && times[end] < time + RATE_WINDOW {
+                end += 1;
             }
-            let delta = *t_tick - last_tick;
-            time += (delta as f64 * last_tempo as f64) / (ticks_per_beat * 1_000_000.0);
-            last_tick = *t_tick;
-            last_tempo = *t_tempo;
-        }
+            end - i
+        })
+        .collect()
+}
 
-        let delta = tick - last_tick;
-        time += (delta as f64 * last_tempo as f64) / (ticks_per_beat * 1_000_000.0);
-        time
+// 找出按键速率超过 target_max_rate 的段，相互重叠的超限窗口合并为一段；不修改音符
+fn measure_input_rate_pass(notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(target) = context.target_max_rate else {
+        return notes;
     };
-
-    // Second pass: collect notes
-    for (i, track) in smf.tracks.iter().enumerate() {
-        let mut current_tick = 0;
-        // Key: (channel, note), Value: (start_tick, velocity)
-        let mut active_notes: HashMap<(u8, u8), (u32, u8)> = HashMap::new();
-
-        for event in track {
-            current_tick += event.delta.as_int();
-
-            match event.kind {
-                TrackEventKind::Midi { channel, message } => {
-                    let channel = channel.as_int();
-                    match message {
-                        MidiMessage::NoteOn { key, vel } => {
-                            let note = key.as_int();
-                            let velocity = vel.as_int();
-                            if velocity > 0 {
-                                active_notes.insert((channel, note), (current_tick, velocity));
-                            } else {
-                                // NoteOn with velocity 0 is NoteOff
-                                if let Some((start_tick, start_vel)) =
-                                    active_notes.remove(&(channel, note))
-                                {
-                                    let start_time = tick_to_seconds(start_tick);
-                                    let mut end_time = tick_to_seconds(current_tick);
-                                    let mut duration = end_time - start_time;
-
-                                    // 优化：如果持续时间超过1秒且启用了修剪，强制修剪为0.99秒
-                                    if trim_long_notes && duration > 1.0 {
-                                        duration = 0.99;
-                                        end_time = start_time + duration;
-                                    }
-
-                                    events.push(MidiEvent {
-                                        time: start_time,
-                                        type_: "note_on".to_string(),
-                                        note,
-                                        channel,
-                                        track: i,
-                                        velocity: start_vel,
-                                        duration,
-                                        end: end_time,
-                                    });
-                                    events.push(MidiEvent {
-                                        time: end_time,
-                                        type_: "note_off".to_string(),
-                                        note,
-                                        channel,
-                                        track: i,
-                                        velocity: 0,
-                                        duration: 0.0,
-                                        end: end_time,
-                                    });
-                                }
-                            }
-                        }
-                        MidiMessage::NoteOff { key, .. } => {
-                            let note = key.as_int();
-                            if let Some((start_tick, start_vel)) =
-                                active_notes.remove(&(channel, note))
-                            {
-                                let start_time = tick_to_seconds(start_tick);
-                                let mut end_time = tick_to_seconds(current_tick);
-                                let mut duration = end_time - start_time;
-
-                                // 优化：如果持续时间超过1秒且启用了修剪，强制修剪为0.99秒
-                                if trim_long_notes && duration > 1.0 {
-                                    duration = 0.99;
-                                    end_time = start_time + duration;
-                                }
-
-                                events.push(MidiEvent {
-                                    time: start_time,
-                                    type_: "note_on".to_string(),
-                                    note,
-                                    channel,
-                                    track: i,
-                                    velocity: start_vel,
-                                    duration,
-                                    end: end_time,
-                                });
-                                events.push(MidiEvent {
-                                    time: end_time,
-                                    type_: "note_off".to_string(),
-                                    note,
-                                    channel,
-                                    track: i,
-                                    velocity: 0,
-                                    duration: 0.0,
-                                    end: end_time,
-                                });
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                _ => {}
+    let times = rate_onsets(&notes, context);
+    let mut windows: Vec<RateWindow> = Vec::new();
+    for (&time, count) in times.iter().zip(window_counts(&times)) {
+        if count as f64 <= target {
+            continue;
+        }
+        match windows.last_mut() {
+            Some(window) if time <= window.start + window.duration => {
+                window.duration = time + RATE_WINDOW - window.start;
+                window.rate = window.rate.max(count);
             }
+            _ => windows.push(RateWindow {
+                start: time,
+                duration: RATE_WINDOW,
+                rate: count,
+                final_rate: 0,
+                measure: None,
+            }),
         }
+    }
+    context.rate_windows = windows;
+    notes
+}
 
-        // 处理该音轨中未关闭的音符（自动生成0.2秒的off事件）
-        for ((channel, note), (start_tick, start_vel)) in active_notes {
-            let start_time = tick_to_seconds(start_tick);
-            let duration = 0.2; // 默认给0.2秒
-            let end_time = start_time + duration;
-
-            events.push(MidiEvent {
-                time: start_time,
-                type_: "note_on".to_string(),
-                note,
-                channel,
-                track: i,
-                velocity: start_vel,
-                duration,
-                end: end_time,
-            });
-            events.push(MidiEvent {
-                time: end_time,
-                type_: "note_off".to_string(),
-                note,
-                channel,
-                track: i,
-                velocity: 0,
-                duration: 0.0,
-                end: end_time,
+// 处理完成后的速率检查：填写各超速段处理后的速率和小节号，并为每段加一条警告
+fn input_rate_report(
+    notes: &[Note],
+    context: &mut PassContext,
+    measures: &[f64],
+) -> Option<InputRateReport> {
+    let target = context.target_max_rate?;
+    let times = rate_onsets(notes, context);
+    let counts = window_counts(&times);
+    let (peak_time, peak_rate) =
+        times
+            .iter()
+            .zip(&counts)
+            .fold((0.0, 0), |(at, peak), (&time, &count)| {
+                if count > peak {
+                    (time, count)
+                } else {
+                    (at, peak)
+                }
             });
+    for (index, window) in context.rate_windows.iter_mut().enumerate() {
+        // 段内开始的窗口，第一个音被删掉时后面的窗口仍在段内
+        let end = window.start + window.duration;
+        window.final_rate = times
+            .iter()
+            .zip(&counts)
+            .filter(|(&time, _)| window.start <= time && time < end)
+            .map(|(_, &count)| count)
+            .max()
+            .unwrap_or(0);
+        // 第 i 项是第 i+1 小节的起点
+        window.measure = (!measures.is_empty()).then(|| {
+            measures
+                .partition_point(|&boundary| boundary <= window.start)
+                .max(1)
+        });
+        let location = match window.measure {
+            Some(measure) => format!("{:.2}s (measure {})", window.start, measure),
+            None => format!("{:.2}s", window.start),
+        };
+        let after = if window.final_rate == window.rate {
+            String::new()
+        } else {
+            format!(", {} after processing", window.final_rate)
+        };
+        context.warnings.push(AnalysisWarning {
+            kind: "input_rate".to_string(),
+            time: window.start,
+            message: format!(
+                "Peaks at {} keys/second at {} for {:.1}s, above the target of {}{}",
+                window.rate, location, window.duration, target, after
+            ),
+            rate_window: Some(index),
+        });
+    }
+    Some(InputRateReport {
+        target_max_rate: target,
+        peak_rate,
+        peak_time,
+        passed: peak_rate as f64 <= target,
+        windows: context.rate_windows.clone(),
+    })
+}
+
+/// 同时发声的音数超过 max_polyphony 时腾出位置的方式
+/// 新音的优先级按力度、再按音高（高音通常是旋律）从高到低
+/// 无论哪种方式，同时起音的和弦本身超过限制时都只保留优先级高的几个音
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolyphonyEviction {
+    #[default]
+    CutOldest, // 松开最早按下的音
+    CutQuietest, // 松开力度最小的音，相同时松开较早的
+    // 不动正在发声的音：新音推迟到最早有空位时起音，剩余不到一半时值时丢弃
+    ShortenIncoming,
+}
+
+impl PolyphonyEviction {
+    fn as_str(self) -> &'static str {
+        match self {
+            PolyphonyEviction::CutOldest => "cut_oldest",
+            PolyphonyEviction::CutQuietest => "cut_quietest",
+            PolyphonyEviction::ShortenIncoming => "shorten_incoming",
         }
     }
+}
 
-    // Sort events by time
-    events.sort_by(|a, b| {
-        a.time
-            .partial_cmp(&b.time)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
+fn voice_priority(note: &Note) -> (u8, u8) {
+    (note.velocity, note.note)
+}
 
-    // Apply black key mode conversion if enabled
-    // This matches the Python implementation in midi_analyzer.py lines 529-541
-    if black_key_mode == "auto_sharp" {
-        for event in &mut events {
-            let note = event.note;
-            let pc = note % 12;
+// 按起音顺序维护正在发声的音，超出限制时按 eviction 腾出位置，每次记一条 warning
+fn limit_polyphony_pass(mut notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(limit) = context.max_polyphony else {
+        return notes;
+    };
+    let eviction = context.eviction;
+    let mut order: Vec<usize> = (0..notes.len()).collect();
+    order.sort_by(|&a, &b| {
+        notes[a]
+            .time
+            .total_cmp(&notes[b].time)
+            .then(voice_priority(&notes[b]).cmp(&voice_priority(&notes[a])))
+    });
 
-            // Check if this is a black key
-            if BLACK_PCS.contains(&pc) {
-                // Find the nearest white key pitch class
-                let new_pc = nearest_white_pc(pc);
-                // Keep the octave, only change the pitch class
-                event.note = (note - pc) + new_pc;
+    let describe = |note: &Note| {
+        format!(
+            "{} (track {}, {:.3}s)",
+            get_note_name(note.note),
+            note.track,
+            note.time
+        )
+    };
+    let mut removed = vec![false; notes.len()];
+    let mut sounding: Vec<usize> = Vec::new();
+    for i in order {
+        let time = notes[i].time;
+        sounding.retain(|&s| notes[s].end > time);
+        if sounding.len() < limit {
+            sounding.push(i);
+            continue;
+        }
+        let incoming = describe(&notes[i]);
+        // 在这之前按下的音才能被松开，同时起音的都比新音优先
+        let earlier = sounding
+            .iter()
+            .enumerate()
+            .filter(|&(_, &s)| notes[s].time < time);
+        let victim = match eviction {
+            PolyphonyEviction::CutOldest => {
+                earlier.min_by(|a, b| notes[*a.1].time.total_cmp(&notes[*b.1].time))
             }
+            PolyphonyEviction::CutQuietest => earlier.min_by(|a, b| {
+                let (a, b) = (&notes[*a.1], &notes[*b.1]);
+                a.velocity.cmp(&b.velocity).then(a.time.total_cmp(&b.time))
+            }),
+            PolyphonyEviction::ShortenIncoming => None,
         }
+        .map(|(position, _)| position);
+
+        let resolution = match victim {
+            Some(position) => {
+                let v = sounding.swap_remove(position);
+                let cut = describe(&notes[v]);
+                notes[v].end = time;
+                notes[v].duration = time - notes[v].time;
+                sounding.push(i);
+                format!("cut {} for {}", cut, incoming)
+            }
+            None => {
+                // 最早松开的音空出的位置由新音接替
+                let (position, free_at) = sounding
+                    .iter()
+                    .enumerate()
+                    .map(|(position, &s)| (position, notes[s].end))
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap_or((0, f64::INFINITY));
+                let note = &mut notes[i];
+                if eviction == PolyphonyEviction::ShortenIncoming
+                    && note.end - free_at >= note.duration / 2.0
+                {
+                    note.time = free_at;
+                    note.duration = note.end - free_at;
+                    sounding[position] = i;
+                    format!("delayed {} by {:.0}ms", incoming, (free_at - time) * 1000.0)
+                } else {
+                    removed[i] = true;
+                    format!("dropped {}", incoming)
+                }
+            }
+        };
+        context.warnings.push(AnalysisWarning {
+            kind: "polyphony".to_string(),
+            time,
+            message: format!(
+                "Polyphony limit {} reached ({}): {}",
+                limit,
+                eviction.as_str(),
+                resolution
+            ),
+            rate_window: context.rate_window_at(time, time),
+        });
     }
+    notes
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !removed)
+        .map(|(note, _)| note)
+        .collect()
+}
+
+// 用保持总时长不变的单一速度代替速度表：每个时刻按原来的拍位置重新换算
+// 起音和结束分别换算，时值随所在段的速度伸缩
+fn flatten_tempo_pass(mut notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let original = context.tempo.clone();
+    let beats = original.beats_at(context.duration);
+    if beats <= 0.0 {
+        return notes;
+    }
+    let beat_seconds = context.duration / beats;
+    for note in &mut notes {
+        note.time = original.beats_at(note.time) * beat_seconds;
+        note.end = original.beats_at(note.end) * beat_seconds;
+        note.duration = note.end - note.time;
+    }
+    let (min_bpm, max_bpm) = original.bpm_range();
+    context.tempo_flattening = Some(TempoFlattening {
+        original_bpm: 60.0 / original.beat_seconds_at(0.0),
+        original_min_bpm: min_bpm,
+        original_max_bpm: max_bpm,
+        tempo_changes: original.changes.len(),
+        flattened_bpm: 60.0 / beat_seconds,
+    });
+    context.tempo = TempoMap::new(vec![(0.0, beat_seconds)]);
+    notes
+}
+
+// 优化：如果持续时间超过1秒，强制修剪为0.99秒
+fn trim_long_notes_pass(mut notes: Vec<Note>, _context: &mut PassContext) -> Vec<Note> {
+    for note in &mut notes {
+        if note.duration > 1.0 {
+            note.duration = 0.99;
+            note.end = note.time + note.duration;
+        }
+    }
+    notes
+}
+
+// This matches the Python implementation in midi_analyzer.py lines 529-541
+fn auto_sharp_pass(mut notes: Vec<Note>, _context: &mut PassContext) -> Vec<Note> {
+    for note in notes.iter_mut().filter(|note| note.key.is_none()) {
+        note.note = apply_black_key_mode(note.note, BlackKeyMode::AutoSharp);
+    }
+    notes
+}
+
+/// 乐句之间的换气间隙
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct PhraseGap {
+    pub seconds: f64, // 乐句边界至少空出的静音
+    // true：只缩短前一个音、推迟下一个起音，之后的音符不动，总时长基本不变
+    // false：边界之后的音符整体后移，每个间隙让歌曲变长 seconds 的一半
+    pub keep_length: bool,
+}
+
+impl Default for PhraseGap {
+    fn default() -> Self {
+        Self {
+            seconds: 0.06,
+            keep_length: true,
+        }
+    }
+}
+
+impl PhraseGap {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.01..=0.5).contains(&self.seconds) {
+            return Err(format!(
+                "phrase_gap.seconds must be within 0.01-0.5, got {}",
+                self.seconds
+            ));
+        }
+        Ok(())
+    }
+}
+
+// 长音之后的跳进达到这么多半音时视为换了乐句
+const PHRASE_REGISTER_JUMP: i32 = 12;
+
+// 插入间隙时音符最多缩短为原时长的这个比例
+const PHRASE_MIN_KEEP: f64 = 0.5;
+
+struct PhraseChord {
+    start: f64,
+    notes: Vec<usize>, // 在 notes 中的下标
+    top: u8,
+    top_end: f64, // 最高音的结束时间，休止按旋律（最高音）判断
+    longest: f64,
+}
+
+// 在乐句边界空出静音：旋律休止超过一拍，或一拍以上的长音之后跳进一个八度以上
+// 间隙的一半由缩短之前仍在发声的音得到，另一半由推迟下一个起音得到
+fn phrase_gap_pass(mut notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(gap) = context.phrase_gap.clone() else {
+        return notes;
+    };
+    let half = gap.seconds / 2.0;
+    let onsets: Vec<f64> = notes.iter().map(|note| note.time).collect();
+    let chord_ids =
+        ChordGrouper::new(context.chord_grouping.clone(), context.tempo.clone()).group(&onsets);
+
+    let mut chords: Vec<PhraseChord> = Vec::new();
+    for (i, &chord) in chord_ids.iter().enumerate() {
+        if chord >= chords.len() {
+            chords.resize_with(chord + 1, || PhraseChord {
+                start: f64::INFINITY,
+                notes: Vec::new(),
+                top: 0,
+                top_end: 0.0,
+                longest: 0.0,
+            });
+        }
+        let note = &notes[i];
+        let entry = &mut chords[chord];
+        entry.start = entry.start.min(note.time);
+        entry.notes.push(i);
+        if entry.notes.len() == 1 || note.note > entry.top {
+            entry.top = note.note;
+            entry.top_end = note.end;
+        }
+        entry.longest = entry.longest.max(note.duration);
+    }
+
+    // 之后的音符整体后移的量（keep_length 为 false 时累加）
+    let mut shift = 0.0;
+    // 可能还在发声的音符，包括更早起音的长音
+    let mut sounding: Vec<usize> = Vec::new();
+    for k in 0..chords.len() {
+        for &i in &chords[k].notes {
+            notes[i].time += shift;
+            notes[i].end += shift;
+        }
+        sounding.extend(&chords[k].notes);
+        let Some(next) = chords.get(k + 1) else {
+            break;
+        };
+        // 之后的边界都更晚，在这之前结束的音不会再影响静音长度
+        let boundary = next.start + shift;
+        sounding.retain(|&i| notes[i].end > boundary - gap.seconds);
+        let current = &chords[k];
+        let beat = context.tempo.beat_seconds_at(current.start);
+        let rest = next.start - current.top_end > beat;
+        let jump = current.longest >= beat
+            && (next.top as i32 - current.top as i32).abs() >= PHRASE_REGISTER_JUMP;
+        // 已经有足够的静音
+        if (!rest && !jump) || sounding.is_empty() {
+            continue;
+        }
+
+        // 仍在发声的音在 boundary - half 前松开
+        let release_by = boundary - half;
+        for &i in &sounding {
+            let note = &mut notes[i];
+            if note.end > release_by {
+                note.end = release_by.max(note.time + note.duration * PHRASE_MIN_KEEP);
+                note.duration = note.end - note.time;
+            }
+        }
+        if gap.keep_length {
+            // 只推迟下一组的起音，结束时间不变
+            for &i in &next.notes {
+                let note = &mut notes[i];
+                note.time += half.min(note.duration * (1.0 - PHRASE_MIN_KEEP));
+                note.duration = note.end - note.time;
+            }
+        } else {
+            shift += half;
+        }
+        context.phrase_gaps += 1;
+    }
+    notes
+}
+
+/// 同一个键上两个音重叠时保留哪一个的完整时值
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPreference {
+    Earlier, // 先起音的
+    Longer,  // 时值长的，相同时先起音的
+    Louder,  // 力度大的，相同时时值长的，再相同时先起音的
+}
+
+impl OverlapPreference {
+    fn as_str(self) -> &'static str {
+        match self {
+            OverlapPreference::Earlier => "earlier",
+            OverlapPreference::Longer => "longer",
+            OverlapPreference::Louder => "louder",
+        }
+    }
+
+    // first 先起音；返回 true 表示保留 first
+    fn keeps_first(self, first: &Note, second: &Note) -> bool {
+        let longer = || second.duration <= first.duration;
+        match self {
+            OverlapPreference::Earlier => true,
+            OverlapPreference::Longer => longer(),
+            OverlapPreference::Louder => match second.velocity.cmp(&first.velocity) {
+                std::cmp::Ordering::Equal => longer(),
+                ordering => ordering == std::cmp::Ordering::Less,
+            },
+        }
+    }
+}
+
+/// 同一个键上的重叠：被保留的音完整发声，另一个让开
+/// 保留先起音的时后一个推迟到它结束，保留后起音的时前一个在它起音时松开
+/// 让开后剩下不到 min_remaining_ms 的音删除，如装饰音之后的长音、长音之内的重复音
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct SameKeyOverlap {
+    pub prefer: OverlapPreference,
+    pub min_remaining_ms: f64,
+}
+
+impl Default for SameKeyOverlap {
+    fn default() -> Self {
+        Self {
+            prefer: OverlapPreference::Earlier,
+            min_remaining_ms: 30.0,
+        }
+    }
+}
+
+impl SameKeyOverlap {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1.0..=500.0).contains(&self.min_remaining_ms) {
+            return Err(format!(
+                "same_key_overlap.min_remaining_ms must be within 1-500, got {}",
+                self.min_remaining_ms
+            ));
+        }
+        Ok(())
+    }
+}
+
+// 同一个键上时间重叠的两个音按 same_key_overlap 处理，每处记一条 warning
+// 推迟的音按新的起点重新排队，可能与之后的音再次重叠
+fn resolve_overlaps_pass(mut notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(overlap) = context.same_key_overlap.clone() else {
+        return notes;
+    };
+    let min_remaining = overlap.min_remaining_ms / 1000.0;
+    let mut by_key: HashMap<KeySlot, Vec<usize>> = HashMap::new();
+    for (i, slot) in key_slots(&notes, context.key_map.as_ref())
+        .into_iter()
+        .enumerate()
+    {
+        by_key.entry(slot).or_default().push(i);
+    }
+    let mut keys: Vec<KeySlot> = by_key.keys().cloned().collect();
+    keys.sort_unstable();
+
+    let mut removed = vec![false; notes.len()];
+    let mut found = Vec::new();
+    for key in keys {
+        // 按起音从晚到早排列，从末尾取出；同时起音时按解析顺序
+        let order = |notes: &[Note], a: usize, b: usize| {
+            notes[b]
+                .time
+                .total_cmp(&notes[a].time)
+                .then(notes[b].id.cmp(&notes[a].id))
+        };
+        let mut pending = by_key.remove(&key).unwrap_or_default();
+        pending.sort_by(|&a, &b| order(&notes, a, b));
+        // 当前占着这个键的音
+        let mut active: Option<usize> = None;
+        while let Some(i) = pending.pop() {
+            let Some(a) = active.filter(|&a| notes[i].time < notes[a].end) else {
+                if active.is_none_or(|a| notes[i].end > notes[a].end) {
+                    active = Some(i);
+                }
+                continue;
+            };
+            let (first, second) = (notes[a].clone(), notes[i].clone());
+            let resolution = if overlap.prefer.keeps_first(&first, &second) {
+                if second.end - first.end >= min_remaining {
+                    let note = &mut notes[i];
+                    note.time = first.end;
+                    note.duration = note.end - note.time;
+                    let at = pending.partition_point(|&j| order(&notes, j, i).is_lt());
+                    pending.insert(at, i);
+                    format!("delayed the later note to {:.3}s", first.end)
+                } else {
+                    removed[i] = true;
+                    "dropped the later note".to_string()
+                }
+            } else {
+                active = Some(i);
+                if second.time - first.time >= min_remaining {
+                    let note = &mut notes[a];
+                    note.end = second.time;
+                    note.duration = note.end - note.time;
+                    format!("released the earlier note at {:.3}s", second.time)
+                } else {
+                    removed[a] = true;
+                    "dropped the earlier note".to_string()
+                }
+            };
+            found.push(AnalysisWarning {
+                kind: "same_key_overlap".to_string(),
+                time: second.time,
+                message: format!(
+                    "{} at {:.3}s ({:.3}s) overlaps {:.3}s ({:.3}s) (prefer {}): {}",
+                    key.label(),
+                    first.time,
+                    first.duration,
+                    second.time,
+                    second.duration,
+                    overlap.prefer.as_str(),
+                    resolution
+                ),
+                rate_window: None,
+            });
+        }
+    }
+    found.sort_by(|a, b| a.time.total_cmp(&b.time));
+    context.warnings.extend(found);
+    notes
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !removed)
+        .map(|(note, _)| note)
+        .collect()
+}
+
+/// 每秒同一个键最多按下的次数范围
+pub const MIN_SAME_KEY_RATE: f64 = 1.0;
+pub const MAX_SAME_KEY_RATE: f64 = 100.0;
+
+pub fn validate_same_key_rate(rate: Option<f64>) -> Result<(), String> {
+    match rate {
+        Some(rate) if !(MIN_SAME_KEY_RATE..=MAX_SAME_KEY_RATE).contains(&rate) => Err(format!(
+            "max_same_key_rate must be within {}-{}, got {}",
+            MIN_SAME_KEY_RATE, MAX_SAME_KEY_RATE, rate
+        )),
+        _ => Ok(()),
+    }
+}
+
+// 力度比同一串连音的中位数高出这么多时视为重音，抽稀时优先保留
+const ACCENT_VELOCITY_MARGIN: u8 = 16;
+
+/// 被抽稀的一串同键连音（震音、滚奏）
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThinnedRun {
+    pub note: u8, // 映射后的音高，即实际按下的键
+    pub start: f64,
+    pub end: f64,     // 最后一个起音的时间
+    pub notes: usize, // 抽稀前的音符数
+    pub removed: usize,
+    // 这串连音所在的超速段（MidiAnalysis.input_rate.windows 的序号）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_window: Option<usize>,
+}
+
+// 同一个音高上相邻起音间隔小于 1 / max_same_key_rate 的音符串成一串
+// 每串保留开头、结尾和重音，其余每 k 个保留一个，保留的音之间都不小于这个间隔
+// 被删掉的音的时值并入前一个保留的音，声音不会出现空洞
+fn thin_same_key_pass(mut notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(rate) = context.max_same_key_rate else {
+        return notes;
+    };
+    let interval = 1.0 / rate;
+    let mut by_key: HashMap<u8, Vec<usize>> = HashMap::new();
+    for (i, note) in notes.iter().enumerate() {
+        by_key.entry(note.note).or_default().push(i);
+    }
+    let mut keys: Vec<u8> = by_key.keys().copied().collect();
+    keys.sort_unstable();
+
+    let mut removed = vec![false; notes.len()];
+    for key in keys {
+        let mut indices = by_key.remove(&key).unwrap_or_default();
+        indices.sort_by(|&a, &b| notes[a].time.total_cmp(&notes[b].time));
+        let mut run_start = 0;
+        for k in 1..=indices.len() {
+            let continues =
+                k < indices.len() && notes[indices[k]].time - notes[indices[k - 1]].time < interval;
+            if continues {
+                continue;
+            }
+            let run = &indices[run_start..k];
+            run_start = k;
+            if run.len() < 2 {
+                continue;
+            }
+            let kept = select_run_notes(&notes, run, interval);
+            let removed_count = run.len() - kept.iter().filter(|&&keep| keep).count();
+            if removed_count == 0 {
+                continue;
+            }
+            // 删掉的音并入前一个保留的音，但不越过下一个保留的起音
+            let mut holder = run[0];
+            for (j, &i) in run.iter().enumerate() {
+                if kept[j] {
+                    holder = i;
+                    continue;
+                }
+                removed[i] = true;
+                let next_kept = run[j..]
+                    .iter()
+                    .zip(&kept[j..])
+                    .find(|(_, &keep)| keep)
+                    .map(|(&n, _)| notes[n].time)
+                    .unwrap_or(f64::INFINITY);
+                let end = notes[i].end.min(next_kept);
+                let holder_note = &mut notes[holder];
+                if end > holder_note.end {
+                    holder_note.end = end;
+                    holder_note.duration = end - holder_note.time;
+                }
+            }
+            let (start, end) = (notes[run[0]].time, notes[run[run.len() - 1]].time);
+            context.thinned_runs.push(ThinnedRun {
+                note: key,
+                start,
+                end,
+                notes: run.len(),
+                removed: removed_count,
+                rate_window: context.rate_window_at(start, end),
+            });
+        }
+    }
+    context
+        .thinned_runs
+        .sort_by(|a, b| a.start.total_cmp(&b.start).then(a.note.cmp(&b.note)));
+    notes
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !removed)
+        .map(|(note, _)| note)
+        .collect()
+}
+
+// 按开头、结尾、重音（力度从高到低）、每 k 个一个的顺序挑选
+// 与已保留的音间隔不足 interval 的跳过，返回 run 中每个音是否保留
+fn select_run_notes(notes: &[Note], run: &[usize], interval: f64) -> Vec<bool> {
+    let last = run.len() - 1;
+    let span = notes[run[last]].time - notes[run[0]].time;
+    // 保持在速率以内需要的抽取间隔
+    let k = if span > 0.0 {
+        ((last as f64 / span * interval).ceil() as usize).max(1)
+    } else {
+        run.len()
+    };
+    let mut velocities: Vec<u8> = run.iter().map(|&i| notes[i].velocity).collect();
+    velocities.sort_unstable();
+    let accent = velocities[velocities.len() / 2].saturating_add(ACCENT_VELOCITY_MARGIN);
+    let mut accents: Vec<usize> = (1..last)
+        .filter(|&j| notes[run[j]].velocity >= accent)
+        .collect();
+    accents.sort_by_key(|&j| std::cmp::Reverse(notes[run[j]].velocity));
+
+    let candidates = [0, last]
+        .into_iter()
+        .chain(accents)
+        .chain((k..last).step_by(k));
+    let mut kept = vec![false; run.len()];
+    let mut kept_times: Vec<f64> = Vec::new();
+    for j in candidates {
+        let time = notes[run[j]].time;
+        if kept[j] || kept_times.iter().any(|&t| (t - time).abs() < interval) {
+            continue;
+        }
+        kept[j] = true;
+        kept_times.push(time);
+    }
+    kept
+}
+
+/// 前端的音高到按键映射，如 61 -> "shift+a"
+pub type KeyMap = HashMap<u8, String>;
+
+/// 分层映射中的一层：只负责 min_note-max_note 内的音高
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyMapLayer {
+    pub min_note: u8,
+    pub max_note: u8,
+    pub keys: KeyMap,
+}
+
+/// parse_midi 的 key_map：单个映射，或按顺序排列的分层映射（如旋律区和低音区用不同的修饰键方案）
+/// 每个音高由第一个音域包含它的层决定，该层没有给出按键或没有层包含它时视为没有映射
+#[derive(Debug, Clone)]
+pub enum KeyMapSpec {
+    Single(KeyMap),
+    Layered(Vec<KeyMapLayer>),
+}
+
+impl<'de> Deserialize<'de> for KeyMapSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // untagged 枚举会把对象的键缓存成字符串，无法再解析为 u8，所以先读成 JSON 再按形状分派
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let spec = if value.is_array() {
+            serde_json::from_value(value).map(KeyMapSpec::Layered)
+        } else {
+            serde_json::from_value(value).map(KeyMapSpec::Single)
+        };
+        spec.map_err(serde::de::Error::custom)
+    }
+}
+
+/// 展开后的映射：keys 可直接当作单个映射使用
+#[derive(Debug, Clone)]
+pub struct ResolvedKeyMap {
+    pub keys: KeyMap,
+    // 分层映射时每个音高的按键来自哪一层
+    pub layers: Option<HashMap<u8, usize>>,
+    // 重叠的层给同一音高不同按键时的警告，后面的层被忽略
+    pub warnings: Vec<AnalysisWarning>,
+}
+
+/// 把映射中的按键整理成规范写法，写错的按键报出音高和拼写建议
+pub fn normalize_key_map(keys: &KeyMap) -> Result<KeyMap, String> {
+    let mut notes: Vec<u8> = keys.keys().copied().collect();
+    notes.sort_unstable();
+    notes
+        .into_iter()
+        .map(|note| {
+            uni_input::normalize_key_string(&keys[&note])
+                .map(|key| (note, key))
+                .map_err(|e| format!("key_map note {} ({}): {}", note, get_note_name(note), e))
+        })
+        .collect()
+}
+
+impl KeyMapSpec {
+    /// 展开映射，所有按键整理成规范写法，同一按键的不同写法视为相同
+    pub fn resolve(&self) -> Result<ResolvedKeyMap, String> {
+        let layers = match self {
+            KeyMapSpec::Single(keys) => {
+                return Ok(ResolvedKeyMap {
+                    keys: normalize_key_map(keys)?,
+                    layers: None,
+                    warnings: Vec::new(),
+                })
+            }
+            KeyMapSpec::Layered(layers) => layers,
+        };
+        if layers.is_empty() {
+            return Err("key_map must contain at least one layer".to_string());
+        }
+        for (i, layer) in layers.iter().enumerate() {
+            if layer.min_note > layer.max_note || layer.max_note > 127 {
+                return Err(format!(
+                    "Key map layer {}: invalid range {}-{}",
+                    i, layer.min_note, layer.max_note
+                ));
+            }
+            let mut outside: Vec<u8> = layer
+                .keys
+                .keys()
+                .copied()
+                .filter(|note| !(layer.min_note..=layer.max_note).contains(note))
+                .collect();
+            outside.sort_unstable();
+            if let Some(note) = outside.first() {
+                return Err(format!(
+                    "Key map layer {} maps note {} outside its range {}-{}",
+                    i, note, layer.min_note, layer.max_note
+                ));
+            }
+        }
+        let layers = layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                Ok(KeyMapLayer {
+                    min_note: layer.min_note,
+                    max_note: layer.max_note,
+                    keys: normalize_key_map(&layer.keys)
+                        .map_err(|e| format!("Key map layer {}: {}", i, e))?,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let mut keys = KeyMap::new();
+        let mut origin = HashMap::new();
+        let mut warnings = Vec::new();
+        for note in 0..=127u8 {
+            let mut covering = layers
+                .iter()
+                .enumerate()
+                .filter(|(_, layer)| (layer.min_note..=layer.max_note).contains(&note));
+            let Some((index, layer)) = covering.next() else {
+                continue;
+            };
+            let Some(key) = layer.keys.get(&note) else {
+                continue;
+            };
+            keys.insert(note, key.clone());
+            origin.insert(note, index);
+            for (other, layer) in covering {
+                match layer.keys.get(&note) {
+                    Some(other_key) if other_key != key => warnings.push(AnalysisWarning {
+                        kind: "key_map_overlap".to_string(),
+                        time: 0.0,
+                        message: format!(
+                            "Layers {} and {} both map {}: using {:?} from layer {}, ignoring {:?}",
+                            index,
+                            other,
+                            get_note_name(note),
+                            key,
+                            index,
+                            other_key
+                        ),
+                        rate_window: None,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        Ok(ResolvedKeyMap {
+            keys,
+            layers: Some(origin),
+            warnings,
+        })
+    }
+}
+
+/// 分层映射时在 note_on 事件上标出按键来自哪一层，并在警告开头附上层之间的冲突
+pub fn annotate_key_layers(analysis: &mut MidiAnalysis, key_map: &ResolvedKeyMap) {
+    let Some(layers) = &key_map.layers else {
+        return;
+    };
+    for event in analysis.events.iter_mut() {
+        if event.type_ == "note_on" {
+            event.key_layer = layers.get(&event.note).copied();
+        }
+    }
+    analysis
+        .analysis
+        .warnings
+        .splice(0..0, key_map.warnings.iter().cloned());
+}
+
+/// 修饰键状态的切换次数，游戏里每次切换都相当于多按一个键
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ModifierChurn {
+    pub changes: usize,
+    pub per_second: f64,        // 全曲平均
+    pub peak_per_second: usize, // 任意一秒内最多的切换数
+}
+
+// 有映射的音符按起音排序后的 (下标, 修饰键)，没有映射的音不会被按下，不参与统计
+fn modifier_sequence(notes: &[Note], masks: &HashMap<u8, u8>) -> Vec<(usize, u8)> {
+    let mut sequence: Vec<(usize, u8)> = notes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, note)| match &note.key {
+            Some(key) => Some((i, modifier_mask(key))),
+            None => masks.get(&note.note).map(|&mask| (i, mask)),
+        })
+        .collect();
+    sequence.sort_by(|&(a, _), &(b, _)| {
+        notes[a]
+            .time
+            .total_cmp(&notes[b].time)
+            .then(notes[a].note.cmp(&notes[b].note))
+    });
+    sequence
+}
+
+fn modifier_masks(key_map: &KeyMap) -> HashMap<u8, u8> {
+    key_map
+        .iter()
+        .map(|(&note, key)| (note, modifier_mask(key)))
+        .collect()
+}
+
+/// 按起音顺序相邻两个音需要的修饰键不同时记一次切换
+pub fn modifier_churn(notes: &[Note], key_map: &KeyMap) -> ModifierChurn {
+    let presses: Vec<(f64, u8)> = modifier_sequence(notes, &modifier_masks(key_map))
+        .into_iter()
+        .map(|(i, mask)| (notes[i].time, mask))
+        .collect();
+    churn_of_presses(&presses)
+}
+
+/// 按时间排序的 (按下时刻, 修饰键) 序列的修饰键切换统计
+pub fn churn_of_presses(presses: &[(f64, u8)]) -> ModifierChurn {
+    let changes: Vec<f64> = presses
+        .windows(2)
+        .filter(|pair| pair[0].1 != pair[1].1)
+        .map(|pair| pair[1].0)
+        .collect();
+    let length = presses
+        .last()
+        .zip(presses.first())
+        .map_or(0.0, |(&(last, _), &(first, _))| last - first);
+    let mut peak = 0;
+    let mut window_start = 0;
+    for (i, &time) in changes.iter().enumerate() {
+        while time - changes[window_start] >= 1.0 {
+            window_start += 1;
+        }
+        peak = peak.max(i + 1 - window_start);
+    }
+    ModifierChurn {
+        changes: changes.len(),
+        per_second: if length > 0.0 {
+            changes.len() as f64 / length
+        } else {
+            0.0
+        },
+        peak_per_second: peak,
+    }
+}
+
+/// 按起音顺序统计每个键的按下次数、按住时长和最长连按
+/// 多个音高映射到同一个键时合并计算
+pub fn key_usage(notes: &[Note], key_map: &KeyMap) -> BTreeMap<String, KeyUsage> {
+    let mut presses: Vec<(&Note, &String)> = notes
+        .iter()
+        .filter_map(|note| {
+            note.key
+                .as_ref()
+                .or_else(|| key_map.get(&note.note))
+                .map(|key| (note, key))
+        })
+        .collect();
+    presses.sort_by(|(a, _), (b, _)| a.time.total_cmp(&b.time).then(a.note.cmp(&b.note)));
+
+    let mut usage: BTreeMap<String, KeyUsage> = BTreeMap::new();
+    let mut run: Option<(&String, usize)> = None;
+    for (note, key) in presses {
+        let entry = usage.entry(key.clone()).or_default();
+        entry.count += 1;
+        entry.held_seconds += note.duration;
+        let repeats = match run {
+            Some((last, repeats)) if last == key => repeats + 1,
+            _ => 1,
+        };
+        entry.max_repeats = entry.max_repeats.max(repeats);
+        run = Some((key, repeats));
+    }
+    usage
+}
+
+/// 按下次数最多的 limit 个键，次数相同时按总按住时长，再按键名
+pub fn top_keys(usage: &BTreeMap<String, KeyUsage>, limit: usize) -> Vec<String> {
+    let mut keys: Vec<(&String, &KeyUsage)> = usage.iter().collect();
+    keys.sort_by(|(a_key, a), (b_key, b)| {
+        b.count
+            .cmp(&a.count)
+            .then(b.held_seconds.total_cmp(&a.held_seconds))
+            .then(a_key.cmp(b_key))
+    });
+    keys.into_iter()
+        .take(limit)
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+// 前后相邻的音都不需要修饰键、只有自己需要的音移一个八度，换到不需要修饰键的键上
+// 优先朝映射音域的中间移；目标音高上有重叠的音时不移，避免两个音落到同一个键
+// 每次替换记一条 warning
+fn reduce_modifier_churn_pass(mut notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(key_map) = &context.key_map else {
+        return notes;
+    };
+    let masks = modifier_masks(key_map);
+    let plain: Vec<u8> = masks
+        .iter()
+        .filter(|&(_, &mask)| mask == 0)
+        .map(|(&note, _)| note)
+        .collect();
+    if plain.is_empty() {
+        return notes;
+    }
+    let center = plain.iter().map(|&note| note as f64).sum::<f64>() / plain.len() as f64;
+
+    let mut sequence = modifier_sequence(&notes, &masks);
+    let mut by_pitch: HashMap<u8, Vec<usize>> = HashMap::new();
+    for &(i, _) in &sequence {
+        by_pitch.entry(notes[i].note).or_default().push(i);
+    }
+    for k in 0..sequence.len() {
+        let (i, mask) = sequence[k];
+        let neighbours_plain = (k == 0 || sequence[k - 1].1 == 0)
+            && sequence.get(k + 1).is_none_or(|&(_, next)| next == 0);
+        // 打击乐的按键是固定的，不移八度
+        if mask == 0 || !neighbours_plain || notes[i].key.is_some() {
+            continue;
+        }
+        let pitch = notes[i].note;
+        let octaves: [i16; 2] = if pitch as f64 > center {
+            [-12, 12]
+        } else {
+            [12, -12]
+        };
+        let target = octaves.into_iter().find_map(|offset| {
+            let target = u8::try_from(pitch as i16 + offset).ok()?;
+            if masks.get(&target) != Some(&0) {
+                return None;
+            }
+            let collides = by_pitch.get(&target).is_some_and(|others| {
+                others
+                    .iter()
+                    .any(|&j| notes[j].time < notes[i].end && notes[i].time < notes[j].end)
+            });
+            (!collides).then_some(target)
+        });
+        let Some(target) = target else {
+            continue;
+        };
+        if let Some(others) = by_pitch.get_mut(&pitch) {
+            others.retain(|&j| j != i);
+        }
+        by_pitch.entry(target).or_default().push(i);
+        notes[i].note = target;
+        sequence[k].1 = 0;
+        context.warnings.push(AnalysisWarning {
+            kind: "modifier_substitution".to_string(),
+            time: notes[i].time,
+            message: format!(
+                "{} moved {} an octave to {} ({} -> {}) to avoid a modifier change",
+                get_note_name(pitch),
+                if target > pitch { "up" } else { "down" },
+                get_note_name(target),
+                key_map[&pitch],
+                key_map[&target]
+            ),
+            rate_window: None,
+        });
+    }
+    notes
+}
+
+// 映射到保留键的音移到别的键：先朝可用按键音域的中间移一个八度，再试反方向，然后两个八度
+// 目标音高须有映射、不是保留键、且上面没有重叠的音；都不行时删除。每个音记一条 warning
+fn avoid_reserved_keys_pass(mut notes: Vec<Note>, context: &mut PassContext) -> Vec<Note> {
+    let Some(key_map) = &context.key_map else {
+        return notes;
+    };
+    let reserved: HashSet<u8> = key_map
+        .iter()
+        .filter(|(_, key)| context.reserved_keys.blocks(key))
+        .map(|(&note, _)| note)
+        .collect();
+    if reserved.is_empty() {
+        return notes;
+    }
+    let usable: Vec<u8> = key_map
+        .keys()
+        .copied()
+        .filter(|note| !reserved.contains(note))
+        .collect();
+    let center = if usable.is_empty() {
+        0.0
+    } else {
+        usable.iter().map(|&note| note as f64).sum::<f64>() / usable.len() as f64
+    };
+
+    let mut by_pitch: HashMap<u8, Vec<usize>> = HashMap::new();
+    for (i, note) in notes.iter().enumerate() {
+        if note.key.is_none() {
+            by_pitch.entry(note.note).or_default().push(i);
+        }
+    }
+    let mut dropped = HashSet::new();
+    for i in 0..notes.len() {
+        let pitch = notes[i].note;
+        // 打击乐映射到保留键的音已在 map_drums 中删除
+        if !reserved.contains(&pitch) || notes[i].key.is_some() {
+            continue;
+        }
+        let toward: i16 = if pitch as f64 > center { -12 } else { 12 };
+        let target = [toward, -toward, toward * 2, -toward * 2]
+            .into_iter()
+            .find_map(|offset| {
+                let target = u8::try_from(pitch as i16 + offset).ok()?;
+                if !key_map.contains_key(&target) || reserved.contains(&target) {
+                    return None;
+                }
+                let collides = by_pitch.get(&target).is_some_and(|others| {
+                    others.iter().any(|&j| {
+                        !dropped.contains(&j)
+                            && notes[j].time < notes[i].end
+                            && notes[i].time < notes[j].end
+                    })
+                });
+                (!collides).then_some(target)
+            });
+        let message = match target {
+            Some(target) => {
+                if let Some(others) = by_pitch.get_mut(&pitch) {
+                    others.retain(|&j| j != i);
+                }
+                by_pitch.entry(target).or_default().push(i);
+                notes[i].note = target;
+                format!(
+                    "{} moved to {} ({} -> {}) to avoid a reserved key",
+                    get_note_name(pitch),
+                    get_note_name(target),
+                    key_map[&pitch],
+                    key_map[&target]
+                )
+            }
+            None => {
+                dropped.insert(i);
+                format!(
+                    "{} dropped: its key {} is reserved and no octave has a free key",
+                    get_note_name(pitch),
+                    key_map[&pitch]
+                )
+            }
+        };
+        context.warnings.push(AnalysisWarning {
+            kind: if target.is_some() {
+                "reserved_key_substitution"
+            } else {
+                "reserved_key_dropped"
+            }
+            .to_string(),
+            time: notes[i].time,
+            message,
+            rate_window: None,
+        });
+    }
+    notes
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !dropped.contains(i))
+        .map(|(_, note)| note)
+        .collect()
+}
+
+/// 一个处理步骤对音符列表的影响
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PassTrace {
+    pub name: String,
+    pub notes_before: usize,
+    pub notes_after: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+}
+
+/// 抽样音符经过的变化，note 为 None 表示在该步骤被删除
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NoteChange {
+    pub pass: String,
+    pub note: Option<Note>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NoteProvenance {
+    pub original: Note,
+    pub changes: Vec<NoteChange>, // 只记录有变化的步骤
+}
+
+/// 开启 trace 时记录的处理过程，用于回答"这个音符为什么不见了"
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PipelineTrace {
+    pub passes: Vec<PassTrace>,
+    pub samples: Vec<NoteProvenance>,
+}
+
+// 记录来历的抽样音符数，在全曲中均匀抽取
+const TRACE_SAMPLE_SIZE: usize = 32;
+
+/// 依次执行处理步骤，trace 为 true 时同时记录每步的增删改
+/// context.raw_notes 为 Some 时在固定步骤（FIXED_PASSES）之后记下当时的音符
+pub fn run_pipeline(
+    passes: &[AnalyzerPass],
+    mut notes: Vec<Note>,
+    context: &mut PassContext,
+    trace: bool,
+) -> (Vec<Note>, Option<PipelineTrace>) {
+    let raw_at = passes
+        .iter()
+        .take_while(|pass| FIXED_PASSES.contains(&pass.name))
+        .count();
+    let record_raw = |i: usize, notes: &[Note], context: &mut PassContext| {
+        if i == raw_at && context.raw_notes.is_some() {
+            context.raw_notes = Some(notes.to_vec());
+        }
+    };
+
+    if !trace {
+        for (i, pass) in passes.iter().enumerate() {
+            record_raw(i, &notes, context);
+            notes = (pass.run)(notes, context);
+        }
+        record_raw(passes.len(), &notes, context);
+        return (notes, None);
+    }
+
+    let step = notes.len().div_ceil(TRACE_SAMPLE_SIZE).max(1);
+    let mut samples: Vec<NoteProvenance> = notes
+        .iter()
+        .step_by(step)
+        .map(|note| NoteProvenance {
+            original: note.clone(),
+            changes: Vec::new(),
+        })
+        .collect();
+    let mut pass_traces = Vec::new();
+
+    for (i, pass) in passes.iter().enumerate() {
+        record_raw(i, &notes, context);
+        let before: HashMap<usize, Note> = notes.iter().map(|n| (n.id, n.clone())).collect();
+        notes = (pass.run)(notes, context);
+        let after: HashMap<usize, &Note> = notes.iter().map(|n| (n.id, n)).collect();
+
+        let mut added = 0;
+        let mut modified = 0;
+        for note in &notes {
+            match before.get(&note.id) {
+                None => added += 1,
+                Some(old) if old != note => modified += 1,
+                Some(_) => {}
+            }
+        }
+        pass_traces.push(PassTrace {
+            name: pass.name.to_string(),
+            notes_before: before.len(),
+            notes_after: notes.len(),
+            added,
+            removed: before.keys().filter(|id| !after.contains_key(id)).count(),
+            modified,
+        });
+
+        for sample in &mut samples {
+            let id = sample.original.id;
+            let (old, new) = (before.get(&id), after.get(&id).copied());
+            if old != new {
+                sample.changes.push(NoteChange {
+                    pass: pass.name.to_string(),
+                    note: new.cloned(),
+                });
+            }
+        }
+    }
+
+    record_raw(passes.len(), &notes, context);
+    let pipeline = PipelineTrace {
+        passes: pass_traces,
+        samples,
+    };
+    (notes, Some(pipeline))
+}
+
+/// 和弦判定窗口：毫秒，或按当时速度换算的拍数（如 0.0625 为十六分之一拍）
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(tag = "unit", content = "value", rename_all = "snake_case")]
+pub enum ChordWindow {
+    Ms(f64),
+    Beats(f64),
+}
+
+/// 怎样把起音归为同一个和弦
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChordStrategy {
+    FixedWindow,   // 与和弦第一个起音相差不超过窗口
+    GapClustering, // 与前一个起音相差不超过窗口，连续的滚奏会连成一组
+}
+
+/// 和弦分组设置，解析时使用，分组结果写入 note_on 事件的 chord
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ChordGrouping {
+    pub window: ChordWindow,
+    pub strategy: ChordStrategy,
+}
+
+impl Default for ChordGrouping {
+    fn default() -> Self {
+        Self {
+            window: ChordWindow::Ms(20.0),
+            strategy: ChordStrategy::FixedWindow,
+        }
+    }
+}
+
+impl ChordGrouping {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.window {
+            ChordWindow::Ms(ms) if !(0.0..=200.0).contains(&ms) => Err(format!(
+                "chord_grouping.window must be within 0-200 ms, got {}",
+                ms
+            )),
+            ChordWindow::Beats(beats) if !(0.0..=1.0).contains(&beats) => Err(format!(
+                "chord_grouping.window must be within 0-1 beats, got {}",
+                beats
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 速度表：(开始秒数, 每拍秒数)，按时间升序，第一项从 0 秒开始
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    changes: Vec<(f64, f64)>,
+}
+
+impl Default for TempoMap {
+    // 没有速度信息时按 120 BPM
+    fn default() -> Self {
+        Self {
+            changes: vec![(0.0, 0.5)],
+        }
+    }
+}
+
+impl TempoMap {
+    pub fn new(mut changes: Vec<(f64, f64)>) -> Self {
+        if changes.first().is_none_or(|&(time, _)| time > 0.0) {
+            changes.insert(0, (0.0, 0.5));
+        }
+        Self { changes }
+    }
+
+    pub fn beat_seconds_at(&self, time: f64) -> f64 {
+        let i = self.changes.partition_point(|&(start, _)| start <= time);
+        self.changes[i.saturating_sub(1)].1
+    }
+
+    /// 从 0 秒到 time 经过的拍数
+    pub fn beats_at(&self, time: f64) -> f64 {
+        let mut beats = 0.0;
+        for (i, &(start, beat_seconds)) in self.changes.iter().enumerate() {
+            if start >= time {
+                break;
+            }
+            let end = self
+                .changes
+                .get(i + 1)
+                .map_or(time, |&(next, _)| next.min(time));
+            beats += (end - start) / beat_seconds;
+        }
+        beats
+    }
+
+    /// 各段速度中最慢和最快的 (BPM, BPM)
+    pub fn bpm_range(&self) -> (f64, f64) {
+        self.changes
+            .iter()
+            .map(|&(_, beat_seconds)| 60.0 / beat_seconds)
+            .fold((f64::INFINITY, 0.0), |(min, max), bpm| {
+                (min.min(bpm), max.max(bpm))
+            })
+    }
+}
+
+/// 把起音分成和弦，需要判断"是否同一和弦"的功能（修饰键冲突、练习模式等）都使用这里的结果
+pub struct ChordGrouper {
+    grouping: ChordGrouping,
+    tempo: TempoMap,
+}
+
+impl ChordGrouper {
+    pub fn new(grouping: ChordGrouping, tempo: TempoMap) -> Self {
+        Self { grouping, tempo }
+    }
+
+    // time 处的窗口（秒）
+    fn window_at(&self, time: f64) -> f64 {
+        match self.grouping.window {
+            ChordWindow::Ms(ms) => ms / 1000.0,
+            ChordWindow::Beats(beats) => beats * self.tempo.beat_seconds_at(time),
+        }
+    }
+
+    /// 返回每个起音所属和弦的序号，序号按时间从 0 开始递增；onsets 不需要有序
+    pub fn group(&self, onsets: &[f64]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..onsets.len()).collect();
+        order.sort_by(|&a, &b| {
+            onsets[a]
+                .partial_cmp(&onsets[b])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let mut chords = vec![0; onsets.len()];
+        // (当前和弦序号, 和弦第一个起音, 上一个起音)
+        let mut current: Option<(usize, f64, f64)> = None;
+        for i in order {
+            let time = onsets[i];
+            let chord = match current {
+                Some((chord, first, previous)) => {
+                    let anchor = match self.grouping.strategy {
+                        ChordStrategy::FixedWindow => first,
+                        ChordStrategy::GapClustering => previous,
+                    };
+                    // 加一点余量，量化过的和弦不会因浮点误差被拆开
+                    if time - anchor <= self.window_at(anchor) + 1e-9 {
+                        current = Some((chord, first, time));
+                        chord
+                    } else {
+                        current = Some((chord + 1, time, time));
+                        chord + 1
+                    }
+                }
+                None => {
+                    current = Some((0, time, time));
+                    0
+                }
+            };
+            chords[i] = chord;
+        }
+        chords
+    }
+}
+
+/// 解析进度，每个音轨开始时和每处理 PROGRESS_INTERVAL 个事件报告一次
+#[derive(Debug, Clone, Serialize)]
+pub struct ParseProgress {
+    pub pass: u8, // 1: 统计音轨和速度变化，2: 收集音符
+    pub track: usize,
+    pub track_count: usize,
+    pub percent: f64,
+}
+
+/// 进度回调，返回 false 时中止解析
+pub type ProgressCallback<'a> = &'a mut dyn FnMut(ParseProgress) -> bool;
+
+/// 解析被进度回调中止时 Cancelled 错误的信息
+pub const PARSE_CANCELLED: &str = "Parse cancelled";
+
+const PROGRESS_INTERVAL: usize = 5000;
+
+// 两遍遍历全部音轨，按已处理的事件数计算百分比
+struct ProgressTracker<'a> {
+    callback: ProgressCallback<'a>,
+    track_count: usize,
+    done: usize,
+    total: usize,
+}
+
+impl ProgressTracker<'_> {
+    fn report(&mut self, pass: u8, track: usize) -> Result<(), CommandError> {
+        let percent = if self.total == 0 {
+            100.0
+        } else {
+            self.done as f64 * 100.0 / self.total as f64
+        };
+        let progress = ParseProgress {
+            pass,
+            track,
+            track_count: self.track_count,
+            percent,
+        };
+        if (self.callback)(progress) {
+            Ok(())
+        } else {
+            Err(CommandError::Cancelled(PARSE_CANCELLED.to_string()))
+        }
+    }
+
+    fn advance(&mut self, pass: u8, track: usize) -> Result<(), CommandError> {
+        self.done += 1;
+        if self.done % PROGRESS_INTERVAL == 0 {
+            self.report(pass, track)?;
+        }
+        Ok(())
+    }
+}
+
+/// 解析选项，与读取的文件无关
+/// 前端按文件记住的设置（recent_files 的 FileSettings）就是这些字段
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnalyzerOptions {
+    pub min_note: u8,
+    pub max_note: u8,
+    pub black_key_mode: BlackKeyMode,
+    pub trim_long_notes: bool,
+    pub chord_grouping: ChordGrouping,
+    pub phrase_gap: Option<PhraseGap>,
+    pub max_same_key_rate: Option<f64>,
+    pub reduce_modifier_churn: bool, // 需要同时给出 key_map
+    // 只合并这些音轨，各自先移调；None 时合并全部音轨
+    pub track_selection: Option<Vec<TrackSelection>>,
+    pub on_unison: Option<UnisonPolicy>, // 不同音轨撞到同一个键时的处理，None 时不处理
+    pub chord_spread_ms: f64,            // keep_both_staggered 时后一个音推迟的时间
+    pub include_measures: bool,          // 在结果中附带小节边界，用于按小节循环播放
+    pub flatten_tempo: bool,             // 用保持总时长的平均速度代替速度变化
+    pub max_polyphony: Option<usize>,    // 最多同时发声的音数，None 时不限制
+    pub eviction: PolyphonyEviction,     // 超出 max_polyphony 时腾出位置的方式
+    pub target_max_rate: Option<f64>,    // 游戏能接受的每秒按键数，给出时检查超速段，只做提示
+    pub include_raw_events: bool,        // 在结果中附带可选处理步骤之前的事件，用于对比处理效果
+    // 同一个键上前一个音还没结束、后一个音就起音时保留哪一个的完整时值，None 时不处理
+    pub same_key_overlap: Option<SameKeyOverlap>,
+    // 调整可选处理步骤的顺序（名称见 PASS_NAMES），如把 trim_long_notes 放到 limit_polyphony 之后：
+    // 复音数按截短之前的时值计算，重叠的长音会先挤掉别的音
+    pub pass_order: Option<Vec<String>>,
+    // 游戏里另有用途的键（如移动用的 w/a/s/d），映射到这些键的音移八度到别的键，移不开时删除，需要 key_map
+    pub reserved_keys: Vec<String>,
+    // 有弱起时小节号从第一个完整小节开始（见 MidiAnalysis::pickup），事件时间不变
+    pub align_to_downbeat: bool,
+    // 打击乐通道（DRUM_CHANNEL）的音不按音高映射，而是按 GM 打击乐映射定下按键（见 MidiEvent::key）
+    pub drum_mode: bool,
+    // 覆盖默认打击乐映射的项，如 {"38": "h"}；空字符串取消该音高的映射
+    pub drum_keys: KeyMap,
+}
+
+impl Default for AnalyzerOptions {
+    fn default() -> Self {
+        Self {
+            min_note: 48,
+            max_note: 83,
+            black_key_mode: BlackKeyMode::SupportBlackKey,
+            trim_long_notes: false,
+            chord_grouping: ChordGrouping::default(),
+            phrase_gap: None,
+            max_same_key_rate: None,
+            reduce_modifier_churn: false,
+            track_selection: None,
+            on_unison: None,
+            chord_spread_ms: DEFAULT_CHORD_SPREAD_MS,
+            same_key_overlap: None,
+            include_measures: false,
+            flatten_tempo: false,
+            max_polyphony: None,
+            eviction: PolyphonyEviction::CutOldest,
+            target_max_rate: None,
+            include_raw_events: false,
+            pass_order: None,
+            reserved_keys: Vec::new(),
+            align_to_downbeat: false,
+            drum_mode: false,
+            drum_keys: KeyMap::new(),
+        }
+    }
+}
+
+const MAX_POLYPHONY: usize = 32;
+
+const DEFAULT_CHORD_SPREAD_MS: f64 = 30.0;
+const MAX_CHORD_SPREAD_MS: f64 = 200.0;
+
+// MIDI 音高的最大值
+const MAX_MIDI_NOTE: u8 = 127;
+
+impl AnalyzerOptions {
+    /// 在打开文件之前检查解析选项，错误信息说明是哪个参数、取值是多少
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, note) in [("min_note", self.min_note), ("max_note", self.max_note)] {
+            if note > MAX_MIDI_NOTE {
+                return Err(format!(
+                    "{} must be within 0-{}, got {}",
+                    name, MAX_MIDI_NOTE, note
+                ));
+            }
+        }
+        if self.min_note > self.max_note {
+            return Err(format!(
+                "min_note ({}) must not be greater than max_note ({})",
+                self.min_note, self.max_note
+            ));
+        }
+        self.chord_grouping.validate()?;
+        if let Some(gap) = &self.phrase_gap {
+            gap.validate()?;
+        }
+        if let Some(overlap) = &self.same_key_overlap {
+            overlap.validate()?;
+        }
+        validate_same_key_rate(self.max_same_key_rate)?;
+        if let Some(limit) = self.max_polyphony {
+            if !(1..=MAX_POLYPHONY).contains(&limit) {
+                return Err(format!(
+                    "max_polyphony must be within 1-{}, got {}",
+                    MAX_POLYPHONY, limit
+                ));
+            }
+        }
+        if let Some(rate) = self.target_max_rate {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(format!("target_max_rate must be positive, got {}", rate));
+            }
+        }
+        if !(1.0..=MAX_CHORD_SPREAD_MS).contains(&self.chord_spread_ms) {
+            return Err(format!(
+                "chord_spread_ms must be within 1-{}, got {}",
+                MAX_CHORD_SPREAD_MS, self.chord_spread_ms
+            ));
+        }
+        if let Some(selection) = &self.track_selection {
+            if selection.is_empty() {
+                return Err("track_selection must not be empty".to_string());
+            }
+            for (i, track) in selection.iter().enumerate() {
+                if selection[..i]
+                    .iter()
+                    .any(|other| other.index == track.index)
+                {
+                    return Err(format!(
+                        "track_selection[{}]: track {} is selected more than once",
+                        i, track.index
+                    ));
+                }
+            }
+        }
+        ReservedKeys::parse(&self.reserved_keys)?;
+        drum_key_table(&self.drum_keys)?;
+        if let Some(order) = &self.pass_order {
+            reorder_passes(&mut default_pipeline(self, true), order)?;
+        }
+        Ok(())
+    }
+
+    /// 读取文件之后检查音轨选择：音轨必须存在且有音符，移调不能把整个音轨移出音域
+    /// 预览读取只有开头一段，不检查音域
+    pub fn validate_tracks(&self, raw: &RawMidi) -> Result<(), String> {
+        let Some(selection) = &self.track_selection else {
+            return Ok(());
+        };
+        for (i, selected) in selection.iter().enumerate() {
+            let Some(track) = raw.tracks.iter().find(|track| track.id == selected.index) else {
+                let available: Vec<String> = raw
+                    .tracks
+                    .iter()
+                    .map(|track| track.id.to_string())
+                    .collect();
+                return Err(format!(
+                    "track_selection[{}].index must be a track with notes ({}), got {}",
+                    i,
+                    available.join(", "),
+                    selected.index
+                ));
+            };
+            if selected.transpose == 0 || raw.partial {
+                continue;
+            }
+            let in_range = track.pitches.iter().any(|&pitch| {
+                transpose_note(pitch, selected.transpose)
+                    .is_some_and(|note| (self.min_note..=self.max_note).contains(&note))
+            });
+            if !in_range {
+                return Err(format!(
+                    "track_selection[{}].transpose ({:+}) moves every note of track {} out of range {}-{}",
+                    i, selected.transpose, selected.index, self.min_note, self.max_note
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_preview(preview: Option<f64>) -> Result<(), String> {
+    match preview {
+        Some(seconds) if !(seconds.is_finite() && seconds > 0.0) => Err(format!(
+            "preview must be a positive number of seconds, got {}",
+            seconds
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// 读取并分析 MIDI 文件
+/// 选项无效时返回 InvalidArgument，文件不存在时返回 FileNotFound，无法解析时返回 InvalidFile
+pub fn analyze_midi_file(
+    file_path: &str,
+    options: &AnalyzerOptions,
+) -> Result<MidiAnalysis, CommandError> {
+    analyze_midi_file_with_progress(file_path, options, None, false, None, &mut |_| true)
+}
+
+/// 与 analyze_midi_file 相同，解析过程中通过 progress 报告进度，中止时返回 Cancelled
+/// key_map 为当前的按键映射，给出时统计修饰键切换和每个键的使用情况，reduce_modifier_churn 也需要它
+/// trace 为 true 时在结果的 pipeline 中记录每个处理步骤的影响
+/// preview 见 read_midi_file
+pub fn analyze_midi_file_with_progress(
+    file_path: &str,
+    options: &AnalyzerOptions,
+    key_map: Option<&KeyMap>,
+    trace: bool,
+    preview: Option<f64>,
+    progress: ProgressCallback,
+) -> Result<MidiAnalysis, CommandError> {
+    options.validate().map_err(CommandError::InvalidArgument)?;
+    let raw = read_midi_file(file_path, preview, progress)?;
+    options
+        .validate_tracks(&raw)
+        .map_err(CommandError::InvalidArgument)?;
+    Ok(analyze_raw(&raw, options, key_map, trace))
+}
+
+/// 有音符的音轨
+#[derive(Debug, Clone)]
+pub struct RawTrack {
+    pub id: usize,
+    pub name: String,
+    pub pitches: Vec<u8>, // 所有 note on 的音高，按出现顺序
+}
+
+/// 读取文件得到的原始音符和速度信息，与解析选项无关，可以缓存后按不同选项重复分析
+#[derive(Debug, Clone)]
+pub struct RawMidi {
+    pub notes: Vec<Note>,
+    pub tracks: Vec<RawTrack>,
+    pub tempo: TempoMap,
+    pub initial_tempo: u32, // 开头的每拍微秒数
+    pub time_signature: (u8, u8),
+    pub measures: Vec<f64>, // 小节边界，见 MidiAnalysis::measures
+    pub duration: f64,      // 整个文件最后一个事件的时间，预览读取时也是整首歌的
+    pub partial: bool,      // 预览读取，只有开头一段
+    pub loop_start: Option<f64>,
+    pub loop_end: Option<f64>, // 只在 loop_start 之后才有
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LoopPoint {
+    Start,
+    End,
+}
+
+// 游戏音乐的循环点约定：CC111（RPG Maker 等）表示循环起点，
+// 或文字为 loopStart / loopEnd 的 Marker、Text、CuePoint 事件（不区分大小写，忽略空格、下划线和连字符）
+fn loop_point_kind(kind: &TrackEventKind) -> Option<LoopPoint> {
+    let text = match kind {
+        TrackEventKind::Midi {
+            message: MidiMessage::Controller { controller, .. },
+            ..
+        } if controller.as_int() == 111 => return Some(LoopPoint::Start),
+        TrackEventKind::Meta(
+            midly::MetaMessage::Marker(text)
+            | midly::MetaMessage::Text(text)
+            | midly::MetaMessage::CuePoint(text),
+        ) => text,
+        _ => return None,
+    };
+    let name: String = String::from_utf8_lossy(text)
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '_' | '-'))
+        .collect::<String>()
+        .to_lowercase();
+    match name.as_str() {
+        "loopstart" => Some(LoopPoint::Start),
+        "loopend" => Some(LoopPoint::End),
+        _ => None,
+    }
+}
+
+/// 读取并解析 MIDI 文件，通过 progress 报告进度
+/// preview 为 Some(秒) 时只收集这之前起音的音符（不含该时刻），跨过该时刻的音符截断，结果标记为 partial
+pub fn read_midi_file(
+    file_path: &str,
+    preview: Option<f64>,
+    progress: ProgressCallback,
+) -> Result<RawMidi, CommandError> {
+    validate_preview(preview).map_err(CommandError::InvalidArgument)?;
+    parse_midi_bytes(&read_file_bytes(file_path)?, preview, progress)
+}
+
+fn read_file_bytes(file_path: &str) -> Result<Vec<u8>, CommandError> {
+    let path = Path::new(file_path);
+    if !path.exists() {
+        return Err(CommandError::FileNotFound(format!(
+            "File not found: {}",
+            file_path
+        )));
+    }
+    fs::read(path).map_err(|e| CommandError::InvalidFile(format!("Failed to read file: {}", e)))
+}
+
+/// 文件内容的哈希，用于识别内容相同的文件（同一进程内稳定）
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// 解析已读入内存的 MIDI 文件，preview 见 read_midi_file
+pub fn parse_midi_bytes(
+    bytes: &[u8],
+    preview: Option<f64>,
+    progress: ProgressCallback,
+) -> Result<RawMidi, CommandError> {
+    validate_preview(preview).map_err(CommandError::InvalidArgument)?;
+    let smf = Smf::parse(bytes)
+        .map_err(|e| CommandError::InvalidFile(format!("Failed to parse MIDI: {}", e)))?;
+
+    let mut progress = ProgressTracker {
+        callback: progress,
+        track_count: smf.tracks.len(),
+        done: 0,
+        total: smf.tracks.iter().map(|track| track.len()).sum::<usize>() * 2,
+    };
+
+    let ticks_per_beat = match smf.header.timing {
+        midly::Timing::Metrical(t) => t.as_int() as f64,
+        midly::Timing::Timecode(_, _) => {
+            return Err(CommandError::InvalidFile(
+                "SMPTE timing not supported yet".to_string(),
+            ))
+        }
+    };
+
+    let mut notes = Vec::new();
+    let mut tracks = Vec::new();
+    let mut tempo_changes = Vec::new(); // (tick, microseconds_per_beat)
+    let mut time_signature: Option<(u32, (u8, u8))> = None; // 最早的拍号 (tick, (分子, 分母))
+    let mut signature_changes = Vec::new(); // 全部拍号 (tick, (分子, 分母))，用于划分小节
+    let mut end_tick = 0; // 所有音轨中最后一个事件的 tick
+    let mut loop_points = Vec::new(); // (tick, LoopPoint)
+
+    // First pass: collect tempo changes from all tracks (usually track 0)
+    // And also track names and per-track pitches
+
+    for (i, track) in smf.tracks.iter().enumerate() {
+        let mut current_tick = 0;
+        let mut track_name = format!("Track {}", i);
+        let mut note_count = 0;
+        let mut notes_in_track = Vec::new();
+        progress.report(1, i)?;
+
+        for event in track {
+            progress.advance(1, i)?;
+            current_tick += event.delta.as_int();
+
+            if let Some(point) = loop_point_kind(&event.kind) {
+                loop_points.push((current_tick, point));
+            }
+            match event.kind {
+                TrackEventKind::Meta(midly::MetaMessage::Tempo(t)) => {
+                    tempo_changes.push((current_tick, t.as_int()));
+                }
+                TrackEventKind::Meta(midly::MetaMessage::TimeSignature(num, denom_pow, _, _)) => {
+                    let denominator = 1u8.checked_shl(denom_pow as u32).unwrap_or(4);
+                    signature_changes.push((current_tick, (num, denominator)));
+                    if time_signature.is_none_or(|(tick, _)| current_tick < tick) {
+                        time_signature = Some((current_tick, (num, denominator)));
+                    }
+                }
+                TrackEventKind::Meta(midly::MetaMessage::TrackName(name)) => {
+                    if let Ok(n) = String::from_utf8(name.to_vec()) {
+                        track_name = n;
+                    }
+                }
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { key, vel },
+                    ..
+                } => {
+                    if vel.as_int() > 0 {
+                        note_count += 1;
+                        notes_in_track.push(key.as_int());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        end_tick = end_tick.max(current_tick);
+        if note_count > 0 {
+            tracks.push(RawTrack {
+                id: i,
+                name: track_name,
+                pitches: notes_in_track,
+            });
+        }
+    }
+
+    // Sort tempo changes by tick
+    tempo_changes.sort_by_key(|k| k.0);
+    // Dedup tempo changes (keep last one for same tick)
+    let mut unique_tempo_changes: Vec<(u32, u32)> = Vec::new();
+    for tc in tempo_changes {
+        if let Some(last) = unique_tempo_changes.last_mut() {
+            if last.0 == tc.0 {
+                *last = tc;
+            } else {
+                unique_tempo_changes.push(tc);
+            }
+        } else {
+            unique_tempo_changes.push(tc);
+        }
+    }
+    // Ensure there is a tempo at tick 0 (default 120 BPM = 500,000 microseconds per beat)
+    if unique_tempo_changes.is_empty() || unique_tempo_changes[0].0 > 0 {
+        unique_tempo_changes.insert(0, (0, 500_000));
+    }
+
+    let initial_tempo = unique_tempo_changes[0].1.max(1);
+
+    // Helper to convert ticks to seconds
+    let tick_to_seconds = |tick: u32| -> f64 {
+        let mut time = 0.0;
+        let mut last_tick = 0;
+        let mut last_tempo = 500_000; // Default
+
+        for (t_tick, t_tempo) in &unique_tempo_changes {
+            if *t_tick > tick {
+                break;
+            }
+            let delta = *t_tick - last_tick;
+            time += (delta as f64 * last_tempo as f64) / (ticks_per_beat * 1_000_000.0);
+            last_tick = *t_tick;
+            last_tempo = *t_tempo;
+        }
+
+        let delta = tick - last_tick;
+        time += (delta as f64 * last_tempo as f64) / (ticks_per_beat * 1_000_000.0);
+        time
+    };
+
+    // Second pass: collect notes
+    for (i, track) in smf.tracks.iter().enumerate() {
+        let mut current_tick = 0;
+        // Key: (channel, note), Value: (start_tick, velocity)
+        let mut active_notes: HashMap<(u8, u8), (u32, u8)> = HashMap::new();
+        progress.report(2, i)?;
+
+        // 预览时到达截止时刻后不再读取该音轨
+        let mut cut_at = None;
+
+        for event in track {
+            progress.advance(2, i)?;
+            current_tick += event.delta.as_int();
+
+            if let TrackEventKind::Midi { channel, message } = event.kind {
+                if let Some(limit) = preview.filter(|&limit| tick_to_seconds(current_tick) >= limit)
+                {
+                    cut_at = Some(limit);
+                    break;
+                }
+                let channel = channel.as_int();
+                let (note, velocity) = match message {
+                    MidiMessage::NoteOn { key, vel } => (key.as_int(), vel.as_int()),
+                    // NoteOn with velocity 0 is NoteOff
+                    MidiMessage::NoteOff { key, .. } => (key.as_int(), 0),
+                    _ => continue,
+                };
+                if velocity > 0 {
+                    active_notes.insert((channel, note), (current_tick, velocity));
+                } else if let Some((start_tick, start_vel)) = active_notes.remove(&(channel, note))
+                {
+                    let time = tick_to_seconds(start_tick);
+                    let end = tick_to_seconds(current_tick);
+                    notes.push(Note {
+                        id: notes.len(),
+                        time,
+                        duration: end - time,
+                        end,
+                        note,
+                        channel,
+                        track: i,
+                        velocity: start_vel,
+                        key: None,
+                    });
+                }
+            }
+        }
+
+        // 处理该音轨中未关闭的音符（自动生成0.2秒的off事件，预览时截断在截止时刻）
+        for ((channel, note), (start_tick, start_vel)) in active_notes {
+            let time = tick_to_seconds(start_tick);
+            let duration = cut_at.map_or(0.2, |limit| limit - time); // 默认给0.2秒
+            notes.push(Note {
+                id: notes.len(),
+                time,
+                duration,
+                end: time + duration,
+                note,
+                channel,
+                track: i,
+                velocity: start_vel,
+                key: None,
+            });
+        }
+    }
+
+    progress.report(2, smf.tracks.len().saturating_sub(1))?;
+
+    let tempo = TempoMap::new(
+        unique_tempo_changes
+            .iter()
+            .map(|&(tick, tempo)| (tick_to_seconds(tick), tempo as f64 / 1_000_000.0))
+            .collect(),
+    );
+    let duration = tick_to_seconds(end_tick);
+    // 多个标记时取最早的起点，和它之后最早的终点
+    let loop_start = loop_points
+        .iter()
+        .filter(|(_, point)| *point == LoopPoint::Start)
+        .map(|&(tick, _)| tick)
+        .min();
+    let loop_end = loop_start.and_then(|start| {
+        loop_points
+            .iter()
+            .filter(|&&(tick, point)| point == LoopPoint::End && tick > start)
+            .map(|&(tick, _)| tick)
+            .min()
+    });
+    let measures = measure_ticks(signature_changes, ticks_per_beat, end_tick)
+        .into_iter()
+        .map(tick_to_seconds)
+        .collect();
+    Ok(RawMidi {
+        notes,
+        tracks,
+        tempo,
+        initial_tempo,
+        time_signature: time_signature.map_or((4, 4), |(_, signature)| signature),
+        measures,
+        duration,
+        partial: preview.is_some(),
+        loop_start: loop_start.map(tick_to_seconds),
+        loop_end: loop_end.map(tick_to_seconds),
+    })
+}
+
+/// 按拍号划分小节，返回各小节起点的 tick，最后一项是覆盖 end_tick 的那一小节的终点
+/// 拍号出现在小节中间时从该处另起一小节
+fn measure_ticks(
+    mut changes: Vec<(u32, (u8, u8))>,
+    ticks_per_beat: f64,
+    end_tick: u32,
+) -> Vec<u32> {
+    changes.sort_by_key(|&(tick, _)| tick);
+    let mut signature = (4, 4);
+    let mut next_change = 0;
+    let mut tick = 0;
+    let mut boundaries = vec![0];
+    while tick < end_tick || boundaries.len() < 2 {
+        while next_change < changes.len() && changes[next_change].0 <= tick {
+            signature = changes[next_change].1;
+            next_change += 1;
+        }
+        let (beats, unit) = signature;
+        let length = (ticks_per_beat * 4.0 / unit.max(1) as f64 * beats.max(1) as f64).round();
+        let mut next = tick.saturating_add((length as u32).max(1));
+        if let Some(&(change, _)) = changes.get(next_change) {
+            next = next.min(change);
+        }
+        tick = next;
+        boundaries.push(tick);
+    }
+    boundaries
+}
+
+// 第一个音离小节起点至少这么多拍（四分音符）时当作弱起，更近的只是起音不齐
+const MIN_PICKUP_OFFSET_BEATS: f64 = 0.5;
+
+// 检测弱起，返回第一个音的时间和第一个完整小节在 raw.measures 中的序号
+// 第一个音所在的小节比下一小节短（拍号表里的弱起小节），或第一个音在小节中间（用休止补齐的弱起小节）时是弱起
+fn detect_pickup(raw: &RawMidi) -> Option<(f64, usize)> {
+    let first = raw
+        .notes
+        .iter()
+        .map(|note| note.time)
+        .min_by(f64::total_cmp)?;
+    let measures = &raw.measures;
+    let index = measures
+        .partition_point(|&boundary| boundary <= first)
+        .max(1)
+        - 1;
+    // 后面至少还要有一个完整的小节
+    if index + 2 >= measures.len() {
+        return None;
+    }
+    let beats = |time: f64| raw.tempo.beats_at(time);
+    let length = beats(measures[index + 1]) - beats(measures[index]);
+    let next_length = beats(measures[index + 2]) - beats(measures[index + 1]);
+    let short = length < next_length - 1e-6;
+    let offset = beats(first) - beats(measures[index]);
+    (short || offset >= MIN_PICKUP_OFFSET_BEATS).then_some((first, index + 1))
+}
+
+// 一个音轨的音域统计和移调建议
+fn track_info(track: &RawTrack, limit_min: u8, limit_max: u8) -> TrackInfo {
+    let max_note = track.pitches.iter().max().copied();
+    let min_note = track.pitches.iter().min().copied();
+
+    let upper_over_limit = track.pitches.iter().filter(|&&n| n > limit_max).count();
+    let lower_over_limit = track.pitches.iter().filter(|&&n| n < limit_min).count();
+
+    let is_max_over_limit = max_note.map_or(false, |n| n > limit_max || n < limit_min);
+    let is_min_over_limit = min_note.map_or(false, |n| n < limit_min || n > limit_max);
+
+    // 计算建议值（当前移调和转位都是0）
+    let current_transpose = 0;
+    let current_octave = 0;
+
+    let (suggested_max_transpose, suggested_max_octave) = if is_max_over_limit {
+        max_note
+            .and_then(|n| {
+                let diff = limit_max as i32 - n as i32;
+                optimize_transpose_suggestion(diff, current_transpose, current_octave)
+            })
+            .map(|(t, o)| (Some(t), Some(o)))
+            .unwrap_or((None, None))
+    } else {
+        (None, None)
+    };
+
+    let (suggested_min_transpose, suggested_min_octave) = if is_min_over_limit {
+        min_note
+            .and_then(|n| {
+                let diff = limit_min as i32 - n as i32;
+                optimize_transpose_suggestion(diff, current_transpose, current_octave)
+            })
+            .map(|(t, o)| (Some(t), Some(o)))
+            .unwrap_or((None, None))
+    } else {
+        (None, None)
+    };
+
+    let analysis = TrackAnalysis {
+        max_note,
+        min_note,
+        max_note_name: max_note.map(get_note_name).unwrap_or_default(),
+        min_note_name: min_note.map(get_note_name).unwrap_or_default(),
+        max_note_group: max_note.map(get_note_group).unwrap_or_default(),
+        min_note_group: min_note.map(get_note_group).unwrap_or_default(),
+        upper_over_limit,
+        lower_over_limit,
+        is_max_over_limit,
+        is_min_over_limit,
+        suggested_max_transpose,
+        suggested_max_octave,
+        suggested_min_transpose,
+        suggested_min_octave,
+    };
+
+    TrackInfo {
+        id: track.id,
+        name: track.name.clone(),
+        note_count: track.pitches.len(),
+        analysis,
+    }
+}
+
+/// 按解析选项处理读取的音符，生成事件和统计；预览读取的结果跳过 phrase_gap 和 trace
+/// 选项需先经过 validate 和 validate_tracks 检查
+pub fn analyze_raw(
+    raw: &RawMidi,
+    options: &AnalyzerOptions,
+    key_map: Option<&KeyMap>,
+    trace: bool,
+) -> MidiAnalysis {
+    let mut options = options.clone();
+    let trace = trace && !raw.partial;
+    if raw.partial {
+        options.phrase_gap = None;
+    }
+    let tracks_info = raw
+        .tracks
+        .iter()
+        .map(|track| track_info(track, options.min_note, options.max_note))
+        .collect();
+
+    let passes = build_pipeline(&options, key_map.is_some());
+    let mut context = PassContext {
+        tempo: raw.tempo.clone(),
+        chord_grouping: options.chord_grouping,
+        phrase_gap: options.phrase_gap,
+        phrase_gaps: 0,
+        max_same_key_rate: options.max_same_key_rate,
+        thinned_runs: Vec::new(),
+        key_map: key_map.cloned(),
+        warnings: Vec::new(),
+        track_selection: options.track_selection,
+        on_unison: options.on_unison,
+        same_key_overlap: options.same_key_overlap,
+        chord_spread: options.chord_spread_ms / 1000.0,
+        duration: raw.duration,
+        tempo_flattening: None,
+        max_polyphony: options.max_polyphony,
+        eviction: options.eviction,
+        target_max_rate: options.target_max_rate,
+        note_range: (options.min_note, options.max_note),
+        rate_windows: Vec::new(),
+        raw_notes: options.include_raw_events.then(Vec::new),
+        // validate 已检查过
+        reserved_keys: ReservedKeys::parse(&options.reserved_keys).unwrap_or_default(),
+        drum_keys: options
+            .drum_mode
+            .then(|| drum_key_table(&options.drum_keys).unwrap_or_default()),
+    };
+    let pass_order = passes.iter().map(|pass| pass.name.to_string()).collect();
+    let (notes, pipeline) = run_pipeline(&passes, raw.notes.clone(), &mut context, trace);
+    let churn = key_map.map(|key_map| modifier_churn(&notes, key_map));
+    let usage = key_map.map(|key_map| key_usage(&notes, key_map));
+    let top = usage
+        .as_ref()
+        .map(|usage| top_keys(usage, TOP_KEYS))
+        .unwrap_or_default();
+    // 小节边界和循环点与音符一样换算到拉平后的时间
+    let flattened_beat = context
+        .tempo_flattening
+        .as_ref()
+        .map(|_| context.tempo.beat_seconds_at(0.0));
+    let song_time = |time: f64| flattened_beat.map_or(time, |beat| raw.tempo.beats_at(time) * beat);
+    let pickup = options
+        .align_to_downbeat
+        .then(|| detect_pickup(raw))
+        .flatten();
+    let first_measure = pickup.map_or(0, |(_, index)| index);
+    let measure_times: Vec<f64> = raw.measures[first_measure..]
+        .iter()
+        .map(|&time| song_time(time))
+        .collect();
+    let pickup = pickup.map(|(start, index)| Pickup {
+        start: song_time(start),
+        downbeat: song_time(raw.measures[index]),
+    });
+    let loop_start = raw.loop_start.map(song_time);
+    let loop_end = raw.loop_end.map(song_time);
+    let input_rate = input_rate_report(&notes, &mut context, &measure_times);
+    let measures = options.include_measures.then_some(measure_times);
+
+    let grouper = ChordGrouper::new(context.chord_grouping, context.tempo);
+    let events = note_events(&notes, &grouper);
+    let (raw_events, raw_diff) = match context.raw_notes.take() {
+        Some(raw_notes) => {
+            let mut diff = EventDiff::between(&raw_notes, &notes);
+            let mut raw_events = note_events(&raw_notes, &grouper);
+            if raw_events.len() > MAX_RAW_EVENTS {
+                raw_events.truncate(MAX_RAW_EVENTS);
+                diff.truncated = true;
+            }
+            (Some(raw_events), Some(diff))
+        }
+        None => (None, None),
+    };
 
     // Analyze min/max
     let mut min_note = None;
@@ -521,8 +3303,9 @@ pub fn analyze_midi_file(
     let limit_min = 48; // C3? No, 48 is C3 in some standards, C2 in others. Python code says 48.
     let limit_max = 83; // B5?
 
+    // 打击乐的音高不对应按键，不计入音域
     for event in &events {
-        if event.type_ == "note_on" {
+        if event.type_ == "note_on" && event.key.is_none() {
             if min_note.is_none() || event.note < min_note.unwrap() {
                 min_note = Some(event.note);
             }
@@ -541,13 +3324,13 @@ pub fn analyze_midi_file(
 
     // Debug: print first few events
     if events.len() > 0 {
-        println!("First 3 events:");
+        eprintln!("First 3 events:");
         for (i, event) in events.iter().take(3).enumerate() {
-            println!("Event {}: {:?}", i, event);
+            eprintln!("Event {}: {:?}", i, event);
         }
     }
 
-    Ok(MidiAnalysis {
+    MidiAnalysis {
         events,
         analysis: AnalysisResult {
             min_note,
@@ -557,7 +3340,363 @@ pub fn analyze_midi_file(
             min_note_name: min_note.map(get_note_name).unwrap_or_default(),
             max_note_name: max_note.map(get_note_name).unwrap_or_default(),
             total_over_limit_count: under_min_count + over_max_count,
+            bpm: context
+                .tempo_flattening
+                .as_ref()
+                .map_or(60_000_000.0 / raw.initial_tempo as f64, |f| f.flattened_bpm),
+            time_signature: raw.time_signature,
+            phrase_gaps: context.phrase_gaps,
+            thinned_runs: context.thinned_runs,
+            modifier_churn: churn,
+            warnings: context.warnings,
+            tempo_flattening: context.tempo_flattening,
         },
         tracks: tracks_info,
+        pipeline,
+        partial: raw.partial,
+        measures,
+        pickup,
+        input_rate,
+        pass_order,
+        raw_events,
+        raw_diff,
+        loop_start,
+        loop_end,
+        key_usage: usage,
+        top_keys: top,
+    }
+}
+
+// 音符转为按时间排序的 note_on/note_off 事件，起音带上和弦序号
+fn note_events(notes: &[Note], grouper: &ChordGrouper) -> Vec<MidiEvent> {
+    let onsets: Vec<f64> = notes.iter().map(|note| note.time).collect();
+    let mut events: Vec<MidiEvent> = notes
+        .iter()
+        .zip(grouper.group(&onsets))
+        .flat_map(|(note, chord)| note.to_events(chord))
+        .collect();
+    // Sort events by time
+    events.sort_by(|a, b| {
+        a.time
+            .partial_cmp(&b.time)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    events
+}
+
+// 缓存的原始解析结果数量
+const PARSE_CACHE_SIZE: usize = 4;
+
+struct CachedMidi {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    hash: u64, // 见 content_hash
+    raw: Arc<RawMidi>,
+}
+
+/// 最近读取的原始解析结果，用不同选项重新解析或建议音域时不必再读文件
+/// 按规范化路径和修改时间区分，文件改动后自动失效；预览读取不缓存
+#[derive(Default)]
+pub struct ParseCache {
+    entries: Mutex<VecDeque<CachedMidi>>,
+}
+
+impl ParseCache {
+    pub fn read(
+        &self,
+        file_path: &str,
+        preview: Option<f64>,
+        progress: ProgressCallback,
+    ) -> Result<Arc<RawMidi>, CommandError> {
+        if preview.is_some() {
+            return read_midi_file(file_path, preview, progress).map(Arc::new);
+        }
+        let path = fs::canonicalize(file_path).unwrap_or_else(|_| PathBuf::from(file_path));
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        {
+            let entries = self.entries.lock().unwrap();
+            if let Some(entry) = entries
+                .iter()
+                .find(|entry| entry.path == path && entry.modified == modified)
+            {
+                return Ok(Arc::clone(&entry.raw));
+            }
+        }
+
+        let bytes = read_file_bytes(file_path)?;
+        let hash = content_hash(&bytes);
+        let raw = Arc::new(parse_midi_bytes(&bytes, None, progress)?);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.path != path);
+        if entries.len() >= PARSE_CACHE_SIZE {
+            entries.pop_front();
+        }
+        entries.push_back(CachedMidi {
+            path,
+            modified,
+            hash,
+            raw: Arc::clone(&raw),
+        });
+        Ok(raw)
+    }
+
+    /// 内容哈希相同的缓存结果，不论路径（如复制或改名的文件）
+    pub fn find_by_hash(&self, hash: u64) -> Option<Arc<RawMidi>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.hash == hash)
+            .map(|entry| Arc::clone(&entry.raw))
+    }
+}
+
+/// 建议的音域
+#[derive(Debug, Clone, Serialize)]
+pub struct NoteRangeSuggestion {
+    pub min_note: u8,
+    pub max_note: u8,
+    pub covered_notes: usize,
+    pub total_notes: usize,
+    pub coverage: f64, // 覆盖的音符百分比（0-100）
+    // 按八度移调多少半音后，音域落在默认音域（48-83）的中间
+    pub transpose: i32,
+}
+
+// 旋律音（和弦最高音）在音高统计中的额外权重
+const MELODY_WEIGHT: f64 = 1.0;
+
+// 默认音域 48-83 的中心
+const DEFAULT_RANGE_CENTER: f64 = 65.5;
+
+/// 在能放下 available_keys 个键的连续音域中选覆盖音符最多的一个（旋律音加权）
+/// auto_sharp 时黑键先移到白键，音域按白键计数；覆盖相同时选最靠近音高重心的
+pub fn suggest_note_range(
+    raw: &RawMidi,
+    available_keys: usize,
+    black_key_mode: BlackKeyMode,
+) -> Result<NoteRangeSuggestion, CommandError> {
+    if !(1..=MAX_MIDI_NOTE as usize + 1).contains(&available_keys) {
+        return Err(CommandError::InvalidArgument(format!(
+            "available_keys must be within 1-{}, got {}",
+            MAX_MIDI_NOTE as usize + 1,
+            available_keys
+        )));
+    }
+    if raw.notes.is_empty() {
+        return Err(CommandError::InvalidFile(
+            "No notes to fit a range to".to_string(),
+        ));
+    }
+
+    let onsets: Vec<f64> = raw.notes.iter().map(|note| note.time).collect();
+    let chords = ChordGrouper::new(ChordGrouping::default(), raw.tempo.clone()).group(&onsets);
+    let mut top: HashMap<usize, u8> = HashMap::new();
+    for (note, &chord) in raw.notes.iter().zip(&chords) {
+        let pitch = top.entry(chord).or_insert(note.note);
+        *pitch = (*pitch).max(note.note);
+    }
+
+    let mut counts = [0usize; 128];
+    let mut weights = [0.0f64; 128];
+    for (note, chord) in raw.notes.iter().zip(&chords) {
+        let pitch = apply_black_key_mode(note.note, black_key_mode) as usize;
+        counts[pitch] += 1;
+        weights[pitch] += if top[chord] == note.note {
+            1.0 + MELODY_WEIGHT
+        } else {
+            1.0
+        };
+    }
+    let total_weight: f64 = weights.iter().sum();
+    let center_of_mass = weights
+        .iter()
+        .enumerate()
+        .map(|(pitch, weight)| pitch as f64 * weight)
+        .sum::<f64>()
+        / total_weight;
+
+    // 可以作为音域最低音的音高，以及各自放下 available_keys 个键后的最高音
+    let usable = |pitch: u8| {
+        black_key_mode == BlackKeyMode::SupportBlackKey || !BLACK_PCS.contains(&(pitch % 12))
+    };
+    let keys: Vec<u8> = (0..=MAX_MIDI_NOTE).filter(|&pitch| usable(pitch)).collect();
+    let width = available_keys.min(keys.len());
+    let mut best: Option<(f64, f64, u8, u8)> = None; // (权重, 离重心的距离, 最低音, 最高音)
+    for window in keys.windows(width) {
+        let (low, high) = (window[0], window[width - 1]);
+        let weight: f64 = weights[low as usize..=high as usize].iter().sum();
+        let distance = ((low as f64 + high as f64) / 2.0 - center_of_mass).abs();
+        let better = best.is_none_or(|(best_weight, best_distance, _, _)| {
+            weight > best_weight + 1e-9
+                || ((weight - best_weight).abs() <= 1e-9 && distance < best_distance)
+        });
+        if better {
+            best = Some((weight, distance, low, high));
+        }
+    }
+    let (_, _, min_note, max_note) = best.expect("at least one window");
+
+    let covered_notes: usize = counts[min_note as usize..=max_note as usize].iter().sum();
+    let total_notes = raw.notes.len();
+    let center = (min_note as f64 + max_note as f64) / 2.0;
+    Ok(NoteRangeSuggestion {
+        min_note,
+        max_note,
+        covered_notes,
+        total_notes,
+        coverage: covered_notes as f64 * 100.0 / total_notes as f64,
+        transpose: ((DEFAULT_RANGE_CENTER - center) / 12.0).round() as i32 * 12,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(min_note: u8, max_note: u8) -> AnalyzerOptions {
+        AnalyzerOptions {
+            min_note,
+            max_note,
+            ..AnalyzerOptions::default()
+        }
+    }
+
+    #[test]
+    fn min_note_above_127_is_rejected() {
+        assert_eq!(
+            options(200, 127).validate().unwrap_err(),
+            "min_note must be within 0-127, got 200"
+        );
+    }
+
+    #[test]
+    fn max_note_above_127_is_rejected() {
+        assert_eq!(
+            options(0, 128).validate().unwrap_err(),
+            "max_note must be within 0-127, got 128"
+        );
+    }
+
+    // 两个值都超出时先报 min_note，不会报成范围颠倒
+    #[test]
+    fn both_notes_above_127_report_min_note() {
+        assert_eq!(
+            options(255, 130).validate().unwrap_err(),
+            "min_note must be within 0-127, got 255"
+        );
+    }
+
+    #[test]
+    fn inverted_range_names_both_values() {
+        assert_eq!(
+            options(84, 48).validate().unwrap_err(),
+            "min_note (84) must not be greater than max_note (48)"
+        );
+    }
+
+    #[test]
+    fn full_and_single_note_ranges_are_valid() {
+        assert_eq!(options(0, 127).validate(), Ok(()));
+        assert_eq!(options(60, 60).validate(), Ok(()));
+    }
+
+    #[test]
+    fn unknown_black_key_mode_lists_valid_values() {
+        assert_eq!(
+            "sharp".parse::<BlackKeyMode>().unwrap_err(),
+            "black_key_mode must be one of support_black_key, auto_sharp, got \"sharp\""
+        );
+        assert_eq!("auto_sharp".parse(), Ok(BlackKeyMode::AutoSharp));
+    }
+
+    // parse_midi 的 options 只需给出要改的字段，其余取出厂值
+    #[test]
+    fn options_deserialize_from_a_partial_object() {
+        let options: AnalyzerOptions = serde_json::from_value(serde_json::json!({
+            "min_note": 50,
+            "black_key_mode": "auto_sharp",
+            "max_same_key_rate": 8.0,
+        }))
+        .unwrap();
+        assert_eq!(options.min_note, 50);
+        assert_eq!(options.max_note, 83);
+        assert_eq!(options.black_key_mode, BlackKeyMode::AutoSharp);
+        assert_eq!(options.max_same_key_rate, Some(8.0));
+
+        let error = serde_json::from_value::<AnalyzerOptions>(serde_json::json!({
+            "black_key_mode": "sharp",
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("support_black_key"), "{}", error);
+    }
+
+    // 选项错误在打开文件之前报告，前端按 InvalidArgument 提示对应的控件
+    #[test]
+    fn invalid_options_are_reported_before_reading_the_file() {
+        let error = analyze_midi_file("/nonexistent.mid", &options(84, 48)).unwrap_err();
+        assert!(
+            matches!(error, CommandError::InvalidArgument(_)),
+            "{:?}",
+            error
+        );
+    }
+
+    fn grouper(window: ChordWindow, strategy: ChordStrategy) -> ChordGrouper {
+        ChordGrouper::new(ChordGrouping { window, strategy }, TempoMap::default())
+    }
+
+    // 刚好落在 20ms 窗口外的倚音自成一组，窗口边上的仍算同一个和弦
+    #[test]
+    fn grace_notes_just_outside_the_window_stay_separate() {
+        for strategy in [ChordStrategy::FixedWindow, ChordStrategy::GapClustering] {
+            let grouper = grouper(ChordWindow::Ms(20.0), strategy);
+            assert_eq!(grouper.group(&[0.479, 0.5, 0.5, 0.52]), [0, 1, 1, 1]);
+        }
+    }
+
+    // 固定窗口从和弦第一个起音算起，间隙聚类从前一个起音算起
+    #[test]
+    fn strategies_differ_on_a_roll() {
+        let roll = [0.0, 0.015, 0.03, 0.045];
+        let fixed = grouper(ChordWindow::Ms(20.0), ChordStrategy::FixedWindow);
+        assert_eq!(fixed.group(&roll), [0, 0, 1, 1]);
+        let gap = grouper(ChordWindow::Ms(20.0), ChordStrategy::GapClustering);
+        assert_eq!(gap.group(&roll), [0, 0, 0, 0]);
+    }
+
+    // 量化到同一位置的起音换算成秒后可能差一点浮点误差，窗口为 0 时也归为一组
+    #[test]
+    fn quantized_chords_group_together() {
+        let tempo = TempoMap::new(vec![(0.0, 0.6)]);
+        let third = tempo.beat_seconds_at(0.0) / 3.0;
+        let onsets = [third * 3.0, 0.6, third + third + third, 0.6 + third];
+        let grouper = ChordGrouper::new(
+            ChordGrouping {
+                window: ChordWindow::Ms(0.0),
+                strategy: ChordStrategy::FixedWindow,
+            },
+            tempo,
+        );
+        assert_eq!(grouper.group(&onsets), [0, 0, 0, 1]);
+    }
+
+    // 按拍数的窗口随速度变化：60 BPM 时 0.05 拍是 50ms，120 BPM 时是 25ms
+    #[test]
+    fn beat_window_follows_the_tempo_map() {
+        let tempo = TempoMap::new(vec![(0.0, 1.0), (10.0, 0.5)]);
+        let grouping = ChordGrouping {
+            window: ChordWindow::Beats(0.05),
+            strategy: ChordStrategy::FixedWindow,
+        };
+        let grouper = ChordGrouper::new(grouping, tempo);
+        assert_eq!(grouper.group(&[1.0, 1.04, 11.0, 11.04]), [0, 0, 1, 2]);
+    }
+
+    // 起音不需要有序，序号按时间分配
+    #[test]
+    fn unsorted_onsets_are_numbered_by_time() {
+        let grouper = grouper(ChordWindow::Ms(20.0), ChordStrategy::FixedWindow);
+        assert_eq!(grouper.group(&[1.0, 0.0, 0.01, 2.0]), [1, 0, 0, 2]);
+    }
+}
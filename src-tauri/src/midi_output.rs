@@ -0,0 +1,96 @@
+use crate::keypress_simulator::KeySender;
+use midir::{MidiOutput, MidiOutputConnection};
+use std::collections::HashMap;
+
+const CLIENT_NAME: &str = "OpenGamesAutoPlay";
+
+// 预览时统一使用的通道和力度
+const CHANNEL: u8 = 0;
+const VELOCITY: u8 = 100;
+
+// CC 123：All Notes Off
+const ALL_NOTES_OFF: u8 = 123;
+
+pub fn list_midi_outputs() -> Result<Vec<String>, String> {
+    let output = MidiOutput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+    Ok(output
+        .ports()
+        .iter()
+        .filter_map(|port| output.port_name(port).ok())
+        .collect())
+}
+
+/// 把按键播放转为 MIDI 音符的发送后端，用于在软件合成器里试听
+/// 按键通过 pitch_map（按键字符串 -> 音符）换算回音符
+pub struct MidiOutputSender {
+    connection: MidiOutputConnection,
+    pitch_map: HashMap<String, u8>,
+}
+
+impl MidiOutputSender {
+    /// 连接名为 port_name 的输出端口
+    /// macOS/Linux 下端口不存在时创建同名虚拟端口；
+    /// Windows 不支持虚拟端口，需要先用 loopMIDI 等工具创建并从 list_midi_outputs 中选择
+    pub fn new(port_name: &str, pitch_map: HashMap<String, u8>) -> Result<Self, String> {
+        let output = MidiOutput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+        let port = output
+            .ports()
+            .into_iter()
+            .find(|port| output.port_name(port).ok().as_deref() == Some(port_name));
+        let connection = match port {
+            Some(port) => output
+                .connect(&port, "preview")
+                .map_err(|e| format!("Failed to connect to {}: {}", port_name, e))?,
+            None => create_virtual(output, port_name)?,
+        };
+        Ok(Self {
+            connection,
+            pitch_map,
+        })
+    }
+
+    fn send_note(&mut self, key: &str, status: u8, velocity: u8) -> Result<(), String> {
+        let note = *self
+            .pitch_map
+            .get(key)
+            .ok_or_else(|| format!("No note mapped to key: {}", key))?;
+        self.connection
+            .send(&[status | CHANNEL, note, velocity])
+            .map_err(|e| format!("Failed to send MIDI message: {}", e))
+    }
+}
+
+#[cfg(unix)]
+fn create_virtual(output: MidiOutput, port_name: &str) -> Result<MidiOutputConnection, String> {
+    use midir::os::unix::VirtualOutput;
+
+    output
+        .create_virtual(port_name)
+        .map_err(|e| format!("Failed to create virtual MIDI port {}: {}", port_name, e))
+}
+
+#[cfg(not(unix))]
+fn create_virtual(_output: MidiOutput, port_name: &str) -> Result<MidiOutputConnection, String> {
+    Err(format!(
+        "MIDI output not found: {}. Virtual ports are not supported on this platform; \
+         create one with a tool such as loopMIDI and pick it from list_midi_outputs",
+        port_name
+    ))
+}
+
+impl KeySender for MidiOutputSender {
+    fn press(&mut self, key: &str) -> Result<(), String> {
+        self.send_note(key, 0x90, VELOCITY)
+    }
+
+    fn release(&mut self, key: &str) -> Result<(), String> {
+        self.send_note(key, 0x80, 0)
+    }
+}
+
+impl Drop for MidiOutputSender {
+    fn drop(&mut self) {
+        // 播放线程已释放按住的键，这里再保险地关闭所有音符
+        let _ = self.connection.send(&[0xB0 | CHANNEL, ALL_NOTES_OFF, 0]);
+    }
+}
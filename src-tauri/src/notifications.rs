@@ -0,0 +1,96 @@
+//! 播放结束或出错时的桌面通知，内容取自播放统计报告
+
+use crate::keypress_simulator::PlaybackEvent;
+use crate::playback_stats::PlaybackReport;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tauri::AppHandle;
+use tauri_plugin_notification::NotificationExt;
+
+/// 播放通知，通过 Tauri `.manage()` 注册
+pub struct PlaybackNotifier {
+    enabled: AtomicBool,
+    // 当前曲目名；单曲播放时由 start_playback 设置，队列播放时取当前条目名
+    title: Mutex<Option<String>>,
+}
+
+impl Default for PlaybackNotifier {
+    fn default() -> Self {
+        Self {
+            enabled: AtomicBool::new(true),
+            title: Mutex::new(None),
+        }
+    }
+}
+
+// 1234567 -> "1,234,567"
+fn group_thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+/// 通知正文，例如 "2,431 keys sent, 3 skipped"
+fn report_summary(report: &PlaybackReport) -> String {
+    let mut summary = format!("{} keys sent", group_thousands(report.sent));
+    if report.skipped > 0 {
+        summary.push_str(&format!(", {} skipped", group_thousands(report.skipped)));
+    }
+    if report.failed > 0 {
+        summary.push_str(&format!(", {} failed", group_thousands(report.failed)));
+    }
+    summary
+}
+
+impl PlaybackNotifier {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn set_title(&self, title: Option<String>) {
+        *self.title.lock().unwrap() = title;
+    }
+
+    /// 根据播放事件决定是否发通知；用户主动停止（未完成且没有错误）不通知
+    pub fn handle(&self, app: &AppHandle, event: &PlaybackEvent) {
+        let (heading, body) = match event {
+            PlaybackEvent::QueueEntryStarted { name, .. } => {
+                self.set_title(Some(name.clone()));
+                return;
+            }
+            PlaybackEvent::Finished {
+                completed,
+                error,
+                report,
+                ..
+            } => {
+                let title = self.title.lock().unwrap().take();
+                let song = title.as_deref().unwrap_or("Song");
+                match (completed, error) {
+                    (_, Some(error)) => (format!("{} could not be played", song), error.clone()),
+                    (true, None) => (format!("{} finished", song), report_summary(report)),
+                    (false, None) => return,
+                }
+            }
+            _ => return,
+        };
+        if !self.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = app
+            .notification()
+            .builder()
+            .title(heading)
+            .body(body)
+            .show()
+        {
+            eprintln!("Failed to show notification: {}", e);
+        }
+    }
+}
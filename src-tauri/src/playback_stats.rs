@@ -0,0 +1,183 @@
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// 单次播放的统计报告
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaybackReport {
+    pub completed: bool,         // false 表示被停止或出错
+    pub started_at_unix_ms: u64, // 开始时间（Unix 毫秒）
+    pub total_events: usize,
+    pub sent: usize,                // 成功发送的按键数
+    pub taps: usize,                // 其中时长为 0、按最短按住时长轻点的
+    pub skipped: usize,             // 未发送的按键数（停止、限流等）
+    pub rate_limited: usize,        // 其中因超过速率上限被丢弃的按键数
+    pub failed: usize,              // 发送失败的按键数
+    pub failed_keys: Vec<String>,   // 发送失败过的按键（去重）
+    pub mistakes: usize,            // 练习模式下按错的次数
+    pub modifier_conflicts: usize,  // 因修饰键冲突被错开或丢弃的按键数
+    pub keep_alive_releases: usize, // keep_alive_seconds 补发的松开数，不计入 sent
+    pub avg_lateness_ms: f64,       // 实际发送时刻相对计划时刻的平均延迟
+    pub median_lateness_ms: f64,
+    pub max_lateness_ms: f64,
+    pub avg_send_ms: f64, // 单次发送调用本身的耗时
+    pub max_send_ms: f64,
+    pub wall_time: f64,                   // 总耗时（秒，含暂停）
+    pub timer_resolution_ms: Option<u32>, // 播放期间的系统计时器精度，未修改时为 None
+}
+
+/// 播放线程内使用的统计收集器
+pub struct StatsRecorder {
+    started: Instant,
+    started_at_unix_ms: u64,
+    total_events: usize,
+    sent: usize,
+    taps: usize,
+    skipped: usize,
+    rate_limited: usize,
+    failed: usize,
+    failed_keys: BTreeSet<String>,
+    mistakes: usize,
+    modifier_conflicts: usize,
+    keep_alive_releases: usize,
+    timer_resolution_ms: Option<u32>,
+    lateness: Vec<f64>,
+    send_times: Vec<f64>,
+}
+
+impl StatsRecorder {
+    pub fn new(total_events: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            started_at_unix_ms: unix_ms(),
+            total_events,
+            sent: 0,
+            taps: 0,
+            skipped: 0,
+            rate_limited: 0,
+            failed: 0,
+            failed_keys: BTreeSet::new(),
+            mistakes: 0,
+            modifier_conflicts: 0,
+            keep_alive_releases: 0,
+            timer_resolution_ms: None,
+            lateness: Vec::with_capacity(total_events),
+            send_times: Vec::with_capacity(total_events),
+        }
+    }
+
+    /// 记录一次按下动作
+    /// `scheduled` 与 `actual` 为歌曲时间（秒），`send_secs` 为发送调用耗时
+    pub fn record_press(
+        &mut self,
+        key: &str,
+        scheduled: f64,
+        actual: f64,
+        send_secs: f64,
+        ok: bool,
+    ) {
+        self.lateness.push((actual - scheduled).max(0.0));
+        self.send_times.push(send_secs);
+        if ok {
+            self.sent += 1;
+        } else {
+            self.failed += 1;
+            self.failed_keys.insert(key.to_string());
+        }
+    }
+
+    /// 记录一次时长为 0 的轻点，在 record_press 之后调用
+    pub fn record_tap(&mut self) {
+        self.taps += 1;
+    }
+
+    /// 练习模式下用户按对了一组音符，计入 sent
+    pub fn record_played(&mut self, notes: usize) {
+        self.sent += notes;
+    }
+
+    /// 练习模式下用户按错了一次
+    pub fn record_mistake(&mut self) {
+        self.mistakes += 1;
+    }
+
+    /// 记录一次修饰键冲突的处理（错开、提前松开或丢弃）
+    pub fn record_modifier_conflict(&mut self) {
+        self.modifier_conflicts += 1;
+    }
+
+    /// 记录保活时补发的松开
+    pub fn record_keep_alive(&mut self, releases: usize) {
+        self.keep_alive_releases += releases;
+    }
+
+    pub fn set_timer_resolution(&mut self, period_ms: Option<u32>) {
+        self.timer_resolution_ms = period_ms;
+    }
+
+    /// 记录一次有意跳过的按键
+    pub fn record_skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// 记录一次因速率限制被丢弃的按键
+    pub fn record_rate_limited(&mut self) {
+        self.skipped += 1;
+        self.rate_limited += 1;
+    }
+
+    pub fn finish(self, completed: bool) -> PlaybackReport {
+        // 没走到的事件（如中途停止）也算作跳过
+        let handled = self.sent + self.failed + self.skipped;
+        let skipped = self.skipped + self.total_events.saturating_sub(handled);
+
+        let mut lateness = self.lateness;
+        lateness.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        PlaybackReport {
+            completed,
+            started_at_unix_ms: self.started_at_unix_ms,
+            total_events: self.total_events,
+            sent: self.sent,
+            taps: self.taps,
+            skipped,
+            rate_limited: self.rate_limited,
+            failed: self.failed,
+            failed_keys: self.failed_keys.into_iter().collect(),
+            mistakes: self.mistakes,
+            modifier_conflicts: self.modifier_conflicts,
+            keep_alive_releases: self.keep_alive_releases,
+            avg_lateness_ms: mean(&lateness) * 1000.0,
+            median_lateness_ms: median(&lateness) * 1000.0,
+            max_lateness_ms: lateness.last().copied().unwrap_or(0.0) * 1000.0,
+            avg_send_ms: mean(&self.send_times) * 1000.0,
+            max_send_ms: self.send_times.iter().copied().fold(0.0, f64::max) * 1000.0,
+            wall_time: self.started.elapsed().as_secs_f64(),
+            timer_resolution_ms: self.timer_resolution_ms,
+        }
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+// values 需已排序
+fn median(values: &[f64]) -> f64 {
+    let len = values.len();
+    match len {
+        0 => 0.0,
+        _ if len % 2 == 1 => values[len / 2],
+        _ => (values[len / 2 - 1] + values[len / 2]) / 2.0,
+    }
+}
+
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,428 @@
+use crate::humanize::HumanizeConfig;
+use crate::input_capabilities::{InputCapabilities, InputFeature};
+use crate::keypress_simulator::{self, KeyEventOptions, PlaybackSettings};
+use crate::midi_analyzer::{self, AnalyzerOptions, RawMidi};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use uni_input::{parse_key_string, LayoutTranslation, Modifier, Side};
+
+/// 当前的预设文件格式版本
+/// 新增字段时依靠 serde(default) 兼容旧文件，字段改名或语义变化时递增并在 migrate 中转换
+pub const PRESET_SCHEMA_VERSION: u32 = 1;
+
+const MAX_NAME_LEN: usize = 64;
+
+/// 一套按游戏保存的设置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresetSettings {
+    pub min_note: u8,
+    pub max_note: u8,
+    pub black_key_mode: String,
+    pub note_to_key: HashMap<u8, String>,
+    pub layout: LayoutTranslation,
+    pub speed: f64,
+    pub humanize: Option<HumanizeConfig>,
+    pub playback: PlaybackSettings, // 按住时长、速率上限等
+    pub hotkeys: HashMap<String, String>,
+    pub target_window: Option<String>,
+}
+
+impl Default for PresetSettings {
+    fn default() -> Self {
+        Self {
+            min_note: 48,
+            max_note: 83,
+            black_key_mode: "support_black_key".to_string(),
+            note_to_key: HashMap::new(),
+            layout: LayoutTranslation::None,
+            speed: 1.0,
+            humanize: None,
+            playback: PlaybackSettings::default(),
+            hotkeys: HashMap::new(),
+            target_window: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PresetFile {
+    version: u32,
+    name: String,
+    settings: PresetSettings,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PresetInfo {
+    pub name: String,
+    pub builtin: bool, // 内置预设只读
+}
+
+// 按顺序给音符分配按键
+fn key_map(notes: &[u8], keys: &str) -> HashMap<u8, String> {
+    notes
+        .iter()
+        .zip(keys.chars())
+        .map(|(note, key)| (*note, key.to_string()))
+        .collect()
+}
+
+/// 随应用发布的内置预设
+fn builtin_presets() -> Vec<(&'static str, PresetSettings)> {
+    // 两个游戏的乐器都没有半音，黑键移到最近的白键
+    let lyre_notes = [
+        48, 50, 52, 53, 55, 57, 59, 60, 62, 64, 65, 67, 69, 71, 72, 74, 76, 77, 79, 81, 83,
+    ];
+    let sky_notes = [60, 62, 64, 65, 67, 69, 71, 72, 74, 76, 77, 79, 81, 83, 84];
+    vec![
+        (
+            "Genshin Lyre",
+            PresetSettings {
+                min_note: 48,
+                max_note: 83,
+                black_key_mode: "auto_sharp".to_string(),
+                note_to_key: key_map(&lyre_notes, "zxcvbnmasdfghjqwertyu"),
+                target_window: Some("Genshin Impact".to_string()),
+                ..Default::default()
+            },
+        ),
+        (
+            "Sky",
+            PresetSettings {
+                min_note: 60,
+                max_note: 84,
+                black_key_mode: "auto_sharp".to_string(),
+                note_to_key: key_map(&sky_notes, "yuiophjkl;nm,./"),
+                target_window: Some("Sky".to_string()),
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+/// 按名称查找内置预设（不区分大小写）
+pub fn builtin_preset(name: &str) -> Option<PresetSettings> {
+    builtin_presets()
+        .into_iter()
+        .find(|(builtin, _)| builtin.eq_ignore_ascii_case(name))
+        .map(|(_, settings)| settings)
+}
+
+/// evaluate_layouts 的候选布局：只有 name 时按名称查找预设，给出 settings 时直接使用（未保存的布局）
+#[derive(Debug, Clone, Deserialize)]
+pub struct LayoutSpec {
+    pub name: String,
+    #[serde(default)]
+    pub settings: Option<PresetSettings>,
+}
+
+/// 一个布局能按出这首歌的多少音符
+#[derive(Debug, Clone, Serialize)]
+pub struct LayoutCoverage {
+    pub name: String,
+    pub total_notes: usize,    // 参与合并的音轨中的音符数
+    pub mapped_notes: usize,   // 最终有按键的音符数
+    pub coverage: f64,         // mapped_notes 占 total_notes 的百分比（0-100）
+    pub modifier_notes: usize, // 其中需要修饰键的
+    pub dropped_notes: usize,  // 超出音域或没有映射而丢弃的
+    pub collisions: usize,     // 在同一个键仍按住时又要按下的次数
+    // 布局需要但当前发送后端不支持的功能，见 required_features
+    pub unsupported_features: Vec<InputFeature>,
+}
+
+/// 布局依赖的发送功能：布局转换、只按修饰键的按键、右侧修饰键
+pub fn required_features(layout: &PresetSettings) -> Vec<InputFeature> {
+    let mut features = Vec::new();
+    if layout.layout != LayoutTranslation::None {
+        features.push(InputFeature::LayoutTranslation);
+    }
+    let parsed: Vec<_> = layout
+        .note_to_key
+        .values()
+        .filter_map(|key| parse_key_string(key).ok())
+        .collect();
+    if parsed.iter().any(|key| key.main.is_none()) {
+        features.push(InputFeature::ModifierOnly);
+    }
+    let right = |modifier: &Modifier| {
+        matches!(
+            modifier,
+            Modifier::Shift(Side::Right)
+                | Modifier::Control(Side::Right)
+                | Modifier::Alt(Side::Right)
+                | Modifier::Meta(Side::Right)
+        )
+    };
+    if parsed.iter().any(|key| key.modifiers.iter().any(right)) {
+        features.push(InputFeature::SideSpecificModifiers);
+    }
+    features
+}
+
+/// 按 parse_midi 和前端生成按键事件的同一套流程映射 raw，统计布局的覆盖情况
+/// base 提供布局之外的解析选项（和弦判定、音轨选择等），音域和黑键处理取布局的设置
+/// 给出 capabilities 时列出布局需要但后端不支持的功能
+pub fn evaluate_layout(
+    raw: &RawMidi,
+    base: &AnalyzerOptions,
+    name: String,
+    layout: &PresetSettings,
+    capabilities: Option<&InputCapabilities>,
+) -> Result<LayoutCoverage, String> {
+    let options = AnalyzerOptions {
+        min_note: layout.min_note,
+        max_note: layout.max_note,
+        black_key_mode: layout.black_key_mode.parse()?,
+        include_raw_events: false,
+        reserved_keys: layout.playback.reserved_keys.clone(),
+        ..base.clone()
+    };
+    options
+        .validate()
+        .and_then(|()| options.validate_tracks(raw))
+        .map_err(|e| format!("Layout \"{}\": {}", name, e))?;
+    let analysis = midi_analyzer::analyze_raw(raw, &options, Some(&layout.note_to_key), false);
+    let events = keypress_simulator::key_events(
+        &analysis.events,
+        &layout.note_to_key,
+        &KeyEventOptions {
+            min_note: layout.min_note,
+            max_note: layout.max_note,
+            ..KeyEventOptions::default()
+        },
+    );
+
+    let total_notes = match &options.track_selection {
+        Some(selection) => raw
+            .notes
+            .iter()
+            .filter(|note| selection.iter().any(|s| s.index == note.track))
+            .count(),
+        None => raw.notes.len(),
+    };
+    let modifier_notes = events
+        .iter()
+        .filter(|event| keypress_simulator::modifier_mask(&event.key) != 0)
+        .count();
+
+    // 每个键上一次按下的松开时间
+    let mut order: Vec<usize> = (0..events.len()).collect();
+    order.sort_by(|&a, &b| events[a].time.total_cmp(&events[b].time));
+    let mut held_until: HashMap<&str, f64> = HashMap::new();
+    let mut collisions = 0;
+    for i in order {
+        let event = &events[i];
+        let until = held_until.entry(event.key.as_str()).or_insert(f64::MIN);
+        if event.time < *until {
+            collisions += 1;
+        }
+        *until = until.max(event.time + event.duration);
+    }
+
+    let mapped_notes = events.len();
+    Ok(LayoutCoverage {
+        name,
+        total_notes,
+        mapped_notes,
+        coverage: if total_notes == 0 {
+            100.0
+        } else {
+            mapped_notes as f64 / total_notes as f64 * 100.0
+        },
+        modifier_notes,
+        dropped_notes: total_notes.saturating_sub(mapped_notes),
+        collisions,
+        unsupported_features: capabilities
+            .map(|capabilities| {
+                required_features(layout)
+                    .into_iter()
+                    .filter(|feature| !capabilities.supports(*feature))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    })
+}
+
+/// 预设名直接用作文件名，拒绝可能越出预设目录或在部分系统上非法的名字
+fn validate_name(name: &str) -> Result<(), String> {
+    let trimmed = name.trim();
+    if trimmed.is_empty() {
+        return Err("Preset name must not be empty".to_string());
+    }
+    if trimmed != name {
+        return Err("Preset name must not start or end with whitespace".to_string());
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(format!(
+            "Preset name must be at most {} characters",
+            MAX_NAME_LEN
+        ));
+    }
+    if name.starts_with('.')
+        || name
+            .chars()
+            .any(|c| c.is_control() || "/\\:*?\"<>|".contains(c))
+    {
+        return Err(format!("Invalid preset name: {}", name));
+    }
+    Ok(())
+}
+
+// 把旧版本的预设转换为当前格式
+fn migrate(mut value: Value) -> Result<PresetFile, String> {
+    if !value.is_object() {
+        return Err("Invalid preset file: expected a JSON object".to_string());
+    }
+    let version = value
+        .get("version")
+        .and_then(Value::as_u64)
+        .unwrap_or(PRESET_SCHEMA_VERSION as u64) as u32;
+    if version > PRESET_SCHEMA_VERSION {
+        return Err(format!(
+            "Preset was saved by a newer version (schema {}, supported {})",
+            version, PRESET_SCHEMA_VERSION
+        ));
+    }
+    value["version"] = Value::from(PRESET_SCHEMA_VERSION);
+    serde_json::from_value(value).map_err(|e| format!("Invalid preset file: {}", e))
+}
+
+/// 预设存储，每个用户预设一个 JSON 文件
+pub struct PresetStore {
+    dir: PathBuf,
+}
+
+impl PresetStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    // 用户预设名（不区分大小写匹配），返回磁盘上实际的名字
+    fn find_user(&self, name: &str) -> Option<String> {
+        self.user_names()
+            .into_iter()
+            .find(|existing| existing.eq_ignore_ascii_case(name))
+    }
+
+    fn user_names(&self) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "json" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_string())
+            })
+            .collect()
+    }
+
+    pub fn list(&self) -> Vec<PresetInfo> {
+        let mut presets: Vec<PresetInfo> = builtin_presets()
+            .into_iter()
+            .map(|(name, _)| PresetInfo {
+                name: name.to_string(),
+                builtin: true,
+            })
+            .collect();
+        let mut user = self.user_names();
+        user.sort_by_key(|name| name.to_lowercase());
+        presets.extend(user.into_iter().map(|name| PresetInfo {
+            name,
+            builtin: false,
+        }));
+        presets
+    }
+
+    /// 把候选布局解析为 (显示名, 设置)，未知的预设名报错并列出可用的预设
+    pub fn resolve(&self, spec: LayoutSpec) -> Result<(String, PresetSettings), String> {
+        let LayoutSpec { name, settings } = spec;
+        if let Some(settings) = settings {
+            return Ok((name, settings));
+        }
+        if builtin_preset(&name).is_none() && self.find_user(&name).is_none() {
+            let available: Vec<String> =
+                self.list().into_iter().map(|preset| preset.name).collect();
+            return Err(format!(
+                "Unknown layout preset \"{}\"; available: {}",
+                name,
+                available.join(", ")
+            ));
+        }
+        let settings = self.load(&name)?;
+        Ok((name, settings))
+    }
+
+    /// 保存预设；同名预设已存在时除非 overwrite 否则拒绝，内置预设不可覆盖
+    pub fn save(
+        &self,
+        name: &str,
+        mut settings: PresetSettings,
+        overwrite: bool,
+    ) -> Result<(), String> {
+        validate_name(name)?;
+        if builtin_preset(name).is_some() {
+            return Err(format!(
+                "\"{}\" is a built-in preset and cannot be changed",
+                name
+            ));
+        }
+        // 保留键等设置在保存时就检查，不必等到播放时才发现写错；按键保存为规范写法
+        settings.playback.validate()?;
+        settings.note_to_key = midi_analyzer::normalize_key_map(&settings.note_to_key)?;
+        let existing = self.find_user(name);
+        if existing.is_some() && !overwrite {
+            return Err(format!("Preset \"{}\" already exists", name));
+        }
+
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Failed to create preset directory: {}", e))?;
+        // 覆盖时沿用原文件名，避免只有大小写不同的两个文件
+        let path = self.path(existing.as_deref().unwrap_or(name));
+        let file = PresetFile {
+            version: PRESET_SCHEMA_VERSION,
+            name: name.to_string(),
+            settings,
+        };
+        let json = serde_json::to_string_pretty(&file).map_err(|e| e.to_string())?;
+        fs::write(&path, json).map_err(|e| format!("Failed to save preset: {}", e))
+    }
+
+    pub fn load(&self, name: &str) -> Result<PresetSettings, String> {
+        validate_name(name)?;
+        if let Some(settings) = builtin_preset(name) {
+            return Ok(settings);
+        }
+        let existing = self
+            .find_user(name)
+            .ok_or_else(|| format!("Preset not found: {}", name))?;
+        let content = fs::read_to_string(self.path(&existing))
+            .map_err(|e| format!("Failed to read preset: {}", e))?;
+        let value: Value =
+            serde_json::from_str(&content).map_err(|e| format!("Invalid preset file: {}", e))?;
+        Ok(migrate(value)?.settings)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), String> {
+        validate_name(name)?;
+        if builtin_preset(name).is_some() {
+            return Err(format!(
+                "\"{}\" is a built-in preset and cannot be deleted",
+                name
+            ));
+        }
+        let existing = self
+            .find_user(name)
+            .ok_or_else(|| format!("Preset not found: {}", name))?;
+        fs::remove_file(self.path(&existing)).map_err(|e| format!("Failed to delete preset: {}", e))
+    }
+}
@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+use uni_window::{ForegroundError, ForegroundWindow};
+
+// 前台窗口检查间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 窗口标题包含 window_title（不区分大小写）时使用 preset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileRule {
+    pub window_title: String,
+    pub preset: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileMatch {
+    pub preset: String,
+    pub rule_index: usize,
+    pub window_title: String,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ProfileSettings {
+    rules: Vec<ProfileRule>,
+    auto_apply: bool, // 点击播放时自动应用检测到的预设
+}
+
+/// 按前台窗口自动识别游戏配置
+/// 规则按顺序匹配，第一个匹配的生效
+#[derive(Default)]
+pub struct ProfileDetector {
+    settings: Mutex<ProfileSettings>,
+    // 最近一次检测结果；前台是本应用自己时保留上一次的结果
+    current: Mutex<Option<ProfileMatch>>,
+    // 每次启动监视线程递增，旧线程发现不一致后自行退出
+    generation: AtomicU64,
+}
+
+// 第一个标题匹配的规则，空规则不参与匹配
+fn match_rules(rules: &[ProfileRule], window: &ForegroundWindow) -> Option<ProfileMatch> {
+    let title = window.title.to_lowercase();
+    rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| {
+            let pattern = rule.window_title.trim().to_lowercase();
+            !pattern.is_empty() && title.contains(&pattern)
+        })
+        .map(|(rule_index, rule)| ProfileMatch {
+            preset: rule.preset.clone(),
+            rule_index,
+            window_title: window.title.clone(),
+        })
+}
+
+impl ProfileDetector {
+    /// 更新规则并重新开始监视，规则为空时停止监视
+    pub fn set_rules(&self, app: AppHandle, rules: Vec<ProfileRule>, auto_apply: bool) {
+        let watching = !rules.is_empty();
+        *self.settings.lock().unwrap() = ProfileSettings { rules, auto_apply };
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.update(&app);
+        if watching {
+            thread::spawn(move || {
+                let detector = app.state::<ProfileDetector>();
+                loop {
+                    thread::sleep(CHECK_INTERVAL);
+                    if detector.generation.load(Ordering::SeqCst) != generation {
+                        break;
+                    }
+                    if let Err(ForegroundError::Unsupported(reason)) = detector.update(&app) {
+                        eprintln!("Profile detection disabled: {}", reason);
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn auto_apply(&self) -> bool {
+        self.settings.lock().unwrap().auto_apply
+    }
+
+    pub fn current(&self) -> Option<ProfileMatch> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// 检查前台窗口，结果变化时推送 profile://changed
+    pub fn update(&self, app: &AppHandle) -> Result<Option<ProfileMatch>, ForegroundError> {
+        let window = uni_window::foreground_window()?;
+        // 用户切回本应用点击播放时，前台是自己，沿用之前检测到的游戏
+        if window.pid == std::process::id() {
+            return Ok(self.current());
+        }
+        let detected = match_rules(&self.settings.lock().unwrap().rules, &window);
+        let mut current = self.current.lock().unwrap();
+        // 只比较匹配到的规则，游戏内标题变化不算切换
+        let key = |m: &Option<ProfileMatch>| m.as_ref().map(|m| (m.rule_index, m.preset.clone()));
+        if key(&current) != key(&detected) {
+            current.clone_from(&detected);
+            let _ = app.emit("profile://changed", &detected);
+        }
+        Ok(detected)
+    }
+}
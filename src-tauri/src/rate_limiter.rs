@@ -0,0 +1,98 @@
+use crate::keypress_simulator::KeyEvent;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// 默认每秒最多按下的键数
+pub const DEFAULT_MAX_PRESSES_PER_SECOND: u32 = 40;
+
+/// 提交的事件峰值速率超过该值时拒绝播放（除非显式确认）
+pub const ABSOLUTE_MAX_PRESSES_PER_SECOND: u32 = 200;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// 按键速率限制器（1 秒滑动窗口，按真实时间计）
+pub struct RateLimiter {
+    max_per_second: usize,
+    recent: VecDeque<Instant>,
+    // 当前是否处于一次超限爆发中，用于每次爆发只警告一次
+    in_burst: bool,
+}
+
+/// 一次按键请求的判定结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    Allowed,
+    Dropped { burst_started: bool },
+}
+
+impl RateLimiter {
+    pub fn new(max_per_second: u32) -> Self {
+        Self {
+            max_per_second: max_per_second as usize,
+            recent: VecDeque::with_capacity(max_per_second as usize),
+            in_burst: false,
+        }
+    }
+
+    pub fn check(&mut self, now: Instant) -> RateDecision {
+        while let Some(&oldest) = self.recent.front() {
+            if now.duration_since(oldest) >= WINDOW {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.recent.len() < self.max_per_second {
+            self.recent.push_back(now);
+            self.in_burst = false;
+            RateDecision::Allowed
+        } else {
+            let burst_started = !self.in_burst;
+            self.in_burst = true;
+            RateDecision::Dropped { burst_started }
+        }
+    }
+}
+
+pub fn validate_max_rate(max_per_second: u32) -> Result<(), String> {
+    if !(1..=ABSOLUTE_MAX_PRESSES_PER_SECOND).contains(&max_per_second) {
+        return Err(format!(
+            "max_presses_per_second must be within 1-{}, got {}",
+            ABSOLUTE_MAX_PRESSES_PER_SECOND, max_per_second
+        ));
+    }
+    Ok(())
+}
+
+/// 任意 1 秒窗口内按下次数的最大值
+pub fn peak_rate(events: &[KeyEvent]) -> usize {
+    peak_rate_of(events.iter().map(|e| e.time).collect())
+}
+
+/// 按下时刻（秒，不要求有序）中任意 1 秒窗口内的最多次数
+pub fn peak_rate_of(mut times: Vec<f64>) -> usize {
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut peak = 0;
+    let mut start = 0;
+    for end in 0..times.len() {
+        while times[end] - times[start] >= WINDOW.as_secs_f64() {
+            start += 1;
+        }
+        peak = peak.max(end - start + 1);
+    }
+    peak
+}
+
+/// 播放前检查：峰值速率超过绝对上限时拒绝，防止损坏的 MIDI 塞满系统输入队列
+pub fn preflight(events: &[KeyEvent], i_know_what_im_doing: bool) -> Result<(), String> {
+    let peak = peak_rate(events);
+    if peak > ABSOLUTE_MAX_PRESSES_PER_SECOND as usize && !i_know_what_im_doing {
+        return Err(format!(
+            "Peak rate of {} presses/second exceeds the limit of {}; refusing to start",
+            peak, ABSOLUTE_MAX_PRESSES_PER_SECOND
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use crate::midi_analyzer::AnalyzerOptions;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 最多保留的最近文件数量
+pub const MAX_RECENT_FILES: usize = 50;
+
+/// 单个音轨的设置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrackSettings {
+    pub id: usize,
+    pub enabled: bool,
+    pub transpose: i32,
+    pub octave: i32,
+}
+
+impl Default for TrackSettings {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            enabled: true,
+            transpose: 0,
+            octave: 0,
+        }
+    }
+}
+
+/// 某个文件上次使用的设置，再次打开时供前端回填
+/// 解析参数与 AnalyzerOptions 相同，序列化时平铺在同一层
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileSettings {
+    #[serde(flatten)]
+    pub analyzer: AnalyzerOptions,
+    pub tracks: Vec<TrackSettings>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentFile {
+    pub path: String,
+    pub title: String,
+    pub settings: FileSettings,
+    pub last_opened: u64, // Unix 时间戳（秒）
+    // 文件已被移动或删除，仅在查询时计算
+    #[serde(default, skip_deserializing)]
+    pub missing: bool,
+}
+
+// 同一个文件的不同写法（相对路径、大小写等）视为同一条记录
+fn same_file(a: &str, b: &str) -> bool {
+    let canonical = |p: &str| fs::canonicalize(p).unwrap_or_else(|_| PathBuf::from(p));
+    a == b || canonical(a) == canonical(b)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// 最近打开的文件列表，保存在应用数据目录的 JSON 文件中
+/// 最近打开的在前
+pub struct RecentFiles {
+    path: PathBuf,
+    entries: Mutex<Vec<RecentFile>>,
+}
+
+impl RecentFiles {
+    /// 从 path 读取列表，文件不存在或损坏时从空列表开始
+    pub fn load(path: PathBuf) -> Self {
+        let entries = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                eprintln!("Ignoring invalid recent files list: {}", e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        Self {
+            path,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    fn save(&self, entries: &[RecentFile]) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create data directory: {}", e))?;
+        }
+        let json = serde_json::to_string_pretty(entries).map_err(|e| e.to_string())?;
+        fs::write(&self.path, json).map_err(|e| format!("Failed to save recent files: {}", e))
+    }
+
+    /// 记录一次解析，返回该文件之前保存的设置
+    /// 解析参数写入记录，音轨设置沿用上次的值
+    pub fn record_open(
+        &self,
+        file_path: &str,
+        title: String,
+        mut settings: FileSettings,
+    ) -> Option<FileSettings> {
+        let mut entries = self.entries.lock().unwrap();
+        let previous = entries
+            .iter()
+            .position(|entry| same_file(&entry.path, file_path))
+            .map(|index| entries.remove(index));
+        let remembered = previous.map(|entry| entry.settings);
+        if let Some(remembered) = &remembered {
+            settings.tracks = remembered.tracks.clone();
+        }
+
+        entries.insert(
+            0,
+            RecentFile {
+                path: file_path.to_string(),
+                title,
+                settings,
+                last_opened: now_secs(),
+                missing: false,
+            },
+        );
+        entries.truncate(MAX_RECENT_FILES);
+        if let Err(e) = self.save(&entries) {
+            eprintln!("{}", e);
+        }
+        remembered
+    }
+
+    /// 保存前端调整后的设置（移调、音轨选择等）
+    pub fn update_settings(&self, file_path: &str, settings: FileSettings) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .iter_mut()
+            .find(|entry| same_file(&entry.path, file_path))
+            .ok_or_else(|| format!("Not a recent file: {}", file_path))?;
+        entry.settings = settings;
+        self.save(&entries)
+    }
+
+    /// 该文件上次使用的设置
+    pub fn settings_for(&self, file_path: &str) -> Option<FileSettings> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| same_file(&entry.path, file_path))
+            .map(|entry| entry.settings.clone())
+    }
+
+    pub fn list(&self) -> Vec<RecentFile> {
+        let mut entries = self.entries.lock().unwrap().clone();
+        for entry in &mut entries {
+            entry.missing = !Path::new(&entry.path).exists();
+        }
+        entries
+    }
+
+    pub fn clear(&self) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clear();
+        self.save(&entries)
+    }
+}
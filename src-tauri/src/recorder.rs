@@ -0,0 +1,439 @@
+use crate::keypress_simulator::KeyEvent;
+use crate::timer_resolution::{TimerResolution, DEFAULT_TIMER_RESOLUTION_MS};
+use rdev::{Event, EventType, Key};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+use uni_input::{parse_key_string, LayoutTranslation, ParsedKey};
+
+// 本程序发出的按键在这段时间内被监听到时视为回环，不记录
+const INJECTION_WINDOW: Duration = Duration::from_millis(50);
+
+// 最近注入的按键名（小写，按 '+' 拆开）
+lazy_static::lazy_static! {
+    static ref INJECTED: Mutex<VecDeque<(Instant, Vec<String>)>> = Mutex::new(VecDeque::new());
+}
+
+/// 记录一次由本程序注入的按键，供录制时排除
+pub fn note_injected(key: &str) {
+    let now = Instant::now();
+    let mut injected = INJECTED.lock().unwrap();
+    while injected
+        .front()
+        .is_some_and(|(at, _)| now.duration_since(*at) > INJECTION_WINDOW)
+    {
+        injected.pop_front();
+    }
+    let parts = key.split('+').map(|p| p.trim().to_lowercase()).collect();
+    injected.push_back((now, parts));
+}
+
+fn was_injected(names: &[&str]) -> bool {
+    let now = Instant::now();
+    INJECTED.lock().unwrap().iter().any(|(at, parts)| {
+        now.duration_since(*at) <= INJECTION_WINDOW
+            && parts.iter().any(|p| names.contains(&p.as_str()))
+    })
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RecordingOptions {
+    // 只记录这些按键（通常是当前映射中的按键），不设置则记录全部
+    pub keys: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ModifierKey {
+    Shift,
+    RShift,
+    Ctrl,
+    RCtrl,
+    Alt,
+    RAlt,
+    Meta,
+    RMeta,
+}
+
+impl ModifierKey {
+    fn from_rdev(key: Key) -> Option<Self> {
+        let modifier = match key {
+            Key::ShiftLeft => ModifierKey::Shift,
+            Key::ShiftRight => ModifierKey::RShift,
+            Key::ControlLeft => ModifierKey::Ctrl,
+            Key::ControlRight => ModifierKey::RCtrl,
+            Key::Alt => ModifierKey::Alt,
+            Key::AltGr => ModifierKey::RAlt,
+            Key::MetaLeft => ModifierKey::Meta,
+            Key::MetaRight => ModifierKey::RMeta,
+            _ => return None,
+        };
+        Some(modifier)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ModifierKey::Shift => "shift",
+            ModifierKey::RShift => "rshift",
+            ModifierKey::Ctrl => "ctrl",
+            ModifierKey::RCtrl => "rctrl",
+            ModifierKey::Alt => "alt",
+            ModifierKey::RAlt => "ralt",
+            ModifierKey::Meta => "meta",
+            ModifierKey::RMeta => "rmeta",
+        }
+    }
+
+    // 注入的按键字符串里可能出现的等价写法
+    fn aliases(self) -> &'static [&'static str] {
+        match self {
+            ModifierKey::Shift => &["shift", "lshift"],
+            ModifierKey::RShift => &["rshift"],
+            ModifierKey::Ctrl => &["ctrl", "control", "lctrl"],
+            ModifierKey::RCtrl => &["rctrl"],
+            ModifierKey::Alt => &["alt", "lalt"],
+            ModifierKey::RAlt => &["ralt"],
+            ModifierKey::Meta => &["meta", "cmd", "win", "lmeta", "lcmd", "lwin"],
+            ModifierKey::RMeta => &["rmeta", "rcmd", "rwin"],
+        }
+    }
+}
+
+/// rdev 按键对应的主键名（按 QWERTY 物理位置）
+fn main_key_name(key: Key) -> Option<String> {
+    let name = match key {
+        Key::KeyA => "a",
+        Key::KeyB => "b",
+        Key::KeyC => "c",
+        Key::KeyD => "d",
+        Key::KeyE => "e",
+        Key::KeyF => "f",
+        Key::KeyG => "g",
+        Key::KeyH => "h",
+        Key::KeyI => "i",
+        Key::KeyJ => "j",
+        Key::KeyK => "k",
+        Key::KeyL => "l",
+        Key::KeyM => "m",
+        Key::KeyN => "n",
+        Key::KeyO => "o",
+        Key::KeyP => "p",
+        Key::KeyQ => "q",
+        Key::KeyR => "r",
+        Key::KeyS => "s",
+        Key::KeyT => "t",
+        Key::KeyU => "u",
+        Key::KeyV => "v",
+        Key::KeyW => "w",
+        Key::KeyX => "x",
+        Key::KeyY => "y",
+        Key::KeyZ => "z",
+        Key::Num0 => "0",
+        Key::Num1 => "1",
+        Key::Num2 => "2",
+        Key::Num3 => "3",
+        Key::Num4 => "4",
+        Key::Num5 => "5",
+        Key::Num6 => "6",
+        Key::Num7 => "7",
+        Key::Num8 => "8",
+        Key::Num9 => "9",
+        Key::Minus => "-",
+        Key::Equal => "=",
+        Key::LeftBracket => "[",
+        Key::RightBracket => "]",
+        Key::SemiColon => ";",
+        Key::Quote => "'",
+        Key::BackQuote => "`",
+        Key::BackSlash => "\\",
+        Key::Comma => ",",
+        Key::Dot => ".",
+        Key::Slash => "/",
+        Key::Kp0 => "numpad0",
+        Key::Kp1 => "numpad1",
+        Key::Kp2 => "numpad2",
+        Key::Kp3 => "numpad3",
+        Key::Kp4 => "numpad4",
+        Key::Kp5 => "numpad5",
+        Key::Kp6 => "numpad6",
+        Key::Kp7 => "numpad7",
+        Key::Kp8 => "numpad8",
+        Key::Kp9 => "numpad9",
+        Key::KpPlus => "numpadadd",
+        Key::KpMinus => "numpadsubtract",
+        Key::KpMultiply => "numpadmultiply",
+        Key::KpDivide => "numpaddivide",
+        Key::KpDelete => "numpaddecimal",
+        Key::KpReturn => "numpadenter",
+        Key::UpArrow => "up",
+        Key::DownArrow => "down",
+        Key::LeftArrow => "left",
+        Key::RightArrow => "right",
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+// 监听到的一次按键变化
+enum KeyInput {
+    Press { id: String, key: String }, // id 为物理键，key 为带修饰键的按键字符串
+    Release { id: String },
+}
+
+// 跟踪修饰键状态，把 rdev 事件转换成按键字符串；忽略本程序注入的按键
+struct KeyTracker {
+    layout: LayoutTranslation,
+    modifiers: Vec<ModifierKey>, // 当前按住的修饰键（按按下顺序）
+}
+
+impl KeyTracker {
+    fn new(layout: LayoutTranslation) -> Self {
+        Self {
+            layout,
+            modifiers: Vec::new(),
+        }
+    }
+
+    fn key_string(&self, main: Option<&str>) -> String {
+        let mut parts: Vec<&str> = self.modifiers.iter().map(|m| m.name()).collect();
+        parts.extend(main);
+        parts.join("+")
+    }
+
+    fn handle(&mut self, event: &Event) -> Option<KeyInput> {
+        match event.event_type {
+            EventType::KeyPress(key) => {
+                if let Some(modifier) = ModifierKey::from_rdev(key) {
+                    if was_injected(modifier.aliases()) || self.modifiers.contains(&modifier) {
+                        return None;
+                    }
+                    // 单独按修饰键也可能是映射中的按键
+                    let key = self.key_string(Some(modifier.name()));
+                    self.modifiers.push(modifier);
+                    Some(KeyInput::Press {
+                        id: modifier.name().to_string(),
+                        key,
+                    })
+                } else {
+                    let position = main_key_name(key)?;
+                    let main = match position.chars().collect::<Vec<_>>().as_slice() {
+                        [ch] => self.layout.from_qwerty_position(*ch).to_string(),
+                        _ => position.clone(),
+                    };
+                    if was_injected(&[position.as_str(), main.as_str()]) {
+                        return None;
+                    }
+                    let key = self.key_string(Some(&main));
+                    Some(KeyInput::Press { id: position, key })
+                }
+            }
+            EventType::KeyRelease(key) => {
+                if let Some(modifier) = ModifierKey::from_rdev(key) {
+                    self.modifiers.retain(|m| *m != modifier);
+                    Some(KeyInput::Release {
+                        id: modifier.name().to_string(),
+                    })
+                } else {
+                    main_key_name(key).map(|id| KeyInput::Release { id })
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+// 一次录制的数据
+struct Session {
+    filter: Option<Vec<ParsedKey>>,
+    tracker: KeyTracker,
+    pressed: HashMap<String, (Instant, String)>, // 物理键 -> (按下时刻, 按键字符串)
+    events: Vec<(Instant, String, f64)>,
+    // 录制期间提高系统计时器精度，结束录制时恢复
+    _timer: TimerResolution,
+}
+
+impl Session {
+    fn accepts(&self, key: &str) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+        parse_key_string(key)
+            .map(|parsed| filter.contains(&parsed))
+            .unwrap_or(false)
+    }
+
+    fn press(&mut self, id: String, key: String, now: Instant) {
+        // 长按时系统的自动重复不重复记录
+        if self.pressed.contains_key(&id) || !self.accepts(&key) {
+            return;
+        }
+        self.pressed.insert(id, (now, key));
+    }
+
+    fn release(&mut self, id: &str, now: Instant) {
+        if let Some((start, key)) = self.pressed.remove(id) {
+            self.events
+                .push((start, key, now.duration_since(start).as_secs_f64()));
+        }
+    }
+
+    fn handle(&mut self, event: &Event) {
+        let now = Instant::now();
+        match self.tracker.handle(event) {
+            Some(KeyInput::Press { id, key }) => self.press(id, key, now),
+            Some(KeyInput::Release { id }) => self.release(&id, now),
+            None => {}
+        }
+    }
+
+    fn finish(mut self) -> Vec<KeyEvent> {
+        // 停止时仍按住的键按停止时刻结束
+        let now = Instant::now();
+        let held: Vec<String> = self.pressed.keys().cloned().collect();
+        for id in held {
+            self.release(&id, now);
+        }
+
+        self.events.sort_by_key(|(start, _, _)| *start);
+        let Some(&(first, _, _)) = self.events.first() else {
+            return Vec::new();
+        };
+        self.events
+            .into_iter()
+            .map(|(start, key, duration)| KeyEvent {
+                time: start.duration_since(first).as_secs_f64(),
+                key,
+                duration,
+                group: None,
+                note: None,
+                chord: None,
+            })
+            .collect()
+    }
+}
+
+// 练习模式下把用户按下的键转交给播放控制器
+struct PracticeListener {
+    tracker: KeyTracker,
+    pressed: HashSet<String>, // 按住的物理键，过滤自动重复
+    on_press: Box<dyn Fn(String) + Send>,
+}
+
+impl PracticeListener {
+    fn handle(&mut self, event: &Event) {
+        match self.tracker.handle(event) {
+            Some(KeyInput::Press { id, key }) => {
+                if self.pressed.insert(id) {
+                    (self.on_press)(key);
+                }
+            }
+            Some(KeyInput::Release { id }) => {
+                self.pressed.remove(&id);
+            }
+            None => {}
+        }
+    }
+}
+
+/// 录制用户按键，练习模式也通过这里监听用户的按键
+/// 全局键盘监听线程在第一次使用时启动并一直保留（rdev::listen 无法停止），不需要时直接忽略事件
+#[derive(Default)]
+pub struct Recorder {
+    session: Arc<Mutex<Option<Session>>>,
+    practice: Arc<Mutex<Option<PracticeListener>>>,
+    listener: Once,
+}
+
+impl Recorder {
+    pub fn start(
+        &self,
+        options: RecordingOptions,
+        layout: LayoutTranslation,
+    ) -> Result<(), String> {
+        let filter = match options.keys {
+            Some(keys) => Some(
+                keys.iter()
+                    .map(|k| parse_key_string(k).map(normalize))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+
+        {
+            let mut session = self.session.lock().unwrap();
+            if session.is_some() {
+                return Err("Recording already in progress".to_string());
+            }
+            *session = Some(Session {
+                filter,
+                tracker: KeyTracker::new(layout),
+                pressed: HashMap::new(),
+                events: Vec::new(),
+                _timer: TimerResolution::acquire(DEFAULT_TIMER_RESOLUTION_MS),
+            });
+        }
+
+        self.ensure_listener();
+        Ok(())
+    }
+
+    /// 练习模式：把用户按下的键（与事件中相同格式的按键字符串）交给 on_press
+    pub fn start_practice(
+        &self,
+        layout: LayoutTranslation,
+        on_press: impl Fn(String) + Send + 'static,
+    ) {
+        *self.practice.lock().unwrap() = Some(PracticeListener {
+            tracker: KeyTracker::new(layout),
+            pressed: HashSet::new(),
+            on_press: Box::new(on_press),
+        });
+        self.ensure_listener();
+    }
+
+    pub fn stop_practice(&self) {
+        self.practice.lock().unwrap().take();
+    }
+
+    fn ensure_listener(&self) {
+        self.listener.call_once(|| {
+            let session = Arc::clone(&self.session);
+            let practice = Arc::clone(&self.practice);
+            thread::spawn(move || {
+                let callback = move |event: Event| {
+                    if let Some(session) = session.lock().unwrap().as_mut() {
+                        session.handle(&event);
+                    }
+                    if let Some(practice) = practice.lock().unwrap().as_mut() {
+                        practice.handle(&event);
+                    }
+                };
+                if let Err(e) = rdev::listen(callback) {
+                    eprintln!("Failed to listen for keyboard events: {:?}", e);
+                }
+            });
+        });
+    }
+
+    /// 结束录制，返回时间相对第一次按键的按键列表
+    pub fn stop(&self) -> Result<Vec<KeyEvent>, String> {
+        let session = self.session.lock().unwrap().take();
+        session
+            .map(Session::finish)
+            .ok_or_else(|| "No recording in progress".to_string())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.session.lock().unwrap().is_some()
+    }
+}
+
+// 字符主键统一用小写比较
+fn normalize(mut parsed: ParsedKey) -> ParsedKey {
+    if let Some(uni_input::MainKey::Char(ch)) = parsed.main {
+        parsed.main = Some(uni_input::MainKey::Char(ch.to_ascii_lowercase()));
+    }
+    parsed
+}
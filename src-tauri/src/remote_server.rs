@@ -0,0 +1,337 @@
+//! 局域网遥控服务
+//! 提供一组简单的 HTTP 接口和推送播放事件的 WebSocket，驱动与 Tauri 命令相同的 PlaybackController
+
+use crate::default_settings::DefaultSettingsStore;
+use crate::duet::Duet;
+use crate::error::CommandError;
+use crate::keypress_simulator::{PlaybackController, PlaybackEvent, QueueOptions};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager};
+use tokio::sync::{broadcast, watch};
+
+pub const DEFAULT_PORT: u16 = 17380;
+
+// 自动生成的令牌长度
+const TOKEN_LEN: usize = 32;
+
+// WebSocket 客户端跟不上时最多缓存的事件数
+const EVENT_BUFFER: usize = 256;
+
+/// 遥控服务设置，默认关闭
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RemoteSettings {
+    pub enabled: bool,
+    pub allow_lan: bool, // false 时只监听 127.0.0.1
+    pub port: Option<u16>,
+    pub token: Option<String>, // 不设置时自动生成
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteStatus {
+    pub running: bool,
+    pub address: Option<String>,
+    pub token: Option<String>,
+}
+
+struct Running {
+    address: SocketAddr,
+    token: String,
+    // 发送 true 时 HTTP 服务和所有 WebSocket 连接一起退出
+    shutdown: watch::Sender<bool>,
+    // 服务任务，结束时监听的端口已关闭
+    task: tauri::async_runtime::JoinHandle<()>,
+}
+
+#[derive(Clone)]
+struct ApiState {
+    app: AppHandle,
+    token: Arc<String>,
+    // 遥控命令逐个执行，不与另一个遥控命令交错
+    command_lock: Arc<tokio::sync::Mutex<()>>,
+    events: broadcast::Sender<String>,
+    shutdown: watch::Receiver<bool>,
+}
+
+#[derive(Deserialize)]
+struct SeekRequest {
+    position: f64,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// 遥控服务，通过 Tauri `.manage()` 注册
+pub struct RemoteServer {
+    events: broadcast::Sender<String>,
+    running: Mutex<Option<Running>>,
+}
+
+impl Default for RemoteServer {
+    fn default() -> Self {
+        Self {
+            events: broadcast::channel(EVENT_BUFFER).0,
+            running: Mutex::new(None),
+        }
+    }
+}
+
+impl RemoteServer {
+    /// 转发一条播放事件给已连接的 WebSocket 客户端，格式与前端收到的一致
+    pub fn publish(&self, event: &PlaybackEvent) {
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        let message = serde_json::json!({ "event": event.name(), "payload": event });
+        let _ = self.events.send(message.to_string());
+    }
+
+    pub fn status(&self) -> RemoteStatus {
+        match self.running.lock().unwrap().as_ref() {
+            Some(running) => RemoteStatus {
+                running: true,
+                address: Some(running.address.to_string()),
+                token: Some(running.token.clone()),
+            },
+            None => RemoteStatus {
+                running: false,
+                address: None,
+                token: None,
+            },
+        }
+    }
+
+    /// 按设置启动、重启或关闭服务
+    pub async fn apply(
+        &self,
+        app: AppHandle,
+        settings: RemoteSettings,
+    ) -> Result<RemoteStatus, String> {
+        // 优雅退出是异步的，等旧服务放开端口后才能再次监听
+        if let Some(task) = self.shutdown() {
+            let _ = task.await;
+        }
+        if !settings.enabled {
+            return Ok(self.status());
+        }
+
+        let token = match settings.token.filter(|t| !t.is_empty()) {
+            Some(token) if token.len() < 16 => {
+                return Err("Remote control token must be at least 16 characters".to_string())
+            }
+            Some(token) => token,
+            None => rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(TOKEN_LEN)
+                .map(char::from)
+                .collect(),
+        };
+        let ip = if settings.allow_lan {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        };
+        let address = SocketAddr::new(ip, settings.port.unwrap_or(DEFAULT_PORT));
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+        let address = listener.local_addr().map_err(|e| e.to_string())?;
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let state = ApiState {
+            app,
+            token: Arc::new(token.clone()),
+            command_lock: Arc::new(tokio::sync::Mutex::new(())),
+            events: self.events.clone(),
+            shutdown: shutdown_rx.clone(),
+        };
+        let router = Router::new()
+            .route("/api/status", get(get_status))
+            .route("/api/queue", get(get_queue))
+            .route("/api/start", post(start))
+            .route("/api/stop", post(stop))
+            .route("/api/pause", post(pause))
+            .route("/api/resume", post(resume))
+            .route("/api/seek", post(seek))
+            .route("/api/events", get(events))
+            .route("/api/duet", get(get_duet))
+            .layer(middleware::from_fn_with_state(state.clone(), require_token))
+            .with_state(state);
+
+        let mut stopped = shutdown_rx;
+        let task = tauri::async_runtime::spawn(async move {
+            let result = axum::serve(listener, router)
+                .with_graceful_shutdown(async move {
+                    let _ = stopped.wait_for(|stop| *stop).await;
+                })
+                .await;
+            if let Err(e) = result {
+                eprintln!("Remote control server error: {}", e);
+            }
+        });
+
+        *self.running.lock().unwrap() = Some(Running {
+            address,
+            token,
+            shutdown,
+            task,
+        });
+        Ok(self.status())
+    }
+
+    /// 通知服务退出，不等待它结束
+    pub fn stop(&self) {
+        self.shutdown();
+    }
+
+    // 通知服务退出，返回它的任务以便等待
+    fn shutdown(&self) -> Option<tauri::async_runtime::JoinHandle<()>> {
+        let running = self.running.lock().unwrap().take()?;
+        let _ = running.shutdown.send(true);
+        Some(running.task)
+    }
+}
+
+// 逐字节比较全部内容，耗时与第一个不同字符的位置无关
+fn token_matches(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// 每个请求都要带令牌：Authorization: Bearer <token>，或浏览器 WebSocket 用的 ?token=<token>
+async fn require_token(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    let from_header = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let from_query = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    });
+    match from_header.or(from_query) {
+        Some(token) if token_matches(token, &state.token) => next.run(request).await,
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+// 在阻塞线程池中执行控制器操作（stop 等会等待播放线程）
+async fn control<T, F>(state: &ApiState, action: F) -> Response
+where
+    T: Serialize + Send + 'static,
+    F: FnOnce(&AppHandle) -> Result<T, CommandError> + Send + 'static,
+{
+    let _guard = state.command_lock.lock().await;
+    let app = state.app.clone();
+    match tokio::task::spawn_blocking(move || action(&app)).await {
+        Ok(Ok(value)) => Json(value).into_response(),
+        Ok(Err(error)) => (
+            StatusCode::CONFLICT,
+            Json(ErrorBody {
+                error: error.to_string(),
+            }),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorBody {
+                error: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+fn controller(app: &AppHandle) -> tauri::State<'_, PlaybackController> {
+    app.state::<PlaybackController>()
+}
+
+async fn get_status(State(state): State<ApiState>) -> Response {
+    control(&state, |app| Ok(controller(app).status())).await
+}
+
+async fn get_queue(State(state): State<ApiState>) -> Response {
+    control(&state, |app| {
+        let settings = app.state::<DefaultSettingsStore>().get().playback;
+        Ok(controller(app).queue_list(&settings))
+    })
+    .await
+}
+
+// 合奏的跟奏方轮询这里，见 duet 模块
+async fn get_duet(State(state): State<ApiState>) -> Response {
+    control(&state, |app| {
+        let scheduled_at = controller(app).status().scheduled_at_unix_ms;
+        Ok(app.state::<Duet>().announcement(scheduled_at))
+    })
+    .await
+}
+
+async fn start(State(state): State<ApiState>, body: Option<Json<QueueOptions>>) -> Response {
+    let options = body.map_or_else(QueueOptions::default, |Json(options)| options);
+    control(&state, move |app| crate::start_queue_playback(app, options)).await
+}
+
+async fn stop(State(state): State<ApiState>) -> Response {
+    control(&state, |app| controller(app).stop()).await
+}
+
+async fn pause(State(state): State<ApiState>) -> Response {
+    control(&state, |app| controller(app).pause()).await
+}
+
+async fn resume(State(state): State<ApiState>) -> Response {
+    control(&state, |app| controller(app).resume()).await
+}
+
+async fn seek(State(state): State<ApiState>, Json(body): Json<SeekRequest>) -> Response {
+    control(&state, move |app| controller(app).seek(body.position)).await
+}
+
+async fn events(State(state): State<ApiState>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| forward_events(socket, state))
+}
+
+// 推送播放事件直到客户端断开或服务关闭
+async fn forward_events(mut socket: WebSocket, state: ApiState) {
+    let mut events = state.events.subscribe();
+    let mut shutdown = state.shutdown.clone();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(message) => {
+                    if socket.send(Message::Text(message)).await.is_err() {
+                        break;
+                    }
+                }
+                // 客户端太慢时丢弃积压的事件，继续推送新的
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => {}
+            },
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
@@ -0,0 +1,410 @@
+//! 超大调度的磁盘暂存：动作按固定长度的记录写入缓存目录下的临时文件，播放时按窗口读回
+//! 内存中只保留当前窗口和每个窗口的起始时间；文件在用完时删除，异常退出留下的文件在下次启动时清理
+
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// 默认的暂存阈值（动作数），约 30 MB 的内存调度
+pub const DEFAULT_SPILL_THRESHOLD: usize = 500_000;
+
+/// 每次读回的记录数
+pub const SPILL_WINDOW: usize = 4096;
+
+const EXTENSION: &str = "spill";
+
+// time(8) + event_index(8) + key(4) + press(1) + conflict_tag(1) + conflict_value(8)
+const RECORD_SIZE: usize = 30;
+
+/// 暂存设置
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpillSettings {
+    pub enabled: bool,
+    // 动作数达到这个数的调度写入磁盘
+    pub threshold_actions: usize,
+    // 不论大小都写入磁盘，用于测试
+    pub force: bool,
+}
+
+impl SpillSettings {
+    const DEFAULT: Self = Self {
+        enabled: false,
+        threshold_actions: DEFAULT_SPILL_THRESHOLD,
+        force: false,
+    };
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold_actions < SPILL_WINDOW {
+            return Err(format!(
+                "threshold_actions must be at least {}, got {}",
+                SPILL_WINDOW, self.threshold_actions
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SpillSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+struct SpillState {
+    dir: Option<PathBuf>, // init 之前不暂存
+    settings: SpillSettings,
+}
+
+static STATE: Mutex<SpillState> = Mutex::new(SpillState {
+    dir: None,
+    settings: SpillSettings::DEFAULT,
+});
+
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// 设置暂存目录并删除上次运行留下的文件，启动时调用一次
+pub fn init(dir: PathBuf) {
+    let removed = sweep(&dir);
+    if removed > 0 {
+        log::info!("Removed {} stale schedule spill files", removed);
+    }
+    STATE.lock().unwrap().dir = Some(dir);
+}
+
+/// 删除暂存目录中的所有暂存文件，退出时调用；返回删除的文件数
+pub fn cleanup() -> usize {
+    let dir = STATE.lock().unwrap().dir.clone();
+    dir.map_or(0, |dir| sweep(&dir))
+}
+
+fn sweep(dir: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == EXTENSION))
+        .filter(|path| fs::remove_file(path).is_ok())
+        .count()
+}
+
+pub fn settings() -> SpillSettings {
+    STATE.lock().unwrap().settings
+}
+
+pub fn set_settings(settings: SpillSettings) -> Result<(), String> {
+    settings.validate()?;
+    STATE.lock().unwrap().settings = settings;
+    Ok(())
+}
+
+/// 有 actions 个动作的调度是否应写入磁盘；没有初始化暂存目录时总是 false
+pub fn should_spill(actions: usize) -> bool {
+    let state = STATE.lock().unwrap();
+    let settings = state.settings;
+    state.dir.is_some()
+        && actions > 0
+        && (settings.force || (settings.enabled && actions >= settings.threshold_actions))
+}
+
+/// 一个动作的记录，按键以序号保存，按键表留在内存中
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpillRecord {
+    pub time: f64,
+    pub event_index: u64,
+    pub key: u32,
+    pub press: bool,
+    pub conflict_tag: u8,
+    pub conflict_value: f64,
+}
+
+impl SpillRecord {
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..8].copy_from_slice(&self.time.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.event_index.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.key.to_le_bytes());
+        buf[20] = self.press as u8;
+        buf[21] = self.conflict_tag;
+        buf[22..30].copy_from_slice(&self.conflict_value.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let f64_at = |at: usize| f64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
+        Self {
+            time: f64_at(0),
+            event_index: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            key: u32::from_le_bytes(buf[16..20].try_into().unwrap()),
+            press: buf[20] != 0,
+            conflict_tag: buf[21],
+            conflict_value: f64_at(22),
+        }
+    }
+}
+
+// 当前读入内存的窗口
+struct Window {
+    index: usize,
+    records: Vec<SpillRecord>,
+}
+
+/// 写入磁盘的按时间排序的记录序列，drop 时删除文件
+pub struct SpillFile {
+    path: PathBuf,
+    file: RefCell<File>,
+    len: usize,
+    // 每个窗口第一条记录的时间，用于按时间查找
+    window_starts: Vec<f64>,
+    window: RefCell<Option<Window>>,
+}
+
+impl std::fmt::Debug for SpillFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpillFile")
+            .field("path", &self.path)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl SpillFile {
+    /// 把 records 写入暂存目录中的新文件
+    pub fn create(records: impl Iterator<Item = SpillRecord>) -> io::Result<Self> {
+        let dir = STATE.lock().unwrap().dir.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "schedule spill is not initialized")
+        })?;
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "schedule-{}-{}.{}",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed),
+            EXTENSION
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // 从这里开始出错时由 drop 删除写了一半的文件
+        let mut spill = Self {
+            path,
+            file: RefCell::new(file),
+            len: 0,
+            window_starts: Vec::new(),
+            window: RefCell::new(None),
+        };
+        {
+            let mut file = spill.file.borrow_mut();
+            let mut writer = BufWriter::new(&mut *file);
+            let mut buf = [0u8; RECORD_SIZE];
+            for record in records {
+                if spill.len % SPILL_WINDOW == 0 {
+                    spill.window_starts.push(record.time);
+                }
+                record.encode(&mut buf);
+                writer.write_all(&buf)?;
+                spill.len += 1;
+            }
+            writer.flush()?;
+        }
+        Ok(spill)
+    }
+
+    /// 记录数
+    pub fn count(&self) -> usize {
+        self.len
+    }
+
+    /// 第 index 条记录，不在当前窗口时读入它所在的窗口
+    pub fn get(&self, index: usize) -> io::Result<Option<SpillRecord>> {
+        if index >= self.len {
+            return Ok(None);
+        }
+        let window_index = index / SPILL_WINDOW;
+        let mut window = self.window.borrow_mut();
+        if window.as_ref().is_none_or(|w| w.index != window_index) {
+            *window = Some(self.read_window(window_index)?);
+        }
+        let window = window.as_ref().unwrap();
+        Ok(Some(window.records[index - window_index * SPILL_WINDOW]))
+    }
+
+    fn read_window(&self, index: usize) -> io::Result<Window> {
+        let start = index * SPILL_WINDOW;
+        let count = SPILL_WINDOW.min(self.len - start);
+        let mut bytes = vec![0u8; count * RECORD_SIZE];
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start((start * RECORD_SIZE) as u64))?;
+        file.read_exact(&mut bytes)?;
+        Ok(Window {
+            index,
+            records: bytes
+                .chunks_exact(RECORD_SIZE)
+                .map(SpillRecord::decode)
+                .collect(),
+        })
+    }
+
+    /// 第一条时间不早于 time 的记录的序号
+    pub fn partition_point(&self, time: f64) -> io::Result<usize> {
+        // 第一个起始时间不早于 time 的窗口之前的那个窗口里才可能有分界
+        let window = self.window_starts.partition_point(|&start| start < time);
+        if window == 0 {
+            return Ok(0);
+        }
+        let first = (window - 1) * SPILL_WINDOW;
+        let last = (window * SPILL_WINDOW).min(self.len);
+        for index in first..last {
+            match self.get(index)? {
+                Some(record) if record.time >= time => return Ok(index),
+                _ => {}
+            }
+        }
+        Ok(last)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!(
+                "Failed to remove schedule spill file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// 测试用的暂存目录，每个进程只初始化一次，init 的清理不会删掉其他测试正在用的文件
+#[cfg(test)]
+pub(crate) fn init_for_tests() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        init(std::env::temp_dir().join(format!("spill-tests-{}", std::process::id())))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: f64, index: usize) -> SpillRecord {
+        SpillRecord {
+            time,
+            event_index: index as u64,
+            key: (index % 7) as u32,
+            press: index % 2 == 0,
+            conflict_tag: (index % 3) as u8,
+            conflict_value: index as f64 * 0.001,
+        }
+    }
+
+    fn spill(records: &[SpillRecord]) -> SpillFile {
+        init_for_tests();
+        SpillFile::create(records.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut buf = [0u8; RECORD_SIZE];
+        for record in [
+            record(0.0, 0),
+            record(1234.5678, 5),
+            SpillRecord {
+                time: f64::MAX,
+                event_index: u64::MAX,
+                key: u32::MAX,
+                press: true,
+                conflict_tag: u8::MAX,
+                conflict_value: -0.025,
+            },
+        ] {
+            record.encode(&mut buf);
+            assert_eq!(SpillRecord::decode(&buf), record);
+        }
+    }
+
+    #[test]
+    fn get_reads_across_window_boundaries() {
+        let records: Vec<SpillRecord> = (0..SPILL_WINDOW * 2 + 5)
+            .map(|i| record(i as f64 * 0.01, i))
+            .collect();
+        let file = spill(&records);
+        assert_eq!(file.count(), records.len());
+        // 来回跨过窗口边界，每次都读入正确的窗口
+        for index in [
+            0,
+            SPILL_WINDOW - 1,
+            SPILL_WINDOW,
+            SPILL_WINDOW - 1,
+            SPILL_WINDOW * 2 + 4,
+            1,
+            SPILL_WINDOW * 2,
+        ] {
+            assert_eq!(file.get(index).unwrap(), Some(records[index]), "{}", index);
+        }
+        assert_eq!(file.get(records.len()).unwrap(), None);
+        for (index, expected) in records.iter().enumerate().rev() {
+            assert_eq!(file.get(index).unwrap().as_ref(), Some(expected));
+        }
+    }
+
+    // 同一时刻的记录跨过两个窗口边界时，分界仍是这一时刻的第一条记录
+    #[test]
+    fn partition_point_with_one_time_across_windows() {
+        let first = SPILL_WINDOW - 10;
+        let last = SPILL_WINDOW * 2 + 10;
+        let records: Vec<SpillRecord> = (0..SPILL_WINDOW * 3)
+            .map(|i| {
+                let time = match i {
+                    i if i < first => i as f64 * 0.001,
+                    i if i < last => 5.0,
+                    i => 5.0 + (i - last + 1) as f64 * 0.001,
+                };
+                record(time, i)
+            })
+            .collect();
+        let file = spill(&records);
+        for time in [-1.0, 0.0, 0.0005, 4.0, 5.0, 5.0001, 5.001, 6.0, 100.0] {
+            assert_eq!(
+                file.partition_point(time).unwrap(),
+                records.partition_point(|r| r.time < time),
+                "partition_point({})",
+                time
+            );
+        }
+        assert_eq!(file.partition_point(5.0).unwrap(), first);
+        assert_eq!(file.partition_point(5.0001).unwrap(), last);
+    }
+
+    #[test]
+    fn drop_deletes_the_file() {
+        let file = spill(&[record(0.0, 0), record(1.0, 1)]);
+        let path = file.path.clone();
+        assert!(path.exists());
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    // 启动时删除上次运行留下的暂存文件，目录中的其他文件不动
+    #[test]
+    fn init_sweeps_stale_files() {
+        let dir = std::env::temp_dir().join(format!("spill-sweep-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["schedule-1-0.spill", "schedule-1-1.spill", "notes.txt"] {
+            fs::write(dir.join(name), b"stale").unwrap();
+        }
+        init(dir.clone());
+        // 之后其他测试的暂存文件可能写到这个目录，只检查预先放入的文件
+        assert!(!dir.join("schedule-1-0.spill").exists());
+        assert!(!dir.join("schedule-1-1.spill").exists());
+        assert!(dir.join("notes.txt").exists());
+        assert_eq!(sweep(&dir.join("missing")), 0);
+        fs::remove_file(dir.join("notes.txt")).unwrap();
+    }
+}
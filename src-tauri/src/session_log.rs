@@ -0,0 +1,46 @@
+//! 播放诊断日志
+//! 播放线程用 `log` 的 debug 级别写入 TARGET，日志插件把这个 target 单独写到 playback.log
+//! 只记录本应用自己发送的按键，录制功能监听到的用户输入不写日志
+
+use log::LevelFilter;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// 播放诊断日志的 target
+pub const TARGET: &str = "playback";
+
+/// 日志目录下的文件名（不含扩展名）
+pub const FILE_NAME: &str = "playback";
+
+/// 默认日志级别，播放诊断（debug）默认不记录
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+// 实际发送比计划晚这么多时记一条警告
+pub const LATE_WARNING_MS: f64 = 20.0;
+
+// get_session_log 最多返回的行数
+const MAX_TAIL_LINES: usize = 2000;
+
+/// 设置全局日志级别："off"、"error"、"warn"、"info"、"debug" 或 "trace"
+pub fn set_level(level: &str) -> Result<(), String> {
+    let filter =
+        LevelFilter::from_str(level).map_err(|_| format!("Invalid log level: {}", level))?;
+    log::set_max_level(filter);
+    Ok(())
+}
+
+/// 读取日志文件的最后 n 行，文件不存在时返回空
+pub fn tail(path: &Path, n: usize) -> Result<Vec<String>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read session log: {}", e)),
+    };
+    let lines: Vec<&str> = content.lines().collect();
+    let n = n.min(MAX_TAIL_LINES);
+    Ok(lines[lines.len().saturating_sub(n)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect())
+}
@@ -0,0 +1,127 @@
+//! 崩溃恢复：把当前的工作状态写入应用数据目录的恢复文件
+//! 前端在状态有明显变化时推送（打开的文件、调整过的设置、循环区间等），后台线程定期连同播放队列落盘
+//! 启动时前端读取恢复文件并询问是否恢复；先写临时文件再改名，写到一半崩溃也不会损坏已有的文件
+
+use crate::keypress_simulator::QueueEntry;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 后台自动保存的间隔，内容没有变化时不写
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// 默认忽略多久以前的恢复文件（小时）
+pub const DEFAULT_MAX_AGE_HOURS: f64 = 72.0;
+
+/// 恢复文件的内容
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveredSession {
+    pub saved_at: u64, // Unix 时间戳（秒）
+    // 前端推送的工作状态，原样保存
+    pub state: serde_json::Value,
+    #[serde(default)]
+    pub queue: Vec<QueueEntry>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // 前端最近推送的状态；本次启动后还没有推送过时为 None，此时不覆盖上次留下的文件
+    state: Option<serde_json::Value>,
+    // 上次写入的内容（不含保存时间），相同时跳过
+    written: Option<String>,
+}
+
+/// 恢复文件的读写，通过 Tauri `.manage()` 注册
+pub struct SessionRecovery {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl SessionRecovery {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// 记录前端推送的状态并立即写入
+    pub fn update(&self, state: serde_json::Value, queue: Vec<QueueEntry>) -> Result<(), String> {
+        self.inner.lock().unwrap().state = Some(state);
+        self.flush(queue).map(|_| ())
+    }
+
+    /// 连同当前队列写入最近的状态，返回是否实际写了文件
+    /// 前端还没有推送过状态，或内容与上次写入的相同时不写
+    pub fn flush(&self, queue: Vec<QueueEntry>) -> Result<bool, String> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(state) = inner.state.clone() else {
+            return Ok(false);
+        };
+        let mut session = RecoveredSession {
+            saved_at: 0,
+            state,
+            queue,
+        };
+        let content = serde_json::to_string(&session).map_err(|e| e.to_string())?;
+        if inner.written.as_ref() == Some(&content) {
+            return Ok(false);
+        }
+        session.saved_at = now_secs();
+        let json = serde_json::to_string(&session).map_err(|e| e.to_string())?;
+        self.write_atomic(json.as_bytes())
+            .map_err(|e| format!("Failed to save session state: {}", e))?;
+        inner.written = Some(content);
+        Ok(true)
+    }
+
+    // 先写同目录的临时文件并刷到磁盘，再改名覆盖
+    fn write_atomic(&self, content: &[u8]) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = self.path.with_extension("json.tmp");
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp, &self.path)
+    }
+
+    /// 读取恢复文件；不存在、损坏或早于 max_age_hours 小时时返回 None
+    pub fn load(&self, max_age_hours: f64) -> Option<RecoveredSession> {
+        let content = fs::read_to_string(&self.path).ok()?;
+        let session: RecoveredSession = match serde_json::from_str(&content) {
+            Ok(session) => session,
+            Err(e) => {
+                eprintln!("Ignoring invalid session recovery file: {}", e);
+                return None;
+            }
+        };
+        let age = now_secs().saturating_sub(session.saved_at) as f64;
+        if age > max_age_hours * 3600.0 {
+            return None;
+        }
+        Some(session)
+    }
+
+    /// 删除恢复文件，用户选择不恢复时调用；本次启动推送的状态仍会在下次保存时写入
+    pub fn discard(&self) -> Result<(), String> {
+        self.inner.lock().unwrap().written = None;
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Failed to delete session state: {}", e)),
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 播放速度倍率的范围
+pub const MIN_SPEED: f64 = 0.25;
+pub const MAX_SPEED: f64 = 4.0;
+
+pub fn validate_speed(speed: f64) -> Result<(), String> {
+    if !(speed.is_finite() && (MIN_SPEED..=MAX_SPEED).contains(&speed)) {
+        return Err(format!(
+            "Speed must be within {}-{}, got {}",
+            MIN_SPEED, MAX_SPEED, speed
+        ));
+    }
+    Ok(())
+}
+
+/// 随歌曲时间变化的速度：0 秒时为 start，length 秒时为 end，中间线性过渡
+/// 0 秒之前（预备拍）保持 start，length 之后保持 end
+#[derive(Debug, Clone, Copy)]
+pub struct SpeedCurve {
+    start: f64,
+    end: f64,
+    length: f64,
+}
+
+impl SpeedCurve {
+    pub fn constant(speed: f64) -> Self {
+        Self {
+            start: speed,
+            end: speed,
+            length: 0.0,
+        }
+    }
+
+    pub fn ramp(start: f64, end: f64, length: f64) -> Self {
+        Self { start, end, length }
+    }
+
+    // 过渡段的斜率（每秒歌曲时间的速度变化），没有过渡段时为 0
+    fn slope(&self) -> f64 {
+        if self.length > 0.0 {
+            (self.end - self.start) / self.length
+        } else {
+            0.0
+        }
+    }
+
+    /// 歌曲时间 position 处的速度
+    pub fn speed_at(&self, position: f64) -> f64 {
+        if position <= 0.0 {
+            self.start
+        } else if position >= self.length {
+            self.end
+        } else {
+            self.start + self.slope() * position
+        }
+    }
+
+    /// 歌曲时间从 from 走到 to（to >= from）需要的实际秒数
+    /// 过渡段内 ds/dt = v(s) 为线性函数，积分得到对数形式
+    pub fn wall_seconds(&self, from: f64, to: f64) -> f64 {
+        let mut total = 0.0;
+        let mut position = from;
+        if position < 0.0 {
+            let until = to.min(0.0);
+            total += (until - position) / self.start;
+            position = until;
+        }
+        if position < self.length && position < to {
+            let until = to.min(self.length);
+            let k = self.slope();
+            total += if k.abs() < 1e-12 {
+                (until - position) / self.start
+            } else {
+                (self.speed_at(until) / self.speed_at(position)).ln() / k
+            };
+            position = until;
+        }
+        if position < to {
+            total += (to - position) / self.end;
+        }
+        total
+    }
+
+    /// 从歌曲时间 from 起经过 seconds 实际秒数后的歌曲时间，wall_seconds 的反函数
+    pub fn advance(&self, from: f64, seconds: f64) -> f64 {
+        let mut remaining = seconds;
+        let mut position = from;
+        if position < 0.0 {
+            let needed = -position / self.start;
+            if remaining <= needed {
+                return position + remaining * self.start;
+            }
+            remaining -= needed;
+            position = 0.0;
+        }
+        if position < self.length {
+            let needed = self.wall_seconds(position, self.length);
+            if remaining <= needed {
+                let k = self.slope();
+                if k.abs() < 1e-12 {
+                    return position + remaining * self.start;
+                }
+                let speed = self.speed_at(position) * (k * remaining).exp();
+                return (speed - self.start) / k;
+            }
+            remaining -= needed;
+            position = self.length;
+        }
+        position + remaining * self.end
+    }
+}
+
+/// 播放调度的时间来源，默认是系统时钟
+/// 换成 VirtualClock 后等待不必真的休眠，整首歌在几毫秒内播完
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// 调用方需要等待 duration 时先询问时钟
+    /// 返回 true 表示时钟已直接推进了这段时间，调用方不用实际等待
+    fn skip(&self, _duration: Duration) -> bool {
+        false
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// 只在等待时推进的虚拟时钟
+/// 无限期的等待（如没有定时的暂停）仍然实际等待，由控制命令唤醒
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+}
+
+impl VirtualClock {
+    /// 创建以来经过的虚拟时间
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn skip(&self, duration: Duration) -> bool {
+        if duration == Duration::MAX {
+            return false;
+        }
+        self.advance(duration);
+        true
+    }
+}
+
+/// 歌曲时间时钟：实际时间按速度曲线换算为歌曲时间
+pub struct SongClock {
+    source: Arc<dyn Clock>,
+    // anchor 时刻对应的歌曲时间（从中途开始播放时不为 0）
+    offset: f64,
+    // 歌曲时间 offset 对应的时刻，暂停后会向后平移
+    anchor: Instant,
+    curve: SpeedCurve,
+}
+
+impl SongClock {
+    pub fn new(source: Arc<dyn Clock>) -> Self {
+        Self {
+            offset: 0.0,
+            anchor: source.now(),
+            curve: SpeedCurve::constant(1.0),
+            source,
+        }
+    }
+
+    pub fn now(&self) -> f64 {
+        let now = self.source.now();
+        // reset_at 排定在将来开始时，起点之前按起点的速度往回推算
+        if now < self.anchor {
+            let ahead = (self.anchor - now).as_secs_f64();
+            return self.offset - ahead * self.curve.speed_at(self.offset);
+        }
+        let elapsed = now.saturating_duration_since(self.anchor);
+        self.curve.advance(self.offset, elapsed.as_secs_f64())
+    }
+
+    /// 让歌曲时间从 position 开始计时
+    pub fn reset(&mut self, position: f64) {
+        self.offset = position;
+        self.anchor = self.source.now();
+    }
+
+    /// 让歌曲时间 position 对应时刻 anchor（可以在将来），用于无缝衔接上一首
+    pub fn reset_at(&mut self, position: f64, anchor: Instant) {
+        self.offset = position;
+        self.anchor = anchor;
+    }
+
+    /// 歌曲时间 position 对应的时刻，position 早于计时起点时按起点计算
+    pub fn instant_at(&self, position: f64) -> Instant {
+        let wall = self
+            .curve
+            .wall_seconds(self.offset, position.max(self.offset));
+        self.anchor + Duration::from_secs_f64(wall)
+    }
+
+    /// 暂停了 paused 这么久，这段时间不计入歌曲时间
+    pub fn shift(&mut self, paused: Duration) {
+        self.anchor += paused;
+    }
+
+    /// 从当前位置起改用新的速度曲线
+    pub fn set_curve(&mut self, curve: SpeedCurve) {
+        let position = self.now();
+        self.curve = curve;
+        self.reset(position);
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.curve.speed_at(self.now())
+    }
+
+    pub fn speed_at(&self, position: f64) -> f64 {
+        self.curve.speed_at(position)
+    }
+
+    /// 到达歌曲时间 target 还需要等待的实际时间
+    pub fn wall_until(&self, target: f64) -> Duration {
+        let now = self.now();
+        if target <= now {
+            return Duration::ZERO;
+        }
+        if !target.is_finite() {
+            return Duration::MAX;
+        }
+        let instant = self.source.now();
+        let wait = if instant < self.anchor {
+            self.instant_at(target) - instant
+        } else {
+            Duration::from_secs_f64(self.curve.wall_seconds(now, target))
+        };
+        // 换算为 Duration 时舍去了不足 1ns 的部分，还没到 target 时至少等 1ns，
+        // 否则虚拟时钟每次只推进 0，调度循环停在 target 之前空转
+        wait.max(Duration::from_nanos(1))
+    }
+}
@@ -0,0 +1,204 @@
+//! 导出和导入的歌曲文件：按键序列和可选的 MIDI 分析结果
+//! 导出的结构新增字段时递增 SONG_SCHEMA_VERSION，新字段必须有 serde 默认值，
+//! 并在 upgrade 中说明旧文件缺少的数据
+
+use crate::error::CommandError;
+use crate::keypress_simulator::{self, KeyEvent};
+use crate::midi_analyzer::MidiAnalysis;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+// 版本 1：KeyEvent 只有 time/key/duration，AnalysisResult 没有 bpm 和 time_signature
+// 版本 2：KeyEvent 增加 group 和 note，AnalysisResult 增加 bpm 和 time_signature，
+//         MidiAnalysis 增加 pipeline
+pub const SONG_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SongDocument {
+    pub schema_version: u32,
+    #[serde(default)]
+    pub title: Option<String>,
+    pub events: Vec<KeyEvent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub analysis: Option<MidiAnalysis>,
+}
+
+/// 导入结果；warnings 说明旧版本文件中缺少、已按默认值补上的数据
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedSong {
+    pub document: SongDocument,
+    pub warnings: Vec<String>,
+}
+
+/// 把歌曲文件写入 out，由 export_worker 负责写到目标路径
+pub fn write(
+    out: &mut dyn Write,
+    title: Option<String>,
+    events: Vec<KeyEvent>,
+    analysis: Option<MidiAnalysis>,
+) -> Result<(), CommandError> {
+    let document = SongDocument {
+        schema_version: SONG_SCHEMA_VERSION,
+        title,
+        events,
+        analysis,
+    };
+    serde_json::to_writer_pretty(out, &document)
+        .map_err(|e| CommandError::Other(format!("Failed to save song file: {}", e)))
+}
+
+pub fn load(path: &Path) -> Result<ImportedSong, CommandError> {
+    if !path.is_file() {
+        return Err(CommandError::FileNotFound(format!(
+            "File not found: {}",
+            path.display()
+        )));
+    }
+    let content = fs::read_to_string(path)
+        .map_err(|e| CommandError::Other(format!("Failed to read song file: {}", e)))?;
+    let value: Value = serde_json::from_str(&content)
+        .map_err(|e| CommandError::InvalidFile(format!("Invalid song file: {}", e)))?;
+    upgrade(value)
+}
+
+/// 把任意版本的文档转换为当前格式
+/// 没有 schema_version 的文档，以及前端缓存的按键数组，按版本 1 处理
+pub fn upgrade(value: Value) -> Result<ImportedSong, CommandError> {
+    let mut value = match value {
+        Value::Array(events) => serde_json::json!({ "schema_version": 1, "events": events }),
+        Value::Object(_) => value,
+        _ => {
+            return Err(CommandError::InvalidFile(
+                "Invalid song file: expected a JSON object".to_string(),
+            ))
+        }
+    };
+    let version = match value.get("schema_version") {
+        None => 1,
+        Some(version) => version.as_u64().filter(|&v| v >= 1).ok_or_else(|| {
+            CommandError::InvalidFile(format!("Invalid schema_version: {}", version))
+        })?,
+    };
+    if version > SONG_SCHEMA_VERSION as u64 {
+        return Err(CommandError::NewerSchema(format!(
+            "This file was saved by a newer version of the app (schema {}, supported {}). \
+             Please update the app to open it",
+            version, SONG_SCHEMA_VERSION
+        )));
+    }
+
+    let mut warnings = Vec::new();
+    if version < 2 {
+        warnings.extend(upgrade_v1(&value));
+    }
+    value["schema_version"] = Value::from(SONG_SCHEMA_VERSION);
+    let mut document: SongDocument = serde_json::from_value(value)
+        .map_err(|e| CommandError::InvalidFile(format!("Invalid song file: {}", e)))?;
+    // 与分块上传的事件一样检查，按键整理成规范写法
+    for (i, event) in document.events.iter_mut().enumerate() {
+        keypress_simulator::validate_event(event).map_err(|e| {
+            CommandError::InvalidFile(format!("Invalid song file: event {}: {}", i, e))
+        })?;
+    }
+    Ok(ImportedSong { document, warnings })
+}
+
+// 版本 1 → 2 只新增了字段，由 serde 默认值补上，这里只统计缺少的数据
+fn upgrade_v1(value: &Value) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let events = value["events"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let missing = |field: &str| events.iter().filter(|e| e.get(field).is_none()).count();
+
+    let without_group = missing("group");
+    if without_group > 0 {
+        warnings.push(format!(
+            "{} events have no track group; muting by track is unavailable for them",
+            without_group
+        ));
+    }
+    let without_note = missing("note");
+    if without_note > 0 {
+        warnings.push(format!(
+            "{} events have no MIDI note; modifier conflicts between them are resolved by key order",
+            without_note
+        ));
+    }
+    if value["analysis"].is_object() && value["analysis"]["analysis"].get("bpm").is_none() {
+        warnings.push(
+            "The MIDI analysis has no tempo or time signature; assuming 120 BPM in 4/4".to_string(),
+        );
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 每个历史版本一个文件，见 tests/fixtures/songs
+    const V1: &str = include_str!("../tests/fixtures/songs/v1.json");
+    const V1_EVENTS: &str = include_str!("../tests/fixtures/songs/v1_events.json");
+    const V2: &str = include_str!("../tests/fixtures/songs/v2.json");
+
+    fn import(content: &str) -> ImportedSong {
+        upgrade(serde_json::from_str(content).unwrap()).unwrap()
+    }
+
+    fn save(song: &ImportedSong) -> String {
+        let document = song.document.clone();
+        let mut out = Vec::new();
+        write(&mut out, document.title, document.events, document.analysis).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // 当前版本的文件原样读入、原样写出
+    #[test]
+    fn v2_resaves_byte_for_byte() {
+        let song = import(V2);
+        assert!(song.warnings.is_empty());
+        assert_eq!(song.document.events[2].chord, Some(2));
+        assert_eq!(save(&song), V2);
+    }
+
+    // 旧版本升级后补上默认值，再次保存和读入不再变化
+    #[test]
+    fn v1_upgrades_and_resaves_stably() {
+        let song = import(V1);
+        assert_eq!(song.document.schema_version, SONG_SCHEMA_VERSION);
+        assert_eq!(song.warnings.len(), 3);
+        assert_eq!(song.document.events[2].key, "shift+G");
+        let analysis = song.document.analysis.as_ref().unwrap();
+        assert_eq!(analysis.analysis.bpm, 120.0);
+        assert_eq!(analysis.analysis.time_signature, (4, 4));
+
+        let saved = save(&song);
+        let again = import(&saved);
+        assert!(again.warnings.is_empty());
+        assert_eq!(save(&again), saved);
+    }
+
+    // 前端缓存的按键数组按版本 1 处理
+    #[test]
+    fn v1_event_array_upgrades_and_resaves_stably() {
+        let song = import(V1_EVENTS);
+        assert_eq!(song.document.title, None);
+        assert_eq!(song.document.events.len(), 2);
+        assert_eq!(song.warnings.len(), 2);
+
+        let saved = save(&song);
+        assert_eq!(save(&import(&saved)), saved);
+    }
+
+    #[test]
+    fn newer_schema_is_rejected() {
+        let mut value: Value = serde_json::from_str(V2).unwrap();
+        value["schema_version"] = Value::from(SONG_SCHEMA_VERSION + 1);
+        assert!(matches!(upgrade(value), Err(CommandError::NewerSchema(_))));
+    }
+}
@@ -0,0 +1,310 @@
+//! 歌曲库：文件夹扫描得到的元数据和用户的标签，保存在应用数据目录的 JSON 文件中
+//! 记录按文件内容的哈希索引，改名或移动过的文件保留原来的标签；重新扫描时找不到的文件只标记为 missing
+//! 搜索在后端完成，前端每次只取一页结果
+
+use crate::folder_scan::{ScanResult, ScanSummary};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 每页结果数的默认值和上限
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+/// 每首歌的标签数和单个标签的长度上限
+pub const MAX_TAGS: usize = 32;
+pub const MAX_TAG_LENGTH: usize = 64;
+
+/// 文件内容的哈希（FNV-1a，16 位十六进制），与 midi_analyzer::content_hash 不同，跨启动和版本稳定
+pub fn file_hash(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    pub file_hash: String,
+    pub path: String, // 最近一次扫描到的位置
+    pub title: String,
+    pub duration: f64, // 秒
+    pub note_count: usize,
+    // 任意 1 秒内开始的音符数的最大值
+    pub difficulty: usize,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // 最近一次完整扫描所在文件夹时没有找到这个文件
+    #[serde(default)]
+    pub missing: bool,
+    pub updated_at: u64, // Unix 时间戳（秒），最近一次扫描到的时间
+}
+
+/// library_search 的过滤条件，都是可选的
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LibraryFilters {
+    pub min_duration: Option<f64>,
+    pub max_duration: Option<f64>,
+    pub min_difficulty: Option<usize>,
+    pub max_difficulty: Option<usize>,
+    // 必须带有全部这些标签（不区分大小写）
+    pub tags: Vec<String>,
+    pub include_missing: bool,
+    pub offset: usize,
+    pub limit: Option<usize>, // 默认 DEFAULT_PAGE_SIZE，最多 MAX_PAGE_SIZE
+}
+
+/// 一页搜索结果，按标题排序
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchPage {
+    pub total: usize, // 符合条件的记录总数
+    pub entries: Vec<LibraryEntry>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn file_stem(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+// 去掉首尾空白、空标签和重复的标签（不区分大小写），保留第一次出现的写法
+fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    let mut seen = HashSet::new();
+    let mut normalized = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_string();
+        if tag.is_empty() || !seen.insert(tag.to_lowercase()) {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH {
+            return Err(format!(
+                "Tag is longer than {} characters: {}",
+                MAX_TAG_LENGTH, tag
+            ));
+        }
+        normalized.push(tag);
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(format!(
+            "Too many tags: {} (at most {})",
+            normalized.len(),
+            MAX_TAGS
+        ));
+    }
+    Ok(normalized)
+}
+
+impl LibraryEntry {
+    // 查询词逐个匹配标题、路径或标签
+    fn matches(&self, words: &[String], filters: &LibraryFilters, tags: &[String]) -> bool {
+        if self.missing && !filters.include_missing {
+            return false;
+        }
+        let (duration, difficulty) = (self.duration, self.difficulty);
+        if filters.min_duration.is_some_and(|min| duration < min)
+            || filters.max_duration.is_some_and(|max| duration > max)
+            || filters.min_difficulty.is_some_and(|min| difficulty < min)
+            || filters.max_difficulty.is_some_and(|max| difficulty > max)
+        {
+            return false;
+        }
+        let own_tags: Vec<String> = self.tags.iter().map(|tag| tag.to_lowercase()).collect();
+        if !tags.iter().all(|tag| own_tags.contains(tag)) {
+            return false;
+        }
+        let title = self.title.to_lowercase();
+        let path = self.path.to_lowercase();
+        words.iter().all(|word| {
+            title.contains(word.as_str())
+                || path.contains(word.as_str())
+                || own_tags.iter().any(|tag| tag.contains(word.as_str()))
+        })
+    }
+}
+
+/// 歌曲库，通过 Tauri `.manage()` 注册
+pub struct SongLibrary {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, LibraryEntry>>,
+    // 进行中的扫描已经扫描到的内容哈希，按扫描编号
+    scans: Mutex<HashMap<u64, HashSet<String>>>,
+}
+
+impl SongLibrary {
+    /// 从 path 读取歌曲库，文件不存在或损坏时从空库开始
+    pub fn load(path: PathBuf) -> Self {
+        let entries: Vec<LibraryEntry> = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                eprintln!("Ignoring invalid song library: {}", e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        Self {
+            path,
+            entries: Mutex::new(
+                entries
+                    .into_iter()
+                    .map(|entry| (entry.file_hash.clone(), entry))
+                    .collect(),
+            ),
+            scans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn save(&self, entries: &BTreeMap<String, LibraryEntry>) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create data directory: {}", e))?;
+        }
+        let list: Vec<&LibraryEntry> = entries.values().collect();
+        let json = serde_json::to_string_pretty(&list).map_err(|e| e.to_string())?;
+        fs::write(&self.path, json).map_err(|e| format!("Failed to save song library: {}", e))
+    }
+
+    /// 记入一个文件的扫描结果；解析失败的文件不更新记录，但不算找不到
+    pub fn record_scan(&self, result: &ScanResult) {
+        let Some(file_hash) = &result.file_hash else {
+            return;
+        };
+        self.scans
+            .lock()
+            .unwrap()
+            .entry(result.scan_id)
+            .or_default()
+            .insert(file_hash.clone());
+        let Some(summary) = &result.summary else {
+            return;
+        };
+        // 同一内容的其他副本不改动记录的位置
+        if result.duplicate_of.is_some() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        // 同一位置的文件内容变了：沿用旧记录的标签，旧记录由新记录取代
+        let replaced = entries
+            .iter()
+            .find(|(hash, entry)| *hash != file_hash && entry.path == result.path)
+            .map(|(hash, _)| hash.clone());
+        let inherited = replaced
+            .and_then(|hash| entries.remove(&hash))
+            .map(|entry| entry.tags);
+        let entry = entries
+            .entry(file_hash.clone())
+            .or_insert_with(|| LibraryEntry {
+                file_hash: file_hash.clone(),
+                path: String::new(),
+                title: String::new(),
+                duration: 0.0,
+                note_count: 0,
+                difficulty: 0,
+                tags: Vec::new(),
+                missing: false,
+                updated_at: 0,
+            });
+        entry.path = result.path.clone();
+        entry.title = summary
+            .title
+            .clone()
+            .unwrap_or_else(|| file_stem(&result.path));
+        entry.duration = summary.duration;
+        entry.note_count = summary.note_count;
+        entry.difficulty = summary.peak_notes_per_second;
+        if entry.tags.is_empty() {
+            entry.tags = inherited.unwrap_or_default();
+        }
+        entry.missing = false;
+        entry.updated_at = now_secs();
+    }
+
+    /// 扫描结束时调用：dir 中这次没有找到的记录标记为 missing，然后保存
+    /// 取消的扫描只保存已扫描到的记录
+    pub fn finish_scan(&self, dir: &str, summary: &ScanSummary) {
+        let seen = self
+            .scans
+            .lock()
+            .unwrap()
+            .remove(&summary.scan_id)
+            .unwrap_or_default();
+        let mut entries = self.entries.lock().unwrap();
+        if !summary.cancelled {
+            for entry in entries.values_mut() {
+                let in_dir = Path::new(&entry.path).parent() == Some(Path::new(dir));
+                if in_dir && !seen.contains(&entry.file_hash) {
+                    entry.missing = true;
+                }
+            }
+        }
+        if let Err(e) = self.save(&entries) {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// 按查询词和过滤条件搜索，query 按空白分成多个词，全部匹配（不区分大小写）才算符合
+    pub fn search(&self, query: &str, filters: &LibraryFilters) -> SearchPage {
+        let words: Vec<String> = query
+            .split_whitespace()
+            .map(|word| word.to_lowercase())
+            .collect();
+        let tags: Vec<String> = filters
+            .tags
+            .iter()
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        let entries = self.entries.lock().unwrap();
+        let mut matched: Vec<&LibraryEntry> = entries
+            .values()
+            .filter(|entry| entry.matches(&words, filters, &tags))
+            .collect();
+        matched.sort_by(|a, b| {
+            a.title
+                .to_lowercase()
+                .cmp(&b.title.to_lowercase())
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        let limit = filters
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        SearchPage {
+            total: matched.len(),
+            entries: matched
+                .into_iter()
+                .skip(filters.offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, file_hash: &str) -> Option<LibraryEntry> {
+        self.entries.lock().unwrap().get(file_hash).cloned()
+    }
+
+    /// 替换一首歌的标签，返回更新后的记录
+    pub fn set_tags(&self, file_hash: &str, tags: Vec<String>) -> Result<LibraryEntry, String> {
+        let tags = normalize_tags(tags)?;
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get_mut(file_hash)
+            .ok_or_else(|| format!("Not in the song library: {}", file_hash))?;
+        entry.tags = tags;
+        let updated = entry.clone();
+        self.save(&entries)?;
+        Ok(updated)
+    }
+}
@@ -0,0 +1,124 @@
+//! Windows 系统计时器精度：默认约 15.6ms，sleep 和条件变量超时都按这个粒度唤醒
+//! 只在播放和录制期间提高，结束后恢复，避免一直增加耗电
+
+/// 默认请求的计时器精度（毫秒）
+pub const DEFAULT_TIMER_RESOLUTION_MS: u32 = 1;
+
+// 可以请求的最粗精度，再粗就和系统默认值差不多了
+pub const MAX_TIMER_RESOLUTION_MS: u32 = 15;
+
+/// 提高计时器精度的守卫，drop 时恢复
+/// 非 Windows 平台什么都不做
+pub struct TimerResolution {
+    // 实际生效的精度，未提高时为 None
+    period_ms: Option<u32>,
+}
+
+impl TimerResolution {
+    /// 请求 requested_ms 毫秒的精度，0 表示不修改
+    /// 低于系统支持的最小值时按最小值请求
+    #[cfg(windows)]
+    pub fn acquire(requested_ms: u32) -> Self {
+        use windows::Win32::Media::{timeBeginPeriod, timeGetDevCaps, TIMECAPS, TIMERR_NOERROR};
+
+        if requested_ms == 0 {
+            return Self { period_ms: None };
+        }
+        let mut caps = TIMECAPS::default();
+        let size = std::mem::size_of::<TIMECAPS>() as u32;
+        let period = if unsafe { timeGetDevCaps(&mut caps, size) } == TIMERR_NOERROR {
+            requested_ms.clamp(caps.wPeriodMin, caps.wPeriodMax)
+        } else {
+            requested_ms
+        };
+        if unsafe { timeBeginPeriod(period) } == TIMERR_NOERROR {
+            Self {
+                period_ms: Some(period),
+            }
+        } else {
+            eprintln!("Failed to set timer resolution to {}ms", period);
+            Self { period_ms: None }
+        }
+    }
+
+    #[cfg(not(windows))]
+    pub fn acquire(_requested_ms: u32) -> Self {
+        Self { period_ms: None }
+    }
+
+    pub fn period_ms(&self) -> Option<u32> {
+        self.period_ms
+    }
+}
+
+impl Drop for TimerResolution {
+    fn drop(&mut self) {
+        #[cfg(windows)]
+        if let Some(period) = self.period_ms {
+            unsafe {
+                windows::Win32::Media::timeEndPeriod(period);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(windows))]
+    #[test]
+    fn acquire_is_a_no_op_off_windows() {
+        assert_eq!(
+            TimerResolution::acquire(DEFAULT_TIMER_RESOLUTION_MS).period_ms(),
+            None
+        );
+    }
+
+    #[test]
+    fn zero_leaves_the_resolution_alone() {
+        assert_eq!(TimerResolution::acquire(0).period_ms(), None);
+    }
+
+    // sleep(1ms) 多睡的时间的中位数
+    #[cfg(windows)]
+    fn median_oversleep() -> std::time::Duration {
+        use std::time::{Duration, Instant};
+
+        let target = Duration::from_millis(1);
+        let mut samples: Vec<Duration> = (0..50)
+            .map(|_| {
+                let start = Instant::now();
+                std::thread::sleep(target);
+                start.elapsed().saturating_sub(target)
+            })
+            .collect();
+        samples.sort();
+        samples[samples.len() / 2]
+    }
+
+    // 回归测试：提高精度后 sleep 应在几毫秒内醒来，默认精度下通常多睡十几毫秒
+    #[cfg(windows)]
+    #[test]
+    fn guard_improves_sleep_accuracy() {
+        use std::time::Duration;
+
+        let without = median_oversleep();
+        let guard = TimerResolution::acquire(DEFAULT_TIMER_RESOLUTION_MS);
+        assert!(guard.period_ms().is_some());
+        let with = median_oversleep();
+        drop(guard);
+        eprintln!(
+            "median oversleep: {:?} without guard, {:?} with guard",
+            without, with
+        );
+        assert!(
+            with < Duration::from_millis(3),
+            "sleep(1ms) overslept by {:?} with a {}ms timer resolution",
+            with,
+            DEFAULT_TIMER_RESOLUTION_MS
+        );
+        // 其他进程也可能提高了精度，所以只要求不比没有守卫时差
+        assert!(with <= without + Duration::from_millis(1));
+    }
+}
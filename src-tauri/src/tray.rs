@@ -0,0 +1,152 @@
+//! 系统托盘：游戏窗口在前台时也能控制播放
+//! 菜单项的文字和可用状态随播放事件更新
+
+use crate::error::CommandError;
+use crate::keypress_simulator::{PlaybackController, PlaybackEvent, PlaybackStatus, QueueOptions};
+use std::thread;
+use tauri::menu::{Menu, MenuEvent, MenuItem, PredefinedMenuItem};
+use tauri::tray::{MouseButton, MouseButtonState, TrayIcon, TrayIconBuilder, TrayIconEvent};
+use tauri::{AppHandle, Emitter, Manager, Wry};
+
+const NOT_PLAYING: &str = "Not playing";
+
+/// 托盘菜单，通过 Tauri `.manage()` 注册
+pub struct Tray {
+    title: MenuItem<Wry>,
+    play_pause: MenuItem<Wry>,
+    stop: MenuItem<Wry>,
+    skip: MenuItem<Wry>,
+    _icon: TrayIcon<Wry>,
+}
+
+impl Tray {
+    pub fn create(app: &AppHandle) -> tauri::Result<Self> {
+        let title = MenuItem::with_id(app, "title", NOT_PLAYING, false, None::<&str>)?;
+        let play_pause = MenuItem::with_id(app, "play_pause", "Play", true, None::<&str>)?;
+        let stop = MenuItem::with_id(app, "stop", "Stop", false, None::<&str>)?;
+        let skip = MenuItem::with_id(app, "skip", "Skip", false, None::<&str>)?;
+        let release =
+            MenuItem::with_id(app, "release_all", "Release All Keys", true, None::<&str>)?;
+        let show = MenuItem::with_id(app, "show", "Show Window", true, None::<&str>)?;
+        let menu = Menu::with_items(
+            app,
+            &[
+                &title,
+                &PredefinedMenuItem::separator(app)?,
+                &play_pause,
+                &stop,
+                &skip,
+                &release,
+                &PredefinedMenuItem::separator(app)?,
+                &show,
+            ],
+        )?;
+
+        let mut builder = TrayIconBuilder::with_id("main")
+            .tooltip("OpenGamesAutoPlay")
+            .menu(&menu)
+            .show_menu_on_left_click(false)
+            .on_menu_event(on_menu_event)
+            .on_tray_icon_event(|tray, event| {
+                if let TrayIconEvent::Click {
+                    button: MouseButton::Left,
+                    button_state: MouseButtonState::Up,
+                    ..
+                } = event
+                {
+                    show_main_window(tray.app_handle());
+                }
+            });
+        if let Some(icon) = app.default_window_icon() {
+            builder = builder.icon(icon.clone());
+        }
+        let icon = builder.build(app)?;
+
+        Ok(Self {
+            title,
+            play_pause,
+            stop,
+            skip,
+            _icon: icon,
+        })
+    }
+
+    /// 设置当前曲目名，None 表示未播放
+    /// 单曲播放时由 start_playback 设置，队列播放时取当前条目名
+    pub fn set_title(&self, title: Option<String>) {
+        let _ = self.title.set_text(title.as_deref().unwrap_or(NOT_PLAYING));
+    }
+
+    /// 根据播放事件更新菜单，KeysActive 等高频事件直接忽略
+    pub fn update(&self, event: &PlaybackEvent) {
+        match event {
+            PlaybackEvent::StatusChanged(progress) => {
+                self.apply_status(progress.status, progress.queue_index.is_some())
+            }
+            PlaybackEvent::QueueEntryStarted { name, .. } => self.set_title(Some(name.clone())),
+            PlaybackEvent::Finished { .. } => {
+                self.apply_status(PlaybackStatus::Idle, false);
+                self.set_title(None);
+            }
+            _ => {}
+        }
+    }
+
+    fn apply_status(&self, status: PlaybackStatus, in_queue: bool) {
+        let label = match status {
+            PlaybackStatus::Idle | PlaybackStatus::Scheduled => "Play",
+            PlaybackStatus::Playing => "Pause",
+            PlaybackStatus::Paused => "Resume",
+        };
+        let _ = self.play_pause.set_text(label);
+        let _ = self
+            .play_pause
+            .set_enabled(status != PlaybackStatus::Scheduled);
+        let _ = self.stop.set_enabled(status != PlaybackStatus::Idle);
+        let _ = self
+            .skip
+            .set_enabled(status != PlaybackStatus::Idle && in_queue);
+    }
+}
+
+/// 显示并聚焦主窗口
+pub fn show_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
+
+// 菜单回调在主线程执行，而停止等操作会等待播放线程（播放线程又要更新菜单）
+// 所以放到单独的线程里执行
+fn on_menu_event(app: &AppHandle, event: MenuEvent) {
+    let app = app.clone();
+    let id = event.id().as_ref().to_string();
+    if id == "show" {
+        show_main_window(&app);
+        return;
+    }
+    thread::spawn(move || {
+        let controller = app.state::<PlaybackController>();
+        let result = match id.as_str() {
+            "play_pause" => match controller.status().status {
+                PlaybackStatus::Playing => controller.pause(),
+                PlaybackStatus::Paused => controller.resume(),
+                PlaybackStatus::Scheduled => Ok(()),
+                // 队列为空时交给前端播放当前选中的文件（需要倒计时等前端逻辑）
+                PlaybackStatus::Idle if controller.queue_entries().is_empty() => app
+                    .emit("tray://play_requested", ())
+                    .map_err(|e| CommandError::Other(e.to_string())),
+                PlaybackStatus::Idle => crate::start_queue_playback(&app, QueueOptions::default()),
+            },
+            "stop" => controller.stop(),
+            "skip" => controller.skip_to_next(),
+            "release_all" => controller.release_all_keys(Vec::new()).map(|_| ()),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Tray action failed: {}", e);
+        }
+    });
+}
@@ -0,0 +1,497 @@
+//! 解析选项的效果，用 tests/fixtures/midi 下的固定文件或生成的文件验证
+
+mod common;
+
+use common::{analyze, key_map, long_song};
+use opengamesautoplay_lib::metronome::{self, BeatGrid, CountInConfig};
+use opengamesautoplay_lib::midi_analyzer::{
+    self, AnalyzerOptions, MidiAnalysis, OverlapPreference, PolyphonyEviction, SameKeyOverlap,
+    TrackSelection, UnisonPolicy,
+};
+use std::time::{Duration, Instant};
+
+// note_on 事件的 (音轨, 音高, 起音毫秒, 止音毫秒)
+fn notes(analysis: &MidiAnalysis) -> Vec<(usize, u8, u32, u32)> {
+    let ms = |seconds: f64| (seconds * 1000.0).round() as u32;
+    analysis
+        .events
+        .iter()
+        .filter(|e| e.type_ == "note_on")
+        .map(|e| (e.track, e.note, ms(e.time), ms(e.end)))
+        .collect()
+}
+
+fn warnings(analysis: &MidiAnalysis, kind: &str) -> Vec<(f64, String)> {
+    let found = analysis.analysis.warnings.iter().filter(|w| w.kind == kind);
+    found.map(|w| (w.time, w.message.clone())).collect()
+}
+
+fn timed_parse(path: &str, preview: Option<f64>) -> (MidiAnalysis, Duration) {
+    let started = Instant::now();
+    let analysis = midi_analyzer::analyze_midi_file_with_progress(
+        path,
+        &AnalyzerOptions::default(),
+        None,
+        false,
+        preview,
+        &mut |_| true,
+    )
+    .unwrap();
+    (analysis, started.elapsed())
+}
+
+// 10 万个音的文件预览前 10 秒（release 构建）应远低于 100ms，且比完整解析快得多
+// debug 构建下绝对时间没有意义，只比较预览和完整解析
+#[test]
+fn preview_of_a_large_file_is_fast() {
+    let path = std::env::temp_dir().join(format!("preview-{}.mid", std::process::id()));
+    std::fs::write(&path, long_song(100_000)).unwrap();
+    let file = path.to_str().unwrap();
+
+    let (preview, preview_time) = timed_parse(file, Some(10.0));
+    let (full, full_time) = timed_parse(file, None);
+    std::fs::remove_file(&path).unwrap();
+
+    // 10 秒内的 80 个音
+    assert!(preview.partial);
+    assert_eq!(preview.events.len(), 160);
+    // 之前的预览不影响完整解析
+    assert!(!full.partial);
+    assert_eq!(full.events.len(), 200_000);
+
+    eprintln!("preview {:?}, full parse {:?}", preview_time, full_time);
+    assert!(
+        preview_time * 2 < full_time,
+        "preview took {:?}, full parse {:?}",
+        preview_time,
+        full_time
+    );
+    if !cfg!(debug_assertions) {
+        assert!(
+            preview_time < Duration::from_millis(100),
+            "preview took {:?}",
+            preview_time
+        );
+    }
+}
+
+// 低音音轨上移八度后两处撞到旋律：0.5 秒同时起音的 G4，1.75 秒起音时旋律的 C5 还在发声
+fn unison(policy: Option<UnisonPolicy>) -> MidiAnalysis {
+    let options = AnalyzerOptions {
+        on_unison: policy,
+        track_selection: Some(vec![
+            TrackSelection {
+                index: 0,
+                transpose: 0,
+            },
+            TrackSelection {
+                index: 1,
+                transpose: 12,
+            },
+        ]),
+        ..AnalyzerOptions::default()
+    };
+    analyze("two_track_unison.mid", &options)
+}
+
+#[test]
+fn unison_is_left_alone_without_a_policy() {
+    let analysis = unison(None);
+    assert_eq!(
+        notes(&analysis),
+        [
+            (0, 76, 0, 500),
+            (0, 67, 500, 1000),
+            (1, 67, 500, 1500),
+            (0, 72, 1500, 2000),
+            (1, 72, 1750, 2250),
+        ]
+    );
+    assert!(warnings(&analysis, "unison").is_empty());
+}
+
+#[test]
+fn unison_keep_melody_drops_the_bass() {
+    let analysis = unison(Some(UnisonPolicy::KeepMelody));
+    assert_eq!(
+        notes(&analysis),
+        [(0, 76, 0, 500), (0, 67, 500, 1000), (0, 72, 1500, 2000)]
+    );
+    assert_eq!(
+        warnings(&analysis, "unison"),
+        [
+            (
+                0.5,
+                "Tracks 0 and 1 both play 5g¹ (keep_melody): kept track 0".to_string()
+            ),
+            (
+                1.75,
+                "Tracks 0 and 1 both play 1c² (keep_melody): kept track 0".to_string()
+            ),
+        ]
+    );
+}
+
+// 时值相同时保留旋律
+#[test]
+fn unison_keep_longest_keeps_the_longer_note() {
+    let analysis = unison(Some(UnisonPolicy::KeepLongest));
+    assert_eq!(
+        notes(&analysis),
+        [(0, 76, 0, 500), (1, 67, 500, 1500), (0, 72, 1500, 2000)]
+    );
+    assert_eq!(
+        warnings(&analysis, "unison"),
+        [
+            (
+                0.5,
+                "Tracks 0 and 1 both play 5g¹ (keep_longest): kept track 1".to_string()
+            ),
+            (
+                1.75,
+                "Tracks 0 and 1 both play 1c² (keep_longest): kept track 0".to_string()
+            ),
+        ]
+    );
+}
+
+// 同时起音的低音推迟 chord_spread_ms（默认 30ms），旋律在它起音时松开；已经错开的只提前松开旋律
+#[test]
+fn unison_keep_both_staggered_offsets_the_second_note() {
+    let analysis = unison(Some(UnisonPolicy::KeepBothStaggered));
+    assert_eq!(
+        notes(&analysis),
+        [
+            (0, 76, 0, 500),
+            (0, 67, 500, 530),
+            (1, 67, 530, 1500),
+            (0, 72, 1500, 1750),
+            (1, 72, 1750, 2250),
+        ]
+    );
+    assert_eq!(
+        warnings(&analysis, "unison"),
+        [
+            (
+                0.5,
+                "Tracks 0 and 1 both play 5g¹ (keep_both_staggered): delayed track 1 by 30ms"
+                    .to_string()
+            ),
+            (
+                1.75,
+                "Tracks 0 and 1 both play 1c² (keep_both_staggered): released track 0 early"
+                    .to_string()
+            ),
+        ]
+    );
+}
+
+// 两秒的 C3 低音上，0.25 秒起 E4（力度 40）G4，0.5 秒起 F4 A4，复音数限制为 3
+fn pedal_tone(eviction: PolyphonyEviction) -> MidiAnalysis {
+    let options = AnalyzerOptions {
+        max_polyphony: Some(3),
+        eviction,
+        ..AnalyzerOptions::default()
+    };
+    analyze("pedal_tone.mid", &options)
+}
+
+// 最早按下的低音先被松开，留下空洞
+#[test]
+fn cut_oldest_releases_the_pedal_tone() {
+    let analysis = pedal_tone(PolyphonyEviction::CutOldest);
+    assert_eq!(
+        notes(&analysis),
+        [
+            (0, 48, 0, 500),
+            (0, 64, 250, 500),
+            (0, 67, 250, 750),
+            (0, 65, 500, 1000),
+            (0, 69, 500, 1000),
+        ]
+    );
+    assert_eq!(
+        warnings(&analysis, "polyphony"),
+        [
+            (
+                0.5,
+                "Polyphony limit 3 reached (cut_oldest): cut 1c (track 0, 0.000s) for 6a¹ (track 0, 0.500s)"
+                    .to_string()
+            ),
+            (
+                0.5,
+                "Polyphony limit 3 reached (cut_oldest): cut 3e¹ (track 0, 0.250s) for 4f¹ (track 0, 0.500s)"
+                    .to_string()
+            ),
+        ]
+    );
+}
+
+// 低音力度最大，先松开很轻的 E4，再松开 G4
+#[test]
+fn cut_quietest_keeps_the_loud_pedal_tone() {
+    let analysis = pedal_tone(PolyphonyEviction::CutQuietest);
+    assert_eq!(
+        notes(&analysis),
+        [
+            (0, 48, 0, 2000),
+            (0, 64, 250, 500),
+            (0, 67, 250, 500),
+            (0, 65, 500, 1000),
+            (0, 69, 500, 1000),
+        ]
+    );
+    assert_eq!(
+        warnings(&analysis, "polyphony"),
+        [
+            (
+                0.5,
+                "Polyphony limit 3 reached (cut_quietest): cut 3e¹ (track 0, 0.250s) for 6a¹ (track 0, 0.500s)"
+                    .to_string()
+            ),
+            (
+                0.5,
+                "Polyphony limit 3 reached (cut_quietest): cut 5g¹ (track 0, 0.250s) for 4f¹ (track 0, 0.500s)"
+                    .to_string()
+            ),
+        ]
+    );
+}
+
+// 正在发声的音都不动，新和弦推迟到前一个和弦松开
+#[test]
+fn shorten_incoming_delays_the_new_chord() {
+    let analysis = pedal_tone(PolyphonyEviction::ShortenIncoming);
+    assert_eq!(
+        notes(&analysis),
+        [
+            (0, 48, 0, 2000),
+            (0, 64, 250, 750),
+            (0, 67, 250, 750),
+            (0, 65, 750, 1000),
+            (0, 69, 750, 1000),
+        ]
+    );
+    assert_eq!(
+        warnings(&analysis, "polyphony"),
+        [
+            (
+                0.5,
+                "Polyphony limit 3 reached (shorten_incoming): delayed 6a¹ (track 0, 0.500s) by 250ms"
+                    .to_string()
+            ),
+            (
+                0.5,
+                "Polyphony limit 3 reached (shorten_incoming): delayed 4f¹ (track 0, 0.500s) by 250ms"
+                    .to_string()
+            ),
+        ]
+    );
+}
+
+// 两秒长的 C4 下 1.25 秒起音的 E4，复音数限制为 1，新音只能推迟或丢弃
+fn long_note(pass_order: Option<&[&str]>) -> MidiAnalysis {
+    let options = AnalyzerOptions {
+        trim_long_notes: true,
+        max_polyphony: Some(1),
+        eviction: PolyphonyEviction::ShortenIncoming,
+        pass_order: pass_order.map(|order| order.iter().map(|name| name.to_string()).collect()),
+        ..AnalyzerOptions::default()
+    };
+    analyze("long_note.mid", &options)
+}
+
+// 默认先截短长音：C4 在 0.99 秒松开，E4 起音时已经有空位
+#[test]
+fn trimming_before_the_polyphony_limit_keeps_both_notes() {
+    let analysis = long_note(None);
+    assert_eq!(analysis.pass_order, ["trim_long_notes", "limit_polyphony"]);
+    assert_eq!(notes(&analysis), [(0, 60, 0, 990), (0, 64, 1250, 1750)]);
+    assert!(warnings(&analysis, "polyphony").is_empty());
+}
+
+// 先限制复音数时按截短之前的时值计算，长音挤掉了 E4
+#[test]
+fn trimming_after_the_polyphony_limit_drops_the_inner_note() {
+    let analysis = long_note(Some(&["limit_polyphony", "trim_long_notes"]));
+    assert_eq!(analysis.pass_order, ["limit_polyphony", "trim_long_notes"]);
+    assert_eq!(notes(&analysis), [(0, 60, 0, 990)]);
+    assert_eq!(
+        warnings(&analysis, "polyphony"),
+        [(
+            1.25,
+            "Polyphony limit 1 reached (shorten_incoming): dropped 3e¹ (track 0, 1.250s)"
+                .to_string()
+        )]
+    );
+}
+
+// 按最终映射到的键统计；次数相同时按总按住时长排序，top_keys 只取前 TOP_KEYS 个
+#[test]
+fn key_usage_counts_presses_per_key() {
+    let analysis = midi_analyzer::analyze_midi_file_with_progress(
+        &common::fixture("key_usage.mid"),
+        &AnalyzerOptions::default(),
+        Some(&key_map()),
+        false,
+        None,
+        &mut |_| true,
+    )
+    .unwrap();
+    let usage = analysis.key_usage.unwrap();
+    let summary: Vec<(&str, usize, f64, usize)> = usage
+        .iter()
+        .map(|(key, usage)| {
+            (
+                key.as_str(),
+                usage.count,
+                usage.held_seconds,
+                usage.max_repeats,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("a", 3, 1.25, 2),
+            ("d", 1, 0.5, 1),
+            ("f", 1, 1.0, 1),
+            ("g", 1, 0.5, 1),
+            ("h", 1, 0.25, 1),
+            ("s", 2, 1.25, 1),
+        ]
+    );
+    assert_eq!(analysis.top_keys, ["a", "s", "f", "d", "g"]);
+}
+
+fn measures(name: &str, align_to_downbeat: bool) -> MidiAnalysis {
+    let options = AnalyzerOptions {
+        include_measures: true,
+        align_to_downbeat,
+        ..AnalyzerOptions::default()
+    };
+    analyze(name, &options)
+}
+
+// 一拍的弱起：拍号表里的短小节和前面有休止的整小节都从第一个完整小节开始编号
+#[test]
+fn pickup_measures_start_at_the_first_full_bar() {
+    for (name, boundaries, pickup) in [
+        ("pickup_short_bar.mid", [0.0, 0.5, 2.5, 4.5], (0.0, 0.5)),
+        ("pickup_rest.mid", [0.0, 2.0, 4.0, 6.0], (1.5, 2.0)),
+    ] {
+        let plain = measures(name, false);
+        assert_eq!(plain.measures.as_deref(), Some(&boundaries[..]), "{}", name);
+        assert!(plain.pickup.is_none());
+
+        let aligned = measures(name, true);
+        assert_eq!(
+            aligned.measures.as_deref(),
+            Some(&boundaries[1..]),
+            "{}",
+            name
+        );
+        let found = aligned.pickup.unwrap();
+        assert_eq!((found.start, found.downbeat), pickup, "{}", name);
+        // 音符的时间不变
+        assert_eq!(notes(&aligned), notes(&plain), "{}", name);
+        let first_bar = metronome::measure_span(&aligned.measures.unwrap(), 1, 1).unwrap();
+        assert_eq!(first_bar, (boundaries[1], boundaries[2]), "{}", name);
+    }
+}
+
+// 一小节 4/4 的预备拍从歌曲开头起播时，弱起占去最后一拍
+#[test]
+fn pickup_takes_the_last_count_in_beat() {
+    let pickup = measures("pickup_short_bar.mid", true).pickup.unwrap();
+    let config = CountInConfig {
+        downbeat: Some(pickup.downbeat),
+        ..CountInConfig::default()
+    };
+    let origin = config.grid_origin(0.0);
+    assert_eq!(origin, 0.5);
+    let mut grid = BeatGrid::new(&config, origin);
+    grid.reset(0.0);
+    let beats: Vec<(i64, u32, bool)> = (0..3)
+        .map(|_| grid.advance())
+        .map(|beat| (beat.measure, beat.beat, beat.count_in))
+        .collect();
+    assert_eq!(beats, [(0, 4, true), (1, 1, false), (1, 2, false)]);
+}
+
+#[test]
+fn song_without_pickup_keeps_its_measures() {
+    let plain = measures("tempo_change.mid", false);
+    let aligned = measures("tempo_change.mid", true);
+    assert!(aligned.pickup.is_none());
+    assert_eq!(aligned.measures, plain.measures);
+}
+
+fn overlap(name: &str, prefer: Option<OverlapPreference>) -> MidiAnalysis {
+    let options = AnalyzerOptions {
+        same_key_overlap: prefer.map(|prefer| SameKeyOverlap {
+            prefer,
+            ..SameKeyOverlap::default()
+        }),
+        ..AnalyzerOptions::default()
+    };
+    analyze(name, &options)
+}
+
+#[test]
+fn same_key_overlap_is_left_alone_without_the_option() {
+    let analysis = overlap("grace_into_sustain.mid", None);
+    assert_eq!(notes(&analysis), [(0, 60, 0, 250), (1, 60, 125, 2125)]);
+    assert!(warnings(&analysis, "same_key_overlap").is_empty());
+}
+
+// 倚音接长音：earlier 把长音推迟到倚音结束，longer 和 louder 都让倚音在长音起音时松开
+#[test]
+fn grace_note_into_sustain() {
+    let delayed = overlap("grace_into_sustain.mid", Some(OverlapPreference::Earlier));
+    assert_eq!(notes(&delayed), [(0, 60, 0, 250), (1, 60, 250, 2125)]);
+    assert_eq!(
+        warnings(&delayed, "same_key_overlap"),
+        [(
+            0.125,
+            "1c¹ at 0.000s (0.250s) overlaps 0.125s (2.000s) (prefer earlier): \
+             delayed the later note to 0.250s"
+                .to_string()
+        )]
+    );
+    for prefer in [OverlapPreference::Longer, OverlapPreference::Louder] {
+        let cut = overlap("grace_into_sustain.mid", Some(prefer));
+        assert_eq!(
+            notes(&cut),
+            [(0, 60, 0, 125), (1, 60, 125, 2125)],
+            "{:?}",
+            prefer
+        );
+        let found = warnings(&cut, "same_key_overlap");
+        assert_eq!(found.len(), 1);
+        assert!(found[0].1.ends_with("released the earlier note at 0.125s"));
+    }
+}
+
+// 长音里的重复音：earlier 和 longer 丢弃重复音（推迟到长音结束后就没有剩余），louder 让长音在重复音处松开
+#[test]
+fn sustain_into_repeat() {
+    for prefer in [OverlapPreference::Earlier, OverlapPreference::Longer] {
+        let dropped = overlap("sustain_into_repeat.mid", Some(prefer));
+        assert_eq!(notes(&dropped), [(0, 60, 0, 2000)], "{:?}", prefer);
+        let found = warnings(&dropped, "same_key_overlap");
+        assert_eq!(found.len(), 1);
+        assert!(found[0].1.ends_with("dropped the later note"));
+    }
+    let louder = overlap("sustain_into_repeat.mid", Some(OverlapPreference::Louder));
+    assert_eq!(notes(&louder), [(0, 60, 0, 1000), (1, 60, 1000, 1250)]);
+    assert_eq!(
+        warnings(&louder, "same_key_overlap"),
+        [(
+            1.0,
+            "1c¹ at 0.000s (2.000s) overlaps 1.000s (0.250s) (prefer louder): \
+             released the earlier note at 1.000s"
+                .to_string()
+        )]
+    );
+}
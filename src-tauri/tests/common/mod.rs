@@ -0,0 +1,78 @@
+//! 集成测试共用的工具：固定的 MIDI 文件、按键映射，以及在虚拟时钟上播放并记录按键
+#![allow(dead_code)]
+
+use opengamesautoplay_lib::keypress_simulator::{
+    self, KeyEvent, KeyEventOptions, PlaybackController, PlaybackOptions, RecordingSender,
+};
+use opengamesautoplay_lib::midi_analyzer::{self, AnalyzerOptions, KeyMap, MidiAnalysis};
+use opengamesautoplay_lib::song_clock::VirtualClock;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// tests/fixtures/midi 下的文件，由 tests/fixtures/make_midi.py 生成
+pub fn fixture(name: &str) -> String {
+    format!(
+        "{}/tests/fixtures/midi/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    )
+}
+
+/// C4 起的白键依次映射到 a s d f g h j k，其余不映射
+pub fn key_map() -> KeyMap {
+    [60, 62, 64, 65, 67, 69, 71, 72]
+        .into_iter()
+        .zip(["a", "s", "d", "f", "g", "h", "j", "k"])
+        .map(|(note, key)| (note, key.to_string()))
+        .collect()
+}
+
+pub fn analyze(name: &str, options: &AnalyzerOptions) -> MidiAnalysis {
+    midi_analyzer::analyze_midi_file(&fixture(name), options)
+        .unwrap_or_else(|e| panic!("failed to analyze {}: {}", name, e))
+}
+
+/// 按默认选项解析并映射为按键事件，与前端播放前的处理相同
+pub fn key_events(name: &str) -> Vec<KeyEvent> {
+    let analysis = analyze(name, &AnalyzerOptions::default());
+    keypress_simulator::key_events(&analysis.events, &key_map(), &KeyEventOptions::default())
+}
+
+/// 在虚拟时钟上播放到结束，返回记录到的按键（"0.500 +a"）
+pub fn play(events: Vec<KeyEvent>, options: PlaybackOptions) -> Vec<String> {
+    let clock = Arc::new(VirtualClock::default());
+    let sender = RecordingSender::new(clock.clone());
+    let controller = PlaybackController::new(sender.factory()).with_clock(clock);
+    controller.start(events, options).unwrap();
+    // 虚拟时钟上的播放按实际时间只需几毫秒
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while controller.is_active() {
+        assert!(Instant::now() < deadline, "playback did not finish");
+        thread::sleep(Duration::from_millis(1));
+    }
+    sender.lines()
+}
+
+/// 单音轨的 SMF：每 1/4 拍（120 BPM 下 0.125 秒）一个音，在 C4 之上的两个八度里循环
+/// 用来测量大文件的解析时间，不必提交上百 KB 的固定文件
+pub fn long_song(notes: usize) -> Vec<u8> {
+    const TICKS_PER_BEAT: u16 = 480;
+    const STEP: u8 = 120; // 1/4 拍，小于 128，写成一个字节的变长时间
+
+    let mut track = Vec::new();
+    for i in 0..notes {
+        let pitch = 60 + (i % 24) as u8;
+        track.extend_from_slice(&[0, 0x90, pitch, 100, STEP, 0x80, pitch, 0]);
+    }
+    track.extend_from_slice(&[0, 0xFF, 0x2F, 0]);
+
+    let mut smf = b"MThd".to_vec();
+    smf.extend_from_slice(&6u32.to_be_bytes());
+    smf.extend_from_slice(&[0, 0, 0, 1]);
+    smf.extend_from_slice(&TICKS_PER_BEAT.to_be_bytes());
+    smf.extend_from_slice(b"MTrk");
+    smf.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    smf.extend_from_slice(&track);
+    smf
+}
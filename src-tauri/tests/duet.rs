@@ -0,0 +1,306 @@
+//! 两人合奏的流程：两边从同一份歌曲文件划分声部，跟奏方按领奏方公开的开始时间预约
+//! 划分和时间换算直接验证；轮询用本机上假的领奏方，不需要第二个实例
+
+use opengamesautoplay_lib::duet::{
+    arm_follower, clock_offset_ms, filter_events, fingerprint, local_start_ms, Duet,
+    DuetAnnouncement, DuetRole, FollowOutcome,
+};
+use opengamesautoplay_lib::keypress_simulator::KeyEvent;
+use opengamesautoplay_lib::playback_stats;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+fn event(time: f64, key: &str, note: Option<u8>, group: Option<&str>) -> KeyEvent {
+    KeyEvent {
+        time,
+        key: key.to_string(),
+        duration: 0.2,
+        group: group.map(str::to_string),
+        note,
+        chord: None,
+    }
+}
+
+fn keys(events: &[KeyEvent]) -> Vec<&str> {
+    events.iter().map(|e| e.key.as_str()).collect()
+}
+
+// 同一份文件在两个实例中解析出的事件
+fn song() -> Vec<KeyEvent> {
+    vec![
+        event(0.0, "q", Some(72), None),
+        event(0.0, "z", Some(48), None),
+        event(0.5, "w", Some(60), None),
+        event(0.5, "x", Some(59), None),
+        event(1.0, "space", None, None),
+    ]
+}
+
+#[test]
+fn parts_split_by_pitch_without_tags() {
+    let upper = filter_events(song(), DuetRole::Upper).unwrap();
+    let lower = filter_events(song(), DuetRole::Lower).unwrap();
+    // 中央 C 及以上、没有音高的事件归高声部
+    assert_eq!(keys(&upper), ["q", "w", "space"]);
+    assert_eq!(keys(&lower), ["z", "x"]);
+    assert_eq!(upper.len() + lower.len(), song().len());
+    assert_eq!(filter_events(song(), DuetRole::Full).unwrap().len(), 5);
+}
+
+#[test]
+fn group_tags_take_precedence_over_pitch() {
+    let events = vec![
+        event(0.0, "z", Some(48), Some("upper")),
+        event(0.0, "q", Some(72), Some("lower")),
+        // 有标签时未标注的事件两边都不演奏
+        event(0.5, "w", Some(60), Some("track1")),
+    ];
+    assert_eq!(
+        keys(&filter_events(events.clone(), DuetRole::Upper).unwrap()),
+        ["z"]
+    );
+    assert_eq!(
+        keys(&filter_events(events.clone(), DuetRole::Lower).unwrap()),
+        ["q"]
+    );
+    assert_eq!(filter_events(events, DuetRole::Full).unwrap().len(), 3);
+}
+
+#[test]
+fn empty_part_is_an_error() {
+    let events = vec![event(0.0, "q", Some(72), None)];
+    assert!(filter_events(events, DuetRole::Lower).is_err());
+}
+
+#[test]
+fn fingerprint_matches_for_the_same_song_only() {
+    assert_eq!(fingerprint(&song()), fingerprint(&song()));
+    let mut edited = song();
+    edited[0].time = 0.01;
+    assert_ne!(fingerprint(&song()), fingerprint(&edited));
+}
+
+#[test]
+fn clock_offset_from_round_trip() {
+    // 请求在本机 10_000 发出、10_040 收到，领奏方在 10_170 回复：领奏方快 150ms
+    assert_eq!(clock_offset_ms(10_170, 10_000, 10_040), 150);
+    assert_eq!(clock_offset_ms(9_900, 10_000, 10_040), -120);
+    assert_eq!(local_start_ms(20_150, 150), 20_000);
+    assert_eq!(local_start_ms(20_000, -120), 20_120);
+}
+
+#[test]
+fn follower_arms_at_the_leaders_start_in_local_time() {
+    let leader = Duet::default();
+    leader.set_leader(Some(DuetRole::Upper), fingerprint(&song()));
+    let mut announcement = leader.announcement(Some(20_150));
+    announcement.server_unix_ms = 10_170;
+
+    let song_id = fingerprint(&song());
+    let start = arm_follower(&announcement, DuetRole::Lower, &song_id, 10_000, 10_040);
+    assert_eq!(start, Ok(Some(20_000)));
+
+    // 领奏方还没有预约时继续轮询
+    let waiting = DuetAnnouncement {
+        start_at_unix_ms: None,
+        ..announcement.clone()
+    };
+    assert_eq!(
+        arm_follower(&waiting, DuetRole::Lower, &song_id, 10_000, 10_040),
+        Ok(None)
+    );
+}
+
+#[test]
+fn follower_rejects_mismatched_sessions() {
+    let song_id = fingerprint(&song());
+    let leader = Duet::default();
+    let arm =
+        |leader: &Duet, role| arm_follower(&leader.announcement(Some(1)), role, &song_id, 0, 0);
+
+    leader.set_leader(Some(DuetRole::Upper), song_id.clone());
+    assert!(arm(&leader, DuetRole::Upper).is_err());
+    leader.set_leader(Some(DuetRole::Full), song_id.clone());
+    assert!(arm(&leader, DuetRole::Lower).is_err());
+    leader.set_leader(Some(DuetRole::Upper), "another song".to_string());
+    assert!(arm(&leader, DuetRole::Lower).is_err());
+    // 预约的不是合奏
+    leader.set_leader(None, song_id.clone());
+    assert!(arm(&leader, DuetRole::Lower).is_err());
+}
+
+// 本机上假的领奏方：依次用 replies 中的 (状态行, 内容) 回答每个请求，把请求头发到返回的通道
+// 内容里的 {now} 替换为回复时领奏方的时间，领奏方的时钟比本机快 LEADER_AHEAD_MS
+const LEADER_AHEAD_MS: u64 = 1_000;
+
+fn fake_leader(replies: Vec<(&'static str, String)>) -> (String, mpsc::Receiver<String>) {
+    serve_leader(replies, None)
+}
+
+// 每次回复前等 release 放行的领奏方，用于在请求途中做别的事
+fn held_leader(
+    replies: Vec<(&'static str, String)>,
+) -> (String, mpsc::Receiver<String>, mpsc::Sender<()>) {
+    let (release, hold) = mpsc::channel();
+    let (address, requests) = serve_leader(replies, Some(hold));
+    (address, requests, release)
+}
+
+fn serve_leader(
+    replies: Vec<(&'static str, String)>,
+    hold: Option<mpsc::Receiver<()>>,
+) -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (requests, received) = mpsc::channel();
+    thread::spawn(move || {
+        for (status, body) in replies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                head += &line;
+            }
+            let _ = requests.send(head);
+            if let Some(hold) = &hold {
+                let _ = hold.recv();
+            }
+            let now = playback_stats::unix_ms() + LEADER_AHEAD_MS;
+            let body = body.replace("{now}", &now.to_string());
+            write!(stream, "HTTP/1.0 {}\r\n\r\n{}", status, body).unwrap();
+        }
+    });
+    (address, received)
+}
+
+fn announcement(start_at_unix_ms: Option<u64>) -> String {
+    let start = start_at_unix_ms.map_or("null".to_string(), |start| start.to_string());
+    format!(
+        r#"{{"start_at_unix_ms":{},"server_unix_ms":{{now}},"role":"upper","fingerprint":"{}"}}"#,
+        start,
+        fingerprint(&song())
+    )
+}
+
+// 跟奏方的轮询：(arm 收到的本机开始时间, 结束时的结果)
+type Follow = (mpsc::Receiver<u64>, mpsc::Receiver<FollowOutcome>);
+
+fn follow(duet: &Duet, address: &str, role: DuetRole) -> Result<Follow, String> {
+    let (armed, arm_rx) = mpsc::channel();
+    let (finished, done_rx) = mpsc::channel();
+    duet.follow(
+        address,
+        "secret".to_string(),
+        role,
+        fingerprint(&song()),
+        Box::new(move |start| {
+            armed.send(start).unwrap();
+            Ok(())
+        }),
+        Box::new(move |outcome| finished.send(outcome).unwrap()),
+    )?;
+    Ok((arm_rx, done_rx))
+}
+
+const WAIT: Duration = Duration::from_secs(5);
+
+// 领奏方第一次回复时还没有预约，跟奏方继续轮询，拿到开始时间后换算为本机时间预约
+#[test]
+fn follower_polls_the_leader_and_arms_in_local_time() {
+    let leader_start = playback_stats::unix_ms() + LEADER_AHEAD_MS + 60_000;
+    let (address, requests) = fake_leader(vec![
+        ("200 OK", announcement(None)),
+        ("200 OK", announcement(Some(leader_start))),
+    ]);
+    let (armed, done) = follow(&Duet::default(), &address, DuetRole::Lower).unwrap();
+
+    let start = armed.recv_timeout(WAIT).unwrap();
+    let expected = leader_start - LEADER_AHEAD_MS;
+    // 本机回环的往返只有几毫秒，远小于可以接受的偏差
+    assert!(start.abs_diff(expected) < 200, "{} vs {}", start, expected);
+    let outcome = done.recv_timeout(WAIT).unwrap();
+    assert_eq!(outcome.start_at_unix_ms, Some(start));
+    assert!(outcome.error.is_none());
+
+    let head = requests.recv_timeout(WAIT).unwrap();
+    assert!(head.starts_with("GET /api/duet HTTP/1.0\r\n"), "{}", head);
+    assert!(
+        head.contains("Authorization: Bearer secret\r\n"),
+        "{}",
+        head
+    );
+    assert_eq!(requests.recv_timeout(WAIT).unwrap(), head);
+}
+
+#[test]
+fn follower_stops_when_the_leader_rejects_the_token() {
+    let (address, _requests) = fake_leader(vec![("401 Unauthorized", String::new())]);
+    let (armed, done) = follow(&Duet::default(), &address, DuetRole::Lower).unwrap();
+    let outcome = done.recv_timeout(WAIT).unwrap();
+    assert_eq!(
+        outcome.error.as_deref(),
+        Some("The leader rejected the remote control token")
+    );
+    assert!(armed.try_recv().is_err());
+}
+
+// 跟奏方与领奏方选了同一个声部时不预约
+#[test]
+fn follower_stops_on_a_role_clash() {
+    let start = playback_stats::unix_ms() + 60_000;
+    let (address, _requests) = fake_leader(vec![("200 OK", announcement(Some(start)))]);
+    let (armed, done) = follow(&Duet::default(), &address, DuetRole::Upper).unwrap();
+    let outcome = done.recv_timeout(WAIT).unwrap();
+    assert_eq!(
+        outcome.error.as_deref(),
+        Some("The leader also plays the upper part")
+    );
+    assert!(armed.try_recv().is_err());
+}
+
+// 领奏方还没开启遥控服务时一直重试，直到取消；取消没有错误也没有开始时间
+#[test]
+fn follower_keeps_retrying_an_unreachable_leader_until_cancelled() {
+    let address = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let duet = Duet::default();
+    let (armed, done) = follow(&duet, &address, DuetRole::Lower).unwrap();
+    assert!(done.recv_timeout(Duration::from_millis(700)).is_err());
+    duet.cancel_follow();
+    let outcome = done.recv_timeout(WAIT).unwrap();
+    assert_eq!(outcome.start_at_unix_ms, None);
+    assert_eq!(outcome.error, None);
+    assert!(armed.try_recv().is_err());
+}
+
+// 领奏方回复之前取消：回复里已有开始时间也不再预约
+#[test]
+fn follower_cancelled_during_a_request_does_not_arm() {
+    let leader_start = playback_stats::unix_ms() + LEADER_AHEAD_MS + 60_000;
+    let (address, requests, release) =
+        held_leader(vec![("200 OK", announcement(Some(leader_start)))]);
+    let duet = Duet::default();
+    let (armed, done) = follow(&duet, &address, DuetRole::Lower).unwrap();
+    requests.recv_timeout(WAIT).unwrap();
+    duet.cancel_follow();
+    release.send(()).unwrap();
+    let outcome = done.recv_timeout(WAIT).unwrap();
+    assert_eq!(outcome.start_at_unix_ms, None);
+    assert_eq!(outcome.error, None);
+    assert!(armed.try_recv().is_err());
+}
+
+#[test]
+fn full_role_cannot_follow() {
+    let error = follow(&Duet::default(), "127.0.0.1:1", DuetRole::Full).unwrap_err();
+    assert!(error.contains("upper or lower"), "{}", error);
+}
@@ -0,0 +1,159 @@
+#!/usr/bin/env python3
+"""生成 midi/ 下的测试文件：python3 make_midi.py
+
+每个文件用拍（四分音符）写出，480 tick 每拍，没有速度事件时为 120 BPM（每拍 0.5 秒）。
+改动后重新运行并提交生成的 .mid 文件。
+"""
+
+import os
+import struct
+
+TPQ = 480
+
+
+def var_len(value):
+    out = [value & 0x7F]
+    value >>= 7
+    while value:
+        out.insert(0, (value & 0x7F) | 0x80)
+        value >>= 7
+    return bytes(out)
+
+
+def tempo(beat, bpm):
+    return (beat, bytes([0xFF, 0x51, 0x03]) + (60_000_000 // bpm).to_bytes(3, "big"))
+
+
+def time_signature(beat, numerator, denominator):
+    power = denominator.bit_length() - 1
+    return (beat, bytes([0xFF, 0x58, 0x04, numerator, power, 24, 8]))
+
+
+def pedal(beat, down, channel=0):
+    return (beat, bytes([0xB0 | channel, 64, 127 if down else 0]))
+
+
+def note(beat, length, pitch, velocity=100, channel=0):
+    """返回起音和止音两个事件"""
+    return [
+        (beat, bytes([0x90 | channel, pitch, velocity])),
+        (beat + length, bytes([0x80 | channel, pitch, 0])),
+    ]
+
+
+def track(*items, name=None):
+    events = []
+    if name is not None:
+        events.append((0, bytes([0xFF, 0x03]) + var_len(len(name)) + name.encode()))
+    for item in items:
+        events.extend(item if isinstance(item, list) else [item])
+    # 同一时刻先止音再起音，与常见音序器的输出一致
+    events.sort(key=lambda e: (e[0], e[1][0] & 0xF0 != 0x80))
+    data = b""
+    now = 0
+    for beat, message in events:
+        tick = round(beat * TPQ)
+        data += var_len(tick - now) + message
+        now = tick
+    data += var_len(0) + bytes([0xFF, 0x2F, 0x00])
+    return b"MTrk" + struct.pack(">I", len(data)) + data
+
+
+def smf(*tracks):
+    header = struct.pack(">HHH", 1 if len(tracks) > 1 else 0, len(tracks), TPQ)
+    return b"MThd" + struct.pack(">I", 6) + header + b"".join(tracks)
+
+
+def chord(beat, length, *pitches):
+    return [event for pitch in pitches for event in note(beat, length, pitch)]
+
+
+FIXTURES = {
+    # C4 一拍
+    "single_note.mid": smf(track(*note(0, 1, 60))),
+    # C 大三和弦一拍，然后 D4 一拍
+    "chord.mid": smf(track(chord(0, 1, 60, 64, 67), note(1, 1, 62))),
+    # 第 2 拍起从 120 BPM 变为 60 BPM
+    "tempo_change.mid": smf(
+        track(tempo(0, 120), tempo(1, 60), note(0, 1, 60), note(1, 1, 62), note(2, 1, 64))
+    ),
+    # 踩住踏板弹两个短音，第 3 拍才松开踏板
+    "sustain_pedal.mid": smf(
+        track(pedal(0, True), note(0, 0.5, 60), note(1, 0.5, 62), pedal(2, False))
+    ),
+    # C4 连按两拍，然后 D4 一拍
+    "repeated_note.mid": smf(track(note(0, 1, 60), note(1, 1, 60), note(2, 1, 62))),
+    # 音轨 1 的低音上移八度（track_selection transpose 12）后与音轨 0 的旋律撞键：
+    # 第 2 拍两个 G4 同时起音，第 4 拍半低音的 C5 在旋律的 C5 还没结束时起音
+    "two_track_unison.mid": smf(
+        track(note(0, 1, 76), note(1, 1, 67), note(3, 1, 72), name="Melody"),
+        track(note(1, 2, 55), note(3.5, 1, 60), name="Bass"),
+    ),
+    # 持续四拍、力度最大的 C3 低音上两个和弦：半拍处 E4（很轻）G4，第 2 拍 F4 A4，和弦之间有重叠
+    # max_polyphony 3 时第 2 拍的和弦必须腾出位置
+    "pedal_tone.mid": smf(
+        track(
+            note(0, 4, 48, velocity=110),
+            note(0.5, 1, 64, velocity=40),
+            note(0.5, 1, 67),
+            note(1, 1, 65),
+            note(1, 1, 69),
+        )
+    ),
+    # 两秒长的 C4，1.25 秒时 E4 在它下面起音半秒（trim_long_notes 会把 C4 截短到 0.99 秒）
+    "long_note.mid": smf(track(note(0, 4, 60), note(2.5, 1, 64))),
+    # 按 tests/common 的映射：a 三次（前两次连按）、s 两次、f d g h 各一次（按住时长递减），
+    # 最后的 C#4 没有映射
+    "key_usage.mid": smf(
+        track(
+            note(0, 1, 60),
+            note(1, 1, 60),
+            note(2, 0.5, 62),
+            note(3, 0.5, 60),
+            note(4, 2, 62),
+            note(6, 2, 65),
+            note(8, 1, 64),
+            note(9, 1, 67),
+            note(10, 0.5, 69),
+            note(11, 1, 61),
+        )
+    ),
+    # 一拍的弱起 G4：1/4 拍的短小节，第 2 拍起 4/4 两小节
+    "pickup_short_bar.mid": smf(
+        track(
+            time_signature(0, 1, 4),
+            time_signature(1, 4, 4),
+            note(0, 1, 67),
+            *[note(beat, 1, 60) for beat in range(1, 9)],
+        )
+    ),
+    # 同样的一拍弱起写成 4/4 整小节，前三拍是休止
+    "pickup_rest.mid": smf(
+        track(
+            time_signature(0, 4, 4),
+            note(3, 1, 67),
+            *[note(beat, 1, 60) for beat in range(4, 12)],
+        )
+    ),
+    # 同一个键上的重叠放在两个音轨里（同一音轨里同音高的第二个起音会覆盖第一个）
+    # 较轻的 C4 倚音半拍，四分之一拍处较响的 C4 长音持续四拍
+    "grace_into_sustain.mid": smf(
+        track(note(0, 0.5, 60, velocity=70), name="Grace"),
+        track(note(0.25, 4, 60), name="Sustain"),
+    ),
+    # 较轻的 C4 长音四拍，第 2 拍很响的 C4 重复半拍，完全落在长音里
+    "sustain_into_repeat.mid": smf(
+        track(note(0, 4, 60, velocity=60), name="Sustain"),
+        track(note(2, 0.5, 60, velocity=110), name="Repeat"),
+    ),
+    # C4 在默认音域（48-83）内，E7 和 F#1 超出
+    "out_of_range.mid": smf(track(note(0, 1, 60), note(1, 1, 100), note(2, 1, 30))),
+}
+
+
+if __name__ == "__main__":
+    directory = os.path.join(os.path.dirname(os.path.abspath(__file__)), "midi")
+    os.makedirs(directory, exist_ok=True)
+    for name, data in FIXTURES.items():
+        with open(os.path.join(directory, name), "wb") as f:
+            f.write(data)
@@ -0,0 +1,102 @@
+//! 从 MIDI 文件到发出的按键：解析、调度，在虚拟时钟上记录每次按下和松开的时间
+//! 固定文件见 tests/fixtures/make_midi.py，默认 120 BPM，每拍 0.5 秒
+
+mod common;
+
+use common::{analyze, key_events, play};
+use opengamesautoplay_lib::keypress_simulator::PlaybackOptions;
+use opengamesautoplay_lib::midi_analyzer::AnalyzerOptions;
+
+fn play_fixture(name: &str) -> Vec<String> {
+    play(key_events(name), PlaybackOptions::default())
+}
+
+#[test]
+fn single_note() {
+    assert_eq!(play_fixture("single_note.mid"), ["0.000 +a", "0.500 -a"]);
+}
+
+// 同时起音的和弦从高音到低音依次按下
+#[test]
+fn chord_presses_together() {
+    assert_eq!(
+        play_fixture("chord.mid"),
+        [
+            "0.000 +g", "0.000 +d", "0.000 +a", "0.500 -g", "0.500 -d", "0.500 -a", "0.500 +s",
+            "1.000 -s"
+        ]
+    );
+}
+
+// 第 2 拍起每拍 1 秒
+#[test]
+fn tempo_change_stretches_later_notes() {
+    assert_eq!(
+        play_fixture("tempo_change.mid"),
+        ["0.000 +a", "0.500 -a", "0.500 +s", "1.500 -s", "1.500 +d", "2.500 -d"]
+    );
+}
+
+// 踏板不延长按键：每个音在自己的止音处松开
+#[test]
+fn sustain_pedal_does_not_extend_notes() {
+    assert_eq!(
+        play_fixture("sustain_pedal.mid"),
+        ["0.000 +a", "0.250 -a", "0.500 +s", "0.750 -s"]
+    );
+}
+
+#[test]
+fn out_of_range_notes_are_counted_and_not_played() {
+    let analysis = analyze("out_of_range.mid", &AnalyzerOptions::default());
+    assert_eq!(analysis.analysis.under_min_count, 1);
+    assert_eq!(analysis.analysis.over_max_count, 1);
+    assert_eq!(play_fixture("out_of_range.mid"), ["0.000 +a", "0.500 -a"]);
+}
+
+fn gated(gate: f64, min_hold_ms: f64, max_hold_ms: Option<f64>) -> PlaybackOptions {
+    let mut options = PlaybackOptions::default();
+    options.settings.gate = gate;
+    options.settings.min_hold_ms = min_hold_ms;
+    options.settings.max_hold_ms = max_hold_ms;
+    options
+}
+
+// 按住时长按 gate 缩放
+#[test]
+fn gate_scales_durations() {
+    assert_eq!(
+        play(key_events("tempo_change.mid"), gated(0.6, 50.0, None)),
+        ["0.000 +a", "0.300 -a", "0.500 +s", "1.100 -s", "1.500 +d", "2.100 -d"]
+    );
+}
+
+// 缩放之后再按最长按住时长截断
+#[test]
+fn gate_applies_before_max_hold() {
+    assert_eq!(
+        play(
+            key_events("tempo_change.mid"),
+            gated(0.6, 50.0, Some(400.0))
+        ),
+        ["0.000 +a", "0.300 -a", "0.500 +s", "0.900 -s", "1.500 +d", "1.900 -d"]
+    );
+}
+
+// 缩放之后再补足最短按住时长
+#[test]
+fn gate_applies_before_min_hold() {
+    assert_eq!(
+        play(key_events("repeated_note.mid"), gated(0.6, 350.0, None)),
+        ["0.000 +a", "0.350 -a", "0.500 +a", "0.850 -a", "1.000 +s", "1.350 -s"]
+    );
+}
+
+// 连按同一个键时在下一次按下之前松开
+#[test]
+fn gate_never_extends_past_the_next_same_key_onset() {
+    assert_eq!(
+        play(key_events("repeated_note.mid"), gated(1.0, 50.0, None)),
+        ["0.000 +a", "0.500 -a", "0.500 +a", "1.000 -a", "1.000 +s", "1.500 -s"]
+    );
+}
@@ -73,7 +73,9 @@ export function getControlKeyDisplayName(action: string): string {
     'START_PAUSE': '开始/暂停',
     'STOP': '停止',
     'PREV_SONG': '上一曲',
-    'NEXT_SONG': '下一曲'
+    'NEXT_SONG': '下一曲',
+    'LIVE_OCTAVE_UP': '实时输入升八度',
+    'LIVE_OCTAVE_DOWN': '实时输入降八度'
   };
   return displayNames[action] || action;
 }
\ No newline at end of file
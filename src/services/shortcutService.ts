@@ -6,6 +6,9 @@ export interface ShortcutHandlers {
   onStop: () => void;
   onPrevSong: () => void;
   onNextSong: () => void;
+  // 实时 MIDI 输入升高/降低一个八度，可选
+  onLiveOctaveUp?: () => void;
+  onLiveOctaveDown?: () => void;
 }
 
 class ShortcutService {
@@ -61,6 +64,23 @@ class ShortcutService {
         );
       }
 
+      // 实时输入的八度快捷键默认不设置
+      if (shortcuts.LIVE_OCTAVE_UP && handlers.onLiveOctaveUp) {
+        await this.registerSingleShortcut(
+          shortcuts.LIVE_OCTAVE_UP,
+          'LIVE_OCTAVE_UP',
+          handlers.onLiveOctaveUp
+        );
+      }
+
+      if (shortcuts.LIVE_OCTAVE_DOWN && handlers.onLiveOctaveDown) {
+        await this.registerSingleShortcut(
+          shortcuts.LIVE_OCTAVE_DOWN,
+          'LIVE_OCTAVE_DOWN',
+          handlers.onLiveOctaveDown
+        );
+      }
+
       info(`[ShortcutService] 成功注册 ${this.registeredShortcuts.length} 个全局快捷键`);
     } catch (err) {
       error(`[ShortcutService] 注册快捷键失败: ${err}`);
@@ -28,6 +28,17 @@ interface AppSettings {
     maxNote: number;
     blackKeyMode: string;
     trimLongNotes: boolean;
+    // 和弦判定：窗口为毫秒或拍数，固定窗口或按间隔连续归组，缺省时由后端使用 20ms 固定窗口
+    chordGrouping?: {
+      window: { unit: 'ms' | 'beats'; value: number };
+      strategy: 'fixed_window' | 'gap_clustering';
+    };
+    // 乐句边界的换气间隙（秒），keep_length 为 false 时之后的音符整体后移
+    phraseGap?: { seconds: number; keep_length: boolean };
+    // 同一个键每秒最多按下的次数，超出的震音、滚奏会被抽稀
+    maxSameKeyRate?: number;
+    // 把孤立的需要修饰键的音移八度到不需要修饰键的键上，替换记录在解析结果的 warnings 中
+    reduceModifierChurn?: boolean;
   };
   simulationSettings?: {
     simulationType: 'keyboard' | 'mouse';